@@ -0,0 +1,409 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+}
+
+impl Encoding {
+    pub fn bom(self) -> &'static [u8] {
+        match self {
+            Encoding::Utf8 => b"\xEF\xBB\xBF",
+            Encoding::Utf16Le => b"\xFF\xFE",
+            Encoding::Utf16Be => b"\xFE\xFF",
+        }
+    }
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Encoding::Utf8 => write!(f, "UTF-8"),
+            Encoding::Utf16Le => write!(f, "UTF-16LE"),
+            Encoding::Utf16Be => write!(f, "UTF-16BE"),
+        }
+    }
+}
+
+// Offsets are of bytes in the undecoded input, counting any byte order mark.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    // The bytes at `offset` aren't a character in `encoding`.
+    Invalid { encoding: Encoding, offset: usize },
+    // The input ended part way through the character at `offset`.
+    Truncated { encoding: Encoding, offset: usize },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::Invalid { encoding, offset } => {
+                write!(f, "invalid {} at byte {}", encoding, offset)
+            }
+            DecodeError::Truncated { encoding, offset } => {
+                write!(f, "incomplete {} character at byte {}", encoding, offset)
+            }
+        }
+    }
+}
+
+impl Error for DecodeError {}
+
+impl From<DecodeError> for io::Error {
+    fn from(error: DecodeError) -> io::Error {
+        let kind = match error {
+            DecodeError::Invalid { .. } => io::ErrorKind::InvalidData,
+            DecodeError::Truncated { .. } => io::ErrorKind::UnexpectedEof,
+        };
+        io::Error::new(kind, error)
+    }
+}
+
+// Decodes bytes fed to it in chunks of any size into UTF-8. Unless told the encoding, it is taken
+// from the byte order mark, or is UTF-8 if there isn't one. The mark itself is never decoded.
+//
+// Lexers count positions in bytes of the decoded UTF-8, which for UTF-16 or after a byte order
+// mark aren't the positions in the input. `source_offset` maps them back, from a record of where
+// runs of characters of the same width in both encodings start.
+#[derive(Debug, Clone, Default)]
+pub struct Decoder {
+    encoding: Option<Encoding>,
+    // Whether `encoding` was given rather than detected.
+    forced: bool,
+    bom: usize,
+    // Bytes read but not yet decoded, starting at `consumed` in the input.
+    pending: Vec<u8>,
+    consumed: usize,
+    decoded: usize,
+    runs: Vec<Run>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Run {
+    decoded: usize,
+    source: usize,
+    decoded_width: usize,
+    source_width: usize,
+}
+
+impl Decoder {
+    pub fn new() -> Decoder {
+        Self::default()
+    }
+
+    // Decode as `encoding` whatever the input starts with. Its own byte order mark is still
+    // dropped.
+    pub fn with_encoding(encoding: Encoding) -> Decoder {
+        Decoder {
+            encoding: Some(encoding),
+            forced: true,
+            ..Self::default()
+        }
+    }
+
+    // The encoding being decoded, or `None` until enough of the input has been seen to tell.
+    pub fn encoding(&self) -> Option<Encoding> {
+        self.encoding
+    }
+
+    // The length of the byte order mark the input started with, or 0 if it had none.
+    pub fn bom_len(&self) -> usize {
+        self.bom
+    }
+
+    // Decode as much of `bytes` as possible onto `out`, keeping a character split at the end
+    // until the rest of it arrives.
+    pub fn decode(&mut self, bytes: &[u8], out: &mut String) -> Result<(), DecodeError> {
+        self.pending.extend_from_slice(bytes);
+        self.decode_pending(out, false)
+    }
+
+    // Decode whatever is left now that the input has ended.
+    pub fn finish(&mut self, out: &mut String) -> Result<(), DecodeError> {
+        self.decode_pending(out, true)?;
+
+        match self.pending.is_empty() {
+            true => Ok(()),
+            false => Err(DecodeError::Truncated {
+                encoding: self.encoding.unwrap(),
+                offset: self.consumed,
+            }),
+        }
+    }
+
+    // Where the character at byte `offset` of the decoded text starts in the input.
+    pub fn source_offset(&self, offset: usize) -> usize {
+        let i = self.runs.partition_point(|run| run.decoded <= offset);
+        let Some(run) = i.checked_sub(1).map(|i| self.runs[i]) else {
+            return self.bom + offset;
+        };
+
+        run.source + (offset - run.decoded) / run.decoded_width * run.source_width
+    }
+
+    fn decode_pending(&mut self, out: &mut String, end: bool) -> Result<(), DecodeError> {
+        if !self.read_bom(end) {
+            return Ok(());
+        }
+
+        match self.encoding.unwrap() {
+            Encoding::Utf8 => self.decode_utf8(out),
+            Encoding::Utf16Le => self.decode_utf16(out, u16::from_le_bytes),
+            Encoding::Utf16Be => self.decode_utf16(out, u16::from_be_bytes),
+        }
+    }
+
+    // Drop the byte order mark, settling the encoding if it wasn't given. Returns false if too
+    // little of the input has been seen to tell whether there is one.
+    fn read_bom(&mut self, end: bool) -> bool {
+        if self.consumed > 0 || (self.encoding.is_some() && !self.forced) {
+            return true;
+        }
+
+        let candidates = match self.encoding {
+            Some(encoding) => vec![encoding],
+            None => vec![Encoding::Utf8, Encoding::Utf16Le, Encoding::Utf16Be],
+        };
+
+        for &encoding in &candidates {
+            let bom = encoding.bom();
+            if self.pending.starts_with(bom) {
+                self.encoding = Some(encoding);
+                self.bom = bom.len();
+                self.consumed = bom.len();
+                self.pending.drain(..bom.len());
+                self.forced = false;
+                return true;
+            }
+            if !end && bom.starts_with(&self.pending) {
+                return false;
+            }
+        }
+
+        self.encoding.get_or_insert(Encoding::Utf8);
+        self.forced = false;
+        true
+    }
+
+    fn decode_utf8(&mut self, out: &mut String) -> Result<(), DecodeError> {
+        let valid = match std::str::from_utf8(&self.pending) {
+            Ok(s) => s.len(),
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(e) => {
+                return Err(DecodeError::Invalid {
+                    encoding: Encoding::Utf8,
+                    offset: self.consumed + e.valid_up_to(),
+                })
+            }
+        };
+
+        out.push_str(std::str::from_utf8(&self.pending[..valid]).unwrap());
+        self.pending.drain(..valid);
+        self.consumed += valid;
+        self.decoded += valid;
+        Ok(())
+    }
+
+    fn decode_utf16(
+        &mut self,
+        out: &mut String,
+        unit: fn([u8; 2]) -> u16,
+    ) -> Result<(), DecodeError> {
+        let encoding = self.encoding.unwrap();
+        let units = self
+            .pending
+            .chunks_exact(2)
+            .map(|pair| unit([pair[0], pair[1]]))
+            .collect::<Vec<_>>();
+
+        let mut read = 0;
+        for c in char::decode_utf16(units.iter().copied()) {
+            let c = match c {
+                Ok(c) => c,
+                // A high surrogate at the end may yet be followed by its low half.
+                Err(e)
+                    if read + 1 == units.len()
+                        && (0xD800..0xDC00).contains(&e.unpaired_surrogate()) =>
+                {
+                    break
+                }
+                Err(_) => {
+                    return Err(DecodeError::Invalid {
+                        encoding,
+                        offset: self.consumed + read * 2,
+                    })
+                }
+            };
+
+            let (decoded_width, source_width) = (c.len_utf8(), c.len_utf16() * 2);
+            if self.runs.last().is_none_or(|run| {
+                (run.decoded_width, run.source_width) != (decoded_width, source_width)
+            }) {
+                self.runs.push(Run {
+                    decoded: self.decoded,
+                    source: self.consumed + read * 2,
+                    decoded_width,
+                    source_width,
+                });
+            }
+
+            out.push(c);
+            read += c.len_utf16();
+            self.decoded += decoded_width;
+        }
+
+        self.pending.drain(..read * 2);
+        self.consumed += read * 2;
+        Ok(())
+    }
+}
+
+// Decode the whole of `bytes`, returning the text and the decoder for mapping offsets in it back
+// to `bytes`.
+pub fn decode(bytes: &[u8]) -> Result<(String, Decoder), DecodeError> {
+    let mut decoder = Decoder::new();
+    let mut text = String::new();
+
+    decoder.decode(bytes, &mut text)?;
+    decoder.finish(&mut text)?;
+
+    Ok((text, decoder))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn utf16(text: &str, encoding: Encoding, bom: bool) -> Vec<u8> {
+        let mut bytes = match bom {
+            true => encoding.bom().to_vec(),
+            false => vec![],
+        };
+        for unit in text.encode_utf16() {
+            match encoding {
+                Encoding::Utf16Le => bytes.extend(unit.to_le_bytes()),
+                _ => bytes.extend(unit.to_be_bytes()),
+            }
+        }
+        bytes
+    }
+
+    #[test]
+    fn test_detect() {
+        let (text, decoder) = decode(b"(a)").unwrap();
+        assert_eq!(text, "(a)");
+        assert_eq!(decoder.encoding(), Some(Encoding::Utf8));
+        assert_eq!(decoder.bom_len(), 0);
+
+        let (text, decoder) = decode(b"\xEF\xBB\xBF(a)").unwrap();
+        assert_eq!(text, "(a)");
+        assert_eq!(decoder.bom_len(), 3);
+
+        for encoding in [Encoding::Utf16Le, Encoding::Utf16Be] {
+            let (text, decoder) = decode(&utf16("(λ 𝔸)", encoding, true)).unwrap();
+            assert_eq!(text, "(λ 𝔸)");
+            assert_eq!(decoder.encoding(), Some(encoding));
+            assert_eq!(decoder.bom_len(), 2);
+        }
+
+        // Part of a byte order mark is part of a UTF-8 character.
+        assert_eq!(
+            decode(b"\xEF\xBB").unwrap_err(),
+            DecodeError::Truncated {
+                encoding: Encoding::Utf8,
+                offset: 0
+            }
+        );
+        assert_eq!(decode(b"").unwrap().0, "");
+
+        // Without a byte order mark UTF-16 has to be asked for.
+        let bytes = utf16("(a)", Encoding::Utf16Be, false);
+        let mut decoder = Decoder::with_encoding(Encoding::Utf16Be);
+        let mut text = String::new();
+        decoder.decode(&bytes, &mut text).unwrap();
+        decoder.finish(&mut text).unwrap();
+        assert_eq!(text, "(a)");
+        assert_eq!(decoder.bom_len(), 0);
+    }
+
+    #[test]
+    fn test_chunks() {
+        for encoding in [Encoding::Utf16Le, Encoding::Utf16Be] {
+            let bytes = utf16("a𝔸λ\n", encoding, true);
+            let mut decoder = Decoder::new();
+            let mut text = String::new();
+            for &byte in &bytes {
+                decoder.decode(&[byte], &mut text).unwrap();
+            }
+            decoder.finish(&mut text).unwrap();
+            assert_eq!(text, "a𝔸λ\n");
+        }
+
+        let bytes = "\u{feff}a𝔸λ".as_bytes();
+        let mut decoder = Decoder::new();
+        let mut text = String::new();
+        for &byte in bytes {
+            decoder.decode(&[byte], &mut text).unwrap();
+        }
+        decoder.finish(&mut text).unwrap();
+        assert_eq!(text, "a𝔸λ");
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            decode(b"ab\xFFc").unwrap_err(),
+            DecodeError::Invalid {
+                encoding: Encoding::Utf8,
+                offset: 2
+            }
+        );
+        assert_eq!(
+            decode(b"\xEF\xBB\xBFab\xCE").unwrap_err(),
+            DecodeError::Truncated {
+                encoding: Encoding::Utf8,
+                offset: 5
+            }
+        );
+
+        // A lone low surrogate, and a high one with nothing after it.
+        assert_eq!(
+            decode(b"\xFF\xFEa\x00\x00\xDC").unwrap_err(),
+            DecodeError::Invalid {
+                encoding: Encoding::Utf16Le,
+                offset: 4
+            }
+        );
+        assert_eq!(
+            decode(b"\xFE\xFF\x00a\xD8\x35").unwrap_err(),
+            DecodeError::Truncated {
+                encoding: Encoding::Utf16Be,
+                offset: 4
+            }
+        );
+        assert_eq!(
+            decode(b"\xFE\xFF\x00a\x00").unwrap_err().to_string(),
+            "incomplete UTF-16BE character at byte 4"
+        );
+    }
+
+    #[test]
+    fn test_source_offset() {
+        let (text, decoder) = decode(&utf16("ab λλ𝔸c", Encoding::Utf16Le, true)).unwrap();
+
+        let offsets = text
+            .char_indices()
+            .map(|(i, _)| decoder.source_offset(i))
+            .collect::<Vec<_>>();
+        assert_eq!(offsets, vec![2, 4, 6, 8, 10, 12, 16]);
+        assert_eq!(decoder.source_offset(text.len()), 18);
+
+        let (text, decoder) = decode("\u{feff}aλb".as_bytes()).unwrap();
+        assert_eq!(text, "aλb");
+        assert_eq!(decoder.source_offset(3), 6);
+    }
+}
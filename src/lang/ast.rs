@@ -0,0 +1,1128 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::Arc;
+
+use num::{BigInt, BigRational};
+
+use crate::lang::symbol::SymbolTable;
+use crate::lex::lexer::Span;
+
+// Nodes are referred to by their index in the `Ast` they were created in.
+pub type AstRef = u64;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AstNode {
+    Nil,
+    Pair(AstRef, AstRef),
+    // A name interned in the global `SymbolTable`.
+    Symbol(u64),
+    Bool(bool),
+    Integer(BigInt),
+    Rational(BigRational),
+    Float(f64),
+    String(String),
+    Char(char),
+    Vector(Vec<AstRef>),
+    Bytes(Vec<u8>),
+}
+
+// The characters with names, written like `#\newline`, other than as themselves.
+pub(crate) const CHAR_NAMES: &[(&str, char)] = &[
+    ("alarm", '\x07'),
+    ("backspace", '\x08'),
+    ("delete", '\x7f'),
+    ("escape", '\x1b'),
+    ("newline", '\n'),
+    ("null", '\0'),
+    ("return", '\r'),
+    ("space", ' '),
+    ("tab", '\t'),
+];
+
+// Which source file a node was read from, numbered by whatever is reading them.
+pub type FileId = u32;
+
+// Where a node was read from. Lines and columns count from 1, and columns are in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyntaxInfo {
+    pub file: FileId,
+    pub span: Span,
+    pub line: usize,
+    pub column: usize,
+}
+
+// Where a node made by a transformation came from: the node it was made from, and the macro or
+// pass that made it, if it has a name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Origin {
+    pub from: AstRef,
+    pub by: Option<Arc<str>>,
+}
+
+// The forms of a program as a graph of nodes, where lists are chains of pairs ending in `Nil`.
+// Nodes are only removed by `collect_garbage`, so a `AstRef` stays valid until a collection that
+// can't reach it, after which its slot may be reused. Nodes can be shared, and pairs can form
+// cycles. Nodes can have `SyntaxInfo`, which the reader gives every node it creates, and an
+// `Origin`, which transformations give the nodes they make.
+//
+// The root is a list of the program's top-level forms, which the reader adds each form it reads
+// to.
+#[derive(Debug, Clone, Default)]
+pub struct Ast {
+    // `None` for a slot freed by a collection.
+    pub(crate) nodes: Vec<Option<AstNode>>,
+    pub(crate) syntax: Vec<Option<SyntaxInfo>>,
+    origins: HashMap<AstRef, Origin>,
+    pub(crate) free: Vec<AstRef>,
+    pub(crate) root: Option<AstRef>,
+    // The last pair of the root list, once `add_root` has found it.
+    pub(crate) root_last: Option<AstRef>,
+    // The changes made since the first checkpoint, and where in them each checkpoint starts.
+    history: Vec<Edit>,
+    checkpoints: Vec<usize>,
+    // What was allocated under each stage named, and which is counting now.
+    stages: Vec<StageStats>,
+    stage: Option<usize>,
+}
+
+// A change to an `Ast`, holding what is needed to undo it.
+#[derive(Debug, Clone)]
+enum Edit {
+    // A slot was added to the end.
+    Push,
+    // A slot's node and syntax were replaced, and these are what it had.
+    Slot(AstRef, Option<AstNode>, Option<SyntaxInfo>),
+    Syntax(AstRef, Option<SyntaxInfo>),
+    Origin(AstRef, Option<Origin>),
+    // A freed slot was taken for a new node.
+    Reuse(AstRef),
+    // A slot was freed by a collection.
+    Free(AstRef),
+    Root(Option<AstRef>),
+}
+
+// What a `collect_garbage` found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct GcStats {
+    // Nodes reachable from the roots, which were kept.
+    pub live: usize,
+    // Nodes that couldn't be reached, which were removed.
+    pub reclaimed: usize,
+    // Slots free for new nodes after the collection, including ones freed by earlier collections.
+    pub free: usize,
+}
+
+// What was allocated for the nodes created while `Ast::set_stage` named a stage, e.g. a pass. Bytes
+// are those of the slots the nodes were added in, unless they reused ones freed by a collection,
+// and roughly those their values hold on the heap, like the text of a string.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct StageStats {
+    pub name: Arc<str>,
+    pub nodes: usize,
+    pub bytes: usize,
+}
+
+impl Ast {
+    pub fn new() -> Ast {
+        Self::default()
+    }
+
+    // The number of nodes, not counting those removed by collections.
+    pub fn len(&self) -> usize {
+        self.nodes.len() - self.free.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn add(&mut self, node: AstNode) -> AstRef {
+        if let Some(stage) = self.stage {
+            let slot = match self.free.is_empty() {
+                true => size_of::<Option<AstNode>>() + size_of::<Option<SyntaxInfo>>(),
+                false => 0,
+            };
+            self.stages[stage].nodes += 1;
+            self.stages[stage].bytes += slot + heap_bytes(&node);
+        }
+
+        if let Some(id) = self.free.pop() {
+            self.record(Edit::Reuse(id));
+            self.replace_slot(id, Some(node), None);
+            return id;
+        }
+
+        self.record(Edit::Push);
+        self.nodes.push(Some(node));
+        self.syntax.push(None);
+        (self.nodes.len() - 1) as AstRef
+    }
+
+    pub fn get(&self, id: AstRef) -> &AstNode {
+        match &self.nodes[id as usize] {
+            Some(node) => node,
+            None => panic!("AstRef {} refers to a collected node", id),
+        }
+    }
+
+    pub fn set_syntax(&mut self, id: AstRef, syntax: SyntaxInfo) {
+        let old = self.syntax[id as usize].replace(syntax);
+        self.record(Edit::Syntax(id, old));
+    }
+
+    pub fn get_syntax(&self, id: AstRef) -> Option<&SyntaxInfo> {
+        self.syntax[id as usize].as_ref()
+    }
+
+    pub fn set_origin(&mut self, id: AstRef, origin: Origin) {
+        let old = self.origins.insert(id, origin);
+        self.record(Edit::Origin(id, old));
+    }
+
+    pub fn get_origin(&self, id: AstRef) -> Option<&Origin> {
+        self.origins.get(&id)
+    }
+
+    // The origin of `id`, then of the node it came from, and so on back to a node with none, which
+    // is usually one the reader made from what was written.
+    pub fn origin_chain(&self, id: AstRef) -> Vec<&Origin> {
+        let mut chain = vec![];
+        let mut seen = HashSet::from([id]);
+        let mut id = id;
+        while let Some(origin) = self.origins.get(&id) {
+            chain.push(origin);
+            if !seen.insert(origin.from) {
+                break;
+            }
+            id = origin.from;
+        }
+        chain
+    }
+
+    // Replace the node `id` refers to, e.g. to tie a cycle back to a node created before it.
+    pub fn set(&mut self, id: AstRef, node: AstNode) {
+        assert!(
+            self.nodes[id as usize].is_some(),
+            "AstRef {} refers to a collected node",
+            id
+        );
+        // A pair or the `()` ending it could be on the root list, whose end `add_root` then finds
+        // again.
+        let syntax = self.syntax[id as usize];
+        let old = self.replace_slot(id, Some(node), syntax);
+        if matches!(old, Some(AstNode::Pair(..) | AstNode::Nil))
+            || matches!(self.nodes[id as usize], Some(AstNode::Pair(..)))
+        {
+            self.root_last = None;
+        }
+    }
+
+    // Remove every node that can't be reached from `roots` or the root list, freeing their slots
+    // for new nodes. Any `AstRef` to a removed node is invalid afterwards.
+    pub fn collect_garbage(&mut self, roots: &[AstRef]) -> GcStats {
+        let mut marked = vec![false; self.nodes.len()];
+        let mut stack = roots.iter().copied().chain(self.root).collect::<Vec<_>>();
+        while let Some(id) = stack.pop() {
+            if std::mem::replace(&mut marked[id as usize], true) {
+                continue;
+            }
+            self.push_children(id, &mut stack);
+        }
+
+        let mut stats = GcStats::default();
+        for (id, marked) in marked.into_iter().enumerate() {
+            if marked {
+                stats.live += 1;
+            } else if self.nodes[id].is_some() {
+                if let Some(origin) = self.origins.remove(&(id as AstRef)) {
+                    self.record(Edit::Origin(id as AstRef, Some(origin)));
+                }
+                self.replace_slot(id as AstRef, None, None);
+                self.record(Edit::Free(id as AstRef));
+                self.free.push(id as AstRef);
+                stats.reclaimed += 1;
+            }
+        }
+        stats.free = self.free.len();
+        stats
+    }
+
+    // Count the nodes created from now on under the stage `name`, adding to what was counted under
+    // it before, or stop counting if `name` is `None`.
+    pub fn set_stage(&mut self, name: Option<&str>) {
+        self.stage =
+            name.map(
+                |name| match self.stages.iter().position(|stage| &*stage.name == name) {
+                    Some(stage) => stage,
+                    None => {
+                        self.stages.push(StageStats {
+                            name: name.into(),
+                            ..StageStats::default()
+                        });
+                        self.stages.len() - 1
+                    }
+                },
+            );
+    }
+
+    pub fn stage(&self) -> Option<&str> {
+        self.stage.map(|stage| &*self.stages[stage].name)
+    }
+
+    // Each stage named so far, in the order they were first named.
+    pub fn stage_stats(&self) -> &[StageStats] {
+        &self.stages
+    }
+
+    pub fn root(&self) -> Option<AstRef> {
+        self.root
+    }
+
+    pub fn set_root(&mut self, root: AstRef) {
+        self.record(Edit::Root(self.root));
+        self.root = Some(root);
+        self.root_last = None;
+    }
+
+    // Add `form` to the end of the root list, creating the list if there isn't one or the root is
+    // the empty list. Returns the pair added.
+    pub fn add_root(&mut self, form: AstRef) -> AstRef {
+        let root = match self.root {
+            Some(root) if *self.get(root) != AstNode::Nil => root,
+            root => {
+                let nil = match root {
+                    Some(nil) => nil,
+                    None => self.create_nil(),
+                };
+                let pair = self.create_pair(form, nil);
+                self.record(Edit::Root(self.root));
+                self.root = Some(pair);
+                self.root_last = Some(pair);
+                return pair;
+            }
+        };
+
+        let last = match self.root_last {
+            Some(last) => last,
+            None => {
+                let mut last = root;
+                while let Some((_, tail)) = self.get_pair(last) {
+                    match self.get_pair(tail) {
+                        Some(_) => last = tail,
+                        None => break,
+                    }
+                }
+                last
+            }
+        };
+
+        let Some((_, end)) = self.get_pair(last) else {
+            panic!("the root isn't a list");
+        };
+        let pair = self.create_pair(form, end);
+        self.set_tail(last, pair);
+        self.root_last = Some(pair);
+        pair
+    }
+
+    pub fn create_nil(&mut self) -> AstRef {
+        self.add(AstNode::Nil)
+    }
+
+    pub fn create_pair(&mut self, head: AstRef, tail: AstRef) -> AstRef {
+        self.add(AstNode::Pair(head, tail))
+    }
+
+    pub fn create_symbol(&mut self, name: &str) -> AstRef {
+        self.add(AstNode::Symbol(SymbolTable::global().intern(name)))
+    }
+
+    pub fn create_integer(&mut self, value: BigInt) -> AstRef {
+        self.add(AstNode::Integer(value))
+    }
+
+    pub fn create_float(&mut self, value: f64) -> AstRef {
+        self.add(AstNode::Float(value))
+    }
+
+    pub fn create_string(&mut self, value: &str) -> AstRef {
+        self.add(AstNode::String(value.to_string()))
+    }
+
+    pub fn create_char(&mut self, value: char) -> AstRef {
+        self.add(AstNode::Char(value))
+    }
+
+    pub fn create_bool(&mut self, value: bool) -> AstRef {
+        self.add(AstNode::Bool(value))
+    }
+
+    pub fn create_rational(&mut self, value: BigRational) -> AstRef {
+        self.add(AstNode::Rational(value))
+    }
+
+    pub fn create_vector(&mut self, elements: &[AstRef]) -> AstRef {
+        self.add(AstNode::Vector(elements.to_vec()))
+    }
+
+    pub fn create_bytes(&mut self, value: &[u8]) -> AstRef {
+        self.add(AstNode::Bytes(value.to_vec()))
+    }
+
+    // A proper list of `elements`.
+    pub fn create_list(&mut self, elements: &[AstRef]) -> AstRef {
+        let nil = self.create_nil();
+        self.create_dotted_list(elements, nil)
+    }
+
+    // A list of `elements` ending in `tail` rather than `()`, unless `tail` is a list itself.
+    pub fn create_dotted_list(&mut self, elements: &[AstRef], tail: AstRef) -> AstRef {
+        let mut list = tail;
+        for &element in elements.iter().rev() {
+            list = self.create_pair(element, list);
+        }
+        list
+    }
+
+    // The elements of the list `id`, then `tail` tells what the list ended in. A cyclic list never
+    // ends.
+    pub fn list_iter(&self, id: AstRef) -> ListIter<'_> {
+        ListIter { ast: self, id }
+    }
+
+    // The number of elements in `id`, if it is a proper list: one ending in `()` rather than being
+    // dotted or cyclic.
+    pub fn list_len(&self, id: AstRef) -> Option<usize> {
+        // The hare moves two pairs for each of the tortoise's, so meets it if the list is cyclic.
+        let mut len = 0;
+        let mut hare = id;
+        let mut tortoise = id;
+        loop {
+            for _ in 0..2 {
+                match self.get(hare) {
+                    AstNode::Nil => return Some(len),
+                    AstNode::Pair(_, tail) => hare = *tail,
+                    _ => return None,
+                }
+                len += 1;
+            }
+
+            tortoise = self.get_pair(tortoise).unwrap().1;
+            if tortoise == hare {
+                return None;
+            }
+        }
+    }
+
+    pub fn is_list(&self, id: AstRef) -> bool {
+        self.list_len(id).is_some()
+    }
+
+    // A list with at least one pair that ends in something other than `()`, like `(a b . c)`.
+    pub fn is_dotted_list(&self, id: AstRef) -> bool {
+        if self.get_pair(id).is_none() {
+            return false;
+        }
+
+        let mut iter = self.list_iter(id);
+        let mut seen = HashSet::new();
+        while seen.insert(iter.id) && iter.next().is_some() {}
+        self.get_pair(iter.tail()).is_none() && *self.get(iter.tail()) != AstNode::Nil
+    }
+
+    pub fn get_pair(&self, id: AstRef) -> Option<(AstRef, AstRef)> {
+        match *self.get(id) {
+            AstNode::Pair(head, tail) => Some((head, tail)),
+            _ => None,
+        }
+    }
+
+    // Replace the head of the pair `id`, leaving its tail. Panics if `id` isn't a pair.
+    pub fn set_head(&mut self, id: AstRef, head: AstRef) {
+        match self.get_pair(id) {
+            Some((_, tail)) => self.set(id, AstNode::Pair(head, tail)),
+            None => panic!("AstRef {} isn't a pair", id),
+        }
+    }
+
+    // Replace the tail of the pair `id`, leaving its head. Panics if `id` isn't a pair.
+    pub fn set_tail(&mut self, id: AstRef, tail: AstRef) {
+        match self.get_pair(id) {
+            Some((head, _)) => self.set(id, AstNode::Pair(head, tail)),
+            None => panic!("AstRef {} isn't a pair", id),
+        }
+    }
+
+    pub fn get_symbol(&self, id: AstRef) -> Option<Arc<str>> {
+        self.get_symbol_id(id)
+            .map(|symbol| SymbolTable::global().resolve(symbol))
+    }
+
+    // The interned number of the symbol `id`, which is the same for every symbol with its name.
+    pub fn get_symbol_id(&self, id: AstRef) -> Option<u64> {
+        match *self.get(id) {
+            AstNode::Symbol(symbol) => Some(symbol),
+            _ => None,
+        }
+    }
+
+    pub fn get_bool(&self, id: AstRef) -> Option<bool> {
+        match *self.get(id) {
+            AstNode::Bool(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn get_rational(&self, id: AstRef) -> Option<&BigRational> {
+        match self.get(id) {
+            AstNode::Rational(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn get_vector(&self, id: AstRef) -> Option<&[AstRef]> {
+        match self.get(id) {
+            AstNode::Vector(elements) => Some(elements),
+            _ => None,
+        }
+    }
+
+    pub fn get_bytes(&self, id: AstRef) -> Option<&[u8]> {
+        match self.get(id) {
+            AstNode::Bytes(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn is_bool(&self, id: AstRef) -> bool {
+        self.get_bool(id).is_some()
+    }
+
+    pub fn is_rational(&self, id: AstRef) -> bool {
+        self.get_rational(id).is_some()
+    }
+
+    pub fn is_vector(&self, id: AstRef) -> bool {
+        self.get_vector(id).is_some()
+    }
+
+    pub fn is_bytes(&self, id: AstRef) -> bool {
+        self.get_bytes(id).is_some()
+    }
+
+    // Start recording changes, so that `undo` can put the `Ast` back as it is now. Checkpoints
+    // nest: each `undo` goes back to the latest one left.
+    pub fn checkpoint(&mut self) {
+        self.checkpoints.push(self.history.len());
+    }
+
+    // Undo every change since the latest checkpoint and remove it. Returns whether there was one.
+    // `AstRef`s to nodes created since then are invalid afterwards.
+    pub fn undo(&mut self) -> bool {
+        let Some(start) = self.checkpoints.pop() else {
+            return false;
+        };
+
+        for edit in self.history.drain(start..).rev() {
+            match edit {
+                Edit::Push => {
+                    self.nodes.pop();
+                    self.syntax.pop();
+                }
+                Edit::Slot(id, node, syntax) => {
+                    self.nodes[id as usize] = node;
+                    self.syntax[id as usize] = syntax;
+                }
+                Edit::Syntax(id, syntax) => self.syntax[id as usize] = syntax,
+                Edit::Origin(id, Some(origin)) => {
+                    self.origins.insert(id, origin);
+                }
+                Edit::Origin(id, None) => {
+                    self.origins.remove(&id);
+                }
+                Edit::Reuse(id) => self.free.push(id),
+                Edit::Free(id) => {
+                    let freed = self.free.pop();
+                    debug_assert_eq!(freed, Some(id));
+                }
+                Edit::Root(root) => self.root = root,
+            }
+        }
+        self.root_last = None;
+        true
+    }
+
+    // Keep every change since the latest checkpoint and remove it, so that an `undo` goes back to
+    // the one before it instead. Returns whether there was one.
+    pub fn commit(&mut self) -> bool {
+        if self.checkpoints.pop().is_none() {
+            return false;
+        }
+        if self.checkpoints.is_empty() {
+            self.history.clear();
+        }
+        true
+    }
+
+    // The number of checkpoints left to undo.
+    pub fn checkpoints(&self) -> usize {
+        self.checkpoints.len()
+    }
+
+    // Changes are only kept while there is a checkpoint to undo them to.
+    fn record(&mut self, edit: Edit) {
+        if !self.checkpoints.is_empty() {
+            self.history.push(edit);
+        }
+    }
+
+    fn replace_slot(
+        &mut self,
+        id: AstRef,
+        node: Option<AstNode>,
+        syntax: Option<SyntaxInfo>,
+    ) -> Option<AstNode> {
+        let old_node = std::mem::replace(&mut self.nodes[id as usize], node);
+        let old_syntax = std::mem::replace(&mut self.syntax[id as usize], syntax);
+        if !self.checkpoints.is_empty() {
+            self.history
+                .push(Edit::Slot(id, old_node.clone(), old_syntax));
+        }
+        old_node
+    }
+
+    // Push the nodes `id` refers to onto `stack`, last first, so that they're popped in order.
+    pub(crate) fn push_children(&self, id: AstRef, stack: &mut Vec<AstRef>) {
+        match self.get(id) {
+            &AstNode::Pair(head, tail) => stack.extend([tail, head]),
+            AstNode::Vector(elements) => stack.extend(elements.iter().rev()),
+            _ => {}
+        }
+    }
+
+    // `id` written out as it would be read, e.g. `(define (f x) "x\n")`. Pairs and vectors reached
+    // more than once are labelled where first written, like `#0=(a . #0#)`.
+    pub fn display(&self, id: AstRef) -> AstDisplay<'_> {
+        AstDisplay { ast: self, id }
+    }
+}
+
+// Roughly what `node` holds on the heap, outside its slot.
+fn heap_bytes(node: &AstNode) -> usize {
+    let digits = |value: &BigInt| value.bits().div_ceil(64) as usize * size_of::<u64>();
+    match node {
+        AstNode::Integer(value) => digits(value),
+        AstNode::Rational(value) => digits(value.numer()) + digits(value.denom()),
+        AstNode::String(value) => value.capacity(),
+        AstNode::Vector(elements) => elements.capacity() * size_of::<AstRef>(),
+        AstNode::Bytes(bytes) => bytes.capacity(),
+        _ => 0,
+    }
+}
+
+pub struct ListIter<'a> {
+    ast: &'a Ast,
+    id: AstRef,
+}
+
+impl ListIter<'_> {
+    // What is left of the list: once the elements run out, the `()` or other node it ends in.
+    pub fn tail(&self) -> AstRef {
+        self.id
+    }
+}
+
+impl Iterator for ListIter<'_> {
+    type Item = AstRef;
+
+    fn next(&mut self) -> Option<AstRef> {
+        let (head, tail) = self.ast.get_pair(self.id)?;
+        self.id = tail;
+        Some(head)
+    }
+}
+
+pub struct AstDisplay<'a> {
+    ast: &'a Ast,
+    id: AstRef,
+}
+
+impl AstDisplay<'_> {
+    // The pairs and vectors reached more than once from `id`, numbered in the order they are
+    // written.
+    fn shared(&self) -> HashMap<AstRef, usize> {
+        let mut seen = HashSet::new();
+        let mut shared = HashMap::new();
+
+        let mut stack = vec![self.id];
+        while let Some(id) = stack.pop() {
+            if !self.is_compound(id) {
+                continue;
+            }
+            if !seen.insert(id) {
+                let count = shared.len();
+                shared.entry(id).or_insert(count);
+                continue;
+            }
+            self.ast.push_children(id, &mut stack);
+        }
+
+        // Number the labels by where they are first written rather than first revisited.
+        let mut order = vec![];
+        let mut seen = HashSet::new();
+        let mut stack = vec![self.id];
+        while let Some(id) = stack.pop() {
+            if !self.is_compound(id) || !seen.insert(id) {
+                continue;
+            }
+            if shared.contains_key(&id) {
+                order.push(id);
+            }
+            self.ast.push_children(id, &mut stack);
+        }
+
+        order
+            .into_iter()
+            .enumerate()
+            .map(|(i, id)| (id, i))
+            .collect()
+    }
+
+    fn is_compound(&self, id: AstRef) -> bool {
+        matches!(self.ast.get(id), AstNode::Pair(..) | AstNode::Vector(_))
+    }
+
+    fn write(
+        &self,
+        f: &mut fmt::Formatter,
+        id: AstRef,
+        labels: &HashMap<AstRef, usize>,
+        written: &mut HashSet<AstRef>,
+    ) -> fmt::Result {
+        let ast = self.ast;
+
+        if let Some(label) = labels.get(&id) {
+            if !written.insert(id) {
+                return write!(f, "#{}#", label);
+            }
+            write!(f, "#{}=", label)?;
+        }
+
+        match ast.get(id) {
+            AstNode::Nil => write!(f, "()"),
+            AstNode::Pair(head, tail) => {
+                write!(f, "(")?;
+                self.write(f, *head, labels, written)?;
+
+                let mut tail = *tail;
+                while let Some((head, rest)) = ast.get_pair(tail) {
+                    if labels.contains_key(&tail) {
+                        break;
+                    }
+                    write!(f, " ")?;
+                    self.write(f, head, labels, written)?;
+                    tail = rest;
+                }
+                if *ast.get(tail) != AstNode::Nil {
+                    write!(f, " . ")?;
+                    self.write(f, tail, labels, written)?;
+                }
+
+                write!(f, ")")
+            }
+            AstNode::Symbol(id) => write!(f, "{}", SymbolTable::global().resolve(*id)),
+            AstNode::Bool(true) => write!(f, "#t"),
+            AstNode::Bool(false) => write!(f, "#f"),
+            AstNode::Integer(value) => write!(f, "{}", value),
+            AstNode::Rational(value) => write!(f, "{}/{}", value.numer(), value.denom()),
+            AstNode::Float(value) => write!(f, "{:?}", value),
+            AstNode::String(value) => {
+                write!(f, "\"")?;
+                for c in value.chars() {
+                    match c {
+                        '"' => write!(f, "\\\"")?,
+                        '\\' => write!(f, "\\\\")?,
+                        '$' => write!(f, "\\$")?,
+                        '\n' => write!(f, "\\n")?,
+                        '\t' => write!(f, "\\t")?,
+                        '\r' => write!(f, "\\r")?,
+                        '\0' => write!(f, "\\0")?,
+                        c => write!(f, "{}", c)?,
+                    }
+                }
+                write!(f, "\"")
+            }
+            AstNode::Char(value) => match CHAR_NAMES.iter().find(|(_, c)| c == value) {
+                Some((name, _)) => write!(f, "#\\{}", name),
+                None if value.is_control() => write!(f, "#\\x{:x}", *value as u32),
+                None => write!(f, "#\\{}", value),
+            },
+            AstNode::Vector(elements) => {
+                write!(f, "#(")?;
+                for (i, &element) in elements.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    self.write(f, element, labels, written)?;
+                }
+                write!(f, ")")
+            }
+            AstNode::Bytes(value) => {
+                let value = value.iter().map(|b| b.to_string()).collect::<Vec<_>>();
+                write!(f, "#u8({})", value.join(" "))
+            }
+        }
+    }
+}
+
+impl fmt::Display for AstDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let labels = self.shared();
+        self.write(f, self.id, &labels, &mut HashSet::new())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        let mut ast = Ast::new();
+
+        let nil = ast.create_nil();
+        let string = ast.create_string("a \"b\"\n$");
+        let list = ast.create_pair(string, nil);
+        let float = ast.create_float(1.0);
+        let list = ast.create_pair(float, list);
+        let integer = ast.create_integer(BigInt::from(-12));
+        let list = ast.create_pair(integer, list);
+        let f = ast.create_symbol("f");
+        let list = ast.create_pair(f, list);
+
+        assert_eq!(
+            ast.display(list).to_string(),
+            "(f -12 1.0 \"a \\\"b\\\"\\n\\$\")"
+        );
+        assert_eq!(ast.get_symbol(f).as_deref(), Some("f"));
+        assert_eq!(ast.get_pair(list).map(|(head, _)| head), Some(f));
+        assert_eq!(ast.get_pair(f), None);
+
+        let c = ast.create_char('c');
+        let dotted = ast.create_pair(f, c);
+        assert_eq!(ast.display(dotted).to_string(), "(f . #\\c)");
+        assert_eq!(ast.len(), 11);
+
+        // Symbols are interned, so equal names are equal nodes.
+        let g = ast.create_symbol("f");
+        assert_eq!(ast.get(g), ast.get(f));
+        assert_eq!(ast.get_symbol_id(g), ast.get_symbol_id(f));
+        assert_eq!(ast.get_symbol_id(c), None);
+    }
+
+    #[test]
+    fn test_display_shared() {
+        let mut ast = Ast::new();
+
+        // (a . #0#), tied into a cycle after the pair is created.
+        let a = ast.create_symbol("a");
+        let cycle = ast.create_nil();
+        ast.set(cycle, AstNode::Pair(a, cycle));
+        assert_eq!(ast.display(cycle).to_string(), "#0=(a . #0#)");
+
+        // A list whose second element is the first, and whose tail is shared.
+        let nil = ast.create_nil();
+        let b = ast.create_symbol("b");
+        let shared = ast.create_pair(b, nil);
+        let inner = ast.create_pair(shared, shared);
+        let list = ast.create_pair(shared, inner);
+        assert_eq!(ast.display(list).to_string(), "(#0=(b) #0# . #0#)");
+
+        // The cycle reached through a list, and a list with a cycle in the middle.
+        let list = ast.create_pair(cycle, nil);
+        let list = ast.create_pair(a, list);
+        assert_eq!(ast.display(list).to_string(), "(a #0=(a . #0#))");
+        let tail = ast.create_pair(b, nil);
+        let middle = ast.create_nil();
+        ast.set(middle, AstNode::Pair(middle, tail));
+        let list = ast.create_pair(a, middle);
+        assert_eq!(ast.display(list).to_string(), "(a . #0=(#0# b))");
+    }
+
+    #[test]
+    fn test_root() {
+        let mut ast = Ast::new();
+        assert_eq!(ast.root(), None);
+
+        let a = ast.create_symbol("a");
+        let b = ast.create_symbol("b");
+        let first = ast.add_root(a);
+        assert_eq!(ast.root(), Some(first));
+        ast.add_root(b);
+        assert_eq!(ast.display(first).to_string(), "(a b)");
+
+        // A root that is set is added to at its end.
+        let nil = ast.create_nil();
+        let list = ast.create_pair(b, nil);
+        let list = ast.create_pair(a, list);
+        ast.set_root(list);
+        ast.add_root(a);
+        assert_eq!(ast.display(list).to_string(), "(a b a)");
+        assert_eq!(ast.display(first).to_string(), "(a b)");
+
+        // Changing the root list moves where forms are added.
+        let mut ast = Ast::new();
+        let [a, b, x] = ["a", "b", "x"].map(|name| ast.create_symbol(name));
+        let root = ast.add_root(a);
+        let nil = ast.create_nil();
+        let list = ast.create_pair(b, nil);
+        ast.set_tail(root, list);
+        ast.add_root(x);
+        assert_eq!(ast.display(root).to_string(), "(a b x)");
+
+        // An empty root is replaced by a list of the form.
+        let nil = ast.create_nil();
+        ast.set_root(nil);
+        let pair = ast.add_root(b);
+        assert_eq!(ast.root(), Some(pair));
+        ast.add_root(a);
+        assert_eq!(ast.display(pair).to_string(), "(b a)");
+    }
+
+    #[test]
+    fn test_lists() {
+        let mut ast = Ast::new();
+        let [a, b, c] = ["a", "b", "c"].map(|name| ast.create_symbol(name));
+
+        let list = ast.create_list(&[a, b, c]);
+        assert_eq!(ast.display(list).to_string(), "(a b c)");
+        assert_eq!(ast.list_iter(list).collect::<Vec<_>>(), vec![a, b, c]);
+        assert_eq!(ast.list_len(list), Some(3));
+        assert!(ast.is_list(list) && !ast.is_dotted_list(list));
+
+        let dotted = ast.create_dotted_list(&[a, b], c);
+        assert_eq!(ast.display(dotted).to_string(), "(a b . c)");
+        let mut iter = ast.list_iter(dotted);
+        assert_eq!(iter.by_ref().collect::<Vec<_>>(), vec![a, b]);
+        assert_eq!(iter.tail(), c);
+        assert_eq!(ast.list_len(dotted), None);
+        assert!(!ast.is_list(dotted) && ast.is_dotted_list(dotted));
+
+        let empty = ast.create_list(&[]);
+        assert_eq!(ast.list_len(empty), Some(0));
+        assert!(ast.is_list(empty) && !ast.is_dotted_list(empty));
+        assert_eq!(ast.list_len(a), None);
+        assert!(!ast.is_dotted_list(a));
+
+        // Cyclic lists are neither.
+        let cycle = ast.create_list(&[a, b]);
+        let (_, last) = ast.get_pair(cycle).unwrap();
+        ast.set_tail(last, cycle);
+        assert_eq!(ast.list_len(cycle), None);
+        assert!(!ast.is_list(cycle) && !ast.is_dotted_list(cycle));
+        assert_eq!(ast.list_iter(cycle).take(5).count(), 5);
+    }
+
+    #[test]
+    fn test_set_pair() {
+        let mut ast = Ast::new();
+        let [a, b, c] = ["a", "b", "c"].map(|name| ast.create_symbol(name));
+        let list = ast.create_list(&[a, b]);
+
+        ast.set_head(list, c);
+        assert_eq!(ast.display(list).to_string(), "(c b)");
+
+        let (_, rest) = ast.get_pair(list).unwrap();
+        ast.set_tail(rest, a);
+        assert_eq!(ast.display(list).to_string(), "(c b . a)");
+        assert_eq!(ast.get_pair(rest), Some((b, a)));
+    }
+
+    #[test]
+    #[should_panic(expected = "isn't a pair")]
+    fn test_set_head_not_pair() {
+        let mut ast = Ast::new();
+        let a = ast.create_symbol("a");
+        ast.set_head(a, a);
+    }
+
+    #[test]
+    fn test_gc() {
+        let mut ast = Ast::new();
+        let [a, b, c] = ["a", "b", "c"].map(|name| ast.create_symbol(name));
+        let kept = ast.create_list(&[a, b]);
+        ast.create_list(&[c, c]);
+        ast.add_root(c);
+        assert_eq!(ast.len(), 11);
+
+        // The root list and `kept` are reachable, but the second list and its nil aren't.
+        let stats = ast.collect_garbage(&[kept]);
+        assert_eq!(
+            stats,
+            GcStats {
+                live: 8,
+                reclaimed: 3,
+                free: 3
+            }
+        );
+        assert_eq!(ast.len(), 8);
+        assert_eq!(ast.display(kept).to_string(), "(a b)");
+        assert_eq!(ast.display(ast.root().unwrap()).to_string(), "(c)");
+
+        // Freed slots are reused before the `Ast` grows.
+        let d = ast.create_symbol("d");
+        assert!(d < 11);
+        assert_eq!(ast.len(), 9);
+
+        // Cycles are collected once nothing outside them refers to them.
+        let cycle = ast.create_list(&[a]);
+        ast.set(cycle, AstNode::Pair(a, cycle));
+        let stats = ast.collect_garbage(&[]);
+        assert_eq!(
+            stats,
+            GcStats {
+                live: 3,
+                reclaimed: 8,
+                free: 8
+            }
+        );
+        assert_eq!(ast.display(ast.root().unwrap()).to_string(), "(c)");
+    }
+
+    #[test]
+    fn test_stage_stats() {
+        let slot = size_of::<Option<AstNode>>() + size_of::<Option<SyntaxInfo>>();
+        let mut ast = Ast::new();
+        ast.create_symbol("a");
+        assert!(ast.stage_stats().is_empty());
+
+        ast.set_stage(Some("read"));
+        let a = ast.create_symbol("a");
+        let s = ast.create_string("four");
+        let v = ast.create_vector(&[a, s]);
+        assert_eq!(ast.stage(), Some("read"));
+
+        ast.set_stage(Some("expand"));
+        ast.create_list(&[a]);
+        ast.collect_garbage(&[v]);
+        ast.create_integer(BigInt::from(u64::MAX) + 1);
+
+        // Nodes are counted under the stage they were created in, and reused slots count nothing.
+        ast.set_stage(Some("read"));
+        ast.create_nil();
+        ast.set_stage(None);
+        ast.create_nil();
+        assert_eq!(ast.stage(), None);
+        assert_eq!(
+            ast.stage_stats(),
+            &[
+                StageStats {
+                    name: "read".into(),
+                    nodes: 4,
+                    bytes: 3 * slot + 4 + 2 * size_of::<AstRef>(),
+                },
+                StageStats {
+                    name: "expand".into(),
+                    nodes: 3,
+                    bytes: 2 * slot + 16,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_literals() {
+        let mut ast = Ast::new();
+        let t = ast.create_bool(true);
+        let half = ast.create_rational(BigRational::new(1.into(), 2.into()));
+        let bytes = ast.create_bytes(&[1, 255]);
+        let vector = ast.create_vector(&[t, half, bytes]);
+        assert_eq!(ast.display(vector).to_string(), "#(#t 1/2 #u8(1 255))");
+
+        assert_eq!(ast.get_bool(t), Some(true));
+        assert_eq!(
+            ast.get_rational(half).map(|r| r.to_string()),
+            Some("1/2".into())
+        );
+        assert_eq!(ast.get_vector(vector), Some(&[t, half, bytes][..]));
+        assert_eq!(ast.get_bytes(bytes), Some(&[1, 255][..]));
+        assert!(ast.is_bool(t) && ast.is_rational(half) && ast.is_vector(vector));
+        assert!(ast.is_bytes(bytes) && !ast.is_bytes(t));
+
+        // Vectors can be shared and cyclic like pairs, and their elements are kept by collections.
+        let list = ast.create_list(&[vector, vector]);
+        assert_eq!(
+            ast.display(list).to_string(),
+            "(#0=#(#t 1/2 #u8(1 255)) #0#)"
+        );
+        ast.set(vector, AstNode::Vector(vec![t, vector]));
+        assert_eq!(ast.display(vector).to_string(), "#0=#(#t #0#)");
+        let stats = ast.collect_garbage(&[vector]);
+        assert_eq!((stats.live, stats.reclaimed), (2, 5));
+    }
+
+    #[test]
+    fn test_undo() {
+        let mut ast = Ast::new();
+        assert!(!ast.undo());
+
+        let [a, b] = ["a", "b"].map(|name| ast.create_symbol(name));
+        let list = ast.create_list(&[a, b]);
+        ast.add_root(list);
+        let syntax = SyntaxInfo {
+            file: 0,
+            span: Span { start: 0, end: 5 },
+            line: 1,
+            column: 1,
+        };
+        ast.set_syntax(list, syntax);
+
+        // Edits, new nodes and a new root are all undone.
+        ast.checkpoint();
+        let c = ast.create_symbol("c");
+        ast.set_head(list, c);
+        ast.add_root(c);
+        ast.set_syntax(list, SyntaxInfo { line: 2, ..syntax });
+        ast.set_origin(c, Origin { from: a, by: None });
+        assert_eq!(ast.display(ast.root().unwrap()).to_string(), "((c b) c)");
+        assert!(ast.undo());
+        assert_eq!(ast.display(ast.root().unwrap()).to_string(), "((a b))");
+        assert_eq!(ast.get_syntax(list), Some(&syntax));
+        assert!(ast.origin_chain(c).is_empty());
+        assert_eq!(ast.len(), 7);
+
+        // Checkpoints nest, and committing one leaves its changes to the one before it.
+        ast.checkpoint();
+        ast.add_root(a);
+        ast.checkpoint();
+        ast.add_root(b);
+        ast.checkpoint();
+        ast.add_root(a);
+        assert_eq!(ast.checkpoints(), 3);
+        assert!(ast.commit());
+        assert_eq!(
+            ast.display(ast.root().unwrap()).to_string(),
+            "((a b) a b a)"
+        );
+        assert!(ast.undo());
+        assert_eq!(ast.display(ast.root().unwrap()).to_string(), "((a b) a)");
+        assert!(ast.undo());
+        assert_eq!(ast.display(ast.root().unwrap()).to_string(), "((a b))");
+        assert_eq!(ast.checkpoints(), 0);
+
+        // Collections are undone too, along with the nodes that reused their slots.
+        let garbage = ast.create_list(&[a, a]);
+        ast.checkpoint();
+        ast.collect_garbage(&[]);
+        let d = ast.create_symbol("d");
+        assert!(d <= garbage);
+        assert!(ast.undo());
+        assert_eq!(ast.display(garbage).to_string(), "(a a)");
+        assert_eq!(ast.len(), 10);
+        ast.add_root(garbage);
+        assert_eq!(
+            ast.display(ast.root().unwrap()).to_string(),
+            "((a b) (a a))"
+        );
+    }
+}
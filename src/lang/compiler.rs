@@ -1,13 +1,14 @@
-use std::io::BufRead;
+use std::io::Read;
+use std::sync::OnceLock;
 
 use crate::lex::lexer::*;
-use crate::lex::regex::*;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
-enum Mode {
+pub(crate) enum Mode {
     #[default]
     Default,
     Comment,
+    BlockComment,
     String,
 }
 
@@ -19,63 +20,65 @@ pub enum Token {
     RBrace,
     LBracket,
     RBracket,
+    VectorStart,
+    BytesStart,
 
     Semicolon,
     Comma,
+    CommaAt,
     Quote,
     BackQuote,
-    DoubleQuote,
 
     Whitespace,
     Newline,
 
     Integer,
+    Rational,
     Float,
+    Char,
     Identifier,
-    String,
+    Label,
+    StringStart,
+    StringText,
     StringEscape,
+    InterpStart,
+    StringEnd,
 
     Comment,
+    BlockCommentStart,
+    BlockCommentEnd,
+    DatumComment,
 }
 
 pub struct Compiler {
     lexer: Lexer<Mode, Token>,
 }
 
+impl Default for Compiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Compiler {
     pub fn new() -> Self {
-        let mut compiler = Compiler {
-            lexer: Lexer::new(),
-        };
-
-        compiler.lexer_init();
-
-        compiler
+        Compiler {
+            lexer: Lexer::from_def(lexer_def().clone()),
+        }
     }
 
-    pub fn lex<R>(&mut self, mut io: R, lexemes: &mut Vec<Lexeme<Token>>)
+    pub fn lex<R>(&mut self, io: R, lexemes: &mut Vec<Lexeme<Token>>)
     where
-        R: BufRead,
+        R: Read,
     {
-        let mut buf = vec![];
-
-        while io.read_until(b'\n', &mut buf).unwrap() != 0 {
-            let s = String::from_utf8(buf).unwrap();
+        self.lexer.put_reader(io).unwrap();
 
-            for c in s.chars() {
-                self.lexer.put(c);
-
-                if let Some(error) = self.lexer.get_error() {
-                    let error = error.clone();
-                    while let Some(lexeme) = self.lexer.get() {
-                        lexemes.push(lexeme.clone())
-                    }
-                    panic!("error: {:?}", error);
-                }
+        if let Some(error) = self.lexer.get_error() {
+            let error = error.clone();
+            while let Some(lexeme) = self.lexer.get() {
+                lexemes.push(lexeme.clone())
             }
-
-            buf = s.into_bytes();
-            buf.clear();
+            panic!("error: {}", error);
         }
 
         self.lexer.finish();
@@ -84,99 +87,71 @@ impl Compiler {
             lexemes.push(lexeme.clone())
         }
     }
+}
+
+pub(crate) fn lexer_def() -> &'static LexerDef<Mode, Token> {
+    // Built and compiled once, then cloned for each compiler.
+    static DEF: OnceLock<LexerDef<Mode, Token>> = OnceLock::new();
 
-    fn lexer_init(&mut self) {
+    DEF.get_or_init(|| {
         use Token::*;
 
-        self.lexer = Lexer::new();
-
-        let lparen = Regex::char('(');
-        let rparen = Regex::char(')');
-        let lbrace = Regex::char('{');
-        let rbrace = Regex::char('}');
-        let lbracket = Regex::char('[');
-        let rbracket = Regex::char(']');
-        let semicolon = Regex::char(';');
-        let comma = Regex::char(',');
-        let quote = Regex::char('\'');
-        let backquote = Regex::char('`');
-        let doublequote = Regex::char('"');
-
-        let whitespace = Regex::one_of(" \t").star();
-        let newline = Regex::char('\n');
-
-        let digit = Regex::range('0', '9');
-        let sign = Regex::one_of("+-");
-        let alpha_lower = Regex::range('a', 'z');
-        let alpha_upper = Regex::range('A', 'Z');
-        let alpha = alpha_lower.union(&alpha_upper);
-        let alphanumeric = alpha.union(&digit);
-        let ident_symbols = Regex::none_of("(){}[];,'\" \t\n`0123456789");
-
-        let ident_start = alpha.union(&ident_symbols);
-        let ident_continue = alphanumeric.union(&ident_symbols);
-        let identifier = ident_start.concat(&ident_continue.star());
-
-        let integer = sign.optional().concat(&digit.plus());
-        let exponent = Regex::one_of("eE").concat(&integer);
-        let float_exp = integer.concat(&exponent);
-        let float_frac = integer
-            .concat(&Regex::char('.'))
-            .concat(&integer)
-            .concat(&exponent.optional());
-        let float = float_frac.union(&float_exp);
-
-        let string = Regex::none_of("\"\\").star();
-        let string_escape = Regex::char('\\').concat(&Regex::any());
-
-        let comment = Regex::none_of("\n").star();
-
-        // Normal mode rules
-        self.lexer
-            .with_rule(LParen, &lparen, Mode::Default, Mode::Default, false)
-            .with_rule(RParen, &rparen, Mode::Default, Mode::Default, false)
-            .with_rule(LBrace, &lbrace, Mode::Default, Mode::Default, false)
-            .with_rule(RBrace, &rbrace, Mode::Default, Mode::Default, false)
-            .with_rule(LBracket, &lbracket, Mode::Default, Mode::Default, false)
-            .with_rule(RBracket, &rbracket, Mode::Default, Mode::Default, false)
-            .with_rule(Semicolon, &semicolon, Mode::Default, Mode::Comment, false)
-            .with_rule(Comma, &comma, Mode::Default, Mode::Default, false)
-            .with_rule(Quote, &quote, Mode::Default, Mode::Default, false)
-            .with_rule(BackQuote, &backquote, Mode::Default, Mode::Default, false)
-            .with_rule(
-                DoubleQuote,
-                &doublequote,
-                Mode::Default,
-                Mode::String,
-                false,
-            )
-            .with_rule(Whitespace, &whitespace, Mode::Default, Mode::Default, false)
-            .with_rule(Newline, &newline, Mode::Default, Mode::Default, false)
-            .with_rule(Integer, &integer, Mode::Default, Mode::Default, true)
-            .with_rule(Float, &float, Mode::Default, Mode::Default, true)
-            .with_rule(Identifier, &identifier, Mode::Default, Mode::Default, true);
-
-        self.lexer
-            .with_rule(String, &string, Mode::String, Mode::String, true)
-            .with_rule(
-                StringEscape,
-                &string_escape,
-                Mode::String,
-                Mode::String,
-                true,
-            )
-            .with_rule(
-                DoubleQuote,
-                &doublequote,
-                Mode::String,
-                Mode::Default,
-                false,
-            );
-
-        self.lexer
-            .with_rule(Comment, &comment, Mode::Comment, Mode::Comment, false)
-            .with_rule(Newline, &newline, Mode::Comment, Mode::Default, false);
-    }
+        // `$(` in a string lexes the interpolated expression in the default mode until its closing
+        // paren. Every paren pushes or pops the mode stack so that the one closing the
+        // interpolation is the one that returns to the string; outside one a stray `)` just stays
+        // in the default mode. Block comments nest in the same way.
+        let def = crate::lexer! {
+            mode Mode::Default {
+                "(" => LParen, push(Mode::Default);
+                ")" => RParen, pop_or(Mode::Default);
+                "{" => LBrace;
+                "}" => RBrace;
+                "[" => LBracket;
+                "]" => RBracket;
+                "#(" => VectorStart, push(Mode::Default);
+                "#u8(" => BytesStart, push(Mode::Default);
+                ";" => Semicolon, to(Mode::Comment);
+                "#;" => DatumComment;
+                "#|" => BlockCommentStart, push(Mode::BlockComment);
+                "," => Comma;
+                ",@" => CommaAt;
+                "'" => Quote;
+                "`" => BackQuote;
+                "\"" => StringStart, to(Mode::String);
+                re "[ \t]*" => Whitespace;
+                "\n" => Newline;
+                re "[+-]?[0-9]+" => Integer, keep;
+                re "[+-]?[0-9]+/[0-9]+" => Rational, keep;
+                re "[+-]?[0-9]+(\\.[+-]?[0-9]+([eE][+-]?[0-9]+)?|[eE][+-]?[0-9]+)" => Float, keep;
+                re "[^(){}\\[\\];,'\" \t\n`0-9#][^(){}\\[\\];,'\" \t\n`]*" => Identifier, keep;
+                re "#([^(){}\\[\\];,'\" \t\n`0-9|\\\\][^(){}\\[\\];,'\" \t\n`]*)?" => Identifier, keep;
+                re "#\\\\(.|[^(){}\\[\\];,'\" \t\n`]+)" => Char, keep;
+                re "#[0-9]+[=#]" => Label, keep;
+            }
+            mode Mode::String {
+                re "[^\"\\\\$]+" => StringText, keep;
+                "$" => StringText, keep;
+                re "\\\\." => StringEscape, keep;
+                "$(" => InterpStart, push(Mode::Default);
+                "\"" => StringEnd, to(Mode::Default);
+            }
+            mode Mode::BlockComment {
+                re "[^|#]+" => Comment, keep;
+                "|" => Comment, keep;
+                "#" => Comment, keep;
+                "#|" => BlockCommentStart, push(Mode::BlockComment);
+                "|#" => BlockCommentEnd, pop;
+            }
+            mode Mode::Comment {
+                re "[^\n]*" => Comment;
+                "\n" => Newline, to(Mode::Default);
+            }
+        }
+        .into_def();
+
+        def.compile();
+        def
+    })
 }
 
 #[cfg(test)]
@@ -259,9 +234,45 @@ mod test {
         compiler.lex(Cursor::new(input), &mut lexemes);
 
         assert_eq!(lexemes.len(), 3);
-        assert_eq!(lexemes[0].token, Token::DoubleQuote);
-        assert_eq!(lexemes[1].token, Token::String);
-        assert_eq!(lexemes[2].token, Token::DoubleQuote);
+        assert_eq!(lexemes[0].token, Token::StringStart);
+        assert_eq!(lexemes[1].token, Token::StringText);
+        assert_eq!(lexemes[2].token, Token::StringEnd);
+    }
+
+    #[test]
+    fn test_lex_string_interpolation() {
+        use Token::*;
+
+        let mut compiler = Compiler::new();
+        let input = "\"a $(+ 1 (f 2)) $b\" )";
+        let mut lexemes = vec![];
+        compiler.lex(Cursor::new(input), &mut lexemes);
+
+        let tokens = lexemes.iter().map(|l| l.token).collect::<Vec<_>>();
+        assert_eq!(
+            tokens,
+            vec![
+                StringStart,
+                StringText,
+                InterpStart,
+                Identifier,
+                Whitespace,
+                Integer,
+                Whitespace,
+                LParen,
+                Identifier,
+                Whitespace,
+                Integer,
+                RParen,
+                RParen,
+                StringText,
+                StringText,
+                StringText,
+                StringEnd,
+                Whitespace,
+                RParen,
+            ]
+        );
     }
 
     #[test]
@@ -275,4 +286,112 @@ mod test {
         assert_eq!(lexemes[1].token, Token::Comment);
         assert_eq!(lexemes[2].token, Token::Newline);
     }
+
+    #[test]
+    fn test_lex_quotes() {
+        let mut compiler = Compiler::new();
+        let input = "'`,,@";
+        let mut lexemes = vec![];
+        compiler.lex(Cursor::new(input), &mut lexemes);
+        let tokens = lexemes.iter().map(|l| l.token).collect::<Vec<_>>();
+        assert_eq!(
+            tokens,
+            vec![Token::Quote, Token::BackQuote, Token::Comma, Token::CommaAt]
+        );
+    }
+
+    #[test]
+    fn test_lex_label() {
+        let mut compiler = Compiler::new();
+        let input = "#0=a #12# #x";
+        let mut lexemes = vec![];
+        compiler.lex(Cursor::new(input), &mut lexemes);
+        let tokens = lexemes
+            .iter()
+            .map(|l| (l.token, l.span.as_deref().unwrap_or("")))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            tokens,
+            vec![
+                (Token::Label, "#0="),
+                (Token::Identifier, "a"),
+                (Token::Whitespace, ""),
+                (Token::Label, "#12#"),
+                (Token::Whitespace, ""),
+                (Token::Identifier, "#x"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lex_block_comment() {
+        let mut compiler = Compiler::new();
+        let input = "#| a #| b |# | # |#x #;";
+        let mut lexemes = vec![];
+        compiler.lex(Cursor::new(input), &mut lexemes);
+        let tokens = lexemes.iter().map(|l| l.token).collect::<Vec<_>>();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::BlockCommentStart,
+                Token::Comment,
+                Token::BlockCommentStart,
+                Token::Comment,
+                Token::BlockCommentEnd,
+                Token::Comment,
+                Token::Comment,
+                Token::Comment,
+                Token::Comment,
+                Token::Comment,
+                Token::BlockCommentEnd,
+                Token::Identifier,
+                Token::Whitespace,
+                Token::DatumComment,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lex_literals() {
+        let mut compiler = Compiler::new();
+        let input = "#(1/2) #u8(#t)";
+        let mut lexemes = vec![];
+        compiler.lex(Cursor::new(input), &mut lexemes);
+        let tokens = lexemes.iter().map(|l| l.token).collect::<Vec<_>>();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::VectorStart,
+                Token::Rational,
+                Token::RParen,
+                Token::Whitespace,
+                Token::BytesStart,
+                Token::Identifier,
+                Token::RParen
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lex_char() {
+        let mut compiler = Compiler::new();
+        let input = "#\\a #\\) #\\newline)";
+        let mut lexemes = vec![];
+        compiler.lex(Cursor::new(input), &mut lexemes);
+        let tokens = lexemes
+            .iter()
+            .map(|l| (l.token, l.span.as_deref().unwrap_or("")))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            tokens,
+            vec![
+                (Token::Char, "#\\a"),
+                (Token::Whitespace, ""),
+                (Token::Char, "#\\)"),
+                (Token::Whitespace, ""),
+                (Token::Char, "#\\newline"),
+                (Token::RParen, "")
+            ]
+        );
+    }
 }
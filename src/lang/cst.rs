@@ -0,0 +1,439 @@
+use std::rc::Rc;
+use std::sync::Arc;
+
+use crate::lang::ast::{Ast, AstRef};
+use crate::lang::compiler::{lexer_def, Token};
+use crate::lang::reader::{read, ParseError};
+use crate::lex::lexer::{Lexeme, LexerError, Span};
+
+// A concrete syntax tree keeps every lexeme of the source, whitespace and comments included, so
+// that the source can be written back out exactly, e.g. by a formatter that only changes some of
+// it. It comes in two layers:
+//
+// - Green nodes are immutable and know only their width, so an unchanged subtree can be shared
+//   between versions of a tree.
+// - `CstNode`s are built on demand over a green tree, and add each node's position and parent.
+//
+// Building the tree never fails on badly bracketed input; a stray closing bracket is kept as a
+// token of whatever node it appears in, and anything still open at the end is closed there.
+// `to_ast` reports such problems.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CstKind {
+    Root,
+    // A bracketed list, or the interpolation in a string, including its brackets.
+    List,
+    // A quote, backquote, comma, `,@`, datum label or datum comment, and the form it applies to.
+    Quote,
+    String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GreenNode {
+    kind: CstKind,
+    width: usize,
+    children: Vec<GreenElement>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GreenToken {
+    token: Token,
+    text: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GreenElement {
+    Node(Arc<GreenNode>),
+    Token(Arc<GreenToken>),
+}
+
+impl GreenNode {
+    pub fn new(kind: CstKind, children: Vec<GreenElement>) -> GreenNode {
+        let width = children.iter().map(GreenElement::width).sum();
+        GreenNode {
+            kind,
+            width,
+            children,
+        }
+    }
+
+    pub fn kind(&self) -> CstKind {
+        self.kind
+    }
+
+    // The length of the node's text in bytes.
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn children(&self) -> &[GreenElement] {
+        &self.children
+    }
+}
+
+impl GreenToken {
+    pub fn new(token: Token, text: &str) -> GreenToken {
+        GreenToken {
+            token,
+            text: text.to_string(),
+        }
+    }
+
+    pub fn token(&self) -> Token {
+        self.token
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+}
+
+impl GreenElement {
+    pub fn width(&self) -> usize {
+        match self {
+            GreenElement::Node(node) => node.width,
+            GreenElement::Token(token) => token.text.len(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CstNode(Rc<NodeData>);
+
+#[derive(Debug)]
+struct NodeData {
+    green: Arc<GreenNode>,
+    offset: usize,
+    parent: Option<CstNode>,
+}
+
+#[derive(Debug, Clone)]
+pub struct CstToken {
+    green: Arc<GreenToken>,
+    offset: usize,
+    parent: CstNode,
+}
+
+#[derive(Debug, Clone)]
+pub enum CstElement {
+    Node(CstNode),
+    Token(CstToken),
+}
+
+impl CstNode {
+    pub fn new_root(green: Arc<GreenNode>) -> CstNode {
+        CstNode(Rc::new(NodeData {
+            green,
+            offset: 0,
+            parent: None,
+        }))
+    }
+
+    pub fn green(&self) -> &Arc<GreenNode> {
+        &self.0.green
+    }
+
+    pub fn kind(&self) -> CstKind {
+        self.0.green.kind
+    }
+
+    pub fn span(&self) -> Span {
+        Span {
+            start: self.0.offset,
+            end: self.0.offset + self.0.green.width,
+        }
+    }
+
+    pub fn parent(&self) -> Option<&CstNode> {
+        self.0.parent.as_ref()
+    }
+
+    pub fn children(&self) -> Vec<CstElement> {
+        let mut offset = self.0.offset;
+
+        self.0
+            .green
+            .children
+            .iter()
+            .map(|child| {
+                let start = offset;
+                offset += child.width();
+
+                match child {
+                    GreenElement::Node(green) => CstElement::Node(CstNode(Rc::new(NodeData {
+                        green: green.clone(),
+                        offset: start,
+                        parent: Some(self.clone()),
+                    }))),
+                    GreenElement::Token(green) => CstElement::Token(CstToken {
+                        green: green.clone(),
+                        offset: start,
+                        parent: self.clone(),
+                    }),
+                }
+            })
+            .collect()
+    }
+
+    // Every token under the node, in order.
+    pub fn tokens(&self) -> Vec<CstToken> {
+        let mut tokens = vec![];
+        for child in self.children() {
+            match child {
+                CstElement::Node(node) => tokens.extend(node.tokens()),
+                CstElement::Token(token) => tokens.push(token),
+            }
+        }
+        tokens
+    }
+
+    // The source the node was built from, exactly.
+    pub fn text(&self) -> String {
+        let mut text = String::with_capacity(self.0.green.width);
+        write_text(&self.0.green, &mut text);
+        text
+    }
+
+    // Read the forms under the node, ignoring the whitespace and comments around them.
+    pub fn to_ast(&self, ast: &mut Ast) -> Result<Vec<AstRef>, ParseError> {
+        let lexemes = self.tokens().into_iter().map(|token| Lexeme {
+            token: token.token(),
+            position: token.offset,
+            length: token.green.text.len(),
+            span: Some(token.green.text.clone()),
+        });
+
+        read(lexemes, ast)
+    }
+}
+
+impl CstToken {
+    pub fn token(&self) -> Token {
+        self.green.token
+    }
+
+    pub fn text(&self) -> &str {
+        &self.green.text
+    }
+
+    pub fn span(&self) -> Span {
+        Span {
+            start: self.offset,
+            end: self.offset + self.green.text.len(),
+        }
+    }
+
+    pub fn parent(&self) -> &CstNode {
+        &self.parent
+    }
+}
+
+fn write_text(green: &GreenNode, text: &mut String) {
+    for child in &green.children {
+        match child {
+            GreenElement::Node(node) => write_text(node, text),
+            GreenElement::Token(token) => text.push_str(&token.text),
+        }
+    }
+}
+
+// A node being built, and the token that closes it if it has one.
+struct Open {
+    kind: CstKind,
+    close: Option<Token>,
+    children: Vec<GreenElement>,
+}
+
+fn is_trivia(token: Token) -> bool {
+    matches!(
+        token,
+        Token::Whitespace
+            | Token::Newline
+            | Token::Semicolon
+            | Token::Comment
+            | Token::BlockCommentStart
+            | Token::BlockCommentEnd
+    )
+}
+
+// Close the innermost node, which completes a form, and so any quotes waiting for one.
+fn close(stack: &mut Vec<Open>) {
+    let open = stack.pop().unwrap();
+    let node = GreenElement::Node(Arc::new(GreenNode::new(open.kind, open.children)));
+    add(stack, node, true);
+}
+
+fn add(stack: &mut Vec<Open>, element: GreenElement, form: bool) {
+    let top = stack.last_mut().unwrap();
+    top.children.push(element);
+
+    if form && top.kind == CstKind::Quote {
+        close(stack);
+    }
+}
+
+// Lex `source` and build its concrete syntax tree.
+pub fn parse(source: &str) -> Result<CstNode, LexerError<Token>> {
+    let mut run = lexer_def().run();
+    run.put_str(source);
+    run.finish();
+    if let Some(error) = run.get_error() {
+        return Err(error.clone());
+    }
+
+    let mut stack = vec![Open {
+        kind: CstKind::Root,
+        close: None,
+        children: vec![],
+    }];
+
+    while let Some(lexeme) = run.get() {
+        let text = &source[lexeme.extent().range()];
+        let token = GreenElement::Token(Arc::new(GreenToken::new(lexeme.token, text)));
+
+        let (kind, close_with) = match lexeme.token {
+            Token::LParen | Token::InterpStart | Token::VectorStart | Token::BytesStart => {
+                (CstKind::List, Some(Token::RParen))
+            }
+            Token::LBracket => (CstKind::List, Some(Token::RBracket)),
+            Token::LBrace => (CstKind::List, Some(Token::RBrace)),
+            Token::StringStart => (CstKind::String, Some(Token::StringEnd)),
+            Token::Quote | Token::BackQuote | Token::Comma | Token::CommaAt => {
+                (CstKind::Quote, None)
+            }
+            Token::Label if text.ends_with('=') => (CstKind::Quote, None),
+            Token::DatumComment => (CstKind::Quote, None),
+
+            Token::RParen | Token::RBracket | Token::RBrace | Token::StringEnd => {
+                // Quotes with nothing to quote end with their list.
+                while stack.last().unwrap().kind == CstKind::Quote {
+                    close(&mut stack);
+                }
+
+                let matched = stack.last().unwrap().close == Some(lexeme.token);
+                add(&mut stack, token, false);
+                if matched {
+                    close(&mut stack);
+                }
+                continue;
+            }
+
+            t => {
+                let form = !is_trivia(t) && stack.last().unwrap().kind != CstKind::String;
+                add(&mut stack, token, form);
+                continue;
+            }
+        };
+
+        stack.push(Open {
+            kind,
+            close: close_with,
+            children: vec![token],
+        });
+    }
+
+    while stack.len() > 1 {
+        close(&mut stack);
+    }
+
+    let root = stack.pop().unwrap();
+    Ok(CstNode::new_root(Arc::new(GreenNode::new(
+        CstKind::Root,
+        root.children,
+    ))))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_lossless() {
+        let inputs = [
+            "",
+            "(define (f x) ; add one\n  [+ x 1.5])\n\n'  (a . b) `(c ,d ,@e) #0=(f #0#)",
+            "(a #;(b) #| c #| d |# |# e)",
+            "\"a\\n$(f \"b\") c\" {x}",
+            "#(1/2 #t) #u8(0 1) #\\( #\\newline",
+            // Badly bracketed input is kept too.
+            "(a ']) (b",
+            ")) '",
+        ];
+
+        for input in inputs {
+            let cst = parse(input).unwrap();
+            assert_eq!(cst.text(), input);
+            assert_eq!(
+                cst.span(),
+                Span {
+                    start: 0,
+                    end: input.len()
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn test_structure() {
+        let cst = parse("(f 'x) \"s\"").unwrap();
+
+        let children = cst.children();
+        assert_eq!(children.len(), 3);
+
+        let CstElement::Node(list) = &children[0] else {
+            panic!("expected a list");
+        };
+        assert_eq!(list.kind(), CstKind::List);
+        assert_eq!(list.span(), Span { start: 0, end: 6 });
+        assert_eq!(list.text(), "(f 'x)");
+        assert_eq!(list.parent().unwrap().kind(), CstKind::Root);
+
+        let quote = list
+            .children()
+            .into_iter()
+            .find_map(|child| match child {
+                CstElement::Node(node) => Some(node),
+                CstElement::Token(_) => None,
+            })
+            .unwrap();
+        assert_eq!(quote.kind(), CstKind::Quote);
+        assert_eq!(quote.span(), Span { start: 3, end: 5 });
+
+        let x = quote.tokens().pop().unwrap();
+        assert_eq!((x.token(), x.text()), (Token::Identifier, "x"));
+        assert_eq!(x.span(), Span { start: 4, end: 5 });
+        assert_eq!(x.parent().kind(), CstKind::Quote);
+
+        let CstElement::Node(string) = &children[2] else {
+            panic!("expected a string");
+        };
+        assert_eq!(string.kind(), CstKind::String);
+        assert_eq!(string.green().width(), 3);
+    }
+
+    #[test]
+    fn test_to_ast() {
+        let source = "(define (f x) ; add one\n  [+ x 1.5]) 'y \"a$(g)\"";
+        let cst = parse(source).unwrap();
+
+        let mut ast = Ast::new();
+        let forms = cst
+            .to_ast(&mut ast)
+            .unwrap()
+            .into_iter()
+            .map(|form| ast.display(form).to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            forms,
+            vec![
+                "(define (f x) (+ x 1.5))",
+                "(quote y)",
+                "(string-append \"a\" (g))"
+            ]
+        );
+
+        assert!(matches!(
+            parse("(a ']) (b").unwrap().to_ast(&mut ast),
+            Err(ParseError::MissingForm { .. })
+        ));
+    }
+}
@@ -0,0 +1,90 @@
+use std::io::{Result, Write};
+
+use crate::lang::ast::{Ast, AstNode, AstRef};
+use crate::lex::dot::escape;
+
+impl Ast {
+    // Write the nodes reachable from `root` as a Graphviz graph, e.g. to look at what the reader or
+    // expander produced. Pairs are drawn as cons cells with an edge from each half, vectors as a
+    // row of cells, and anything else as a box labelled the way `display` prints it. Shared nodes
+    // are drawn once, with an edge from everything that refers to them.
+    pub fn write_dot<W: Write>(&self, root: AstRef, mut io: W) -> Result<()> {
+        writeln!(io, "digraph AST {{")?;
+        writeln!(io, "  node [shape=box];")?;
+
+        for id in self.as_graph().reachable(&[root]) {
+            match self.get(id) {
+                &AstNode::Pair(head, tail) => {
+                    writeln!(io, "  {} [label=\"<head>|<tail>\", shape=record];", id)?;
+                    writeln!(io, "  {}:head -> {};", id, head)?;
+                    writeln!(io, "  {}:tail -> {};", id, tail)?;
+                }
+                AstNode::Vector(elements) => {
+                    let cells = (0..elements.len())
+                        .map(|i| format!("|<{}>", i))
+                        .collect::<String>();
+                    writeln!(io, "  {} [label=\"#{}\", shape=record];", id, cells)?;
+                    for (i, element) in elements.iter().enumerate() {
+                        writeln!(io, "  {}:{} -> {};", id, i, element)?;
+                    }
+                }
+                _ => {
+                    let label = escape(&self.display(id).to_string());
+                    writeln!(io, "  {} [label=\"{}\"];", id, label)?;
+                }
+            }
+        }
+
+        writeln!(io, "}}")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_write_dot() {
+        let mut ast = Ast::new();
+        let f = ast.create_symbol("f");
+        let s = ast.create_string("a\"b");
+        let one = ast.create_integer(1.into());
+        let vector = ast.create_vector(&[one, f]);
+        let call = ast.create_list(&[f, s, vector]);
+
+        let mut out = vec![];
+        ast.write_dot(call, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            r##"digraph AST {
+  node [shape=box];
+  7 [label="<head>|<tail>", shape=record];
+  7:head -> 0;
+  7:tail -> 6;
+  0 [label="f"];
+  6 [label="<head>|<tail>", shape=record];
+  6:head -> 1;
+  6:tail -> 5;
+  1 [label="\"a\\\"b\""];
+  5 [label="<head>|<tail>", shape=record];
+  5:head -> 3;
+  5:tail -> 4;
+  3 [label="#|<0>|<1>", shape=record];
+  3:0 -> 2;
+  3:1 -> 0;
+  2 [label="1"];
+  4 [label="()"];
+}
+"##
+        );
+
+        // Cycles are drawn as edges back to where they started.
+        let cycle = ast.create_list(&[one]);
+        ast.set_tail(cycle, cycle);
+        let mut out = vec![];
+        ast.write_dot(cycle, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains(&format!("  {0}:tail -> {0};\n", cycle)));
+        assert_eq!(out.matches("shape=record").count(), 1);
+    }
+}
@@ -0,0 +1,1343 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+
+use crate::lang::ast::{Ast, AstNode, AstRef, Origin, SyntaxInfo};
+use crate::lang::pass::Pass;
+use crate::lang::pattern::{Binding, Bindings, Pattern};
+use crate::lang::symbol::SymbolTable;
+use crate::lex::lexer::Span;
+
+// Spans are `None` for nodes without `SyntaxInfo`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExpandError {
+    // A use of a macro that none of its rules match.
+    NoMatch {
+        name: Arc<str>,
+        node: AstRef,
+        span: Option<Span>,
+    },
+    // A use of a macro whose expansion needed more nested expansions than the limit, e.g. because
+    // it expands to itself.
+    TooDeep {
+        name: Arc<str>,
+        node: AstRef,
+        span: Option<Span>,
+    },
+    // A `define-syntax` or `syntax-rules` of the wrong shape, a program that isn't a list, or a
+    // `quasiquote` or `unquote` of the wrong shape where quasiquotes are rewritten.
+    Malformed {
+        node: AstRef,
+        span: Option<Span>,
+    },
+    // A template with a variable used at a different ellipsis depth than in its pattern, an
+    // ellipsis following no variables matched by one, or variables under the same ellipsis that
+    // matched different numbers of items.
+    InvalidTemplate {
+        node: AstRef,
+        span: Option<Span>,
+    },
+}
+
+impl ExpandError {
+    pub fn span(&self) -> Option<Span> {
+        match *self {
+            ExpandError::NoMatch { span, .. } => span,
+            ExpandError::TooDeep { span, .. } => span,
+            ExpandError::Malformed { span, .. } => span,
+            ExpandError::InvalidTemplate { span, .. } => span,
+        }
+    }
+}
+
+impl fmt::Display for ExpandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExpandError::NoMatch { name, .. } => write!(f, "no rule of {} matches", name)?,
+            ExpandError::TooDeep { name, .. } => write!(f, "expansion of {} is too deep", name)?,
+            ExpandError::Malformed { .. } => write!(f, "malformed macro definition")?,
+            ExpandError::InvalidTemplate { .. } => write!(f, "invalid macro template")?,
+        }
+        match self.span() {
+            Some(span) => write!(f, " at {}", span.start),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Error for ExpandError {}
+
+// One use of a macro rewritten by one of its rules, as traced. `input` is the use and `output`
+// what it was rewritten to, before the uses of macros in that were expanded, and `span` is where
+// the use was written, which the nodes of `output` the template introduced are given too.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Expansion {
+    pub name: Arc<str>,
+    pub input: AstRef,
+    pub output: AstRef,
+    pub span: Option<Span>,
+    // How many other uses this one is inside the expansions of.
+    pub depth: usize,
+}
+
+// What the expander does with quasiquoted forms once the uses of macros in their `unquote`d parts
+// are expanded. The calls they are rewritten to are of whatever the global `list`, `cons` and
+// `append` are, which variables bound where the quasiquote is can't capture.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Quasiquote {
+    // Leave them for a later stage that understands them.
+    #[default]
+    Keep,
+    // Rewrite them to build each pair at run time, as `(append (list item) rest)`, or
+    // `(append spliced rest)` for an `unquote-splicing`, with every atom `quote`d.
+    Naive,
+    // Rewrite them to `quote` the largest parts with nothing unquoted in them, building lists by
+    // `list` and `cons` around the unquoted parts, and only calling `append` for a splice that
+    // isn't at the end of its list.
+    Fold,
+}
+
+#[derive(Debug, Clone)]
+struct Macro {
+    name: Arc<str>,
+    // Each pattern and the template of what it expands to, in the order they're tried.
+    rules: Vec<(Pattern, AstRef)>,
+}
+
+// Expands the uses of macros defined at the top level of a program by
+// `(define-syntax name (syntax-rules (literal ..) (pattern template) ..))`, which are matched as
+// `Pattern`s. Macros stay defined from one expansion to the next, e.g. between the lines of a
+// REPL, but refer to the nodes of their definitions, so only for expansions in the same `Ast`.
+//
+// The variables a template binds, e.g. the `tmp` of a `swap!`, are renamed to `SymbolTable::gensym`
+// symbols, which the program can't have used, so they can't capture the variables of the forms the
+// macro was given. The other symbols a template introduces, like `let` or `+`, are renamed to
+// `SymbolTable::alias`es, which variables bound where the macro is used can't capture either, so
+// they refer to what their origin is bound to at the top level. Quoted forms aren't expanded, nor
+// are quasiquoted ones other than their `unquote`d parts. The nodes a template introduces are
+// given the `SyntaxInfo` of the use, so that errors in them point at it, and an `Origin` of the
+// use and the macro's name. Lists rebuilt around an expansion have the `Origin` of the list they
+// replace.
+#[derive(Debug, Clone)]
+pub struct Expander {
+    macros: HashMap<u64, Macro>,
+    max_depth: usize,
+    quasiquote: Quasiquote,
+    trace: Option<Vec<Expansion>>,
+}
+
+impl Default for Expander {
+    fn default() -> Self {
+        Expander {
+            macros: HashMap::new(),
+            max_depth: 256,
+            quasiquote: Quasiquote::Keep,
+            trace: None,
+        }
+    }
+}
+
+impl Expander {
+    pub fn new() -> Expander {
+        Self::default()
+    }
+
+    // The most macro uses that can be expanded inside one another.
+    pub fn set_max_depth(&mut self, depth: usize) {
+        self.max_depth = depth;
+    }
+
+    pub fn with_max_depth(&mut self, depth: usize) -> &mut Self {
+        self.set_max_depth(depth);
+        self
+    }
+
+    pub fn set_quasiquote(&mut self, quasiquote: Quasiquote) {
+        self.quasiquote = quasiquote;
+    }
+
+    pub fn with_quasiquote(&mut self, quasiquote: Quasiquote) -> &mut Self {
+        self.set_quasiquote(quasiquote);
+        self
+    }
+
+    pub fn is_macro(&self, name: &str) -> bool {
+        SymbolTable::global()
+            .get(name)
+            .is_some_and(|symbol| self.macros.contains_key(&symbol))
+    }
+
+    // Record each use of a macro expanded, until turned off.
+    pub fn set_trace(&mut self, trace: bool) {
+        match trace {
+            true => self.trace = self.trace.take().or(Some(vec![])),
+            false => self.trace = None,
+        }
+    }
+
+    pub fn with_trace(&mut self, trace: bool) -> &mut Self {
+        self.set_trace(trace);
+        self
+    }
+
+    // The expansions recorded since tracing was turned on or the trace was last taken, in the
+    // order they were made.
+    pub fn take_trace(&mut self) -> Vec<Expansion> {
+        self.trace.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    // Expand the program whose top-level forms are the list `root`, like `Ast::root`, returning
+    // the list of its expanded forms, without its macro definitions.
+    pub fn expand(&mut self, ast: &mut Ast, root: AstRef) -> Result<AstRef, ExpandError> {
+        if !ast.is_list(root) {
+            return Err(malformed(ast, root));
+        }
+
+        let mut forms = vec![];
+        for form in ast.list_iter(root).collect::<Vec<_>>() {
+            if !self.define_syntax(ast, form)? {
+                forms.push(self.expand_form(ast, form, 0)?);
+            }
+        }
+        Ok(ast.create_list(&forms))
+    }
+
+    // Rewrite `form` by the first rule that matches it if it is a use of a macro, without
+    // expanding the uses of macros in what it's rewritten to, or give `form` back if it isn't.
+    pub fn expand_once(&mut self, ast: &mut Ast, form: AstRef) -> Result<AstRef, ExpandError> {
+        match self.macro_of(ast, form) {
+            Some(mac) => self.apply(ast, form, &mac, 0),
+            None => Ok(form),
+        }
+    }
+
+    // The macro `id` is a use of, if any.
+    fn macro_of(&self, ast: &Ast, id: AstRef) -> Option<Macro> {
+        let (head, _) = ast.get_pair(id)?;
+        let symbol = SymbolTable::global().origin(ast.get_symbol_id(head)?);
+        self.macros.get(&symbol).cloned()
+    }
+
+    // Define the macro if `form` is a `define-syntax`.
+    fn define_syntax(&mut self, ast: &Ast, form: AstRef) -> Result<bool, ExpandError> {
+        let Some((head, _)) = ast.get_pair(form) else {
+            return Ok(false);
+        };
+        if ast.get_symbol(head).as_deref() != Some("define-syntax") {
+            return Ok(false);
+        }
+
+        let items = list(ast, form)?;
+        let (name, spec) = match items[..] {
+            [_, name, spec] if ast.get_symbol(name).is_some() => (name, spec),
+            _ => return Err(malformed(ast, form)),
+        };
+        let spec = list(ast, spec)?;
+        let (literals, rules) = match &spec[..] {
+            [head, literals, rules @ ..]
+                if ast.get_symbol(*head).as_deref() == Some("syntax-rules") =>
+            {
+                (list(ast, *literals)?, rules)
+            }
+            _ => return Err(malformed(ast, items[2])),
+        };
+
+        let mut parsed = vec![];
+        for &rule in rules {
+            let (pattern, template) = match list(ast, rule)?[..] {
+                [pattern, template] if ast.get_pair(pattern).is_some() => (pattern, template),
+                _ => return Err(malformed(ast, rule)),
+            };
+
+            let mut pattern = Pattern::new(pattern);
+            for &literal in &literals {
+                let literal = ast
+                    .get_symbol(literal)
+                    .ok_or_else(|| malformed(ast, literal))?;
+                pattern.add_literal(&literal);
+            }
+            parsed.push((pattern, template));
+        }
+
+        let symbol = ast.get_symbol_id(name).unwrap();
+        self.macros.insert(
+            symbol,
+            Macro {
+                name: ast.get_symbol(name).unwrap(),
+                rules: parsed,
+            },
+        );
+        Ok(true)
+    }
+
+    // Expand the uses of macros in `id`, inside `depth` others.
+    fn expand_form(
+        &mut self,
+        ast: &mut Ast,
+        id: AstRef,
+        depth: usize,
+    ) -> Result<AstRef, ExpandError> {
+        let Some((head, _)) = ast.get_pair(id) else {
+            return Ok(id);
+        };
+
+        match ast.get_symbol(head).as_deref() {
+            Some("quote") => return Ok(id),
+            Some("quasiquote") => {
+                let expanded = self.expand_quasiquoted(ast, id, depth, 0)?;
+                return match self.quasiquote {
+                    Quasiquote::Keep => Ok(expanded),
+                    Quasiquote::Naive => Quasiquoter::new(ast, expanded, false).form(expanded),
+                    Quasiquote::Fold => Quasiquoter::new(ast, expanded, true).form(expanded),
+                };
+            }
+            _ => {}
+        }
+
+        if let Some(mac) = self.macro_of(ast, id) {
+            if depth == self.max_depth {
+                return Err(ExpandError::TooDeep {
+                    name: mac.name.clone(),
+                    node: id,
+                    span: ast.get_syntax(id).map(|syntax| syntax.span),
+                });
+            }
+
+            let expansion = self.apply(ast, id, &mac, depth)?;
+            return self.expand_form(ast, expansion, depth + 1);
+        }
+
+        self.map_items(ast, id, |expander, ast, item| {
+            expander.expand_form(ast, item, depth)
+        })
+    }
+
+    // Expand the uses of macros in the `unquote`d parts of `id`, which is inside `level`
+    // quasiquotes.
+    fn expand_quasiquoted(
+        &mut self,
+        ast: &mut Ast,
+        id: AstRef,
+        depth: usize,
+        level: usize,
+    ) -> Result<AstRef, ExpandError> {
+        let Some((head, _)) = ast.get_pair(id) else {
+            return Ok(id);
+        };
+
+        let level = match ast.get_symbol(head).as_deref() {
+            Some("unquote" | "unquote-splicing") if level == 1 => {
+                // The keyword is left as it is.
+                let mut keyword = true;
+                return self.map_items(ast, id, |expander, ast, item| {
+                    match std::mem::take(&mut keyword) {
+                        true => Ok(item),
+                        false => expander.expand_form(ast, item, depth),
+                    }
+                });
+            }
+            Some("unquote" | "unquote-splicing") => level - 1,
+            Some("quasiquote") => level + 1,
+            _ => level,
+        };
+        self.map_items(ast, id, |expander, ast, item| {
+            expander.expand_quasiquoted(ast, item, depth, level)
+        })
+    }
+
+    // The list `id` with each of its items replaced by what `f` gives for it, which is `id` itself
+    // unless that changes them. A cyclic list isn't code, so is left alone.
+    fn map_items<F>(&mut self, ast: &mut Ast, id: AstRef, mut f: F) -> Result<AstRef, ExpandError>
+    where
+        F: FnMut(&mut Self, &mut Ast, AstRef) -> Result<AstRef, ExpandError>,
+    {
+        if ast.list_len(id).is_none() && !ast.is_dotted_list(id) {
+            return Ok(id);
+        }
+        let mut pairs = vec![];
+        let mut items = vec![];
+        let mut changed = false;
+        let mut iter = ast.list_iter(id);
+        let mut pair = id;
+        while let Some(item) = iter.next() {
+            pairs.push(pair);
+            items.push(item);
+            pair = iter.tail();
+        }
+        let tail = pair;
+
+        for item in &mut items {
+            let expanded = f(self, ast, *item)?;
+            changed |= expanded != *item;
+            *item = expanded;
+        }
+        if !changed {
+            return Ok(id);
+        }
+
+        let mut list = tail;
+        for (&pair, &item) in pairs.iter().zip(&items).rev() {
+            list = ast.create_pair(item, list);
+            if let Some(&syntax) = ast.get_syntax(pair) {
+                ast.set_syntax(list, syntax);
+            }
+            ast.set_origin(
+                list,
+                Origin {
+                    from: pair,
+                    by: None,
+                },
+            );
+        }
+        Ok(list)
+    }
+
+    // Rewrite the use `id` of `mac`, inside `depth` others, by its first rule that matches.
+    fn apply(
+        &mut self,
+        ast: &mut Ast,
+        id: AstRef,
+        mac: &Macro,
+        depth: usize,
+    ) -> Result<AstRef, ExpandError> {
+        for (pattern, template) in &mac.rules {
+            let Some(bindings) = ast.match_pattern(id, pattern) else {
+                continue;
+            };
+
+            let mut instantiate = Instantiate {
+                syntax: ast.get_syntax(id).copied(),
+                origin: Origin {
+                    from: id,
+                    by: Some(mac.name.clone()),
+                },
+                ast,
+                introduced: vec![],
+            };
+            let expansion = instantiate.template(*template, &bindings)?;
+            let introduced = instantiate.introduced;
+
+            self.rename(ast, expansion, &introduced);
+            if let Some(trace) = self.trace.as_mut() {
+                trace.push(Expansion {
+                    name: mac.name.clone(),
+                    input: id,
+                    output: expansion,
+                    span: ast.get_syntax(id).map(|syntax| syntax.span),
+                    depth,
+                });
+            }
+            return Ok(expansion);
+        }
+
+        Err(ExpandError::NoMatch {
+            name: mac.name.clone(),
+            node: id,
+            span: ast.get_syntax(id).map(|syntax| syntax.span),
+        })
+    }
+
+    // Rename the symbols a template introduced: those that bind variables, and every other with
+    // the same name, to a gensym, and the rest to an alias, the same for each with the same name.
+    fn rename(&mut self, ast: &mut Ast, expansion: AstRef, introduced: &[AstRef]) {
+        let table = SymbolTable::global();
+        let introduced_set = introduced.iter().copied().collect::<HashSet<_>>();
+        let mut binders = vec![];
+        find_binders(ast, expansion, &mut HashSet::new(), &mut binders);
+
+        let mut renames = HashMap::new();
+        for binder in binders {
+            if !introduced_set.contains(&binder) {
+                continue;
+            }
+            let symbol = ast.get_symbol_id(binder).unwrap();
+            renames
+                .entry(symbol)
+                .or_insert_with(|| table.gensym(symbol));
+        }
+
+        for &node in introduced {
+            let symbol = ast.get_symbol_id(node).unwrap();
+            let renamed = *renames.entry(symbol).or_insert_with(|| table.alias(symbol));
+            ast.set(node, AstNode::Symbol(renamed));
+        }
+    }
+}
+
+impl Pass for Expander {
+    fn name(&self) -> &str {
+        "expand"
+    }
+
+    fn run(&mut self, ast: &mut Ast, root: AstRef) -> Result<AstRef, Box<dyn Error + Send + Sync>> {
+        Ok(self.expand(ast, root)?)
+    }
+}
+
+fn malformed(ast: &Ast, node: AstRef) -> ExpandError {
+    ExpandError::Malformed {
+        node,
+        span: ast.get_syntax(node).map(|syntax| syntax.span),
+    }
+}
+
+fn invalid_template(ast: &Ast, node: AstRef) -> ExpandError {
+    ExpandError::InvalidTemplate {
+        node,
+        span: ast.get_syntax(node).map(|syntax| syntax.span),
+    }
+}
+
+// The items of the proper list `id`, which is part of a macro definition.
+fn list(ast: &Ast, id: AstRef) -> Result<Vec<AstRef>, ExpandError> {
+    match ast.is_list(id) {
+        true => Ok(ast.list_iter(id).collect()),
+        false => Err(malformed(ast, id)),
+    }
+}
+
+fn is_ellipsis(ast: &Ast, id: AstRef) -> bool {
+    ast.get_symbol(id).as_deref() == Some("...")
+}
+
+// The symbols bound by the binding forms in `id`, as found by scope resolution.
+fn find_binders(ast: &Ast, id: AstRef, seen: &mut HashSet<AstRef>, binders: &mut Vec<AstRef>) {
+    if ast.list_len(id).unwrap_or(0) == 0 || !seen.insert(id) {
+        return;
+    }
+
+    let items = ast.list_iter(id).collect::<Vec<_>>();
+    let bindings = |binders: &mut Vec<AstRef>, bindings: AstRef| {
+        for binding in ast.list_iter(bindings) {
+            binders.extend(ast.get_pair(binding).map(|(name, _)| name));
+        }
+    };
+    let params = |binders: &mut Vec<AstRef>, params: AstRef| {
+        let mut iter = ast.list_iter(params);
+        binders.extend(iter.by_ref());
+        binders.push(iter.tail());
+    };
+
+    match (ast.get_symbol(items[0]).as_deref(), &items[1..]) {
+        (Some("quote"), _) => return,
+        (Some("quasiquote"), _) => {
+            let mut unquoted = vec![];
+            find_unquoted(ast, id, 0, &mut unquoted);
+            for form in unquoted {
+                find_binders(ast, form, seen, binders);
+            }
+            return;
+        }
+        (Some("lambda"), [formals, ..]) => params(binders, *formals),
+        (Some("define"), [target, ..]) => match ast.get_pair(*target) {
+            Some((name, formals)) => {
+                binders.push(name);
+                params(binders, formals);
+            }
+            None => binders.push(*target),
+        },
+        (Some("let"), [name, named, ..]) if ast.get_symbol(*name).is_some() => {
+            binders.push(*name);
+            bindings(binders, *named);
+        }
+        (Some("let" | "let*" | "letrec" | "letrec*"), [unnamed, ..]) => bindings(binders, *unnamed),
+        _ => {}
+    }
+    binders.retain(|&binder| ast.get_symbol(binder).is_some());
+
+    for item in items {
+        find_binders(ast, item, seen, binders);
+    }
+}
+
+// The forms in the `unquote`d parts of `id`, which is inside `level` quasiquotes.
+fn find_unquoted(ast: &Ast, id: AstRef, level: usize, unquoted: &mut Vec<AstRef>) {
+    if ast.list_len(id).unwrap_or(0) == 0 {
+        return;
+    }
+
+    let items = ast.list_iter(id).collect::<Vec<_>>();
+    let level = match ast.get_symbol(items[0]).as_deref() {
+        Some("unquote" | "unquote-splicing") if level == 1 => {
+            unquoted.extend(&items[1..]);
+            return;
+        }
+        Some("unquote" | "unquote-splicing") => level - 1,
+        Some("quasiquote") => level + 1,
+        _ => level,
+    };
+    for item in items {
+        find_unquoted(ast, item, level, unquoted);
+    }
+}
+
+// Fills in a template with what its pattern's variables matched.
+struct Instantiate<'a> {
+    ast: &'a mut Ast,
+    // The syntax of the use, which the nodes created are given.
+    syntax: Option<SyntaxInfo>,
+    origin: Origin,
+    // The symbols created for those in the template that aren't variables.
+    introduced: Vec<AstRef>,
+}
+
+impl Instantiate<'_> {
+    fn create(&mut self, node: AstNode) -> AstRef {
+        let id = self.ast.add(node);
+        if let Some(syntax) = self.syntax {
+            self.ast.set_syntax(id, syntax);
+        }
+        self.ast.set_origin(id, self.origin.clone());
+        id
+    }
+
+    fn template(&mut self, template: AstRef, bindings: &Bindings) -> Result<AstRef, ExpandError> {
+        match self.ast.get(template) {
+            &AstNode::Symbol(symbol) => {
+                match bindings.get(&SymbolTable::global().resolve(symbol)) {
+                    Some(&Binding::One(id)) => Ok(id),
+                    Some(Binding::Many(_)) => Err(invalid_template(self.ast, template)),
+                    None => {
+                        let id = self.create(AstNode::Symbol(symbol));
+                        self.introduced.push(id);
+                        Ok(id)
+                    }
+                }
+            }
+            AstNode::Pair(..) => {
+                let mut iter = self.ast.list_iter(template);
+                let items = iter.by_ref().collect::<Vec<_>>();
+                let tail = iter.tail();
+
+                // `(... ...)` is a literal `...`.
+                if let [ellipsis, escaped] = items[..] {
+                    if is_ellipsis(self.ast, ellipsis) && *self.ast.get(tail) == AstNode::Nil {
+                        return Ok(escaped);
+                    }
+                }
+
+                let items = self.items(&items, bindings)?;
+                let mut list = self.template(tail, bindings)?;
+                for item in items.into_iter().rev() {
+                    list = self.create(AstNode::Pair(item, list));
+                }
+                Ok(list)
+            }
+            AstNode::Vector(elements) => {
+                let elements = elements.clone();
+                let elements = self.items(&elements, bindings)?;
+                Ok(self.create(AstNode::Vector(elements)))
+            }
+            _ => Ok(template),
+        }
+    }
+
+    // The items of a list or vector template, where an item followed by `...` is repeated for
+    // each of the items its variables matched.
+    fn items(&mut self, items: &[AstRef], bindings: &Bindings) -> Result<Vec<AstRef>, ExpandError> {
+        let mut result = vec![];
+        let mut i = 0;
+        while i < items.len() {
+            let item = items[i];
+            if !items
+                .get(i + 1)
+                .is_some_and(|&next| is_ellipsis(self.ast, next))
+            {
+                result.push(self.template(item, bindings)?);
+                i += 1;
+                continue;
+            }
+
+            let mut repeated = vec![];
+            let mut stack = vec![item];
+            while let Some(id) = stack.pop() {
+                if let Some(Binding::Many(matched)) =
+                    self.ast.get_symbol(id).and_then(|name| bindings.get(&name))
+                {
+                    repeated.push((self.ast.get_symbol(id).unwrap(), matched));
+                }
+                self.ast.push_children(id, &mut stack);
+            }
+
+            let Some(&(_, first)) = repeated.first() else {
+                return Err(invalid_template(self.ast, items[i + 1]));
+            };
+            if repeated
+                .iter()
+                .any(|(_, matched)| matched.len() != first.len())
+            {
+                return Err(invalid_template(self.ast, item));
+            }
+
+            for n in 0..first.len() {
+                let mut inner = bindings.clone();
+                for (name, matched) in &repeated {
+                    inner.insert(name.clone(), matched[n].clone());
+                }
+                result.push(self.template(item, &inner)?);
+            }
+            i += 2;
+        }
+        Ok(result)
+    }
+}
+
+// Rewrites a quasiquoted form to the calls that build it.
+struct Quasiquoter<'a> {
+    ast: &'a mut Ast,
+    fold: bool,
+    // The syntax and origin of the quasiquote, which the nodes created are given.
+    syntax: Option<SyntaxInfo>,
+    origin: Origin,
+    // The aliases of the globals the calls are to, made as they're first needed.
+    names: HashMap<&'static str, u64>,
+}
+
+// What part of a template is rewritten to.
+enum Built {
+    // The part itself, as nothing in it is unquoted.
+    Constant(AstRef),
+    // A list of the values of these.
+    List(Vec<AstRef>),
+    Code(AstRef),
+}
+
+impl<'a> Quasiquoter<'a> {
+    fn new(ast: &'a mut Ast, form: AstRef, fold: bool) -> Quasiquoter<'a> {
+        Quasiquoter {
+            syntax: ast.get_syntax(form).copied(),
+            origin: Origin {
+                from: form,
+                by: Some("quasiquote".into()),
+            },
+            ast,
+            fold,
+            names: HashMap::new(),
+        }
+    }
+
+    fn create(&mut self, node: AstNode) -> AstRef {
+        let id = self.ast.add(node);
+        if let Some(syntax) = self.syntax {
+            self.ast.set_syntax(id, syntax);
+        }
+        self.ast.set_origin(id, self.origin.clone());
+        id
+    }
+
+    // A call of the global `name`, or a use of the special form.
+    fn call(&mut self, name: &'static str, args: &[AstRef]) -> AstRef {
+        let table = SymbolTable::global();
+        let symbol = *self
+            .names
+            .entry(name)
+            .or_insert_with(|| table.alias(table.intern(name)));
+        let mut list = self.create(AstNode::Nil);
+        for &arg in args.iter().rev() {
+            list = self.create(AstNode::Pair(arg, list));
+        }
+        let head = self.create(AstNode::Symbol(symbol));
+        self.create(AstNode::Pair(head, list))
+    }
+
+    // The code the whole `(quasiquote template)` stands for.
+    fn form(&mut self, id: AstRef) -> Result<AstRef, ExpandError> {
+        match list(self.ast, id)?[..] {
+            [_, template] => {
+                let built = self.template(template, 1)?;
+                Ok(self.code(built))
+            }
+            _ => Err(malformed(self.ast, id)),
+        }
+    }
+
+    fn code(&mut self, built: Built) -> AstRef {
+        match built {
+            Built::Constant(id) => self.call("quote", &[id]),
+            Built::List(items) => self.call("list", &items),
+            Built::Code(id) => id,
+        }
+    }
+
+    // What `id`, which is inside `level` quasiquotes, is rewritten to.
+    fn template(&mut self, id: AstRef, level: usize) -> Result<Built, ExpandError> {
+        let Some((head, tail)) = self.ast.get_pair(id) else {
+            return Ok(match self.fold {
+                true => Built::Constant(id),
+                false => Built::Code(self.call("quote", &[id])),
+            });
+        };
+
+        let keyword = self.ast.get_symbol(head);
+        let tail_level = match keyword.as_deref() {
+            Some("unquote") if level == 1 => {
+                return match list(self.ast, id)?[..] {
+                    [_, form] => Ok(Built::Code(form)),
+                    _ => Err(malformed(self.ast, id)),
+                };
+            }
+            Some("unquote" | "unquote-splicing") => level - 1,
+            Some("quasiquote") => level + 1,
+            _ => level,
+        };
+
+        let spliced = match self.ast.get_pair(head) {
+            Some((inner, _)) if level == 1 => {
+                self.ast.get_symbol(inner).as_deref() == Some("unquote-splicing")
+            }
+            _ => false,
+        };
+        let rest = self.template(tail, tail_level)?;
+        if spliced {
+            let form = match list(self.ast, head)?[..] {
+                [_, form] => form,
+                _ => return Err(malformed(self.ast, head)),
+            };
+            return Ok(match rest {
+                Built::Constant(tail) if self.ast.get(tail) == &AstNode::Nil => Built::Code(form),
+                rest => {
+                    let rest = self.code(rest);
+                    Built::Code(self.call("append", &[form, rest]))
+                }
+            });
+        }
+
+        let first = self.template(head, level)?;
+        if !self.fold {
+            let first = self.code(first);
+            let first = self.call("list", &[first]);
+            let rest = self.code(rest);
+            return Ok(Built::Code(self.call("append", &[first, rest])));
+        }
+        Ok(match (first, rest) {
+            (Built::Constant(_), Built::Constant(_)) => Built::Constant(id),
+            (first, Built::Constant(tail)) if self.ast.get(tail) == &AstNode::Nil => {
+                Built::List(vec![self.code(first)])
+            }
+            (first, Built::List(mut items)) => {
+                items.insert(0, self.code(first));
+                Built::List(items)
+            }
+            (first, rest) => {
+                let first = self.code(first);
+                let rest = self.code(rest);
+                Built::Code(self.call("cons", &[first, rest]))
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lang::interpreter::Interpreter;
+    use crate::lang::reader::parse_str;
+    use crate::lang::scope::Resolver;
+    use crate::lang::value::Value;
+    use std::rc::Rc;
+
+    // The forms `root` lists, with the numbers of gensyms, which depend on how many other tests
+    // have made, counted from 0 in the order they're first seen.
+    fn display(ast: &Ast, root: AstRef) -> Vec<String> {
+        let mut numbers = HashMap::new();
+        let mut forms = vec![];
+        for form in ast.list_iter(root) {
+            let form = ast.display(form).to_string();
+            let mut chars = form.chars().peekable();
+            let mut renumbered = String::new();
+            while let Some(c) = chars.next() {
+                renumbered.push(c);
+                if c != '%' {
+                    continue;
+                }
+                let mut number = String::new();
+                while let Some(digit) = chars.next_if(char::is_ascii_digit) {
+                    number.push(digit);
+                }
+                let count = numbers.len();
+                renumbered.push_str(&numbers.entry(number).or_insert(count).to_string());
+            }
+            forms.push(renumbered);
+        }
+        forms
+    }
+
+    fn expand_with(expander: &mut Expander, source: &str) -> Result<Vec<String>, ExpandError> {
+        let mut ast = Ast::new();
+        parse_str(source, &mut ast).unwrap();
+        let root = ast.root().unwrap();
+        let root = expander.expand(&mut ast, root)?;
+        Ok(display(&ast, root))
+    }
+
+    fn expand(source: &str) -> Result<Vec<String>, ExpandError> {
+        expand_with(&mut Expander::new(), source)
+    }
+
+    const SWAP: &str = "(define-syntax swap!
+      (syntax-rules ()
+        ((_ a b) (let ((tmp a)) (set! a b) (set! b tmp)))))";
+
+    #[test]
+    fn test_expand() {
+        assert_eq!(
+            expand(&format!("{} (swap! x y) '(swap! x y)", SWAP)).unwrap(),
+            vec![
+                "(let ((tmp%0 x)) (set! x y) (set! y tmp%0))",
+                "(quote (swap! x y))"
+            ]
+        );
+
+        // Rules are tried in order, and macros can expand to uses of themselves.
+        let or = "(define-syntax my-or
+          (syntax-rules ()
+            ((_) #f)
+            ((_ e) e)
+            ((_ e r ...) (let ((t e)) (if t t (my-or r ...))))))";
+        assert_eq!(
+            expand(&format!("{} (my-or) (f (my-or a b))", or)).unwrap(),
+            vec!["#f", "(f (let ((t%0 a)) (if t%0 t%0 b)))"]
+        );
+
+        // Literals only match themselves, and ellipses nest.
+        let for_in = "(define-syntax for
+          (syntax-rules (in)
+            ((_ x in xs body ...) (map (lambda (x) body ...) xs))))";
+        assert_eq!(
+            expand(&format!("{} (for y in ys (f y) (g y))", for_in)).unwrap(),
+            vec!["(map (lambda (y) (f y) (g y)) ys)"]
+        );
+        let table = "(define-syntax table
+          (syntax-rules ()
+            ((_ (k v ...) ...) (list #(k (v ...)) ... '(... ...)))))";
+        assert_eq!(
+            expand(&format!("{} (table (a 1 2) (b))", table)).unwrap(),
+            vec!["(list #(a (1 2)) #(b ()) (quote ...))"]
+        );
+    }
+
+    #[test]
+    fn test_hygiene() {
+        // The `tmp` the macro binds doesn't capture the one it's given.
+        let source = format!("{} (define tmp 1) (define y 2) (swap! tmp y)", SWAP);
+        let mut ast = Ast::new();
+        parse_str(&source, &mut ast).unwrap();
+        let root = ast.root().unwrap();
+        let root = Expander::new().expand(&mut ast, root).unwrap();
+
+        assert_eq!(
+            display(&ast, root)[2],
+            "(let ((tmp%0 tmp)) (set! tmp y) (set! y tmp%0))"
+        );
+        let scopes = Resolver::new().resolve(&ast, root);
+        assert_eq!(scopes.errors(), &[]);
+        assert_eq!(scopes.binders().len(), 3);
+
+        // Nor does it capture a variable with the name it's renamed to.
+        let source = format!("{} (define tmp%0 1) (define y 2) (swap! tmp%0 y)", SWAP);
+        let mut ast = Ast::new();
+        parse_str(&source, &mut ast).unwrap();
+        let root = ast.root().unwrap();
+        let root = Expander::new().expand(&mut ast, root).unwrap();
+        let scopes = Resolver::new().resolve(&ast, root);
+        assert_eq!(scopes.errors(), &[]);
+        let swap = ast.list_iter(root).nth(2).unwrap();
+        let set = ast.list_iter(swap).nth(2).unwrap();
+        let tmp = ast.list_iter(set).nth(1).unwrap();
+        assert_eq!(scopes.resolve(tmp), Some(0));
+
+        // The symbols a template uses but doesn't bind mean what they do where the macro is
+        // defined, not where it's used.
+        let source = "(define-syntax my-if (syntax-rules () ((_ c a b) (if c a b))))
+            (define-syntax inc (syntax-rules () ((_ x) (+ x 1))))
+            ((lambda (if +) (list (my-if #t 1 2) (inc 1) (quote +))) list -)";
+        let mut ast = Ast::new();
+        parse_str(source, &mut ast).unwrap();
+        let root = ast.root().unwrap();
+        let root = Expander::new().expand(&mut ast, root).unwrap();
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .with_builtin("list", |args| Ok(Value::list(args.to_vec())))
+            .with_builtin("+", |args| match args {
+                [Value::Integer(a), Value::Integer(b)] => Ok(Value::Integer(a + b)),
+                _ => Err("expects 2 integers".to_string()),
+            })
+            .with_builtin("-", |_| Err("shouldn't be called".to_string()));
+        let value = interpreter.run(&ast, root).unwrap();
+        assert_eq!(value.to_string(), "(1 2 +)");
+        let resolver = Resolver::new()
+            .with_global("list")
+            .with_global("+")
+            .with_global("-")
+            .clone();
+        assert_eq!(resolver.resolve(&ast, root).errors(), &[]);
+
+        // Each expansion gets names of its own, and macros stay defined.
+        let mut expander = Expander::new();
+        let mut ast = Ast::new();
+        let forms = parse_str(SWAP, &mut ast).unwrap();
+        let forms = ast.create_list(&forms);
+        expander.expand(&mut ast, forms).unwrap();
+        assert!(expander.is_macro("swap!"));
+
+        let forms = parse_str("(swap! a b) (swap! c d)", &mut ast).unwrap();
+        let forms = ast.create_list(&forms);
+        let forms = expander.expand(&mut ast, forms).unwrap();
+        assert_eq!(
+            display(&ast, forms),
+            vec![
+                "(let ((tmp%0 a)) (set! a b) (set! b tmp%0))",
+                "(let ((tmp%1 c)) (set! c d) (set! d tmp%1))"
+            ]
+        );
+
+        // So does each expander.
+        let forms = parse_str("(swap! a b)", &mut ast).unwrap();
+        let forms = ast.create_list(&forms);
+        let mut other = Expander::new();
+        let mut ast_other = Ast::new();
+        let swap = parse_str(SWAP, &mut ast_other).unwrap();
+        let swap = ast_other.create_list(&swap);
+        other.expand(&mut ast_other, swap).unwrap();
+        let uses = parse_str("(swap! a b)", &mut ast_other).unwrap();
+        let uses = ast_other.create_list(&uses);
+        let uses = other.expand(&mut ast_other, uses).unwrap();
+        let forms = expander.expand(&mut ast, forms).unwrap();
+        let tmp = |ast: &Ast, forms: AstRef| {
+            let form = ast.list_iter(forms).next().unwrap();
+            let bindings = ast.list_iter(form).nth(1).unwrap();
+            let binding = ast.list_iter(bindings).next().unwrap();
+            ast.get_symbol_id(ast.list_iter(binding).next().unwrap())
+        };
+        assert_ne!(tmp(&ast, forms), tmp(&ast_other, uses));
+    }
+
+    #[test]
+    fn test_origins() {
+        let source = "(define-syntax inc (syntax-rules () ((_ x) (+ x 1))))
+            (define-syntax twice (syntax-rules () ((_ x) (inc (inc x)))))
+            (f (twice y))";
+        let mut ast = Ast::new();
+        parse_str(source, &mut ast).unwrap();
+        let root = ast.root().unwrap();
+        let form = ast.list_iter(root).nth(2).unwrap();
+        let (_, rest) = ast.get_pair(form).unwrap();
+        let twice = ast.list_iter(form).nth(1).unwrap();
+
+        let root = Expander::new().expand(&mut ast, root).unwrap();
+        let expanded = ast.list_iter(root).last().unwrap();
+        assert_eq!(ast.display(expanded).to_string(), "(f (+ (+ y 1) 1))");
+
+        // The lists around the expansion come from those they replace.
+        assert_eq!(
+            ast.origin_chain(expanded),
+            vec![&Origin {
+                from: form,
+                by: None
+            }]
+        );
+        let (_, expanded_rest) = ast.get_pair(expanded).unwrap();
+        assert_eq!(ast.get_origin(expanded_rest).unwrap().from, rest);
+
+        // The expansion leads back through each macro to the use that was written. The outer
+        // `inc` expanded first, and its list was rebuilt when the inner one was.
+        let plus = ast.list_iter(expanded).nth(1).unwrap();
+        let chain = ast.origin_chain(plus);
+        let names = chain
+            .iter()
+            .map(|origin| origin.by.as_deref())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec![None, Some("inc"), Some("twice")]);
+        assert_eq!(chain.last().unwrap().from, twice);
+        assert_eq!(ast.get_origin(twice), None);
+        assert_eq!(ast.get_syntax(plus), ast.get_syntax(twice));
+
+        // What a macro was given keeps no origin.
+        let inner = ast.list_iter(plus).nth(1).unwrap();
+        let y = ast.list_iter(inner).nth(1).unwrap();
+        assert!(ast.origin_chain(y).is_empty());
+    }
+
+    #[test]
+    fn test_expand_once() {
+        let source = "(define-syntax inc (syntax-rules () ((_ x) (+ x 1))))
+            (define-syntax twice (syntax-rules () ((_ x) (inc (inc x)))))
+            (twice y) (f (twice y))";
+        let mut ast = Ast::new();
+        parse_str(source, &mut ast).unwrap();
+        let root = ast.root().unwrap();
+        let mut expander = Expander::new();
+        expander.expand(&mut ast, root).unwrap();
+        let forms = ast.list_iter(root).skip(2).collect::<Vec<_>>();
+
+        // Only the use itself is rewritten, not the uses in what it's rewritten to.
+        let once = expander.expand_once(&mut ast, forms[0]).unwrap();
+        assert_eq!(ast.display(once).to_string(), "(inc (inc y))");
+        let twice = expander.expand_once(&mut ast, once).unwrap();
+        assert_eq!(ast.display(twice).to_string(), "(+ (inc y) 1)");
+        assert_eq!(ast.get_origin(twice).unwrap().from, once);
+
+        // Nor the uses inside a form that isn't one.
+        assert_eq!(expander.expand_once(&mut ast, forms[1]), Ok(forms[1]));
+        let form = parse_str("(inc)", &mut ast).unwrap()[0];
+        assert!(matches!(
+            expander.expand_once(&mut ast, form),
+            Err(ExpandError::NoMatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_expand_trace() {
+        let source = "(define-syntax inc (syntax-rules () ((_ x) (+ x 1))))
+            (define-syntax twice (syntax-rules () ((_ x) (inc (inc x)))))
+            (f (twice y))";
+        let mut ast = Ast::new();
+        parse_str(source, &mut ast).unwrap();
+        let root = ast.root().unwrap();
+        let mut expander = Expander::new();
+        expander.set_trace(true);
+        expander.expand(&mut ast, root).unwrap();
+
+        // Each use is traced as it was rewritten, outermost first.
+        let trace = expander.take_trace();
+        let steps = trace
+            .iter()
+            .map(|expansion| {
+                format!(
+                    "{} {}: {} => {}",
+                    expansion.depth,
+                    expansion.name,
+                    ast.display(expansion.input),
+                    ast.display(expansion.output)
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            steps,
+            vec![
+                "0 twice: (twice y) => (inc (inc y))",
+                "1 inc: (inc (inc y)) => (+ (inc y) 1)",
+                "2 inc: (inc y) => (+ y 1)",
+            ]
+        );
+        let start = source.find("(twice y)").unwrap();
+        let span = Some(Span {
+            start,
+            end: start + 9,
+        });
+        assert!(trace.iter().all(|expansion| expansion.span == span));
+        assert!(expander.take_trace().is_empty());
+
+        // Nothing is recorded with tracing off.
+        expander.set_trace(false);
+        let form = parse_str("(inc z)", &mut ast).unwrap()[0];
+        expander.expand_once(&mut ast, form).unwrap();
+        assert!(expander.with_trace(true).take_trace().is_empty());
+        expander.expand_once(&mut ast, form).unwrap();
+        assert_eq!(expander.take_trace()[0].input, form);
+    }
+
+    #[test]
+    fn test_expand_quasiquote() {
+        // Only the unquoted parts of a quasiquote are expanded, at the same depth.
+        let source = format!(
+            "{} `(swap! a b ,(swap! c d) `(,(swap! e f) ,,(swap! g h)) ,@(swap! i j))",
+            SWAP
+        );
+        assert_eq!(
+            expand(&source).unwrap(),
+            vec![
+                "(quasiquote (swap! a b (unquote (let ((tmp%0 c)) (set! c d) (set! d tmp%0))) \
+                 (quasiquote ((unquote (swap! e f)) (unquote (unquote (let ((tmp%1 g)) \
+                 (set! g h) (set! h tmp%1)))))) (unquote-splicing (let ((tmp%2 i)) (set! i j) \
+                 (set! j tmp%2)))))"
+            ]
+        );
+
+        // The variables bound in unquoted parts of a template are renamed.
+        let source = "(define-syntax m (syntax-rules () ((_ x) `(,(let ((t 1)) t) ,x))))
+            (m t)";
+        assert_eq!(
+            expand(source).unwrap(),
+            vec!["(quasiquote ((unquote (let ((t%0 1)) t%0)) (unquote t)))"]
+        );
+    }
+
+    #[test]
+    fn test_fold_quasiquote() {
+        let folded = |source: &str| {
+            let mut expander = Expander::new();
+            expand_with(expander.with_quasiquote(Quasiquote::Fold), source).unwrap()
+        };
+
+        // Only the parts with something unquoted in them are built.
+        assert_eq!(
+            folded("`(a (b c) #(,d))"),
+            vec!["(quote (a (b c) #((unquote d))))"]
+        );
+        assert_eq!(
+            folded("`(a (b ,c))"),
+            vec!["(list (quote a) (list (quote b) c))"]
+        );
+
+        // What follows the last unquoted part is left as it was written.
+        assert_eq!(
+            folded("`(a ,b (c) d)"),
+            vec!["(cons (quote a) (cons b (quote ((c) d))))"]
+        );
+        assert_eq!(folded("`(a . ,b)"), vec!["(cons (quote a) b)"]);
+        assert_eq!(
+            folded("`(a ,b . c)"),
+            vec!["(cons (quote a) (cons b (quote c)))"]
+        );
+
+        // A splice at the end of a list is its tail, and only one before it is appended.
+        assert_eq!(folded("`(a ,@b)"), vec!["(cons (quote a) b)"]);
+        assert_eq!(folded("`(,@a b)"), vec!["(append a (quote (b)))"]);
+
+        // Nested quasiquotes are built like lists, except where unquoted as many times.
+        assert_eq!(
+            folded("`(a `(b ,(c ,d)))"),
+            vec![
+                "(list (quote a) (list (quote quasiquote) (list (quote b) (list (quote unquote) \
+                  (list (quote c) d)))))"
+            ]
+        );
+        assert_eq!(
+            folded("`(a `(b ,c))"),
+            vec!["(quote (a (quasiquote (b (unquote c)))))"]
+        );
+
+        let mut expander = Expander::new();
+        expander.set_quasiquote(Quasiquote::Naive);
+        assert_eq!(
+            expand_with(&mut expander, "`(a ,b)").unwrap(),
+            vec!["(append (list (quote a)) (append (list b) (quote ())))"]
+        );
+
+        assert!(matches!(
+            expand_with(&mut expander, "`(a (unquote b c))"),
+            Err(ExpandError::Malformed { .. })
+        ));
+    }
+
+    #[test]
+    fn test_fold_quasiquote_values() {
+        fn items(mut list: &Value) -> Result<Vec<Value>, String> {
+            let mut items = vec![];
+            while let Value::Pair(pair) = list {
+                items.push(pair.0.clone());
+                list = &pair.1;
+            }
+            match list {
+                Value::Nil => Ok(items),
+                _ => Err("append expects lists".to_string()),
+            }
+        }
+
+        let run = |quasiquote: Quasiquote, source: &str| {
+            let mut ast = Ast::new();
+            parse_str(source, &mut ast).unwrap();
+            let root = ast.root().unwrap();
+            let mut expander = Expander::new();
+            expander.set_quasiquote(quasiquote);
+            let root = expander.expand(&mut ast, root).unwrap();
+
+            let mut interpreter = Interpreter::new();
+            interpreter
+                .with_builtin("list", |args| Ok(Value::list(args.to_vec())))
+                .with_builtin("cons", |args| match args {
+                    [head, tail] => Ok(Value::Pair(Rc::new((head.clone(), tail.clone())))),
+                    _ => Err("cons expects 2 arguments".to_string()),
+                })
+                .with_builtin("append", |args| {
+                    let Some((last, lists)) = args.split_last() else {
+                        return Ok(Value::Nil);
+                    };
+                    let mut value = last.clone();
+                    for list in lists.iter().rev() {
+                        for item in items(list)?.into_iter().rev() {
+                            value = Value::Pair(Rc::new((item, value)));
+                        }
+                    }
+                    Ok(value)
+                });
+            let value = interpreter.run(&ast, root).unwrap();
+            (value.to_string(), display(&ast, root))
+        };
+
+        // Folding builds the same values as building every pair does.
+        let sources = [
+            "`()",
+            "`a",
+            "`(1 \"two\" #\\3 #(4 ,5))",
+            "(define x 1) (define xs '(2 3)) `(a ,x (b ,@xs c) ,@xs . ,x)",
+            "(define x 1) `(a `(b ,(c ,x) ,,x))",
+            "(define xs '(1 2)) `(,@xs ,@xs)",
+            "(define-syntax m (syntax-rules () ((_ x) `(x ,x)))) (define y 2) (m y)",
+            "((lambda (list cons append) `(,list ,@cons)) 1 '(2) 3)",
+        ];
+        for source in sources {
+            let (naive, naive_forms) = run(Quasiquote::Naive, source);
+            let (folded, folded_forms) = run(Quasiquote::Fold, source);
+            assert_eq!(naive, folded, "{}", source);
+            assert!(
+                folded_forms.concat().matches("append").count()
+                    <= naive_forms.concat().matches("append").count()
+            );
+        }
+        assert_eq!(
+            run(Quasiquote::Fold, sources[3]).0,
+            "(a 1 (b 2 3 c) 2 3 . 1)"
+        );
+        assert_eq!(run(Quasiquote::Fold, sources[7]).0, "(1 2)");
+    }
+
+    #[test]
+    fn test_expand_errors() {
+        let span = |start, end| Some(Span { start, end });
+
+        let source = format!("{}\n(swap! x)", SWAP);
+        let error = expand(&source).unwrap_err();
+        assert!(matches!(error, ExpandError::NoMatch { ref name, .. } if &**name == "swap!"));
+        let start = source.find("(swap! x)").unwrap();
+        assert_eq!(error.span(), span(start, start + 9));
+
+        // The use a runaway expansion started from is reported.
+        let source = "(define-syntax loop (syntax-rules () ((_ x) (f (loop x))))) (loop 1)";
+        let mut expander = Expander::new();
+        expander.set_max_depth(10);
+        let error = expand_with(&mut expander, source).unwrap_err();
+        assert!(matches!(error, ExpandError::TooDeep { ref name, .. } if &**name == "loop"));
+        let start = source.find("(loop 1)").unwrap();
+        assert_eq!(error.span(), span(start, start + 8));
+        assert_eq!(
+            error.to_string(),
+            format!("expansion of loop is too deep at {}", start)
+        );
+
+        assert!(matches!(
+            expand("(define-syntax m (syntax-rules () (x y)))"),
+            Err(ExpandError::Malformed { .. })
+        ));
+        assert!(matches!(
+            expand("(define-syntax m (rules))"),
+            Err(ExpandError::Malformed { .. })
+        ));
+
+        // Variables have to be used under as many ellipses as they matched under.
+        let error = expand("(define-syntax m (syntax-rules () ((_ a ...) a))) (m 1)").unwrap_err();
+        assert_eq!(error.span(), span(45, 46));
+        assert!(matches!(
+            expand("(define-syntax m (syntax-rules () ((_ a) (a ...)))) (m 1)"),
+            Err(ExpandError::InvalidTemplate { .. })
+        ));
+        assert!(matches!(
+            expand(
+                "(define-syntax m (syntax-rules () ((_ (a ...) (b ...)) ((a b) ...)))) (m (1) ())"
+            ),
+            Err(ExpandError::InvalidTemplate { .. })
+        ));
+    }
+}
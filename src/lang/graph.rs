@@ -0,0 +1,256 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::lang::ast::{Ast, AstNode, AstRef};
+
+// An `Ast` seen as a directed graph, for analyses that only need its nodes and edges, like finding
+// what a form can reach or where its lists are cyclic. A pair has an edge to its head and then its
+// tail, a vector one to each element in order, and anything else none.
+#[derive(Debug, Clone, Copy)]
+pub struct AstGraph<'a> {
+    ast: &'a Ast,
+}
+
+impl Ast {
+    pub fn as_graph(&self) -> AstGraph<'_> {
+        AstGraph { ast: self }
+    }
+}
+
+impl<'a> AstGraph<'a> {
+    pub fn ast(&self) -> &'a Ast {
+        self.ast
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.ast.len()
+    }
+
+    // Every node, in the order of their `AstRef`s, leaving out those removed by collections.
+    pub fn nodes(&self) -> impl Iterator<Item = AstRef> + 'a {
+        self.ast
+            .nodes
+            .iter()
+            .enumerate()
+            .filter(|(_, node)| node.is_some())
+            .map(|(id, _)| id as AstRef)
+    }
+
+    pub fn node(&self, id: AstRef) -> &'a AstNode {
+        self.ast.get(id)
+    }
+
+    pub fn successors(&self, id: AstRef) -> impl Iterator<Item = AstRef> + 'a {
+        let (pair, elements): ([Option<AstRef>; 2], &'a [AstRef]) = match self.ast.get(id) {
+            &AstNode::Pair(head, tail) => ([Some(head), Some(tail)], &[]),
+            AstNode::Vector(elements) => ([None, None], elements),
+            _ => ([None, None], &[]),
+        };
+        pair.into_iter().flatten().chain(elements.iter().copied())
+    }
+
+    // The nodes reachable from `roots`, each once, in depth-first order, following each node's
+    // edges in order.
+    pub fn reachable(&self, roots: &[AstRef]) -> Vec<AstRef> {
+        let mut reachable = vec![];
+        let mut seen = HashSet::new();
+        let mut stack = roots.iter().rev().copied().collect::<Vec<_>>();
+        while let Some(id) = stack.pop() {
+            if !seen.insert(id) {
+                continue;
+            }
+            reachable.push(id);
+
+            let start = stack.len();
+            stack.extend(self.successors(id));
+            stack[start..].reverse();
+        }
+        reachable
+    }
+
+    // The strongly connected components of the nodes reachable from `roots`: the largest sets of
+    // nodes that can all reach one another, like the pairs of a cyclic list, or a node on its own.
+    // Each component comes after every component it has edges to, and has its nodes in the order
+    // they were reached.
+    pub fn strongly_connected(&self, roots: &[AstRef]) -> Vec<Vec<AstRef>> {
+        let mut components = Components {
+            graph: *self,
+            order: HashMap::new(),
+            low: HashMap::new(),
+            stack: vec![],
+            on_stack: HashSet::new(),
+            calls: vec![],
+            components: vec![],
+        };
+        for &root in roots {
+            if !components.order.contains_key(&root) {
+                components.visit(root);
+            }
+        }
+        components.components
+    }
+}
+
+// Tarjan's algorithm, with the calls it makes kept on a stack of their own so that long lists
+// don't overflow the real one.
+struct Components<'a> {
+    graph: AstGraph<'a>,
+    // The order each node was reached in, and the earliest reached node still on `stack` it can
+    // reach.
+    order: HashMap<AstRef, usize>,
+    low: HashMap<AstRef, usize>,
+    // The nodes reached whose components haven't been found yet.
+    stack: Vec<AstRef>,
+    on_stack: HashSet<AstRef>,
+    // The nodes being visited, with the edges of each left to follow, last first.
+    calls: Vec<(AstRef, Vec<AstRef>)>,
+    components: Vec<Vec<AstRef>>,
+}
+
+impl Components<'_> {
+    fn enter(&mut self, id: AstRef) {
+        let order = self.order.len();
+        self.order.insert(id, order);
+        self.low.insert(id, order);
+        self.stack.push(id);
+        self.on_stack.insert(id);
+
+        let mut successors = self.graph.successors(id).collect::<Vec<_>>();
+        successors.reverse();
+        self.calls.push((id, successors));
+    }
+
+    fn visit(&mut self, root: AstRef) {
+        self.enter(root);
+        while let Some((id, successors)) = self.calls.last_mut() {
+            let id = *id;
+            if let Some(next) = successors.pop() {
+                match self.order.get(&next) {
+                    None => self.enter(next),
+                    Some(&order) if self.on_stack.contains(&next) => {
+                        let low = self.low[&id].min(order);
+                        self.low.insert(id, low);
+                    }
+                    Some(_) => {}
+                }
+                continue;
+            }
+
+            self.calls.pop();
+            if let Some(&(caller, _)) = self.calls.last() {
+                let low = self.low[&caller].min(self.low[&id]);
+                self.low.insert(caller, low);
+            }
+            if self.low[&id] == self.order[&id] {
+                let mut component = vec![];
+                while let Some(node) = self.stack.pop() {
+                    self.on_stack.remove(&node);
+                    component.push(node);
+                    if node == id {
+                        break;
+                    }
+                }
+                component.reverse();
+                self.components.push(component);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_as_graph() {
+        let mut ast = Ast::new();
+        let f = ast.create_symbol("f");
+        let one = ast.create_integer(1.into());
+        let vector = ast.create_vector(&[one, f]);
+        let call = ast.create_list(&[f, vector]);
+        let (_, rest) = ast.get_pair(call).unwrap();
+        let (_, nil) = ast.get_pair(rest).unwrap();
+        ast.create_symbol("unused");
+        ast.collect_garbage(&[call]);
+
+        let graph = ast.as_graph();
+        assert_eq!(graph.node_count(), 6);
+        assert_eq!(graph.nodes().collect::<Vec<_>>(), vec![0, 1, 2, 3, 4, 5]);
+        assert_eq!(
+            graph.node(f),
+            &AstNode::Symbol(ast.get_symbol_id(f).unwrap())
+        );
+        assert_eq!(graph.successors(call).collect::<Vec<_>>(), vec![f, rest]);
+        assert_eq!(graph.successors(vector).collect::<Vec<_>>(), vec![one, f]);
+        assert_eq!(graph.successors(f).count(), 0);
+
+        // Shared nodes are reached once, from the first edge to them.
+        assert_eq!(
+            graph.reachable(&[call]),
+            vec![call, f, rest, vector, one, nil]
+        );
+        assert_eq!(graph.reachable(&[vector, f]), vec![vector, one, f]);
+
+        // Without cycles, every node is a component of its own, after those it refers to.
+        let components = graph.strongly_connected(&[call]);
+        assert_eq!(
+            components,
+            vec![
+                vec![f],
+                vec![one],
+                vec![vector],
+                vec![nil],
+                vec![rest],
+                vec![call]
+            ]
+        );
+    }
+
+    #[test]
+    fn test_strongly_connected() {
+        // (a . #0=(b (c . #0#))), where the pair around `c` refers back to the one around `b`.
+        let mut ast = Ast::new();
+        let [a, b, c] = ["a", "b", "c"].map(|name| ast.create_symbol(name));
+        let inner = ast.create_list(&[c]);
+        let rest = ast.create_list(&[b, inner]);
+        ast.set_tail(inner, rest);
+        let list = ast.create_pair(a, rest);
+        let (_, tail) = ast.get_pair(rest).unwrap();
+        let (_, nil) = ast.get_pair(tail).unwrap();
+
+        let graph = ast.as_graph();
+        assert_eq!(ast.display(list).to_string(), "(a . #0=(b (c . #0#)))");
+        assert_eq!(
+            graph.strongly_connected(&[list]),
+            vec![
+                vec![a],
+                vec![b],
+                vec![c],
+                vec![nil],
+                vec![rest, tail, inner],
+                vec![list]
+            ]
+        );
+        assert_eq!(
+            graph.reachable(&[inner]),
+            vec![inner, c, rest, b, tail, nil]
+        );
+
+        // A pair whose tail is itself is a component on its own.
+        let mut ast = Ast::new();
+        let one = ast.create_integer(1.into());
+        let cycle = ast.create_list(&[one]);
+        ast.set_tail(cycle, cycle);
+        assert_eq!(
+            ast.as_graph().strongly_connected(&[cycle]),
+            vec![vec![one], vec![cycle]]
+        );
+
+        // A long list doesn't need deep recursion.
+        let mut ast = Ast::new();
+        let items = (0..100_000)
+            .map(|i| ast.create_integer(i.into()))
+            .collect::<Vec<_>>();
+        let list = ast.create_list(&items);
+        assert_eq!(ast.as_graph().strongly_connected(&[list]).len(), ast.len());
+    }
+}
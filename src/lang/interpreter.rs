@@ -0,0 +1,767 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use crate::lang::ast::{Ast, AstNode, AstRef};
+use crate::lang::symbol::SymbolTable;
+use crate::lang::value::{Builtin, Value};
+use crate::lex::lexer::Span;
+
+// Spans are `None` for nodes without `SyntaxInfo`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EvalError {
+    Unbound {
+        name: Arc<str>,
+        node: AstRef,
+        span: Option<Span>,
+    },
+    // A special form of the wrong shape, a call that isn't a proper list, or `()`.
+    Malformed {
+        node: AstRef,
+        span: Option<Span>,
+    },
+    // A call of something that isn't a procedure.
+    NotProcedure {
+        node: AstRef,
+        span: Option<Span>,
+    },
+    // A call with the wrong number of arguments. `variadic` if the procedure takes `expected` or
+    // more.
+    Arity {
+        name: Option<Arc<str>>,
+        expected: usize,
+        variadic: bool,
+        given: usize,
+        node: AstRef,
+        span: Option<Span>,
+    },
+    // A call of a builtin that failed.
+    Failed {
+        name: Arc<str>,
+        message: String,
+        node: AstRef,
+        span: Option<Span>,
+    },
+    // A call nested inside more calls than the limit, e.g. by recursing forever.
+    TooDeep {
+        node: AstRef,
+        span: Option<Span>,
+    },
+}
+
+impl EvalError {
+    pub fn span(&self) -> Option<Span> {
+        match *self {
+            EvalError::Unbound { span, .. } => span,
+            EvalError::Malformed { span, .. } => span,
+            EvalError::NotProcedure { span, .. } => span,
+            EvalError::Arity { span, .. } => span,
+            EvalError::Failed { span, .. } => span,
+            EvalError::TooDeep { span, .. } => span,
+        }
+    }
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EvalError::Unbound { name, .. } => write!(f, "unbound variable {}", name)?,
+            EvalError::Malformed { .. } => write!(f, "malformed form")?,
+            EvalError::NotProcedure { .. } => write!(f, "not a procedure")?,
+            EvalError::Arity {
+                name,
+                expected,
+                variadic,
+                given,
+                ..
+            } => write!(
+                f,
+                "{} expects {}{} argument{}, given {}",
+                name.as_deref().unwrap_or("procedure"),
+                if *variadic { "at least " } else { "" },
+                expected,
+                if *expected == 1 { "" } else { "s" },
+                given
+            )?,
+            EvalError::Failed { name, message, .. } => write!(f, "{}: {}", name, message)?,
+            EvalError::TooDeep { .. } => write!(f, "calls nested too deeply")?,
+        }
+        match self.span() {
+            Some(span) => write!(f, " at {}", span.start),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Error for EvalError {}
+
+// The variables of a scope, which looks up those it doesn't have in the scope it's in.
+#[derive(Default)]
+struct Env {
+    vars: RefCell<HashMap<u64, Value>>,
+    parent: Option<Rc<Env>>,
+}
+
+impl Env {
+    fn new(parent: Rc<Env>) -> Env {
+        Env {
+            vars: RefCell::new(HashMap::new()),
+            parent: Some(parent),
+        }
+    }
+
+    fn lookup(&self, symbol: u64) -> Option<Value> {
+        let mut env = self;
+        loop {
+            if let Some(value) = env.vars.borrow().get(&symbol) {
+                return Some(value.clone());
+            }
+            env = env.parent.as_ref()?;
+        }
+    }
+
+    fn is_bound(&self, symbol: u64) -> bool {
+        let mut env = self;
+        loop {
+            if env.vars.borrow().contains_key(&symbol) {
+                return true;
+            }
+            match &env.parent {
+                Some(parent) => env = parent,
+                None => return false,
+            }
+        }
+    }
+
+    fn define(&self, symbol: u64, value: Value) {
+        self.vars.borrow_mut().insert(symbol, value);
+    }
+}
+
+// Forms copied out of the `Ast` they were given in, so the procedures made from them don't depend
+// on it. Errors refer to the nodes they were copied from.
+struct Code {
+    ast: Ast,
+    // The node each node was copied from, by id.
+    origins: Vec<AstRef>,
+}
+
+impl Code {
+    // Copy the form `id` of `ast` and everything it refers to, returning the copy of `id`.
+    fn copy(ast: &Ast, id: AstRef) -> (Rc<Code>, AstRef) {
+        let mut copies = HashMap::from([(id, 0)]);
+        let mut origins = vec![id];
+        let mut i = 0;
+        while i < origins.len() {
+            let children = match ast.get(origins[i]) {
+                &AstNode::Pair(head, tail) => vec![head, tail],
+                AstNode::Vector(elements) => elements.clone(),
+                _ => vec![],
+            };
+            for child in children {
+                copies.entry(child).or_insert_with(|| {
+                    origins.push(child);
+                    origins.len() as AstRef - 1
+                });
+            }
+            i += 1;
+        }
+
+        let mut copy = Ast::new();
+        for &origin in &origins {
+            let node = match ast.get(origin) {
+                &AstNode::Pair(head, tail) => AstNode::Pair(copies[&head], copies[&tail]),
+                AstNode::Vector(elements) => {
+                    AstNode::Vector(elements.iter().map(|element| copies[element]).collect())
+                }
+                node => node.clone(),
+            };
+            let node = copy.add(node);
+            if let Some(syntax) = ast.get_syntax(origin) {
+                copy.set_syntax(node, *syntax);
+            }
+        }
+        (Rc::new(Code { ast: copy, origins }), 0)
+    }
+
+    fn span(&self, id: AstRef) -> Option<Span> {
+        self.ast.get_syntax(id).map(|syntax| syntax.span)
+    }
+
+    fn malformed(&self, id: AstRef) -> EvalError {
+        EvalError::Malformed {
+            node: self.origins[id as usize],
+            span: self.span(id),
+        }
+    }
+
+    // The items of the form `id`, which has to be a proper list.
+    fn list(&self, id: AstRef) -> Result<Vec<AstRef>, EvalError> {
+        match self.ast.is_list(id) {
+            true => Ok(self.ast.list_iter(id).collect()),
+            false => Err(self.malformed(id)),
+        }
+    }
+}
+
+// A procedure made by `lambda`, which runs its body in a scope inside the one it was made in. Its
+// body is kept with the forms it was made from, so it can be called from any `Ast`.
+pub struct Procedure {
+    name: Option<Arc<str>>,
+    params: Vec<u64>,
+    // The parameter the arguments after `params` are passed to as a list, if there is one.
+    rest: Option<u64>,
+    code: Rc<Code>,
+    body: Vec<AstRef>,
+    env: Rc<Env>,
+}
+
+impl Procedure {
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+}
+
+// Evaluates forms by walking them. Supports `quote`, `if`, `define` and `lambda`, applies
+// procedures in the scopes they were made in, and calls in tail position don't nest, so loops
+// written as tail recursion run in constant space. A special form's keyword is only special where
+// it isn't bound as a variable. Procedures are given by builtins, since nothing else is built in.
+//
+// Definitions are kept from one evaluation to the next, e.g. between the lines of a REPL, which
+// can each be read into a different `Ast`. Errors refer to nodes of the `Ast` the failing form was
+// given in, which for the body of a procedure is the one it was defined in.
+pub struct Interpreter {
+    globals: Rc<Env>,
+    max_depth: usize,
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Interpreter {
+            globals: Rc::new(Env::default()),
+            max_depth: 256,
+        }
+    }
+}
+
+impl Interpreter {
+    pub fn new() -> Interpreter {
+        Self::default()
+    }
+
+    // The most calls that can be evaluated inside one another, not counting calls in tail
+    // position.
+    pub fn set_max_depth(&mut self, depth: usize) {
+        self.max_depth = depth;
+    }
+
+    pub fn with_max_depth(&mut self, depth: usize) -> &mut Self {
+        self.set_max_depth(depth);
+        self
+    }
+
+    pub fn add_builtin<F>(&mut self, name: &str, function: F)
+    where
+        F: Fn(&[Value]) -> Result<Value, String> + 'static,
+    {
+        let builtin = Builtin::new(name, function);
+        self.globals.define(
+            SymbolTable::global().intern(name),
+            Value::Builtin(Rc::new(builtin)),
+        );
+    }
+
+    pub fn with_builtin<F>(&mut self, name: &str, function: F) -> &mut Self
+    where
+        F: Fn(&[Value]) -> Result<Value, String> + 'static,
+    {
+        self.add_builtin(name, function);
+        self
+    }
+
+    // The value of the global variable `name`, if it's been defined.
+    pub fn global(&self, name: &str) -> Option<Value> {
+        self.globals.lookup(SymbolTable::global().get(name)?)
+    }
+
+    // The names of the global variables, builtins included, in order.
+    pub fn globals(&self) -> Vec<Arc<str>> {
+        let table = SymbolTable::global();
+        let mut names = self
+            .globals
+            .vars
+            .borrow()
+            .keys()
+            .map(|&symbol| table.resolve(symbol))
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    // Evaluate the form `id` at the top level.
+    pub fn eval(&mut self, ast: &Ast, id: AstRef) -> Result<Value, EvalError> {
+        let (code, id) = Code::copy(ast, id);
+        self.eval_in(code, id, self.globals.clone(), 0)
+    }
+
+    // Evaluate the program whose top-level forms are the list `root`, like `Ast::root`, in order,
+    // returning the value of the last.
+    pub fn run(&mut self, ast: &Ast, root: AstRef) -> Result<Value, EvalError> {
+        let (code, root) = Code::copy(ast, root);
+        let forms = code.list(root)?;
+
+        let mut value = Value::Unspecified;
+        for form in forms {
+            value = self.eval_in(code.clone(), form, self.globals.clone(), 0)?;
+        }
+        Ok(value)
+    }
+
+    // Evaluate `id` inside `depth` calls.
+    fn eval_in(
+        &self,
+        mut code: Rc<Code>,
+        mut id: AstRef,
+        mut env: Rc<Env>,
+        depth: usize,
+    ) -> Result<Value, EvalError> {
+        if depth > self.max_depth {
+            return Err(EvalError::TooDeep {
+                node: code.origins[id as usize],
+                span: code.span(id),
+            });
+        }
+
+        // Forms in tail position are evaluated by going round again rather than recursing.
+        loop {
+            let ast = &code.ast;
+            let head = match *ast.get(id) {
+                AstNode::Pair(head, _) => head,
+                AstNode::Symbol(symbol) => {
+                    return self.lookup(&env, symbol).ok_or_else(|| EvalError::Unbound {
+                        name: SymbolTable::global().resolve(symbol),
+                        node: code.origins[id as usize],
+                        span: code.span(id),
+                    })
+                }
+                AstNode::Nil => return Err(code.malformed(id)),
+                _ => return Value::from_datum(ast, id).ok_or_else(|| code.malformed(id)),
+            };
+
+            let items = code.list(id)?;
+            let keyword = match ast.get_symbol_id(head) {
+                Some(symbol) if !env.is_bound(symbol) => ast.get_symbol(head),
+                _ => None,
+            };
+            match keyword.as_deref() {
+                Some("quote") => {
+                    return match items[..] {
+                        [_, datum] => {
+                            Value::from_datum(ast, datum).ok_or_else(|| code.malformed(id))
+                        }
+                        _ => Err(code.malformed(id)),
+                    }
+                }
+                Some("if") => {
+                    let (test, then, otherwise) = match items[..] {
+                        [_, test, then] => (test, then, None),
+                        [_, test, then, otherwise] => (test, then, Some(otherwise)),
+                        _ => return Err(code.malformed(id)),
+                    };
+                    if self
+                        .eval_in(code.clone(), test, env.clone(), depth + 1)?
+                        .is_true()
+                    {
+                        id = then;
+                    } else {
+                        match otherwise {
+                            Some(otherwise) => id = otherwise,
+                            None => return Ok(Value::Unspecified),
+                        }
+                    }
+                    continue;
+                }
+                Some("define") => {
+                    self.define(&code, id, &items, &env, depth)?;
+                    return Ok(Value::Unspecified);
+                }
+                Some("lambda") => return self.lambda(&code, id, &items, &env, None),
+                _ => {}
+            }
+
+            let procedure = self.eval_in(code.clone(), head, env.clone(), depth + 1)?;
+            let mut args = vec![];
+            for &arg in &items[1..] {
+                args.push(self.eval_in(code.clone(), arg, env.clone(), depth + 1)?);
+            }
+
+            let procedure = match procedure {
+                Value::Builtin(builtin) => {
+                    return builtin.call(&args).map_err(|message| EvalError::Failed {
+                        name: builtin.name().into(),
+                        message,
+                        node: code.origins[id as usize],
+                        span: code.span(id),
+                    })
+                }
+                Value::Procedure(procedure) => procedure,
+                _ => {
+                    return Err(EvalError::NotProcedure {
+                        node: code.origins[id as usize],
+                        span: code.span(id),
+                    })
+                }
+            };
+
+            env = Rc::new(self.bind(&code, id, &procedure, args)?);
+            code = procedure.code.clone();
+            let (&last, body) = procedure.body.split_last().unwrap();
+            for &form in body {
+                self.eval_in(code.clone(), form, env.clone(), depth + 1)?;
+            }
+            id = last;
+        }
+    }
+
+    // `(define name value)`, or `(define (name params ..) body ..)` for `lambda`.
+    fn define(
+        &self,
+        code: &Rc<Code>,
+        id: AstRef,
+        items: &[AstRef],
+        env: &Rc<Env>,
+        depth: usize,
+    ) -> Result<(), EvalError> {
+        let ast = &code.ast;
+        let (name, value) = match *items {
+            [_, name, value] if ast.get_symbol(name).is_some() => {
+                // A procedure is named after the variable it's defined as.
+                let value = match ast.get_pair(value) {
+                    Some((head, _)) if self.is_keyword(ast, head, env, "lambda") => {
+                        let items = code.list(value)?;
+                        self.lambda(code, value, &items, env, ast.get_symbol(name))?
+                    }
+                    _ => self.eval_in(code.clone(), value, env.clone(), depth + 1)?,
+                };
+                (name, value)
+            }
+            [_, signature, _, ..] if ast.get_pair(signature).is_some() => {
+                let (name, params) = ast.get_pair(signature).unwrap();
+                if ast.get_symbol(name).is_none() {
+                    return Err(code.malformed(id));
+                }
+                let procedure = self.procedure(code, id, params, &items[2..], env)?;
+                let procedure = Procedure {
+                    name: ast.get_symbol(name),
+                    ..procedure
+                };
+                (name, Value::Procedure(Rc::new(procedure)))
+            }
+            _ => return Err(code.malformed(id)),
+        };
+
+        env.define(ast.get_symbol_id(name).unwrap(), value);
+        Ok(())
+    }
+
+    // `(lambda params body ..)`.
+    fn lambda(
+        &self,
+        code: &Rc<Code>,
+        id: AstRef,
+        items: &[AstRef],
+        env: &Rc<Env>,
+        name: Option<Arc<str>>,
+    ) -> Result<Value, EvalError> {
+        let [_, params, ref body @ ..] = *items else {
+            return Err(code.malformed(id));
+        };
+        let procedure = self.procedure(code, id, params, body, env)?;
+        Ok(Value::Procedure(Rc::new(Procedure { name, ..procedure })))
+    }
+
+    // A procedure taking `params`, which are a symbol taking every argument as a list, or a list of
+    // symbols ending in one taking the rest.
+    fn procedure(
+        &self,
+        code: &Rc<Code>,
+        id: AstRef,
+        params: AstRef,
+        body: &[AstRef],
+        env: &Rc<Env>,
+    ) -> Result<Procedure, EvalError> {
+        let ast = &code.ast;
+        let cyclic = ast.get_pair(params).is_some()
+            && ast.list_len(params).is_none()
+            && !ast.is_dotted_list(params);
+        if body.is_empty() || cyclic {
+            return Err(code.malformed(id));
+        }
+
+        let mut iter = ast.list_iter(params);
+        let mut names = vec![];
+        for param in iter.by_ref() {
+            names.push(
+                ast.get_symbol_id(param)
+                    .ok_or_else(|| code.malformed(param))?,
+            );
+        }
+        let rest = match *ast.get(iter.tail()) {
+            AstNode::Nil => None,
+            AstNode::Symbol(symbol) => Some(symbol),
+            _ => return Err(code.malformed(iter.tail())),
+        };
+
+        let mut seen = HashSet::new();
+        if !names.iter().chain(&rest).all(|&name| seen.insert(name)) {
+            return Err(code.malformed(params));
+        }
+
+        Ok(Procedure {
+            name: None,
+            params: names,
+            rest,
+            code: code.clone(),
+            body: body.to_vec(),
+            env: env.clone(),
+        })
+    }
+
+    // The scope a call `id` of `procedure` runs its body in.
+    fn bind(
+        &self,
+        code: &Code,
+        id: AstRef,
+        procedure: &Procedure,
+        mut args: Vec<Value>,
+    ) -> Result<Env, EvalError> {
+        let expected = procedure.params.len();
+        let variadic = procedure.rest.is_some();
+        if args.len() < expected || (!variadic && args.len() > expected) {
+            return Err(EvalError::Arity {
+                name: procedure.name.clone(),
+                expected,
+                variadic,
+                given: args.len(),
+                node: code.origins[id as usize],
+                span: code.span(id),
+            });
+        }
+
+        let env = Env::new(procedure.env.clone());
+        let rest = args.split_off(expected);
+        for (&param, arg) in procedure.params.iter().zip(args) {
+            env.define(param, arg);
+        }
+        if let Some(param) = procedure.rest {
+            env.define(param, Value::list(rest));
+        }
+        Ok(env)
+    }
+
+    // The value of the variable `symbol`. A symbol a macro introduced that isn't bound where it's
+    // used refers to the global variable it was made from.
+    fn lookup(&self, env: &Env, symbol: u64) -> Option<Value> {
+        env.lookup(symbol).or_else(|| {
+            let origin = SymbolTable::global().origin(symbol);
+            (origin != symbol).then(|| self.globals.lookup(origin))?
+        })
+    }
+
+    fn is_keyword(&self, ast: &Ast, id: AstRef, env: &Env, keyword: &str) -> bool {
+        ast.get_symbol_id(id).is_some_and(|symbol| {
+            !env.is_bound(symbol) && ast.get_symbol(id).as_deref() == Some(keyword)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lang::reader::parse_str;
+    use num::BigInt;
+
+    fn integers(name: &str, args: &[Value]) -> Result<Vec<BigInt>, String> {
+        args.iter()
+            .map(|arg| match arg {
+                Value::Integer(value) => Ok(value.clone()),
+                _ => Err(format!("{} expects integers", name)),
+            })
+            .collect()
+    }
+
+    fn interpreter() -> Interpreter {
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .with_builtin("+", |args| {
+                Ok(Value::Integer(integers("+", args)?.into_iter().sum()))
+            })
+            .with_builtin("*", |args| {
+                Ok(Value::Integer(integers("*", args)?.into_iter().product()))
+            })
+            .with_builtin("-", |args| match &integers("-", args)?[..] {
+                [a, b] => Ok(Value::Integer(a - b)),
+                _ => Err("expects 2 arguments".to_string()),
+            })
+            .with_builtin("<", |args| match &integers("<", args)?[..] {
+                [a, b] => Ok(Value::Bool(a < b)),
+                _ => Err("expects 2 arguments".to_string()),
+            })
+            .with_builtin("list", |args| Ok(Value::list(args.to_vec())));
+        interpreter
+    }
+
+    fn run_with(interpreter: &mut Interpreter, source: &str) -> Result<String, EvalError> {
+        let mut ast = Ast::new();
+        parse_str(source, &mut ast).unwrap();
+        let root = ast.root().unwrap();
+        Ok(interpreter.run(&ast, root)?.to_string())
+    }
+
+    fn run(source: &str) -> Result<String, EvalError> {
+        run_with(&mut interpreter(), source)
+    }
+
+    #[test]
+    fn test_eval() {
+        // Literals evaluate to themselves, and quoted forms to themselves as data.
+        assert_eq!(run("1").unwrap(), "1");
+        assert_eq!(run("\"a\\nb\"").unwrap(), "\"a\\nb\"");
+        assert_eq!(run("#\\a").unwrap(), "#\\a");
+        assert_eq!(run("#(1/2 x #t)").unwrap(), "#(1/2 x #t)");
+        assert_eq!(run("'(a . (b 1.5))").unwrap(), "(a b 1.5)");
+        assert_eq!(run("(quote ())").unwrap(), "()");
+
+        assert_eq!(run("(if #f 1 2)").unwrap(), "2");
+        assert_eq!(run("(if '() 1 2)").unwrap(), "1");
+        assert_eq!(run("(if #f #f)").unwrap(), "#<unspecified>");
+
+        assert_eq!(run("(define x 2) (+ x 1)").unwrap(), "3");
+        assert_eq!(run("((lambda (x y) (* x y)) 6 7)").unwrap(), "42");
+        assert_eq!(
+            run("((lambda (a . rest) (list a rest)) 1 2 3)").unwrap(),
+            "(1 (2 3))"
+        );
+        assert_eq!(run("((lambda args args))").unwrap(), "()");
+
+        let fact = "(define (fact n) (if (< n 2) 1 (* n (fact (- n 1)))))";
+        assert_eq!(
+            run(&format!("{} (fact 20)", fact)).unwrap(),
+            "2432902008176640000"
+        );
+        assert_eq!(
+            run(&format!("{} fact (define f (lambda () 1)) f", fact)).unwrap(),
+            "#<procedure f>"
+        );
+
+        // Definitions last from one run to the next.
+        let mut interpreter = interpreter();
+        run_with(&mut interpreter, "(define y 5)").unwrap();
+        assert_eq!(run_with(&mut interpreter, "(+ y y)").unwrap(), "10");
+        assert_eq!(interpreter.global("y").unwrap().to_string(), "5");
+
+        // Procedures can be called from forms in another `Ast` than they were defined in.
+        run_with(&mut interpreter, "(define (f x) (if x 1 2)) (define (g) 7)").unwrap();
+        assert_eq!(run_with(&mut interpreter, "(f #t)").unwrap(), "1");
+        assert_eq!(run_with(&mut interpreter, "(g)").unwrap(), "7");
+        let mut ast = Ast::new();
+        let id = parse_str("(list (f #f) (g))", &mut ast).unwrap()[0];
+        assert_eq!(interpreter.eval(&ast, id).unwrap().to_string(), "(2 7)");
+    }
+
+    #[test]
+    fn test_scope() {
+        // Closures see the variables of where they were made, not where they're called.
+        let source = "(define x 1)
+            (define (get) x)
+            (define (shadow x) (get))
+            (shadow 2)";
+        assert_eq!(run(source).unwrap(), "1");
+
+        let source = "(define (adder n) (lambda (x) (+ x n)))
+            (define add2 (adder 2))
+            (define add3 (adder 3))
+            (list (add2 1) (add3 1))";
+        assert_eq!(run(source).unwrap(), "(3 4)");
+
+        // Definitions in a body are local to the call.
+        let source = "(define x 1)
+            (define (f) (define x 2) x)
+            (list (f) x)";
+        assert_eq!(run(source).unwrap(), "(2 1)");
+
+        // Keywords bound as variables aren't special.
+        assert_eq!(
+            run("((lambda (if) (if 1)) -)").unwrap_err().to_string(),
+            "-: expects 2 arguments at 14"
+        );
+        assert_eq!(run("(define (quote x) x) (quote 1)").unwrap(), "1");
+
+        // Calls in tail position don't nest, so loops don't run out of depth.
+        let source = "(define (count n total)
+              (if (< n 1) total (count (- n 1) (+ total 1))))
+            (count 10000 0)";
+        assert_eq!(run(source).unwrap(), "10000");
+    }
+
+    #[test]
+    fn test_eval_errors() {
+        let source = "(define (f x) x) (f y)";
+        let error = run(source).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!("unbound variable y at {}", source.rfind('y').unwrap())
+        );
+
+        // Errors refer to the nodes of the `Ast` they were evaluated from.
+        let mut ast = Ast::new();
+        let ids = parse_str("(define (f x) x) (f y)", &mut ast).unwrap();
+        let error = interpreter().run(&ast, ast.root().unwrap()).unwrap_err();
+        let (_, y) = ast.get_pair(ids[1]).unwrap();
+        assert!(
+            matches!(error, EvalError::Unbound { node, .. } if node == ast.get_pair(y).unwrap().0)
+        );
+
+        assert!(matches!(run("(1 2)"), Err(EvalError::NotProcedure { .. })));
+        assert_eq!(
+            run("(define (f x) x) (f)").unwrap_err().to_string(),
+            "f expects 1 argument, given 0 at 17"
+        );
+        assert_eq!(
+            run("((lambda (x y . z) x) 1)").unwrap_err().to_string(),
+            "procedure expects at least 2 arguments, given 1 at 0"
+        );
+        assert_eq!(
+            run("(+ 1 'a)").unwrap_err().to_string(),
+            "+: + expects integers at 0"
+        );
+
+        for source in [
+            "()",
+            "(if)",
+            "(quote)",
+            "(define)",
+            "(define 1 2)",
+            "(define (f))",
+            "(lambda (x x) x)",
+            "(lambda (1) x)",
+            "(lambda (x))",
+            "(+ 1 . 2)",
+            "'#0=(a . #0#)",
+        ] {
+            assert!(
+                matches!(run(source), Err(EvalError::Malformed { .. })),
+                "{}",
+                source
+            );
+        }
+
+        let mut interpreter = interpreter();
+        interpreter.set_max_depth(100);
+        let error = run_with(&mut interpreter, "(define (f n) (+ 1 (f n))) (f 1)").unwrap_err();
+        assert!(matches!(error, EvalError::TooDeep { .. }));
+    }
+}
@@ -1 +1,18 @@
+pub mod ast;
 pub mod compiler;
+pub mod cst;
+pub mod dot;
+pub mod expand;
+pub mod graph;
+pub mod interpreter;
+pub mod pass;
+pub mod pattern;
+pub mod reader;
+pub mod run;
+pub mod scope;
+pub mod serialize;
+pub mod symbol;
+pub mod synthetic;
+pub mod value;
+pub mod visit;
+pub mod vm;
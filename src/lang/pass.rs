@@ -0,0 +1,321 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::lang::ast::{Ast, AstRef};
+
+// One step of a compilation pipeline, which analyses or transforms the forms under a root. A pass
+// that only analyses returns the root it was given.
+pub trait Pass: Any {
+    fn name(&self) -> &str;
+
+    // The names of the passes, e.g. analyses, that have to run before this one.
+    fn requires(&self) -> &[&str] {
+        &[]
+    }
+
+    // Run over the forms under `root`, returning the root of the result.
+    fn run(&mut self, ast: &mut Ast, root: AstRef) -> Result<AstRef, Box<dyn Error + Send + Sync>>;
+}
+
+#[derive(Debug)]
+pub enum PassError {
+    // A pass requires one that hasn't been added.
+    Missing {
+        pass: String,
+        required: String,
+    },
+    // A pass requires itself, directly or through others.
+    Cycle {
+        pass: String,
+    },
+    // A pass ran and failed.
+    Failed {
+        pass: String,
+        error: Box<dyn Error + Send + Sync>,
+    },
+}
+
+impl fmt::Display for PassError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PassError::Missing { pass, required } => {
+                write!(f, "pass {} requires {}, which wasn't added", pass, required)
+            }
+            PassError::Cycle { pass } => write!(f, "pass {} requires itself", pass),
+            PassError::Failed { pass, error } => write!(f, "pass {} failed: {}", pass, error),
+        }
+    }
+}
+
+impl Error for PassError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PassError::Failed { error, .. } => Some(error.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+// How long a pass took the last time the manager ran.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PassTiming {
+    pub name: String,
+    pub duration: Duration,
+}
+
+// Called after each pass with its name and the forms it produced.
+pub type PassDump = Box<dyn FnMut(&str, &Ast, AstRef)>;
+
+// Runs passes over an `Ast` in order. Passes run in the order they were added, except that a pass
+// is moved after the ones it requires. The nodes each pass creates are counted under its name in
+// `Ast::stage_stats`.
+#[derive(Default)]
+pub struct PassManager {
+    passes: Vec<Box<dyn Pass>>,
+    timings: Vec<PassTiming>,
+    dump: Option<PassDump>,
+}
+
+impl PassManager {
+    pub fn new() -> PassManager {
+        Self::default()
+    }
+
+    pub fn add_pass<P: Pass + 'static>(&mut self, pass: P) {
+        self.passes.push(Box::new(pass));
+    }
+
+    pub fn with_pass<P: Pass + 'static>(&mut self, pass: P) -> &mut Self {
+        self.add_pass(pass);
+        self
+    }
+
+    // Dump the forms after each pass, e.g. by printing them with `Ast::display`.
+    pub fn set_dump<F>(&mut self, dump: F)
+    where
+        F: FnMut(&str, &Ast, AstRef) + 'static,
+    {
+        self.dump = Some(Box::new(dump));
+    }
+
+    // The names of the passes in the order they run.
+    pub fn order(&self) -> Result<Vec<&str>, PassError> {
+        Ok(self
+            .schedule()?
+            .into_iter()
+            .map(|i| self.passes[i].name())
+            .collect())
+    }
+
+    // The pass of type `P`, e.g. to get what an analysis found after running it.
+    pub fn get_pass<P: Pass>(&self) -> Option<&P> {
+        self.passes
+            .iter()
+            .find_map(|pass| (pass.as_ref() as &dyn Any).downcast_ref())
+    }
+
+    pub fn timings(&self) -> &[PassTiming] {
+        &self.timings
+    }
+
+    // Run every pass in order over the forms under `root`, returning the root of the result. Stops
+    // at the first pass that fails.
+    pub fn run(&mut self, ast: &mut Ast, mut root: AstRef) -> Result<AstRef, PassError> {
+        let schedule = self.schedule()?;
+        self.timings.clear();
+
+        let stage = ast.stage().map(str::to_string);
+        for i in schedule {
+            let pass = &mut self.passes[i];
+
+            ast.set_stage(Some(pass.name()));
+            let start = Instant::now();
+            let result = pass.run(ast, root);
+            ast.set_stage(stage.as_deref());
+            self.timings.push(PassTiming {
+                name: pass.name().to_string(),
+                duration: start.elapsed(),
+            });
+
+            root = result.map_err(|error| PassError::Failed {
+                pass: pass.name().to_string(),
+                error,
+            })?;
+            if let Some(dump) = &mut self.dump {
+                dump(pass.name(), ast, root);
+            }
+        }
+
+        Ok(root)
+    }
+
+    // The indices of the passes in the order they run.
+    fn schedule(&self) -> Result<Vec<usize>, PassError> {
+        let indices = self
+            .passes
+            .iter()
+            .enumerate()
+            .map(|(i, pass)| (pass.name(), i))
+            .collect::<HashMap<_, _>>();
+
+        // Whether each pass has been scheduled, or is being and so is part of a cycle if reached.
+        let mut done = vec![None; self.passes.len()];
+        let mut schedule = vec![];
+        for i in 0..self.passes.len() {
+            self.visit(i, &indices, &mut done, &mut schedule)?;
+        }
+        Ok(schedule)
+    }
+
+    fn visit(
+        &self,
+        i: usize,
+        indices: &HashMap<&str, usize>,
+        done: &mut [Option<bool>],
+        schedule: &mut Vec<usize>,
+    ) -> Result<(), PassError> {
+        let pass = &self.passes[i];
+        match done[i] {
+            Some(true) => return Ok(()),
+            Some(false) => {
+                return Err(PassError::Cycle {
+                    pass: pass.name().to_string(),
+                })
+            }
+            None => {}
+        }
+
+        done[i] = Some(false);
+        for &required in pass.requires() {
+            let &j = indices.get(required).ok_or_else(|| PassError::Missing {
+                pass: pass.name().to_string(),
+                required: required.to_string(),
+            })?;
+            self.visit(j, indices, done, schedule)?;
+        }
+        done[i] = Some(true);
+
+        schedule.push(i);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // Wraps the forms in a call to `name`.
+    struct Wrap {
+        name: &'static str,
+        requires: Vec<&'static str>,
+    }
+
+    impl Wrap {
+        fn new(name: &'static str, requires: &[&'static str]) -> Wrap {
+            Wrap {
+                name,
+                requires: requires.to_vec(),
+            }
+        }
+    }
+
+    impl Pass for Wrap {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn requires(&self) -> &[&str] {
+            &self.requires
+        }
+
+        fn run(
+            &mut self,
+            ast: &mut Ast,
+            root: AstRef,
+        ) -> Result<AstRef, Box<dyn Error + Send + Sync>> {
+            if self.name == "fail" {
+                return Err("no".into());
+            }
+
+            let name = ast.create_symbol(self.name);
+            Ok(ast.create_list(&[name, root]))
+        }
+    }
+
+    #[test]
+    fn test_passes() {
+        let mut passes = PassManager::new();
+        passes
+            .with_pass(Wrap::new("c", &["b"]))
+            .with_pass(Wrap::new("a", &[]))
+            .with_pass(Wrap::new("b", &["a"]));
+        assert_eq!(passes.order().unwrap(), vec!["a", "b", "c"]);
+
+        let dumps = Rc::new(RefCell::new(vec![]));
+        let dumped = dumps.clone();
+        passes.set_dump(move |name, ast, root| {
+            dumped
+                .borrow_mut()
+                .push(format!("{}: {}", name, ast.display(root)))
+        });
+
+        let mut ast = Ast::new();
+        let x = ast.create_symbol("x");
+        let root = passes.run(&mut ast, x).unwrap();
+        assert_eq!(ast.display(root).to_string(), "(c (b (a x)))");
+        assert_eq!(
+            *dumps.borrow(),
+            vec!["a: (a x)", "b: (b (a x))", "c: (c (b (a x)))"]
+        );
+
+        assert_eq!(passes.get_pass::<Wrap>().unwrap().name, "c");
+
+        let timings = passes.timings();
+        let names = timings.iter().map(|t| t.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["a", "b", "c"]);
+
+        // Each pass created a symbol and a list of two.
+        let stages = ast
+            .stage_stats()
+            .iter()
+            .map(|stage| (&*stage.name, stage.nodes))
+            .collect::<Vec<_>>();
+        assert_eq!(stages, vec![("a", 4), ("b", 4), ("c", 4)]);
+        assert_eq!(ast.stage(), None);
+    }
+
+    #[test]
+    fn test_pass_errors() {
+        let mut ast = Ast::new();
+        let x = ast.create_symbol("x");
+
+        let mut passes = PassManager::new();
+        passes.with_pass(Wrap::new("a", &["b"]));
+        assert_eq!(
+            passes.run(&mut ast, x).unwrap_err().to_string(),
+            "pass a requires b, which wasn't added"
+        );
+
+        passes.with_pass(Wrap::new("b", &["a"]));
+        assert!(matches!(
+            passes.run(&mut ast, x),
+            Err(PassError::Cycle { pass }) if pass == "a"
+        ));
+
+        // A failed pass stops the ones after it.
+        let mut passes = PassManager::new();
+        passes
+            .with_pass(Wrap::new("a", &[]))
+            .with_pass(Wrap::new("fail", &["a"]))
+            .with_pass(Wrap::new("b", &[]));
+        let error = passes.run(&mut ast, x).unwrap_err();
+        assert_eq!(error.to_string(), "pass fail failed: no");
+        assert_eq!(error.source().unwrap().to_string(), "no");
+        assert_eq!(passes.timings().len(), 2);
+    }
+}
@@ -0,0 +1,362 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use crate::lang::ast::{Ast, AstNode, AstRef};
+use crate::lang::symbol::SymbolTable;
+
+// What a pattern variable matched. A variable under an ellipsis matched once per item the
+// ellipsis matched, and one under two ellipses once per item of each of those, and so on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Binding {
+    One(AstRef),
+    Many(Vec<Binding>),
+}
+
+// The variables of a pattern that matched, by name.
+pub type Bindings = HashMap<Arc<str>, Binding>;
+
+// A form to match others against, in the manner of `syntax-rules`:
+//
+// - A symbol is a variable, which matches anything, unless it's one of the pattern's literals,
+//   which only match the same symbol. `_` matches anything without binding it.
+// - A list or dotted list matches one with items matching its items and a tail matching its
+//   tail, so `(f . args)` matches any call of `f`.
+// - An item followed by `...` matches any number of items, as many as it can while leaving
+//   enough for the items after it. A list can have one of these.
+// - A vector matches one of the same length with items matching its items.
+// - Anything else matches an equal node.
+//
+// If a variable appears more than once, its last match is the one bound. Patterns can't be
+// cyclic, though the forms they're matched against can.
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    form: AstRef,
+    literals: HashSet<u64>,
+}
+
+impl Pattern {
+    pub fn new(form: AstRef) -> Pattern {
+        Pattern {
+            form,
+            literals: HashSet::new(),
+        }
+    }
+
+    pub fn add_literal(&mut self, name: &str) {
+        self.literals.insert(SymbolTable::global().intern(name));
+    }
+
+    pub fn with_literal(&mut self, name: &str) -> &mut Self {
+        self.add_literal(name);
+        self
+    }
+
+    pub fn form(&self) -> AstRef {
+        self.form
+    }
+
+    // The name `id` binds as a variable, if it is one.
+    fn variable(&self, ast: &Ast, id: AstRef) -> Option<Arc<str>> {
+        let symbol = ast.get_symbol_id(id)?;
+        let name = ast.get_symbol(id)?;
+        match &*name {
+            "_" | "..." => None,
+            _ if self.literals.contains(&symbol) => None,
+            _ => Some(name),
+        }
+    }
+
+    // The variables in the pattern at `id`.
+    fn variables(&self, ast: &Ast, id: AstRef) -> Vec<Arc<str>> {
+        let mut variables = vec![];
+        let mut stack = vec![id];
+        while let Some(id) = stack.pop() {
+            variables.extend(self.variable(ast, id));
+            ast.push_children(id, &mut stack);
+        }
+        variables
+    }
+}
+
+// The items of a list and what follows them, along with the pair holding each item, so that the
+// rest of the list after any number of items can be found.
+struct Items {
+    items: Vec<AstRef>,
+    pairs: Vec<AstRef>,
+    tail: AstRef,
+}
+
+impl Items {
+    // The first `limit` items at most. `None` if the list is cyclic before then.
+    fn new(ast: &Ast, id: AstRef, limit: usize) -> Option<Items> {
+        let mut seen = HashSet::new();
+        let mut items = Items {
+            items: vec![],
+            pairs: vec![],
+            tail: id,
+        };
+        while let Some((head, tail)) = ast.get_pair(items.tail) {
+            if items.items.len() == limit {
+                break;
+            }
+            if !seen.insert(items.tail) {
+                return None;
+            }
+            items.items.push(head);
+            items.pairs.push(items.tail);
+            items.tail = tail;
+        }
+        Some(items)
+    }
+
+    fn rest(&self, index: usize) -> AstRef {
+        self.pairs.get(index).copied().unwrap_or(self.tail)
+    }
+}
+
+impl Ast {
+    // Match the form at `id` against `pattern`, returning what its variables matched if it
+    // matches.
+    pub fn match_pattern(&self, id: AstRef, pattern: &Pattern) -> Option<Bindings> {
+        let mut bindings = Bindings::new();
+        match self.match_form(id, pattern.form, pattern, &mut bindings) {
+            true => Some(bindings),
+            false => None,
+        }
+    }
+
+    fn match_form(
+        &self,
+        id: AstRef,
+        form: AstRef,
+        pattern: &Pattern,
+        bindings: &mut Bindings,
+    ) -> bool {
+        if let Some(name) = pattern.variable(self, form) {
+            bindings.insert(name, Binding::One(id));
+            return true;
+        }
+
+        match self.get(form) {
+            AstNode::Pair(..) => self.match_list(id, form, pattern, bindings),
+            AstNode::Vector(forms) => match self.get_vector(id) {
+                Some(items) if items.len() == forms.len() => items
+                    .iter()
+                    .zip(forms)
+                    .all(|(&item, &form)| self.match_form(item, form, pattern, bindings)),
+                _ => false,
+            },
+            AstNode::Symbol(_) if self.get_symbol(form).as_deref() == Some("_") => true,
+            // A literal matches the symbols made from it by macros, too.
+            &AstNode::Symbol(literal) => {
+                let table = SymbolTable::global();
+                self.get_symbol_id(id)
+                    .is_some_and(|symbol| table.origin(symbol) == table.origin(literal))
+            }
+            node => self.get(id) == node,
+        }
+    }
+
+    fn match_list(
+        &self,
+        id: AstRef,
+        form: AstRef,
+        pattern: &Pattern,
+        bindings: &mut Bindings,
+    ) -> bool {
+        let Some(patterns) = Items::new(self, form, usize::MAX) else {
+            return false;
+        };
+
+        let ellipsis = (1..patterns.items.len())
+            .find(|&i| self.get_symbol(patterns.items[i]).as_deref() == Some("..."))
+            .map(|i| i - 1);
+        let limit = match ellipsis {
+            Some(_) => usize::MAX,
+            None => patterns.items.len(),
+        };
+        let Some(items) = Items::new(self, id, limit) else {
+            return false;
+        };
+
+        let Some(start) = ellipsis else {
+            // Without an ellipsis, each item matches the item at the same place.
+            let count = patterns.items.len();
+            return items.items.len() >= count
+                && (0..count).all(|i| {
+                    self.match_form(items.items[i], patterns.items[i], pattern, bindings)
+                })
+                && self.match_form(items.rest(count), patterns.tail, pattern, bindings);
+        };
+
+        let after = &patterns.items[start + 2..];
+        let Some(repeated) = items.items.len().checked_sub(start + after.len()) else {
+            return false;
+        };
+        if !(0..start)
+            .all(|i| self.match_form(items.items[i], patterns.items[i], pattern, bindings))
+        {
+            return false;
+        }
+
+        // Each variable of the repeated item is bound to everything it matched in turn.
+        let mut matches = pattern
+            .variables(self, patterns.items[start])
+            .into_iter()
+            .map(|name| (name, vec![]))
+            .collect::<HashMap<_, _>>();
+        for &item in &items.items[start..start + repeated] {
+            let mut repeat = Bindings::new();
+            if !self.match_form(item, patterns.items[start], pattern, &mut repeat) {
+                return false;
+            }
+            for (name, binding) in repeat {
+                matches.get_mut(&name).unwrap().push(binding);
+            }
+        }
+        bindings.extend(
+            matches
+                .into_iter()
+                .map(|(name, matched)| (name, Binding::Many(matched))),
+        );
+
+        let end = start + repeated;
+        after
+            .iter()
+            .enumerate()
+            .all(|(i, &after)| self.match_form(items.items[end + i], after, pattern, bindings))
+            && self.match_form(
+                items.rest(end + after.len()),
+                patterns.tail,
+                pattern,
+                bindings,
+            )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lang::reader::parse_str;
+
+    fn read(ast: &mut Ast, source: &str) -> AstRef {
+        parse_str(source, ast).unwrap()[0]
+    }
+
+    fn show(ast: &Ast, binding: &Binding) -> String {
+        match binding {
+            Binding::One(id) => ast.display(*id).to_string(),
+            Binding::Many(bindings) => {
+                let bindings = bindings.iter().map(|b| show(ast, b)).collect::<Vec<_>>();
+                format!("[{}]", bindings.join(" "))
+            }
+        }
+    }
+
+    fn matches(source: &str, pattern: &str, literals: &[&str]) -> Option<Vec<(String, String)>> {
+        let mut ast = Ast::new();
+        let id = read(&mut ast, source);
+        let mut pattern = Pattern::new(read(&mut ast, pattern));
+        for literal in literals {
+            pattern.add_literal(literal);
+        }
+
+        let bindings = ast.match_pattern(id, &pattern)?;
+        let mut bindings = bindings
+            .iter()
+            .map(|(name, binding)| (name.to_string(), show(&ast, binding)))
+            .collect::<Vec<_>>();
+        bindings.sort();
+        Some(bindings)
+    }
+
+    fn pairs(bindings: &[(&str, &str)]) -> Option<Vec<(String, String)>> {
+        Some(
+            bindings
+                .iter()
+                .map(|(name, binding)| (name.to_string(), binding.to_string()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_match() {
+        let define = ["define"];
+        assert_eq!(
+            matches("(define x (+ 1 2))", "(define name value)", &define),
+            pairs(&[("name", "x"), ("value", "(+ 1 2)")])
+        );
+        assert_eq!(matches("(set! x 1)", "(define name value)", &define), None);
+        assert_eq!(matches("(define x)", "(define name value)", &define), None);
+        assert_eq!(
+            matches("(define x 1 2)", "(define name value)", &define),
+            None
+        );
+
+        // Atoms match equal atoms, and `_` matches anything.
+        assert_eq!(matches("(f 1 \"a\")", "(_ 1 \"a\")", &[]), pairs(&[]));
+        assert_eq!(matches("(f 2)", "(_ 1)", &[]), None);
+
+        // Dotted tails match the rest of the list.
+        assert_eq!(
+            matches("(f a b)", "(f . args)", &["f"]),
+            pairs(&[("args", "(a b)")])
+        );
+        assert_eq!(
+            matches("(f)", "(f . args)", &["f"]),
+            pairs(&[("args", "()")])
+        );
+
+        // Vectors match item by item.
+        assert_eq!(
+            matches("#(1 (a))", "#(x (y))", &[]),
+            pairs(&[("x", "1"), ("y", "a")])
+        );
+        assert_eq!(matches("#(1 2)", "#(x)", &[]), None);
+        assert_eq!(matches("(1)", "#(x)", &[]), None);
+    }
+
+    #[test]
+    fn test_match_ellipsis() {
+        assert_eq!(
+            matches(
+                "(let ((a 1) (b 2)) a b)",
+                "(let ((x v) ...) body ...)",
+                &["let"]
+            ),
+            pairs(&[("body", "[a b]"), ("v", "[1 2]"), ("x", "[a b]")])
+        );
+        assert_eq!(
+            matches("(let () a)", "(let ((x v) ...) body ...)", &["let"]),
+            pairs(&[("body", "[a]"), ("v", "[]"), ("x", "[]")])
+        );
+
+        // Items after an ellipsis are matched from the end.
+        assert_eq!(
+            matches("(1 2 3 4)", "(first middle ... last)", &[]),
+            pairs(&[("first", "1"), ("last", "4"), ("middle", "[2 3]")])
+        );
+        assert_eq!(matches("(1)", "(first middle ... last)", &[]), None);
+
+        // Ellipses nest.
+        assert_eq!(
+            matches("((a b) () (c))", "((x ...) ...)", &[]),
+            pairs(&[("x", "[[a b] [] [c]]")])
+        );
+
+        // Every item has to match.
+        assert_eq!(matches("((a 1) b)", "((x v) ...)", &[]), None);
+    }
+
+    #[test]
+    fn test_match_cycle() {
+        let mut ast = Ast::new();
+        let id = read(&mut ast, "#0=(a . #0#)");
+        let pattern = Pattern::new(read(&mut ast, "(x ...)"));
+        assert_eq!(ast.match_pattern(id, &pattern), None);
+
+        let pattern = Pattern::new(read(&mut ast, "(x . rest)"));
+        let bindings = ast.match_pattern(id, &pattern).unwrap();
+        assert_eq!(bindings["rest"], Binding::One(id));
+    }
+}
@@ -0,0 +1,1573 @@
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+
+use num::{BigInt, BigRational};
+
+use crate::lang::ast::{Ast, AstNode, AstRef, FileId, SyntaxInfo, CHAR_NAMES};
+use crate::lang::compiler::{lexer_def, Token};
+use crate::lex::lexer::{Lexeme, Span};
+use crate::parse::pratt::{Operators, PrattBuilder, PrattItem};
+
+// Why a sequence of lexemes isn't a sequence of forms. Spans are byte offsets into the source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    // A closing bracket with no list open.
+    Unopened { close: Span },
+    // A list closed with a different kind of bracket than it was opened with.
+    Mismatched { open: Span, close: Span },
+    // The input ended inside the list, string, quote or comment starting at `open`.
+    UnexpectedEof { open: Span },
+    // A quote, comma or other prefix followed by the end of its list instead of a form.
+    MissingForm { quote: Span },
+    // A `.` anywhere but before the last form of a list with at least one other.
+    InvalidDot { dot: Span },
+    // A number or character too malformed to convert, or an item of a `#u8(...)` that isn't a
+    // byte.
+    InvalidLiteral { span: Span },
+    // Braces read as infix whose contents aren't an expression, e.g. `{1 +}`.
+    InvalidInfix { span: Span },
+    // A dispatch handler rejected what it was given.
+    InvalidDispatch { span: Span },
+    // Source the lexer couldn't split into lexemes, e.g. a string ending in a lone `\`.
+    InvalidToken { span: Span },
+    // A datum label defined twice in the same form, labelling only itself, or referred to
+    // without being defined.
+    InvalidLabel { span: Span },
+}
+
+impl ParseError {
+    pub fn span(&self) -> Span {
+        match *self {
+            ParseError::Unopened { close } => close,
+            ParseError::Mismatched { close, .. } => close,
+            ParseError::UnexpectedEof { open } => open,
+            ParseError::MissingForm { quote } => quote,
+            ParseError::InvalidDot { dot } => dot,
+            ParseError::InvalidLiteral { span } => span,
+            ParseError::InvalidInfix { span } => span,
+            ParseError::InvalidDispatch { span } => span,
+            ParseError::InvalidLabel { span } => span,
+            ParseError::InvalidToken { span } => span,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::Unopened { close } => {
+                write!(f, "unexpected closing bracket at {}", close.start)
+            }
+            ParseError::Mismatched { open, close } => write!(
+                f,
+                "closing bracket at {} doesn't match the opening one at {}",
+                close.start, open.start
+            ),
+            ParseError::UnexpectedEof { open } => {
+                write!(f, "input ended inside the form starting at {}", open.start)
+            }
+            ParseError::MissingForm { quote } => {
+                write!(f, "nothing to quote after {}", quote.start)
+            }
+            ParseError::InvalidDot { dot } => write!(f, "misplaced dot at {}", dot.start),
+            ParseError::InvalidLiteral { span } => {
+                write!(f, "invalid literal at {}", span.start)
+            }
+            ParseError::InvalidInfix { span } => {
+                write!(f, "invalid infix expression at {}", span.start)
+            }
+            ParseError::InvalidDispatch { span } => {
+                write!(f, "invalid read syntax at {}", span.start)
+            }
+            ParseError::InvalidLabel { span } => {
+                write!(f, "invalid datum label at {}", span.start)
+            }
+            ParseError::InvalidToken { span } => write!(f, "invalid token at {}", span.start),
+        }
+    }
+}
+
+impl Error for ParseError {}
+
+// Whatever is open at the point the reader has got to. Forms are read bottom up, so a form that
+// is complete is added to the innermost frame, and frames are closed by their closing lexeme.
+enum Frame {
+    List {
+        open: Span,
+        // The lexeme it was opened with, which tells a list from a vector or byte string.
+        start: Token,
+        close: Token,
+        items: Vec<AstRef>,
+        // The `.` of a dotted list and the form after it.
+        dot: Option<Span>,
+        tail: Option<AstRef>,
+    },
+    // The next form is read as `(name form)`.
+    Quote {
+        quote: Span,
+        name: &'static str,
+    },
+    // The next form is given to a dispatch handler.
+    Dispatch {
+        span: Span,
+        handler: DispatchHandler,
+    },
+    // The next form is skipped, after `#;`.
+    Skip {
+        span: Span,
+    },
+    // The next form is labelled `#n=`, and becomes the node `placeholder`, which references to
+    // the label inside it already refer to.
+    Label {
+        span: Span,
+        placeholder: AstRef,
+    },
+    // The text since the last interpolation, and the parts before that.
+    String {
+        open: Span,
+        text: String,
+        parts: Vec<AstRef>,
+    },
+}
+
+// Reads custom syntax starting with a pair of dispatch characters, e.g. `#v(1 2 3)` or `#x1F`,
+// returning `None` if it is invalid.
+pub type DispatchHandler = Arc<dyn Fn(&mut Dispatch) -> Option<AstRef> + Send + Sync>;
+
+// What a dispatch handler is given: the text after the dispatch characters up to the next
+// delimiter, like `1F` in `#x1F`, or if there is none the form after them, like `(1 2 3)` in
+// `#v(1 2 3)`.
+pub struct Dispatch<'a> {
+    ast: &'a mut Ast,
+    text: &'a str,
+    form: Option<AstRef>,
+    handlers: &'a HashMap<(char, char), DispatchHandler>,
+}
+
+impl Dispatch<'_> {
+    pub fn text(&self) -> &str {
+        self.text
+    }
+
+    pub fn form(&self) -> Option<AstRef> {
+        self.form
+    }
+
+    pub fn ast(&mut self) -> &mut Ast {
+        self.ast
+    }
+
+    // Read the forms in `source` into the same `Ast`, e.g. to parse forms out of the text, with the
+    // same dispatch handlers. They are parts of the form being read, so aren't added to the root.
+    pub fn read(&mut self, source: &str) -> Result<Vec<AstRef>, ParseError> {
+        let mut run = lexer_def().run();
+        run.put_str(source);
+        run.finish();
+
+        let mut parser = Parser::with_ast(std::mem::take(self.ast));
+        parser.dispatch = self.handlers.clone();
+        parser.nested = true;
+        std::iter::from_fn(|| run.get()).for_each(|lexeme| parser.put(lexeme));
+        parser.finish();
+
+        let forms = match parser.get_error() {
+            Some(&error) => Err(error),
+            None => Ok(parser.forms.drain(..).collect()),
+        };
+        *self.ast = parser.into_ast();
+
+        forms
+    }
+}
+
+// Reads forms from lexemes as they are put, in the same way a lexer reads lexemes from
+// characters: complete forms are taken with `get`, and an error stops it until `reset`. Between
+// forms, e.g. at the end of a line typed into a REPL, `is_incomplete` tells whether a form has
+// been started but not finished, in which case more input is needed rather than there being an
+// error.
+//
+// Braces are read as lists, like brackets are, unless infix operators have been set, in which case
+// `{1 + 2 * x}` reads as `(+ 1 (* 2 x))`. Symbols named by an operator are read as operators, and
+// anything else, including a nested `{...}`, as an operand.
+//
+// Identifiers starting with a pair of characters a dispatch handler has been added for are read
+// by the handler instead.
+//
+// A form labelled `#n=` can be referred to as `#n#` later in the same top-level form, including
+// from inside itself, which reads as a cycle.
+//
+// Every node created is given the `SyntaxInfo` of the source it was read from, or for a node with
+// no source of its own, like the `quote` in `'x`, of the form it is part of.
+#[derive(Default)]
+pub struct Parser {
+    ast: Ast,
+    frames: Vec<Frame>,
+    forms: VecDeque<AstRef>,
+    error: Option<ParseError>,
+    infix: Option<Operators<String>>,
+    dispatch: HashMap<(char, char), DispatchHandler>,
+    labels: HashMap<u64, AstRef>,
+    // Where each block comment still open starts.
+    comments: Vec<Span>,
+    file: FileId,
+    // Where each line after the first starts.
+    line_starts: Vec<usize>,
+    // Reading for a dispatch handler, so top-level forms aren't roots.
+    nested: bool,
+    // Top-level forms aren't added to the root list.
+    detached: bool,
+}
+
+impl Parser {
+    pub fn new() -> Parser {
+        Self::default()
+    }
+
+    // Read into `ast`, e.g. to add to the forms of an earlier parse.
+    pub fn with_ast(ast: Ast) -> Parser {
+        Parser {
+            ast,
+            ..Self::default()
+        }
+    }
+
+    pub fn ast(&self) -> &Ast {
+        &self.ast
+    }
+
+    pub fn into_ast(self) -> Ast {
+        self.ast
+    }
+
+    // The file to give the nodes read from now on.
+    pub fn set_file(&mut self, file: FileId) {
+        self.file = file;
+    }
+
+    // Leave the top-level forms read out of the root list, e.g. for code a transformation makes to
+    // put somewhere in the program itself.
+    pub fn set_detached(&mut self, detached: bool) {
+        self.detached = detached;
+    }
+
+    pub fn with_detached(&mut self, detached: bool) -> &mut Self {
+        self.set_detached(detached);
+        self
+    }
+
+    pub fn set_infix(&mut self, operators: Operators<String>) {
+        self.infix = Some(operators);
+    }
+
+    // Read identifiers starting with `prefix` then `sub` with `handler`, replacing any handler
+    // added for them before.
+    pub fn add_dispatch<F>(&mut self, prefix: char, sub: char, handler: F)
+    where
+        F: Fn(&mut Dispatch) -> Option<AstRef> + Send + Sync + 'static,
+    {
+        self.dispatch.insert((prefix, sub), Arc::new(handler));
+    }
+
+    pub fn with_dispatch<F>(&mut self, prefix: char, sub: char, handler: F) -> &mut Self
+    where
+        F: Fn(&mut Dispatch) -> Option<AstRef> + Send + Sync + 'static,
+    {
+        self.add_dispatch(prefix, sub, handler);
+        self
+    }
+
+    pub fn remove_dispatch(&mut self, prefix: char, sub: char) {
+        self.dispatch.remove(&(prefix, sub));
+    }
+
+    // Forget any partly read form, the forms not yet taken and the error. The nodes already
+    // created stay in the `Ast`.
+    pub fn reset(&mut self) {
+        self.frames.clear();
+        self.forms.clear();
+        self.labels.clear();
+        self.comments.clear();
+        self.line_starts.clear();
+        self.error = None;
+    }
+
+    pub fn put(&mut self, lexeme: Lexeme<Token>) {
+        if self.error.is_some() {
+            return;
+        }
+
+        if let Err(error) = self.read(lexeme) {
+            self.error = Some(error);
+        }
+    }
+
+    // The input has ended, so a form still open is an error.
+    pub fn finish(&mut self) {
+        if self.error.is_some() {
+            return;
+        }
+
+        if let Some(&open) = self.comments.first() {
+            self.error = Some(ParseError::UnexpectedEof { open });
+            return;
+        }
+
+        self.error = match self.frames.last() {
+            None => None,
+            Some(Frame::List { open, .. } | Frame::String { open, .. }) => {
+                Some(ParseError::UnexpectedEof { open: *open })
+            }
+            Some(Frame::Quote { quote, .. }) => Some(ParseError::UnexpectedEof { open: *quote }),
+            Some(
+                Frame::Dispatch { span, .. } | Frame::Label { span, .. } | Frame::Skip { span },
+            ) => Some(ParseError::UnexpectedEof { open: *span }),
+        };
+    }
+
+    pub fn get(&mut self) -> Option<AstRef> {
+        self.forms.pop_front()
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.error.is_some()
+    }
+
+    pub fn get_error(&self) -> Option<&ParseError> {
+        self.error.as_ref()
+    }
+
+    // A form or block comment has been started but not finished.
+    pub fn is_incomplete(&self) -> bool {
+        self.error.is_none() && !(self.frames.is_empty() && self.comments.is_empty())
+    }
+
+    fn read(&mut self, lexeme: Lexeme<Token>) -> Result<(), ParseError> {
+        let span = lexeme.extent();
+        let text = lexeme.span.as_deref().unwrap_or("");
+
+        match lexeme.token {
+            Token::Newline => self.line_starts.push(span.end),
+            _ => {
+                for (i, _) in text.match_indices('\n') {
+                    self.line_starts.push(span.start + i + 1);
+                }
+            }
+        }
+
+        match lexeme.token {
+            Token::Whitespace | Token::Newline | Token::Semicolon | Token::Comment => {}
+            Token::BlockCommentStart => self.comments.push(span),
+            Token::BlockCommentEnd => {
+                self.comments.pop();
+            }
+            Token::DatumComment => self.frames.push(Frame::Skip { span }),
+
+            Token::LParen | Token::InterpStart | Token::VectorStart | Token::BytesStart => {
+                self.open(span, lexeme.token, Token::RParen)
+            }
+            Token::LBracket => self.open(span, lexeme.token, Token::RBracket),
+            Token::LBrace => self.open(span, lexeme.token, Token::RBrace),
+            Token::RParen | Token::RBracket | Token::RBrace => {
+                return self.close(span, lexeme.token)
+            }
+
+            Token::Quote => self.quote(span, "quote"),
+            Token::BackQuote => self.quote(span, "quasiquote"),
+            Token::Comma => self.quote(span, "unquote"),
+            Token::CommaAt => self.quote(span, "unquote-splicing"),
+
+            Token::Integer => {
+                let value = text
+                    .parse::<BigInt>()
+                    .map_err(|_| ParseError::InvalidLiteral { span })?;
+                let id = self.ast.create_integer(value);
+                return self.push(span, id);
+            }
+            Token::Rational => {
+                let (numer, denom) = text.split_once('/').unwrap();
+                let parse = |text: &str| {
+                    text.parse::<BigInt>()
+                        .map_err(|_| ParseError::InvalidLiteral { span })
+                };
+                let (numer, denom) = (parse(numer)?, parse(denom)?);
+                if denom == BigInt::from(0) {
+                    return Err(ParseError::InvalidLiteral { span });
+                }
+
+                // A ratio of integers that divide, like `4/2`, is just an integer.
+                let value = BigRational::new(numer, denom);
+                let id = match value.is_integer() {
+                    true => self.ast.create_integer(value.to_integer()),
+                    false => self.ast.create_rational(value),
+                };
+                return self.push(span, id);
+            }
+            Token::Float => {
+                let value = text
+                    .parse::<f64>()
+                    .map_err(|_| ParseError::InvalidLiteral { span })?;
+                let id = self.ast.create_float(value);
+                return self.push(span, id);
+            }
+            Token::Char => {
+                let value = character(text).ok_or(ParseError::InvalidLiteral { span })?;
+                let id = self.ast.create_char(value);
+                return self.push(span, id);
+            }
+            Token::Identifier if text == "." => return self.dot(span),
+            Token::Identifier if matches!(text, "#t" | "#true" | "#f" | "#false") => {
+                let id = self.ast.create_bool(text.starts_with("#t"));
+                return self.push(span, id);
+            }
+            Token::Label => {
+                let (n, define) = label(text).ok_or(ParseError::InvalidLabel { span })?;
+                if define {
+                    if self.labels.contains_key(&n) {
+                        return Err(ParseError::InvalidLabel { span });
+                    }
+                    let placeholder = self.ast.create_nil();
+                    self.labels.insert(n, placeholder);
+                    self.frames.push(Frame::Label { span, placeholder });
+                    return Ok(());
+                }
+
+                let id = *self
+                    .labels
+                    .get(&n)
+                    .ok_or(ParseError::InvalidLabel { span })?;
+                return self.push(span, id);
+            }
+            Token::Identifier => {
+                let id = match self.dispatch_handler(text) {
+                    Some((handler, "")) => {
+                        self.frames.push(Frame::Dispatch { span, handler });
+                        return Ok(());
+                    }
+                    Some((handler, rest)) => {
+                        let id = self.call(&handler, span, rest, None)?;
+                        self.locate_all(id, span);
+                        id
+                    }
+                    None => self.ast.create_symbol(text),
+                };
+                return self.push(span, id);
+            }
+
+            Token::StringStart => self.frames.push(Frame::String {
+                open: span,
+                text: String::new(),
+                parts: vec![],
+            }),
+            Token::StringText | Token::StringEscape => {
+                if let Some(Frame::String { text: string, .. }) = self.frames.last_mut() {
+                    match lexeme.token {
+                        Token::StringText => string.push_str(text),
+                        _ => string.push(unescape(text)),
+                    }
+                }
+            }
+            Token::StringEnd => return self.close_string(span),
+        }
+
+        Ok(())
+    }
+
+    fn dispatch_handler<'t>(&self, text: &'t str) -> Option<(DispatchHandler, &'t str)> {
+        let mut chars = text.chars();
+        let key = (chars.next()?, chars.next()?);
+        let handler = self.dispatch.get(&key)?;
+        Some((handler.clone(), chars.as_str()))
+    }
+
+    fn call(
+        &mut self,
+        handler: &DispatchHandler,
+        span: Span,
+        text: &str,
+        form: Option<AstRef>,
+    ) -> Result<AstRef, ParseError> {
+        let mut dispatch = Dispatch {
+            ast: &mut self.ast,
+            text,
+            form,
+            handlers: &self.dispatch,
+        };
+        handler(&mut dispatch).ok_or(ParseError::InvalidDispatch { span })
+    }
+
+    fn syntax(&self, span: Span) -> SyntaxInfo {
+        let line = self
+            .line_starts
+            .partition_point(|&start| start <= span.start);
+        let line_start = match line {
+            0 => 0,
+            _ => self.line_starts[line - 1],
+        };
+
+        SyntaxInfo {
+            file: self.file,
+            span,
+            line: line + 1,
+            column: span.start - line_start + 1,
+        }
+    }
+
+    // Give `id` the syntax of `span`, unless it already has some. A nested parser's spans are in
+    // the dispatch handler's text, so it leaves its nodes to be given the dispatch's span.
+    fn locate(&mut self, id: AstRef, span: Span) {
+        if !self.nested && self.ast.get_syntax(id).is_none() {
+            let syntax = self.syntax(span);
+            self.ast.set_syntax(id, syntax);
+        }
+    }
+
+    // Give `id` and every node it reaches without passing through a node that has syntax the
+    // syntax of `span`, e.g. the nodes created for a form that has no source of its own.
+    fn locate_all(&mut self, id: AstRef, span: Span) {
+        if self.nested {
+            return;
+        }
+
+        let syntax = self.syntax(span);
+        let mut stack = vec![id];
+        while let Some(id) = stack.pop() {
+            if self.ast.get_syntax(id).is_some() {
+                continue;
+            }
+
+            self.ast.set_syntax(id, syntax);
+            self.ast.push_children(id, &mut stack);
+        }
+    }
+
+    fn open(&mut self, open: Span, start: Token, close: Token) {
+        self.frames.push(Frame::List {
+            open,
+            start,
+            close,
+            items: vec![],
+            dot: None,
+            tail: None,
+        });
+    }
+
+    fn quote(&mut self, quote: Span, name: &'static str) {
+        self.frames.push(Frame::Quote { quote, name });
+    }
+
+    fn close(&mut self, span: Span, token: Token) -> Result<(), ParseError> {
+        let (open, start, close, items, dot, tail) = match self.frames.pop() {
+            Some(Frame::List {
+                open,
+                start,
+                close,
+                items,
+                dot,
+                tail,
+            }) => (open, start, close, items, dot, tail),
+            Some(
+                Frame::Quote { quote, .. }
+                | Frame::Dispatch { span: quote, .. }
+                | Frame::Label { span: quote, .. }
+                | Frame::Skip { span: quote },
+            ) => return Err(ParseError::MissingForm { quote }),
+            _ => return Err(ParseError::Unopened { close: span }),
+        };
+
+        if close != token {
+            return Err(ParseError::Mismatched { open, close: span });
+        }
+        if let (Some(dot), None) = (dot, tail) {
+            return Err(ParseError::InvalidDot { dot });
+        }
+
+        let span = Span {
+            start: open.start,
+            end: span.end,
+        };
+
+        match start {
+            Token::VectorStart => {
+                let id = self.ast.create_vector(&items);
+                return self.push(span, id);
+            }
+            Token::BytesStart => {
+                let bytes = items
+                    .iter()
+                    .map(|&item| {
+                        match self.ast.get(item) {
+                            AstNode::Integer(value) => u8::try_from(value).ok(),
+                            _ => None,
+                        }
+                        .ok_or_else(|| ParseError::InvalidLiteral {
+                            span: self.ast.get_syntax(item).map_or(span, |s| s.span),
+                        })
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                let id = self.ast.create_bytes(&bytes);
+                return self.push(span, id);
+            }
+            _ => {}
+        }
+
+        if let (Token::RBrace, Some(operators)) = (close, &self.infix) {
+            if let Some(dot) = dot {
+                return Err(ParseError::InvalidDot { dot });
+            }
+
+            let items = items
+                .into_iter()
+                .map(|item| match self.ast.get_symbol(item) {
+                    Some(name) if operators.is_operator(&name.to_string()) => {
+                        PrattItem::Operator(name.to_string())
+                    }
+                    _ => PrattItem::Operand(item),
+                });
+            let items = items.collect::<Vec<_>>();
+            let id = operators
+                .parse(items, &mut InfixBuilder { ast: &mut self.ast })
+                .map_err(|_| ParseError::InvalidInfix { span })?;
+
+            self.locate_all(id, span);
+            return self.push(span, id);
+        }
+
+        // Each pair of the list runs from its item to the end of the list, and the `()` ending it
+        // is the closing bracket.
+        let mut list = match tail {
+            Some(tail) => tail,
+            None => {
+                let nil = self.ast.create_nil();
+                let close = Span {
+                    start: span.end - 1,
+                    end: span.end,
+                };
+                self.locate(nil, close);
+                nil
+            }
+        };
+        for (i, &item) in items.iter().enumerate().rev() {
+            list = self.ast.create_pair(item, list);
+
+            let start = match i {
+                0 => span.start,
+                _ => self
+                    .ast
+                    .get_syntax(item)
+                    .map_or(span.start, |s| s.span.start),
+            };
+            self.locate(
+                list,
+                Span {
+                    start,
+                    end: span.end,
+                },
+            );
+        }
+
+        self.push(span, list)
+    }
+
+    fn close_string(&mut self, span: Span) -> Result<(), ParseError> {
+        let Some(Frame::String { open, text, parts }) = self.frames.pop() else {
+            return Ok(());
+        };
+
+        let id = match parts.is_empty() {
+            true => self.ast.create_string(&text),
+            false => {
+                // "a $(f x) b" reads as (string-append "a " (f x) " b").
+                let mut parts = parts;
+                if !text.is_empty() {
+                    parts.push(self.ast.create_string(&text));
+                }
+
+                let append = self.ast.create_symbol("string-append");
+                let list = self.ast.create_list(&parts);
+                self.ast.create_pair(append, list)
+            }
+        };
+
+        let span = Span {
+            start: open.start,
+            end: span.end,
+        };
+        self.locate_all(id, span);
+        self.push(span, id)
+    }
+
+    fn dot(&mut self, span: Span) -> Result<(), ParseError> {
+        match self.frames.last_mut() {
+            Some(Frame::List {
+                start: Token::LParen | Token::LBracket | Token::LBrace | Token::InterpStart,
+                items,
+                dot,
+                ..
+            }) if !items.is_empty() && dot.is_none() => {
+                *dot = Some(span);
+                Ok(())
+            }
+            _ => Err(ParseError::InvalidDot { dot: span }),
+        }
+    }
+
+    // Add a complete form to whatever is open, wrapping it in any quotes before it.
+    fn push(&mut self, mut span: Span, mut id: AstRef) -> Result<(), ParseError> {
+        self.locate(id, span);
+
+        loop {
+            match self.frames.last_mut() {
+                Some(&mut Frame::Quote { quote, name }) => {
+                    self.frames.pop();
+
+                    let quoted = self.ast.create_list(&[id]);
+                    self.locate_all(quoted, span);
+                    let name = self.ast.create_symbol(name);
+                    self.locate(name, quote);
+
+                    span = Span {
+                        start: quote.start,
+                        end: span.end,
+                    };
+                    id = self.ast.create_pair(name, quoted);
+                    self.locate(id, span);
+                }
+                Some(Frame::Dispatch { .. }) => {
+                    let Some(Frame::Dispatch {
+                        span: start,
+                        handler,
+                    }) = self.frames.pop()
+                    else {
+                        unreachable!()
+                    };
+
+                    id = self.call(&handler, start, "", Some(id))?;
+
+                    span = Span {
+                        start: start.start,
+                        end: span.end,
+                    };
+                    self.locate_all(id, span);
+                }
+                Some(Frame::Skip { .. }) => {
+                    self.frames.pop();
+                    return Ok(());
+                }
+                Some(&mut Frame::Label {
+                    span: label,
+                    placeholder,
+                }) => {
+                    self.frames.pop();
+
+                    if id == placeholder {
+                        return Err(ParseError::InvalidLabel { span: label });
+                    }
+                    let node = self.ast.get(id).clone();
+                    self.ast.set(placeholder, node);
+
+                    // References inside the form may have located the placeholder already.
+                    span = Span {
+                        start: label.start,
+                        end: span.end,
+                    };
+                    let syntax = self.syntax(span);
+                    self.ast.set_syntax(placeholder, syntax);
+                    id = placeholder;
+                }
+                Some(Frame::List {
+                    items, dot, tail, ..
+                }) => {
+                    match (dot, tail.is_some()) {
+                        (None, _) => items.push(id),
+                        (Some(_), false) => *tail = Some(id),
+                        (Some(_), true) => return Err(ParseError::InvalidDot { dot: span }),
+                    }
+                    return Ok(());
+                }
+                Some(Frame::String { open, text, parts }) => {
+                    let open = *open;
+                    let mut part = None;
+                    if !text.is_empty() {
+                        let text = std::mem::take(text);
+                        part = Some(self.ast.create_string(&text));
+                        parts.extend(part);
+                    }
+                    parts.push(id);
+
+                    if let Some(part) = part {
+                        let text = Span {
+                            start: open.start,
+                            end: span.start,
+                        };
+                        self.locate(part, text);
+                    }
+                    return Ok(());
+                }
+                None => {
+                    if !self.nested && !self.detached {
+                        let pair = self.ast.add_root(id);
+                        self.locate_all(pair, span);
+                    }
+
+                    self.labels.clear();
+                    self.forms.push_back(id);
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+// Builds each operator applied in an infix expression as a call to the operator's symbol.
+struct InfixBuilder<'a> {
+    ast: &'a mut Ast,
+}
+
+impl InfixBuilder<'_> {
+    fn call(&mut self, operator: &str, operands: &[AstRef]) -> AstRef {
+        let operator = self.ast.create_symbol(operator);
+        let list = self.ast.create_list(operands);
+        self.ast.create_pair(operator, list)
+    }
+}
+
+impl PrattBuilder<String> for InfixBuilder<'_> {
+    type Value = AstRef;
+
+    fn prefix(&mut self, operator: String, operand: AstRef) -> AstRef {
+        self.call(&operator, &[operand])
+    }
+
+    fn infix(&mut self, operator: String, lhs: AstRef, rhs: AstRef) -> AstRef {
+        self.call(&operator, &[lhs, rhs])
+    }
+
+    fn postfix(&mut self, operator: String, operand: AstRef) -> AstRef {
+        self.call(&operator, &[operand])
+    }
+}
+
+// How far through a form some input is, for deciding whether to evaluate a line typed into a REPL
+// or to read another one first. Line comments end with the input, so they never leave it
+// incomplete, but block comments do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Completeness {
+    Complete,
+    // `depth` lists are open, or a quote is waiting for its form if it is 0.
+    Incomplete { depth: usize },
+    // The input ends inside a string.
+    InString,
+    // The input ends inside `depth` nested block comments.
+    InComment { depth: usize },
+    // No more input could make this valid, e.g. a bracket closes nothing.
+    Invalid(ParseError),
+}
+
+pub fn is_complete(input: &str) -> Completeness {
+    let mut run = lexer_def().run();
+    run.put_str(input);
+    run.finish();
+
+    let mut parser = Parser::new();
+    while let Some(lexeme) = run.get() {
+        parser.put(lexeme);
+    }
+
+    if let Some(&error) = parser.get_error() {
+        return Completeness::Invalid(error);
+    }
+
+    if !parser.comments.is_empty() {
+        return Completeness::InComment {
+            depth: parser.comments.len(),
+        };
+    }
+
+    // An escape cut off by the end of the input fails to lex, but can only be in a string. Inside
+    // an interpolation the string's own text is not what is unfinished.
+    if let Some(Frame::String { .. }) = parser.frames.last() {
+        return Completeness::InString;
+    }
+
+    match parser.frames.is_empty() {
+        true => Completeness::Complete,
+        false => Completeness::Incomplete {
+            depth: parser
+                .frames
+                .iter()
+                .filter(|frame| matches!(frame, Frame::List { .. }))
+                .count(),
+        },
+    }
+}
+
+// The number of a datum label like `#0=` or `#0#`, and whether it is being defined.
+fn label(text: &str) -> Option<(u64, bool)> {
+    let n = text.get(1..text.len() - 1)?.parse().ok()?;
+    Some((n, text.ends_with('=')))
+}
+
+// The character a literal like `#\a`, `#\newline` or `#\x41` stands for.
+fn character(text: &str) -> Option<char> {
+    let text = text.strip_prefix("#\\")?;
+    let mut chars = text.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return Some(c);
+    }
+
+    if let Some(&(_, c)) = CHAR_NAMES.iter().find(|(name, _)| *name == text) {
+        return Some(c);
+    }
+    let code = u32::from_str_radix(text.strip_prefix('x')?, 16).ok()?;
+    char::from_u32(code)
+}
+
+// The character a string escape like `\n` stands for.
+fn unescape(escape: &str) -> char {
+    match escape.chars().nth(1) {
+        Some('n') => '\n',
+        Some('t') => '\t',
+        Some('r') => '\r',
+        Some('0') => '\0',
+        Some(c) => c,
+        None => '\\',
+    }
+}
+
+// Read every form in `lexemes`, as produced by the turkey lexer, into `ast`.
+pub fn read<I>(lexemes: I, ast: &mut Ast) -> Result<Vec<AstRef>, ParseError>
+where
+    I: IntoIterator<Item = Lexeme<Token>>,
+{
+    let mut parser = Parser::with_ast(std::mem::take(ast));
+
+    for lexeme in lexemes {
+        parser.put(lexeme);
+    }
+    parser.finish();
+
+    let forms = match parser.get_error() {
+        Some(&error) => Err(error),
+        None => Ok(parser.forms.drain(..).collect()),
+    };
+    *ast = parser.into_ast();
+
+    forms
+}
+
+// Lex and read every form in `source` into `ast`, with no infix operators or dispatch handlers.
+pub fn parse_str(source: &str, ast: &mut Ast) -> Result<Vec<AstRef>, ParseError> {
+    let mut run = lexer_def().run();
+    run.put_str(source);
+    run.finish();
+
+    let forms = read(std::iter::from_fn(|| run.get()), ast);
+
+    // The reader runs out of lexemes where the lexer fails, so an error it reports before then is
+    // real, but input ending inside a form is just the input having been cut short.
+    match (forms, run.get_error()) {
+        (Err(error), Some(lexer))
+            if !matches!(error, ParseError::UnexpectedEof { .. })
+                && error.span().start < lexer.position() =>
+        {
+            Err(error)
+        }
+        (_, Some(lexer)) => Err(ParseError::InvalidToken {
+            span: Span {
+                start: lexer.position(),
+                end: lexer.position(),
+            },
+        }),
+        (forms, None) => forms,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lang::compiler::Compiler;
+    use std::io::Cursor;
+
+    fn read_str(input: &str) -> Result<Vec<String>, ParseError> {
+        let mut lexemes = vec![];
+        Compiler::new().lex(Cursor::new(input), &mut lexemes);
+
+        let mut ast = Ast::new();
+        let forms = read(lexemes, &mut ast)?;
+
+        Ok(forms
+            .into_iter()
+            .map(|form| ast.display(form).to_string())
+            .collect())
+    }
+
+    #[test]
+    fn test_read() {
+        assert_eq!(read_str("").unwrap(), Vec::<String>::new());
+        assert_eq!(
+            read_str("(define (f x) ; comment\n  [+ x 1.5]) {a . b} x").unwrap(),
+            vec!["(define (f x) (+ x 1.5))", "(a . b)", "x"]
+        );
+        assert_eq!(
+            read_str("'a `(b ,c ,@d) '()").unwrap(),
+            vec![
+                "(quote a)",
+                "(quasiquote (b (unquote c) (unquote-splicing d)))",
+                "(quote ())"
+            ]
+        );
+        assert_eq!(
+            read_str("-12 +7 123456789012345678901234567890").unwrap(),
+            vec!["-12", "7", "123456789012345678901234567890"]
+        );
+    }
+
+    #[test]
+    fn test_read_string() {
+        assert_eq!(
+            read_str(r#""a\tb\"c\$" """#).unwrap(),
+            vec![r#""a\tb\"c\$""#, r#""""#]
+        );
+        assert_eq!(
+            read_str(r#""x = $(+ x 1)!" "$(f)""#).unwrap(),
+            vec![
+                r#"(string-append "x = " (+ x 1) "!")"#,
+                "(string-append (f))"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_read_errors() {
+        let span = |start, end| Span { start, end };
+
+        assert_eq!(
+            read_str("(a))"),
+            Err(ParseError::Unopened { close: span(3, 4) })
+        );
+        assert_eq!(
+            read_str("(a [b)"),
+            Err(ParseError::Mismatched {
+                open: span(3, 4),
+                close: span(5, 6)
+            })
+        );
+        assert_eq!(
+            read_str("(a (b"),
+            Err(ParseError::UnexpectedEof { open: span(3, 4) })
+        );
+        assert_eq!(
+            read_str("(a \"b"),
+            Err(ParseError::UnexpectedEof { open: span(3, 4) })
+        );
+        assert_eq!(
+            read_str("'"),
+            Err(ParseError::UnexpectedEof { open: span(0, 1) })
+        );
+        assert_eq!(
+            read_str("(a ')"),
+            Err(ParseError::MissingForm { quote: span(3, 4) })
+        );
+        for input in ["(. a)", "(a . b c)", "(a .)", "a . b", "(a . . b)"] {
+            assert!(
+                matches!(read_str(input), Err(ParseError::InvalidDot { .. })),
+                "{}",
+                input
+            );
+        }
+        assert_eq!(
+            read_str("1.+5"),
+            Err(ParseError::InvalidLiteral { span: span(0, 4) })
+        );
+        assert_eq!(
+            read_str("(a (b").unwrap_err().to_string(),
+            "input ended inside the form starting at 3"
+        );
+    }
+
+    #[test]
+    fn test_parser() {
+        let mut compiler = Compiler::new();
+        let mut parser = Parser::new();
+
+        let mut put_line = |parser: &mut Parser, line: &str| {
+            let mut lexemes = vec![];
+            compiler.lex(Cursor::new(line), &mut lexemes);
+            lexemes.into_iter().for_each(|lexeme| parser.put(lexeme));
+        };
+
+        // Each form is available as soon as it is complete.
+        put_line(&mut parser, "a (b");
+        assert!(parser.is_incomplete());
+        let a = parser.get().unwrap();
+        assert_eq!(parser.ast().display(a).to_string(), "a");
+        assert_eq!(parser.get(), None);
+
+        put_line(&mut parser, " c) '");
+        let b = parser.get().unwrap();
+        assert_eq!(parser.ast().display(b).to_string(), "(b c)");
+        assert!(parser.is_incomplete());
+
+        put_line(&mut parser, " d");
+        assert!(!parser.is_incomplete());
+        let d = parser.get().unwrap();
+        assert_eq!(parser.ast().display(d).to_string(), "(quote d)");
+
+        // An error is not incomplete input, and stops the parser until it is reset.
+        put_line(&mut parser, "(e]) f");
+        assert!(!parser.is_incomplete());
+        assert!(matches!(
+            parser.get_error(),
+            Some(ParseError::Mismatched { .. })
+        ));
+        assert_eq!(parser.get(), None);
+
+        parser.reset();
+        put_line(&mut parser, "(g");
+        parser.finish();
+        assert!(matches!(
+            parser.get_error(),
+            Some(ParseError::UnexpectedEof { .. })
+        ));
+
+        // Nodes from before the reset are still there.
+        let ast = parser.into_ast();
+        assert_eq!(ast.display(b).to_string(), "(b c)");
+    }
+
+    #[test]
+    fn test_is_complete() {
+        use Completeness::*;
+
+        assert_eq!(is_complete(""), Complete);
+        assert_eq!(is_complete("(+ 1 2) x ; (comment"), Complete);
+        assert_eq!(is_complete("(define (f x)"), Incomplete { depth: 1 });
+        assert_eq!(is_complete("(f [g"), Incomplete { depth: 2 });
+        assert_eq!(is_complete("'"), Incomplete { depth: 0 });
+        assert_eq!(is_complete("(f \"a)"), InString);
+        assert_eq!(is_complete("\"a\\"), InString);
+        assert_eq!(is_complete("\"$(f \"x"), InString);
+        assert_eq!(is_complete("\"$(f 1"), Incomplete { depth: 1 });
+        assert_eq!(
+            is_complete("(a #| b |#) #| (c #| d"),
+            InComment { depth: 2 }
+        );
+        assert_eq!(is_complete("#| a |# #;"), Incomplete { depth: 0 });
+        assert_eq!(
+            is_complete("(f))"),
+            Invalid(ParseError::Unopened {
+                close: Span { start: 3, end: 4 }
+            })
+        );
+        assert!(matches!(
+            is_complete("(f]"),
+            Invalid(ParseError::Mismatched { .. })
+        ));
+    }
+
+    #[test]
+    fn test_infix() {
+        let mut operators = Operators::new();
+        operators
+            .with_infix("+".to_string(), 1, 2)
+            .with_infix("-".to_string(), 1, 2)
+            .with_infix("*".to_string(), 3, 4)
+            .with_prefix("-".to_string(), 5);
+
+        let read_infix = |input: &str| {
+            let mut lexemes = vec![];
+            Compiler::new().lex(Cursor::new(input), &mut lexemes);
+
+            let mut parser = Parser::new();
+            parser.set_infix(operators.clone());
+            lexemes.into_iter().for_each(|lexeme| parser.put(lexeme));
+            parser.finish();
+
+            match parser.get_error() {
+                Some(&error) => Err(error),
+                None => {
+                    let forms = std::iter::from_fn(|| parser.get()).collect::<Vec<_>>();
+                    Ok(forms
+                        .into_iter()
+                        .map(|form| parser.ast().display(form).to_string())
+                        .collect::<Vec<_>>())
+                }
+            }
+        };
+
+        assert_eq!(
+            read_infix("(f {1 + 2 * x}) {- a - {b - c}} {(g y)} [a b]").unwrap(),
+            vec!["(f (+ 1 (* 2 x)))", "(- (- a) (- b c))", "(g y)", "(a b)"]
+        );
+        assert_eq!(
+            read_infix("{1 +}"),
+            Err(ParseError::InvalidInfix {
+                span: Span { start: 0, end: 5 }
+            })
+        );
+        assert!(matches!(
+            read_infix("{a . b}"),
+            Err(ParseError::InvalidDot { .. })
+        ));
+
+        // Without operators, braces are lists.
+        assert_eq!(read_str("{1 + 2}").unwrap(), vec!["(1 + 2)"]);
+    }
+
+    #[test]
+    fn test_dispatch() {
+        let mut parser = Parser::new();
+
+        // #v(a b) reads as (vector a b).
+        parser.add_dispatch('#', 'v', |dispatch| {
+            let items = dispatch.form()?;
+            let ast = dispatch.ast();
+            ast.get_pair(items)?;
+            let vector = ast.create_symbol("vector");
+            Some(ast.create_pair(vector, items))
+        });
+        parser.add_dispatch('#', 'x', |dispatch| {
+            let value = BigInt::parse_bytes(dispatch.text().as_bytes(), 16)?;
+            Some(dispatch.ast().create_integer(value))
+        });
+        // #qa reads as (quote a).
+        parser.add_dispatch('#', 'q', |dispatch| {
+            let source = format!("(quote {})", dispatch.text());
+            dispatch.read(&source).ok()?.pop()
+        });
+
+        let mut read_line = |line: &str| {
+            let mut lexemes = vec![];
+            Compiler::new().lex(Cursor::new(line), &mut lexemes);
+
+            parser.reset();
+            lexemes.into_iter().for_each(|lexeme| parser.put(lexeme));
+            parser.finish();
+
+            match parser.get_error() {
+                Some(&error) => Err(error),
+                None => {
+                    let forms = std::iter::from_fn(|| parser.get()).collect::<Vec<_>>();
+                    Ok(forms
+                        .into_iter()
+                        .map(|form| parser.ast().display(form).to_string())
+                        .collect::<Vec<_>>())
+                }
+            }
+        };
+
+        assert_eq!(
+            read_line("#v(1 #x1F) '#v (a) #qb #y").unwrap(),
+            vec!["(vector 1 31)", "(quote (vector a))", "(quote b)", "#y"]
+        );
+
+        let span = |start, end| Span { start, end };
+        assert_eq!(
+            read_line("#xZZ"),
+            Err(ParseError::InvalidDispatch { span: span(0, 4) })
+        );
+        assert_eq!(
+            read_line("#v a"),
+            Err(ParseError::InvalidDispatch { span: span(0, 2) })
+        );
+        assert_eq!(
+            read_line("(#v)"),
+            Err(ParseError::MissingForm { quote: span(1, 3) })
+        );
+        assert_eq!(
+            read_line("#v"),
+            Err(ParseError::UnexpectedEof { open: span(0, 2) })
+        );
+
+        // Forms a handler reads are parts of other forms, not roots.
+        let root = parser.ast().root().unwrap();
+        assert_eq!(
+            parser.ast().display(root).to_string(),
+            "((vector 1 31) (quote (vector a)) (quote b) #y)"
+        );
+    }
+
+    #[test]
+    fn test_dispatch_literals() {
+        let mut parser = Parser::new();
+
+        // #d2024-01-15 reads as (date 2024 1 15), and #s"hi" as the symbol named by the string.
+        parser
+            .with_dispatch('#', 'd', |dispatch| {
+                let parts = dispatch
+                    .text()
+                    .split('-')
+                    .map(|part| part.parse::<BigInt>().ok())
+                    .collect::<Option<Vec<_>>>()?;
+                if parts.len() != 3 {
+                    return None;
+                }
+                let ast = dispatch.ast();
+                let mut list = ast.create_nil();
+                for part in parts.into_iter().rev() {
+                    let part = ast.create_integer(part);
+                    list = ast.create_pair(part, list);
+                }
+                let date = ast.create_symbol("date");
+                Some(ast.create_pair(date, list))
+            })
+            .with_dispatch('#', 's', |dispatch| {
+                let form = dispatch.form()?;
+                let AstNode::String(text) = dispatch.ast().get(form).clone() else {
+                    return None;
+                };
+                Some(dispatch.ast().create_symbol(&text))
+            })
+            // Handlers reading their text see the other handlers.
+            .with_dispatch('#', 'q', |dispatch| {
+                let source = format!("(quote {})", dispatch.text());
+                dispatch.read(&source).ok()?.pop()
+            });
+
+        let read_line = |parser: &mut Parser, line: &str| {
+            let mut lexemes = vec![];
+            Compiler::new().lex(Cursor::new(line), &mut lexemes);
+
+            parser.reset();
+            lexemes.into_iter().for_each(|lexeme| parser.put(lexeme));
+            parser.finish();
+
+            match parser.get_error() {
+                Some(&error) => Err(error),
+                None => {
+                    let forms = std::iter::from_fn(|| parser.get()).collect::<Vec<_>>();
+                    Ok(forms
+                        .into_iter()
+                        .map(|form| parser.ast().display(form).to_string())
+                        .collect::<Vec<_>>())
+                }
+            }
+        };
+
+        assert_eq!(
+            read_line(&mut parser, "(at #d2024-01-15) #s\"hi\" #q#d2024-01-15").unwrap(),
+            vec!["(at (date 2024 1 15))", "hi", "(quote (date 2024 1 15))"]
+        );
+        assert_eq!(
+            read_line(&mut parser, "#d2024-01"),
+            Err(ParseError::InvalidDispatch {
+                span: Span { start: 0, end: 9 }
+            })
+        );
+
+        parser.remove_dispatch('#', 'd');
+        assert_eq!(
+            read_line(&mut parser, "#d2024-01-15").unwrap(),
+            vec!["#d2024-01-15"]
+        );
+    }
+
+    #[test]
+    fn test_labels() {
+        assert_eq!(
+            read_str("#0=(a . #0#) (#1=(b) #1#) (#0=c #0#) #x").unwrap(),
+            vec!["#0=(a . #0#)", "(#0=(b) #0#)", "(c c)", "#x"]
+        );
+        assert_eq!(
+            read_str("'#0=(x #0#) #12=#0=(y)").unwrap(),
+            vec!["(quote #0=(x #0#))", "(y)"]
+        );
+
+        // Labelled pairs are shared, not copied.
+        let mut lexemes = vec![];
+        Compiler::new().lex(Cursor::new("(#0=(b) #0#)"), &mut lexemes);
+        let mut ast = Ast::new();
+        let form = read(lexemes, &mut ast).unwrap()[0];
+        let (first, rest) = ast.get_pair(form).unwrap();
+        assert_eq!(ast.get_pair(rest).unwrap().0, first);
+
+        let span = |start, end| Span { start, end };
+        for (input, error) in [
+            ("#0#", span(0, 3)),
+            ("(#0=a #0=b)", span(6, 9)),
+            ("#0=#0#", span(0, 3)),
+            ("#0=a #0#", span(5, 8)),
+        ] {
+            assert_eq!(
+                read_str(input),
+                Err(ParseError::InvalidLabel { span: error }),
+                "{}",
+                input
+            );
+        }
+        assert_eq!(
+            read_str("(#0=)"),
+            Err(ParseError::MissingForm { quote: span(1, 4) })
+        );
+    }
+
+    #[test]
+    fn test_comments() {
+        assert_eq!(
+            read_str("(a #;b c) #;(d e) f #; #; g h i").unwrap(),
+            vec!["(a c)", "f", "i"]
+        );
+        assert_eq!(
+            read_str("(a #| b #| (c |# d |# e) '#;x y").unwrap(),
+            vec!["(a e)", "(quote y)"]
+        );
+        assert_eq!(read_str("#|#||#|#").unwrap(), Vec::<String>::new());
+
+        let span = |start, end| Span { start, end };
+        assert_eq!(
+            read_str("(a #;)"),
+            Err(ParseError::MissingForm { quote: span(3, 5) })
+        );
+        assert_eq!(
+            read_str("a #| b #| c |#"),
+            Err(ParseError::UnexpectedEof { open: span(2, 4) })
+        );
+    }
+
+    #[test]
+    fn test_parse_str() {
+        let mut ast = Ast::new();
+        parse_str("0", &mut ast).unwrap();
+        let forms = parse_str("(f 'x) #| c |# \"s\" 1.5", &mut ast).unwrap();
+        assert_eq!(
+            ast.display(ast.root().unwrap()).to_string(),
+            "(0 (f (quote x)) \"s\" 1.5)"
+        );
+        let forms = forms
+            .into_iter()
+            .map(|form| ast.display(form).to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(forms, vec!["(f (quote x))", "\"s\"", "1.5"]);
+
+        let span = |start, end| Span { start, end };
+        assert_eq!(
+            parse_str("(a \"b\\", &mut ast),
+            Err(ParseError::InvalidToken { span: span(6, 6) })
+        );
+        assert_eq!(
+            parse_str("(a)) \"\\", &mut ast),
+            Err(ParseError::Unopened { close: span(3, 4) })
+        );
+        assert_eq!(
+            parse_str("a \"\\", &mut ast),
+            Err(ParseError::InvalidToken { span: span(4, 4) })
+        );
+    }
+
+    #[test]
+    fn test_syntax() {
+        let source = "(f x)\n  'y #| a\nb |# \"s$(g)\"";
+        let mut lexemes = vec![];
+        Compiler::new().lex(Cursor::new(source), &mut lexemes);
+
+        let mut parser = Parser::new();
+        parser.set_file(3);
+        lexemes.into_iter().for_each(|lexeme| parser.put(lexeme));
+        parser.finish();
+        let forms = std::iter::from_fn(|| parser.get()).collect::<Vec<_>>();
+        let ast = parser.into_ast();
+
+        assert_eq!(
+            ast.display(ast.root().unwrap()).to_string(),
+            "((f x) (quote y) (string-append \"s\" (g)))"
+        );
+
+        // Every node is located, with its file.
+        for id in 0..ast.len() as AstRef {
+            assert_eq!(ast.get_syntax(id).map(|syntax| syntax.file), Some(3));
+        }
+
+        let located = |id| {
+            let syntax = ast.get_syntax(id).unwrap();
+            (
+                &source[syntax.span.start..syntax.span.end],
+                syntax.line,
+                syntax.column,
+            )
+        };
+
+        let (f, rest) = ast.get_pair(forms[0]).unwrap();
+        let (x, nil) = ast.get_pair(rest).unwrap();
+        assert_eq!(located(forms[0]), ("(f x)", 1, 1));
+        assert_eq!(located(f), ("f", 1, 2));
+        assert_eq!(located(rest), ("x)", 1, 4));
+        assert_eq!(located(x), ("x", 1, 4));
+        assert_eq!(located(nil), (")", 1, 5));
+
+        let (quote, rest) = ast.get_pair(forms[1]).unwrap();
+        assert_eq!(located(forms[1]), ("'y", 2, 3));
+        assert_eq!(located(quote), ("'", 2, 3));
+        assert_eq!(located(ast.get_pair(rest).unwrap().0), ("y", 2, 4));
+
+        // Lines inside block comments count.
+        let (append, parts) = ast.get_pair(forms[2]).unwrap();
+        let (text, parts) = ast.get_pair(parts).unwrap();
+        assert_eq!(located(forms[2]), ("\"s$(g)\"", 3, 6));
+        assert_eq!(located(append), ("\"s$(g)\"", 3, 6));
+        assert_eq!(located(text), ("\"s", 3, 6));
+        assert_eq!(located(ast.get_pair(parts).unwrap().0), ("$(g)", 3, 8));
+    }
+
+    #[test]
+    fn test_read_literals() {
+        assert_eq!(
+            read_str("#t #true #f #false").unwrap(),
+            vec!["#t", "#t", "#f", "#f"]
+        );
+        assert_eq!(read_str("1/2 -6/4 4/2").unwrap(), vec!["1/2", "-3/2", "2"]);
+        assert_eq!(
+            read_str("#(1 (a b) #()) #u8(0 17 255) #u8()").unwrap(),
+            vec!["#(1 (a b) #())", "#u8(0 17 255)", "#u8()"]
+        );
+        assert_eq!(read_str("#0=#(a #0#)").unwrap(), vec!["#0=#(a #0#)"]);
+
+        let span = |start, end| Span { start, end };
+        assert_eq!(
+            read_str("1/0"),
+            Err(ParseError::InvalidLiteral { span: span(0, 3) })
+        );
+        assert_eq!(
+            read_str("#u8(1 256)"),
+            Err(ParseError::InvalidLiteral { span: span(6, 9) })
+        );
+        assert_eq!(
+            read_str("#u8(a)"),
+            Err(ParseError::InvalidLiteral { span: span(4, 5) })
+        );
+        assert_eq!(
+            read_str("#(a . b)"),
+            Err(ParseError::InvalidDot { dot: span(4, 5) })
+        );
+    }
+
+    #[test]
+    fn test_read_chars() {
+        assert_eq!(
+            read_str(r"#\a #\( #\  #\newline #\x41 #\x #\x7 (#\))").unwrap(),
+            vec![
+                r"#\a",
+                r"#\(",
+                r"#\space",
+                r"#\newline",
+                r"#\A",
+                r"#\x",
+                r"#\alarm",
+                r"(#\))"
+            ]
+        );
+        assert_eq!(
+            read_str(r"#\bogus"),
+            Err(ParseError::InvalidLiteral {
+                span: Span { start: 0, end: 7 }
+            })
+        );
+        assert_eq!(
+            read_str(r"#\xD800"),
+            Err(ParseError::InvalidLiteral {
+                span: Span { start: 0, end: 7 }
+            })
+        );
+    }
+}
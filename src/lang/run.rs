@@ -0,0 +1,183 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::lang::ast::Ast;
+use crate::lang::expand::{ExpandError, Expander};
+use crate::lang::interpreter::{EvalError, Interpreter};
+use crate::lang::reader::{parse_str, ParseError};
+use crate::lang::scope::{Resolver, ScopeError};
+use crate::lang::value::Value;
+use crate::lex::lexer::Span;
+use crate::lex::source::SourceMap;
+
+// Something wrong with a program, from whichever stage of running it found it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Diagnostic {
+    Parse(ParseError),
+    Expand(ExpandError),
+    Scope(ScopeError),
+    Eval(EvalError),
+}
+
+impl Diagnostic {
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            Diagnostic::Parse(error) => Some(error.span()),
+            Diagnostic::Expand(error) => error.span(),
+            Diagnostic::Scope(error) => error.span(),
+            Diagnostic::Eval(error) => error.span(),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Diagnostic::Parse(error) => write!(f, "{}", error),
+            Diagnostic::Expand(error) => write!(f, "{}", error),
+            Diagnostic::Scope(error) => write!(f, "{}", error),
+            Diagnostic::Eval(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl Error for Diagnostic {}
+
+// What stopped a program running: every diagnostic of the stage that failed, in the order found,
+// along with the source they're in, which `Display` quotes them from.
+#[derive(Debug, Clone)]
+pub struct Diagnostics {
+    source: SourceMap,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Diagnostics {
+    pub fn source(&self) -> &SourceMap {
+        &self.source
+    }
+
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+}
+
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, diagnostic) in self.diagnostics.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            match diagnostic.span() {
+                Some(span) => write!(f, "{}", self.source.report(span, diagnostic))?,
+                None => write!(f, "{}: {}", self.source.name(), diagnostic)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Error for Diagnostics {}
+
+// Read, expand, resolve and evaluate the program `source`, returning the value of its last form.
+// Nothing is built in but the special forms, so the program can only call procedures it defines.
+pub fn run_source(source: &str) -> Result<Value, Diagnostics> {
+    run_source_with(source, &mut Interpreter::new())
+}
+
+// `run_source` with `interpreter`, e.g. one given builtins, whose globals the program's variables
+// are resolved against, and which keeps what the program defines.
+pub fn run_source_with(source: &str, interpreter: &mut Interpreter) -> Result<Value, Diagnostics> {
+    let fail = |diagnostics| Diagnostics {
+        source: SourceMap::new("<source>", source),
+        diagnostics,
+    };
+
+    let mut ast = Ast::new();
+    parse_str(source, &mut ast).map_err(|error| fail(vec![Diagnostic::Parse(error)]))?;
+    let Some(root) = ast.root() else {
+        return Ok(Value::Unspecified);
+    };
+
+    let root = Expander::new()
+        .expand(&mut ast, root)
+        .map_err(|error| fail(vec![Diagnostic::Expand(error)]))?;
+
+    let mut resolver = Resolver::new();
+    for name in interpreter.globals() {
+        resolver.add_global(&name);
+    }
+    let scopes = resolver.resolve(&ast, root);
+    if !scopes.errors().is_empty() {
+        let errors = scopes.errors().iter().cloned().map(Diagnostic::Scope);
+        return Err(fail(errors.collect()));
+    }
+
+    interpreter
+        .run(&ast, root)
+        .map_err(|error| fail(vec![Diagnostic::Eval(error)]))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn span(start: usize, end: usize) -> Option<Span> {
+        Some(Span { start, end })
+    }
+
+    #[test]
+    fn test_run_source() {
+        let value = run_source(
+            "(define-syntax unless* (syntax-rules () ((_ c x) (if c #f x))))
+            (define (pick x) (unless* x 'no))
+            (pick #f)",
+        );
+        assert_eq!(value.unwrap().to_string(), "no");
+        assert_eq!(run_source("").unwrap().to_string(), "#<unspecified>");
+
+        // Builtins given to the interpreter are known to the resolver, and definitions are kept.
+        let mut interpreter = Interpreter::new();
+        interpreter.add_builtin("list", |args| Ok(Value::list(args.to_vec())));
+        run_source_with("(define x 1)", &mut interpreter).unwrap();
+        let value = run_source_with("(list x 'a)", &mut interpreter);
+        assert_eq!(value.unwrap().to_string(), "(1 a)");
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let spans = |source: &str| {
+            let diagnostics = run_source(source).unwrap_err();
+            diagnostics
+                .diagnostics()
+                .iter()
+                .map(Diagnostic::span)
+                .collect::<Vec<_>>()
+        };
+
+        let diagnostics = run_source("(a").unwrap_err();
+        assert_eq!(
+            diagnostics.diagnostics(),
+            &[Diagnostic::Parse(ParseError::UnexpectedEof {
+                open: Span { start: 0, end: 1 }
+            })]
+        );
+        assert_eq!(
+            diagnostics.to_string(),
+            "<source>:1:1: input ended inside the form starting at 0\n1 | (a\n  | ^"
+        );
+
+        // Every variable that can't be resolved is reported, before anything runs.
+        assert_eq!(
+            spans("(define (f) x)\n(g)"),
+            vec![span(12, 13), span(16, 17)]
+        );
+        assert_eq!(spans("(define-syntax m (rules))"), vec![span(17, 24)]);
+        assert_eq!(spans("((lambda (x) x))"), vec![span(0, 16)]);
+
+        let diagnostics = run_source("((lambda (x) x))").unwrap_err();
+        assert!(matches!(
+            diagnostics.diagnostics(),
+            [Diagnostic::Eval(EvalError::Arity { given: 0, .. })]
+        ));
+    }
+}
@@ -0,0 +1,657 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+
+use crate::lang::ast::{Ast, AstNode, AstRef};
+use crate::lang::pass::Pass;
+use crate::lang::symbol::SymbolTable;
+use crate::lex::lexer::Span;
+
+// Numbers each binding of a name, so that two variables with the same name can be told apart.
+pub type BinderId = usize;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Binder {
+    pub name: Arc<str>,
+    // The symbol that binds it, or `None` for a global the resolver was given.
+    pub node: Option<AstRef>,
+    // Bound at the top level, or given to the resolver, rather than by a local form.
+    pub global: bool,
+}
+
+// Spans are `None` for nodes without `SyntaxInfo`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScopeError {
+    // A symbol referring to a variable that isn't bound where it is.
+    Unbound {
+        name: Arc<str>,
+        node: AstRef,
+        span: Option<Span>,
+    },
+    // A form of the wrong shape, like `(let (x) x)`, `(lambda (1) x)` or the dotted `(f . x)`.
+    Malformed {
+        node: AstRef,
+        span: Option<Span>,
+    },
+}
+
+impl ScopeError {
+    pub fn span(&self) -> Option<Span> {
+        match *self {
+            ScopeError::Unbound { span, .. } => span,
+            ScopeError::Malformed { span, .. } => span,
+        }
+    }
+}
+
+impl fmt::Display for ScopeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScopeError::Unbound { name, .. } => write!(f, "unbound variable {}", name)?,
+            ScopeError::Malformed { .. } => write!(f, "malformed form")?,
+        }
+        match self.span() {
+            Some(span) => write!(f, " at {}", span.start),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Error for ScopeError {}
+
+// What resolving a program found: a binder for each variable bound, which binder each symbol
+// binding or referring to a variable is for, and any errors.
+#[derive(Debug, Clone, Default)]
+pub struct Scopes {
+    binders: Vec<Binder>,
+    symbols: HashMap<AstRef, BinderId>,
+    errors: Vec<ScopeError>,
+}
+
+impl Scopes {
+    pub fn binders(&self) -> &[Binder] {
+        &self.binders
+    }
+
+    pub fn binder(&self, id: BinderId) -> &Binder {
+        &self.binders[id]
+    }
+
+    // The binder the symbol `node` binds or refers to, if it's a variable that is bound.
+    pub fn resolve(&self, node: AstRef) -> Option<BinderId> {
+        self.symbols.get(&node).copied()
+    }
+
+    pub fn errors(&self) -> &[ScopeError] {
+        &self.errors
+    }
+}
+
+// Resolves the variables of a program, in which `define`, `lambda`, `let`, `let*`, `letrec` and
+// `letrec*` bind variables, and quoted forms have none. A body's definitions are in scope
+// throughout it, so at the top level and in the body of a `lambda` a variable can be referred to
+// before its definition. Other special forms like `if` and `cond` are resolved as though they
+// were calls, except that their keywords aren't variables, unless they've been bound as one.
+#[derive(Debug, Clone, Default)]
+pub struct Resolver {
+    globals: Vec<Arc<str>>,
+}
+
+impl Resolver {
+    pub fn new() -> Resolver {
+        Self::default()
+    }
+
+    // A variable bound outside the program, like a builtin procedure.
+    pub fn add_global(&mut self, name: &str) {
+        let symbol = SymbolTable::global().intern(name);
+        self.globals.push(SymbolTable::global().resolve(symbol));
+    }
+
+    pub fn with_global(&mut self, name: &str) -> &mut Self {
+        self.add_global(name);
+        self
+    }
+
+    // Resolve the program whose top-level forms are the list `root`, like `Ast::root`.
+    pub fn resolve(&self, ast: &Ast, root: AstRef) -> Scopes {
+        let mut resolve = Resolve {
+            ast,
+            scopes: Scopes::default(),
+            frames: vec![HashMap::new()],
+        };
+        for name in &self.globals {
+            resolve.bind_global(name.clone());
+        }
+
+        if let Some(forms) = resolve.items(root) {
+            resolve.body(&forms);
+        }
+        resolve.scopes
+    }
+}
+
+// Resolves the program as a pass, which fails with the first error found. Later passes can get
+// what it found from the `PassManager`.
+#[derive(Debug, Clone, Default)]
+pub struct ScopePass {
+    resolver: Resolver,
+    scopes: Scopes,
+}
+
+impl ScopePass {
+    pub fn new(resolver: Resolver) -> ScopePass {
+        ScopePass {
+            resolver,
+            scopes: Scopes::default(),
+        }
+    }
+
+    // What the last run found.
+    pub fn scopes(&self) -> &Scopes {
+        &self.scopes
+    }
+}
+
+impl Pass for ScopePass {
+    fn name(&self) -> &str {
+        "scope"
+    }
+
+    fn run(&mut self, ast: &mut Ast, root: AstRef) -> Result<AstRef, Box<dyn Error + Send + Sync>> {
+        self.scopes = self.resolver.resolve(ast, root);
+        match self.scopes.errors.first() {
+            Some(error) => Err(Box::new(error.clone())),
+            None => Ok(root),
+        }
+    }
+}
+
+const KEYWORDS: &[&str] = &[
+    "quote",
+    "quasiquote",
+    "unquote",
+    "unquote-splicing",
+    "define",
+    "lambda",
+    "let",
+    "let*",
+    "letrec",
+    "letrec*",
+    "set!",
+    "if",
+    "begin",
+    "cond",
+    "case",
+    "and",
+    "or",
+    "when",
+    "unless",
+    "else",
+    "=>",
+];
+
+struct Resolve<'a> {
+    ast: &'a Ast,
+    scopes: Scopes,
+    // The variables bound by each scope open, by symbol, outermost first.
+    frames: Vec<HashMap<u64, BinderId>>,
+}
+
+impl Resolve<'_> {
+    fn error_malformed(&mut self, node: AstRef) {
+        let span = self.ast.get_syntax(node).map(|syntax| syntax.span);
+        self.scopes
+            .errors
+            .push(ScopeError::Malformed { node, span });
+    }
+
+    fn bind_global(&mut self, name: Arc<str>) {
+        let symbol = SymbolTable::global().intern(&name);
+        let id = self.scopes.binders.len();
+        self.scopes.binders.push(Binder {
+            name,
+            node: None,
+            global: true,
+        });
+        self.frames[0].insert(symbol, id);
+    }
+
+    // Bind the symbol `node` in the innermost scope.
+    fn bind(&mut self, node: AstRef) {
+        let Some(symbol) = self.ast.get_symbol_id(node) else {
+            self.error_malformed(node);
+            return;
+        };
+
+        let id = self.scopes.binders.len();
+        self.scopes.binders.push(Binder {
+            name: SymbolTable::global().resolve(symbol),
+            node: Some(node),
+            global: self.frames.len() == 1,
+        });
+        self.frames.last_mut().unwrap().insert(symbol, id);
+        self.scopes.symbols.insert(node, id);
+    }
+
+    // A symbol a macro introduced that isn't bound where it's used refers to the global it was
+    // made from.
+    fn lookup(&self, symbol: u64) -> Option<BinderId> {
+        self.frames
+            .iter()
+            .rev()
+            .find_map(|frame| frame.get(&symbol).copied())
+            .or_else(|| {
+                let origin = SymbolTable::global().origin(symbol);
+                (origin != symbol).then(|| self.frames[0].get(&origin).copied())?
+            })
+    }
+
+    fn reference(&mut self, node: AstRef) {
+        let symbol = self.ast.get_symbol_id(node).unwrap();
+        match self.lookup(symbol) {
+            Some(id) => {
+                self.scopes.symbols.insert(node, id);
+            }
+            None => {
+                let span = self.ast.get_syntax(node).map(|syntax| syntax.span);
+                self.scopes.errors.push(ScopeError::Unbound {
+                    name: SymbolTable::global().resolve(symbol),
+                    node,
+                    span,
+                });
+            }
+        }
+    }
+
+    // The items of the proper list `id`, or `None` after reporting it if it isn't one.
+    fn items(&mut self, id: AstRef) -> Option<Vec<AstRef>> {
+        match self.ast.is_list(id) {
+            true => Some(self.ast.list_iter(id).collect()),
+            false => {
+                self.error_malformed(id);
+                None
+            }
+        }
+    }
+
+    // The symbol a `define` defines, whether `(define name value)` or `(define (name ..) ..)`.
+    fn defined(&self, form: AstRef) -> Option<AstRef> {
+        let mut items = self.ast.list_iter(form);
+        let head = items.next()?;
+        if self.ast.get_symbol(head).as_deref() != Some("define")
+            || self.lookup_symbol(head).is_some()
+        {
+            return None;
+        }
+
+        let target = items.next()?;
+        let name = match self.ast.get_pair(target) {
+            Some((name, _)) => name,
+            None => target,
+        };
+        self.ast.get_symbol_id(name).map(|_| name)
+    }
+
+    fn lookup_symbol(&self, node: AstRef) -> Option<BinderId> {
+        self.lookup(self.ast.get_symbol_id(node)?)
+    }
+
+    // Forms in which definitions are in scope throughout, binding them in the innermost scope.
+    fn body(&mut self, forms: &[AstRef]) {
+        for &form in forms {
+            if let Some(name) = self.defined(form) {
+                let symbol = self.ast.get_symbol_id(name).unwrap();
+                match self.frames.last().unwrap().get(&symbol) {
+                    // A redefinition is of the same variable.
+                    Some(&id) => {
+                        self.scopes.symbols.insert(name, id);
+                    }
+                    None => self.bind(name),
+                }
+            }
+        }
+        for &form in forms {
+            self.form(form);
+        }
+    }
+
+    // The body of a binding form, which is a scope of its own.
+    fn local_body(&mut self, forms: &[AstRef]) {
+        self.frames.push(HashMap::new());
+        self.body(forms);
+        self.frames.pop();
+    }
+
+    fn form(&mut self, id: AstRef) {
+        match self.ast.get(id) {
+            AstNode::Symbol(_) => self.reference(id),
+            AstNode::Pair(head, _) => {
+                let head = *head;
+                let keyword = match self.ast.get_symbol(head) {
+                    Some(name) if self.lookup_symbol(head).is_none() => {
+                        KEYWORDS.iter().find(|&&keyword| *keyword == *name).copied()
+                    }
+                    _ => None,
+                };
+
+                let Some(items) = self.items(id) else {
+                    return;
+                };
+                match keyword {
+                    Some("quote") => {}
+                    Some("quasiquote") => self.quasiquote(&items[1..], 1),
+                    Some("define") => self.define(id, &items),
+                    Some("lambda") => match items.get(1) {
+                        Some(&params) => self.lambda(params, &items[2..]),
+                        None => self.error_malformed(id),
+                    },
+                    Some("let") => self.let_(id, &items),
+                    Some("let*") => self.let_star(id, &items),
+                    Some("letrec" | "letrec*") => self.letrec(id, &items),
+                    Some("cond" | "case") => {
+                        for &clause in &items[1..] {
+                            match self.items(clause) {
+                                Some(clause) => self.forms(&clause),
+                                None => return,
+                            }
+                        }
+                    }
+                    Some(_) => self.forms(&items[1..]),
+                    None => self.forms(&items),
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Forms resolved one after another, other than `else` and `=>` in clauses.
+    fn forms(&mut self, forms: &[AstRef]) {
+        for &form in forms {
+            match self.ast.get_symbol(form).as_deref() {
+                Some("else" | "=>") if self.lookup_symbol(form).is_none() => {}
+                _ => self.form(form),
+            }
+        }
+    }
+
+    // Only the `unquote`d parts of a quasiquoted form have variables, and only at the same depth.
+    fn quasiquote(&mut self, forms: &[AstRef], depth: usize) {
+        for &form in forms {
+            let Some((head, _)) = self.ast.get_pair(form) else {
+                continue;
+            };
+            if !self.ast.is_list(form) {
+                continue;
+            }
+
+            let items = self.ast.list_iter(form).collect::<Vec<_>>();
+            match self.ast.get_symbol(head).as_deref() {
+                Some("unquote" | "unquote-splicing") if depth == 1 => self.forms(&items[1..]),
+                Some("unquote" | "unquote-splicing") => self.quasiquote(&items[1..], depth - 1),
+                Some("quasiquote") => self.quasiquote(&items[1..], depth + 1),
+                _ => self.quasiquote(&items, depth),
+            }
+        }
+    }
+
+    fn define(&mut self, id: AstRef, items: &[AstRef]) {
+        let Some(&target) = items.get(1) else {
+            self.error_malformed(id);
+            return;
+        };
+
+        let (name, params) = match self.ast.get_pair(target) {
+            Some((name, params)) => (name, Some(params)),
+            None => (target, None),
+        };
+        if self.ast.get_symbol_id(name).is_none() {
+            self.error_malformed(id);
+            return;
+        }
+        // A define outside a body binds where it is.
+        if self.scopes.resolve(name).is_none() {
+            self.bind(name);
+        }
+
+        match params {
+            Some(params) => self.lambda(params, &items[2..]),
+            None if items.len() == 3 => self.form(items[2]),
+            None => self.error_malformed(id),
+        }
+    }
+
+    fn lambda(&mut self, params: AstRef, body: &[AstRef]) {
+        self.frames.push(HashMap::new());
+
+        let mut params = self.ast.list_iter(params);
+        for param in params.by_ref() {
+            self.bind(param);
+        }
+        // A dotted or symbol parameter list binds the rest of the arguments.
+        let rest = params.tail();
+        if *self.ast.get(rest) != AstNode::Nil {
+            self.bind(rest);
+        }
+
+        self.local_body(body);
+        self.frames.pop();
+    }
+
+    // The variables and values of the bindings of a `let`, reporting any that aren't `(x v)`.
+    fn bindings(&mut self, bindings: AstRef) -> Vec<(AstRef, AstRef)> {
+        let Some(bindings) = self.items(bindings) else {
+            return vec![];
+        };
+
+        let mut pairs = vec![];
+        for binding in bindings {
+            match self.items(binding).as_deref() {
+                Some(&[name, value]) if self.ast.get_symbol_id(name).is_some() => {
+                    pairs.push((name, value))
+                }
+                Some(_) => self.error_malformed(binding),
+                None => {}
+            }
+        }
+        pairs
+    }
+
+    fn let_(&mut self, id: AstRef, items: &[AstRef]) {
+        // `(let name ((x v) ..) body ..)` also binds `name` to a procedure of the variables.
+        let (name, rest) = match items.get(1) {
+            Some(&name) if self.ast.get_symbol_id(name).is_some() => (Some(name), &items[2..]),
+            _ => (None, &items[1..]),
+        };
+        let Some((&bindings, body)) = rest.split_first() else {
+            self.error_malformed(id);
+            return;
+        };
+
+        let bindings = self.bindings(bindings);
+        for &(_, value) in &bindings {
+            self.form(value);
+        }
+
+        if let Some(name) = name {
+            self.frames.push(HashMap::new());
+            self.bind(name);
+        }
+        self.frames.push(HashMap::new());
+        for (variable, _) in bindings {
+            self.bind(variable);
+        }
+        self.local_body(body);
+        self.frames.pop();
+        if name.is_some() {
+            self.frames.pop();
+        }
+    }
+
+    fn let_star(&mut self, id: AstRef, items: &[AstRef]) {
+        let Some(&bindings) = items.get(1) else {
+            self.error_malformed(id);
+            return;
+        };
+
+        // Each value is in the scope of the variables before it.
+        let bindings = self.bindings(bindings);
+        let depth = self.frames.len();
+        for (variable, value) in bindings {
+            self.form(value);
+            self.frames.push(HashMap::new());
+            self.bind(variable);
+        }
+        self.local_body(&items[2..]);
+        self.frames.truncate(depth);
+    }
+
+    fn letrec(&mut self, id: AstRef, items: &[AstRef]) {
+        let Some(&bindings) = items.get(1) else {
+            self.error_malformed(id);
+            return;
+        };
+
+        // Each value is in the scope of every variable.
+        let bindings = self.bindings(bindings);
+        self.frames.push(HashMap::new());
+        for &(variable, _) in &bindings {
+            self.bind(variable);
+        }
+        for (_, value) in bindings {
+            self.form(value);
+        }
+        self.local_body(&items[2..]);
+        self.frames.pop();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lang::pass::PassManager;
+    use crate::lang::reader::parse_str;
+    use crate::lang::visit::AstVisitor;
+
+    // Writes each symbol with the binder it resolves to, if any.
+    struct Resolved<'a>(&'a Scopes, Vec<String>);
+
+    impl AstVisitor for Resolved<'_> {
+        fn visit_symbol(&mut self, _ast: &Ast, id: AstRef, name: &str) {
+            match self.0.resolve(id) {
+                Some(binder) => self.1.push(format!("{}{}", name, binder)),
+                None => self.1.push(name.to_string()),
+            }
+        }
+    }
+
+    fn resolve(source: &str) -> (String, Vec<ScopeError>) {
+        let mut ast = Ast::new();
+        parse_str(source, &mut ast).unwrap();
+        let root = ast.root().unwrap();
+
+        let mut resolver = Resolver::new();
+        resolver.with_global("+").with_global("display");
+        let scopes = resolver.resolve(&ast, root);
+
+        let mut resolved = Resolved(&scopes, vec![]);
+        ast.walk(root, &mut resolved);
+        (resolved.1.join(" "), scopes.errors().to_vec())
+    }
+
+    #[test]
+    fn test_resolve() {
+        // Definitions are in scope throughout their body, and parameters in the lambda's.
+        assert_eq!(
+            resolve("(define (f x) (+ x y)) (define y 1) (f y)"),
+            ("define f2 x4 +0 x4 y3 define y3 f2 y3".to_string(), vec![])
+        );
+
+        // Inner bindings shadow outer ones, but not in the values of a `let`.
+        assert_eq!(
+            resolve("(let ((x 1)) (let ((x x)) x))").0,
+            "let x2 let x3 x2 x3"
+        );
+        assert_eq!(resolve("(let* ((x 1) (x x)) x)").0, "let* x2 x3 x2 x3");
+        assert_eq!(
+            resolve("(letrec ((even? (lambda (n) (odd? n))) (odd? even?)) odd?)").0,
+            "letrec even?2 lambda n4 odd?3 n4 odd?3 even?2 odd?3"
+        );
+        assert_eq!(
+            resolve("(let loop ((i 0)) (loop i))").0,
+            "let loop2 i3 loop2 i3"
+        );
+        assert_eq!(
+            resolve("(lambda (a . rest) (define a rest) a)").0,
+            "lambda a2 rest3 define a4 rest3 a4"
+        );
+
+        // Keywords are only keywords where they aren't bound.
+        assert_eq!(
+            resolve("(define (if x) x) (if 1) (cond (else 2))").0,
+            "define if2 x3 x3 if2 cond else"
+        );
+
+        // Quoted forms have no variables, except where unquoted.
+        assert_eq!(
+            resolve("'(a b) `(a ,display `(b ,c))").0,
+            "quote a b quasiquote a unquote display1 quasiquote b unquote c"
+        );
+    }
+
+    #[test]
+    fn test_resolve_errors() {
+        let span = |start, end| Some(Span { start, end });
+
+        let (_, errors) = resolve("(lambda (x) (g x y))");
+        let names = errors
+            .iter()
+            .map(|error| match error {
+                ScopeError::Unbound { name, span, .. } => (&**name, *span),
+                _ => unreachable!(),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec![("g", span(13, 14)), ("y", span(17, 18))]);
+        assert_eq!(errors[0].to_string(), "unbound variable g at 13");
+
+        let (_, errors) = resolve("(let (x) x) (lambda (1) 1) (+ . 1)");
+        let spans = errors.iter().map(|e| e.span()).collect::<Vec<_>>();
+        // The `x` of the malformed binding isn't bound.
+        assert_eq!(
+            spans,
+            vec![span(6, 7), span(9, 10), span(21, 22), span(27, 34)]
+        );
+        assert!(matches!(errors[0], ScopeError::Malformed { .. }));
+        assert!(matches!(&errors[1], ScopeError::Unbound { name, .. } if &**name == "x"));
+    }
+
+    #[test]
+    fn test_scope_pass() {
+        let mut ast = Ast::new();
+        parse_str("(define x 1) (display x)", &mut ast).unwrap();
+        let root = ast.root().unwrap();
+
+        let mut resolver = Resolver::new();
+        resolver.add_global("display");
+        let mut passes = PassManager::new();
+        passes.add_pass(ScopePass::new(resolver.clone()));
+        assert_eq!(passes.run(&mut ast, root).unwrap(), root);
+
+        let scopes = passes.get_pass::<ScopePass>().unwrap().scopes();
+        let names = scopes
+            .binders()
+            .iter()
+            .map(|b| &*b.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["display", "x"]);
+        assert!(scopes.binders().iter().all(|b| b.global));
+
+        parse_str("(display y)", &mut ast).unwrap();
+        let error = passes.run(&mut ast, root).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "pass scope failed: unbound variable y at 9"
+        );
+    }
+}
@@ -0,0 +1,551 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use num::{BigInt, BigRational, Zero};
+
+use crate::lang::ast::{Ast, AstNode, AstRef, SyntaxInfo};
+use crate::lang::symbol::SymbolTable;
+use crate::lex::lexer::Span;
+
+// A serialized `Ast` starts with these, then the version of the format it was written in.
+const MAGIC: &[u8; 4] = b"TAST";
+const VERSION: u64 = 1;
+
+// What each slot holds, written before its contents.
+const FREE: u8 = 0;
+const NIL: u8 = 1;
+const PAIR: u8 = 2;
+const SYMBOL: u8 = 3;
+const FALSE: u8 = 4;
+const TRUE: u8 = 5;
+const INTEGER: u8 = 6;
+const RATIONAL: u8 = 7;
+const FLOAT: u8 = 8;
+const STRING: u8 = 9;
+const CHAR: u8 = 10;
+const VECTOR: u8 = 11;
+const BYTES: u8 = 12;
+
+// What each symbol is, written before its name, or for one that isn't interned, the symbol it was
+// made from.
+const INTERNED: u8 = 0;
+const GENSYM: u8 = 1;
+const ALIAS: u8 = 2;
+
+// Offsets are of bytes in the serialized `Ast`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeserializeError {
+    // The bytes don't start with the magic number, so aren't a serialized `Ast`.
+    NotAst,
+    // Written in a version of the format this one can't read.
+    Version { version: u64 },
+    // The bytes at `offset` aren't what should be there, e.g. a reference to a node that doesn't
+    // exist.
+    Invalid { offset: usize },
+    // The bytes ended part way through whatever was at `offset`.
+    Truncated { offset: usize },
+}
+
+impl fmt::Display for DeserializeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeserializeError::NotAst => write!(f, "not a serialized Ast"),
+            DeserializeError::Version { version } => {
+                write!(f, "unsupported Ast format version {}", version)
+            }
+            DeserializeError::Invalid { offset } => {
+                write!(f, "invalid Ast data at byte {}", offset)
+            }
+            DeserializeError::Truncated { offset } => {
+                write!(f, "incomplete Ast data at byte {}", offset)
+            }
+        }
+    }
+}
+
+impl Error for DeserializeError {}
+
+impl From<DeserializeError> for io::Error {
+    fn from(error: DeserializeError) -> io::Error {
+        let kind = match error {
+            DeserializeError::Truncated { .. } => io::ErrorKind::UnexpectedEof,
+            _ => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, error)
+    }
+}
+
+// Numbers are written as LEB128, so small ones, which most are, take a byte.
+fn write_varint(bytes: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+fn write_len(bytes: &mut Vec<u8>, data: &[u8]) {
+    write_varint(bytes, data.len() as u64);
+    bytes.extend_from_slice(data);
+}
+
+fn write_integer(bytes: &mut Vec<u8>, value: &BigInt) {
+    write_len(bytes, &value.to_signed_bytes_le());
+}
+
+struct Cursor<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl Cursor<'_> {
+    fn byte(&mut self) -> Result<u8, DeserializeError> {
+        let &byte = self
+            .bytes
+            .get(self.offset)
+            .ok_or(DeserializeError::Truncated {
+                offset: self.offset,
+            })?;
+        self.offset += 1;
+        Ok(byte)
+    }
+
+    fn varint(&mut self) -> Result<u64, DeserializeError> {
+        let start = self.offset;
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            let bits = (byte & 0x7f) as u64;
+            if bits << shift >> shift != bits {
+                return Err(DeserializeError::Invalid { offset: start });
+            }
+            value |= bits << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(DeserializeError::Invalid { offset: start })
+    }
+
+    fn usize(&mut self) -> Result<usize, DeserializeError> {
+        let start = self.offset;
+        usize::try_from(self.varint()?).map_err(|_| DeserializeError::Invalid { offset: start })
+    }
+
+    fn slice(&mut self) -> Result<&[u8], DeserializeError> {
+        let start = self.offset;
+        let len = self.usize()?;
+        match self.bytes.len().checked_sub(self.offset) {
+            Some(left) if left >= len => {
+                self.offset += len;
+                Ok(&self.bytes[self.offset - len..self.offset])
+            }
+            _ => Err(DeserializeError::Truncated { offset: start }),
+        }
+    }
+
+    fn string(&mut self) -> Result<&str, DeserializeError> {
+        let start = self.offset;
+        std::str::from_utf8(self.slice()?).map_err(|_| DeserializeError::Invalid { offset: start })
+    }
+
+    fn integer(&mut self) -> Result<BigInt, DeserializeError> {
+        Ok(BigInt::from_signed_bytes_le(self.slice()?))
+    }
+}
+
+impl Ast {
+    // Write the `Ast` in a compact binary form, e.g. to cache a program after it's been expanded.
+    // `deserialize` reads it back with every node at the same `AstRef`, so shared nodes and
+    // cycles are kept, as are syntax, free slots and the root list, but not origins.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        write_varint(&mut bytes, VERSION);
+
+        // Symbols are interned per process, so are written by name, each once. Those that aren't
+        // interned are made again when read, after the symbols they were made from, so they stay
+        // apart from every other.
+        let table = SymbolTable::global();
+        let mut symbols = HashMap::new();
+        let mut entries = vec![];
+        for node in self.nodes.iter().flatten() {
+            if let &AstNode::Symbol(symbol) = node {
+                let origin = table.origin(symbol);
+                for symbol in [origin, symbol] {
+                    symbols.entry(symbol).or_insert_with(|| {
+                        entries.push(symbol);
+                        entries.len() - 1
+                    });
+                }
+            }
+        }
+        write_varint(&mut bytes, entries.len() as u64);
+        for symbol in entries {
+            let name = table.resolve(symbol);
+            let origin = table.origin(symbol);
+            if origin == symbol {
+                bytes.push(INTERNED);
+                write_len(&mut bytes, name.as_bytes());
+                continue;
+            }
+            // An alias has the name of its origin, which a gensym never does.
+            match name == table.resolve(origin) {
+                true => bytes.push(ALIAS),
+                false => bytes.push(GENSYM),
+            }
+            write_varint(&mut bytes, symbols[&origin] as u64);
+        }
+
+        write_varint(&mut bytes, self.nodes.len() as u64);
+        for (node, syntax) in self.nodes.iter().zip(&self.syntax) {
+            let Some(node) = node else {
+                bytes.push(FREE);
+                continue;
+            };
+
+            match node {
+                AstNode::Nil => bytes.push(NIL),
+                &AstNode::Pair(head, tail) => {
+                    bytes.push(PAIR);
+                    write_varint(&mut bytes, head);
+                    write_varint(&mut bytes, tail);
+                }
+                AstNode::Symbol(symbol) => {
+                    bytes.push(SYMBOL);
+                    write_varint(&mut bytes, symbols[symbol] as u64);
+                }
+                AstNode::Bool(false) => bytes.push(FALSE),
+                AstNode::Bool(true) => bytes.push(TRUE),
+                AstNode::Integer(value) => {
+                    bytes.push(INTEGER);
+                    write_integer(&mut bytes, value);
+                }
+                AstNode::Rational(value) => {
+                    bytes.push(RATIONAL);
+                    write_integer(&mut bytes, value.numer());
+                    write_integer(&mut bytes, value.denom());
+                }
+                AstNode::Float(value) => {
+                    bytes.push(FLOAT);
+                    bytes.extend_from_slice(&value.to_le_bytes());
+                }
+                AstNode::String(value) => {
+                    bytes.push(STRING);
+                    write_len(&mut bytes, value.as_bytes());
+                }
+                &AstNode::Char(value) => {
+                    bytes.push(CHAR);
+                    write_varint(&mut bytes, value as u64);
+                }
+                AstNode::Vector(elements) => {
+                    bytes.push(VECTOR);
+                    write_varint(&mut bytes, elements.len() as u64);
+                    for &element in elements {
+                        write_varint(&mut bytes, element);
+                    }
+                }
+                AstNode::Bytes(value) => {
+                    bytes.push(BYTES);
+                    write_len(&mut bytes, value);
+                }
+            }
+
+            match syntax {
+                Some(syntax) => {
+                    bytes.push(1);
+                    for value in [
+                        syntax.file as usize,
+                        syntax.span.start,
+                        syntax.span.end,
+                        syntax.line,
+                        syntax.column,
+                    ] {
+                        write_varint(&mut bytes, value as u64);
+                    }
+                }
+                None => bytes.push(0),
+            }
+        }
+
+        write_varint(&mut bytes, self.free.len() as u64);
+        for &id in &self.free {
+            write_varint(&mut bytes, id);
+        }
+        write_varint(&mut bytes, self.root.map_or(0, |root| root + 1));
+        bytes
+    }
+
+    pub fn write_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_all(&self.serialize())
+    }
+
+    // Read an `Ast` written by `serialize`.
+    pub fn deserialize(bytes: &[u8]) -> Result<Ast, DeserializeError> {
+        if !bytes.starts_with(MAGIC) {
+            return Err(DeserializeError::NotAst);
+        }
+        let mut cursor = Cursor {
+            bytes,
+            offset: MAGIC.len(),
+        };
+        let version = cursor.varint()?;
+        if version != VERSION {
+            return Err(DeserializeError::Version { version });
+        }
+
+        let table = SymbolTable::global();
+        let mut symbols: Vec<u64> = vec![];
+        for _ in 0..cursor.usize()? {
+            let start = cursor.offset;
+            let symbol = match cursor.byte()? {
+                INTERNED => table.intern(cursor.string()?),
+                kind @ (GENSYM | ALIAS) => {
+                    let origin = *symbols
+                        .get(cursor.usize()?)
+                        .ok_or(DeserializeError::Invalid { offset: start })?;
+                    match kind {
+                        GENSYM => table.gensym(origin),
+                        _ => table.alias(origin),
+                    }
+                }
+                _ => return Err(DeserializeError::Invalid { offset: start }),
+            };
+            symbols.push(symbol);
+        }
+
+        let mut ast = Ast::new();
+        // Where each node was written, and the nodes it refers to, which are checked once every
+        // node has been read.
+        let mut refs = vec![];
+        for _ in 0..cursor.usize()? {
+            let start = cursor.offset;
+            let invalid = DeserializeError::Invalid { offset: start };
+            let node = match cursor.byte()? {
+                FREE => {
+                    ast.nodes.push(None);
+                    ast.syntax.push(None);
+                    continue;
+                }
+                NIL => AstNode::Nil,
+                PAIR => {
+                    let (head, tail) = (cursor.varint()?, cursor.varint()?);
+                    refs.push((start, head));
+                    refs.push((start, tail));
+                    AstNode::Pair(head, tail)
+                }
+                SYMBOL => AstNode::Symbol(*symbols.get(cursor.usize()?).ok_or(invalid)?),
+                FALSE => AstNode::Bool(false),
+                TRUE => AstNode::Bool(true),
+                INTEGER => AstNode::Integer(cursor.integer()?),
+                RATIONAL => {
+                    let (numer, denom) = (cursor.integer()?, cursor.integer()?);
+                    if denom.is_zero() {
+                        return Err(invalid);
+                    }
+                    AstNode::Rational(BigRational::new(numer, denom))
+                }
+                FLOAT => {
+                    let mut value = [0; 8];
+                    for byte in &mut value {
+                        *byte = cursor.byte()?;
+                    }
+                    AstNode::Float(f64::from_le_bytes(value))
+                }
+                STRING => AstNode::String(cursor.string()?.to_string()),
+                CHAR => {
+                    let value = u32::try_from(cursor.varint()?).map_err(|_| invalid)?;
+                    AstNode::Char(char::from_u32(value).ok_or(invalid)?)
+                }
+                VECTOR => {
+                    let mut elements = vec![];
+                    for _ in 0..cursor.usize()? {
+                        let element = cursor.varint()?;
+                        refs.push((start, element));
+                        elements.push(element);
+                    }
+                    AstNode::Vector(elements)
+                }
+                BYTES => AstNode::Bytes(cursor.slice()?.to_vec()),
+                _ => return Err(invalid),
+            };
+
+            let syntax = match cursor.byte()? {
+                0 => None,
+                1 => {
+                    let file = cursor.varint()?;
+                    let file = file.try_into().map_err(|_| invalid)?;
+                    Some(SyntaxInfo {
+                        file,
+                        span: Span {
+                            start: cursor.usize()?,
+                            end: cursor.usize()?,
+                        },
+                        line: cursor.usize()?,
+                        column: cursor.usize()?,
+                    })
+                }
+                _ => return Err(invalid),
+            };
+            ast.nodes.push(Some(node));
+            ast.syntax.push(syntax);
+        }
+
+        let exists = |ast: &Ast, id: AstRef| matches!(ast.nodes.get(id as usize), Some(Some(_)));
+        if let Some(&(offset, _)) = refs.iter().find(|&&(_, id)| !exists(&ast, id)) {
+            return Err(DeserializeError::Invalid { offset });
+        }
+
+        // The free list has to be exactly the free slots, each once.
+        let start = cursor.offset;
+        let mut seen = HashSet::new();
+        for _ in 0..cursor.usize()? {
+            let offset = cursor.offset;
+            let id = cursor.varint()?;
+            if !matches!(ast.nodes.get(id as usize), Some(None)) || !seen.insert(id) {
+                return Err(DeserializeError::Invalid { offset });
+            }
+            ast.free.push(id);
+        }
+        if ast.free.len() != ast.nodes.iter().filter(|node| node.is_none()).count() {
+            return Err(DeserializeError::Invalid { offset: start });
+        }
+
+        let start = cursor.offset;
+        ast.root = match cursor.varint()? {
+            0 => None,
+            root if exists(&ast, root - 1) => Some(root - 1),
+            _ => return Err(DeserializeError::Invalid { offset: start }),
+        };
+
+        if cursor.offset != bytes.len() {
+            return Err(DeserializeError::Invalid {
+                offset: cursor.offset,
+            });
+        }
+        Ok(ast)
+    }
+
+    pub fn read_from<R: Read>(mut reader: R) -> io::Result<Ast> {
+        let mut bytes = vec![];
+        reader.read_to_end(&mut bytes)?;
+        Ok(Ast::deserialize(&bytes)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lang::reader::parse_str;
+
+    fn round_trip(ast: &Ast) -> Ast {
+        let copy = Ast::deserialize(&ast.serialize()).unwrap();
+        assert_eq!(copy.nodes, ast.nodes);
+        assert_eq!(copy.syntax, ast.syntax);
+        assert_eq!(copy.free, ast.free);
+        assert_eq!(copy.root(), ast.root());
+        copy
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let source = "(define (f x) \"s\" #\\a #(1/2 #t) #u8(1 255) 1.5 -12345678901234567890)\n\
+                      #0=(a b . #0#)";
+        let mut ast = Ast::new();
+        parse_str(source, &mut ast).unwrap();
+        let root = ast.root().unwrap();
+
+        let mut copy = round_trip(&ast);
+        assert_eq!(
+            copy.display(root).to_string(),
+            ast.display(root).to_string()
+        );
+
+        // The root list carries on where it left off.
+        let x = copy.create_symbol("x");
+        copy.add_root(x);
+        assert_eq!(copy.list_len(root), Some(3));
+
+        // Free slots are reused in the same order.
+        let y = ast.create_symbol("y");
+        ast.create_list(&[y, y]);
+        ast.collect_garbage(&[]);
+        let mut copy = round_trip(&ast);
+        assert!(!copy.free.is_empty());
+        assert_eq!(copy.create_nil(), ast.create_nil());
+
+        let mut bytes = vec![];
+        Ast::new().write_to(&mut bytes).unwrap();
+        let empty = Ast::read_from(bytes.as_slice()).unwrap();
+        assert!(empty.is_empty() && empty.root().is_none());
+    }
+
+    #[test]
+    fn test_uninterned_symbols() {
+        // Symbols that aren't interned are read as new ones made from the same symbols.
+        let table = SymbolTable::global();
+        let a = table.intern("a");
+        let (gensym, alias) = (table.gensym(a), table.alias(a));
+        let mut ast = Ast::new();
+        let nodes = [gensym, alias, gensym, a].map(|symbol| ast.add(AstNode::Symbol(symbol)));
+
+        let copy = Ast::deserialize(&ast.serialize()).unwrap();
+        let symbols = nodes.map(|id| copy.get_symbol_id(id).unwrap());
+        assert_eq!(symbols[0], symbols[2]);
+        assert_eq!(symbols[3], a);
+        for symbol in &symbols[..2] {
+            assert!(!table.is_interned(*symbol));
+            assert_eq!(table.origin(*symbol), a);
+        }
+        assert_ne!(symbols[0], gensym);
+        assert_eq!(copy.get_symbol(nodes[1]).as_deref(), Some("a"));
+        assert_ne!(copy.get_symbol(nodes[0]).as_deref(), Some("a"));
+    }
+
+    #[test]
+    fn test_deserialize_errors() {
+        let mut ast = Ast::new();
+        parse_str("(a \"b\" #(1))", &mut ast).unwrap();
+        let bytes = ast.serialize();
+
+        assert_eq!(
+            Ast::deserialize(b"LISP").err(),
+            Some(DeserializeError::NotAst)
+        );
+        assert_eq!(
+            Ast::deserialize(b"TAST\x02").unwrap_err().to_string(),
+            "unsupported Ast format version 2"
+        );
+
+        // Any prefix is incomplete.
+        for end in MAGIC.len()..bytes.len() {
+            assert!(matches!(
+                Ast::deserialize(&bytes[..end]),
+                Err(DeserializeError::Truncated { .. })
+            ));
+        }
+
+        let mut extra = bytes.clone();
+        extra.push(0);
+        assert_eq!(
+            Ast::deserialize(&extra).err(),
+            Some(DeserializeError::Invalid {
+                offset: bytes.len()
+            })
+        );
+
+        // A pair referring to a node that doesn't exist.
+        let mut ast = Ast::new();
+        let nil = ast.create_nil();
+        ast.create_pair(nil, nil);
+        let mut bytes = ast.serialize();
+        let pair = bytes.len() - 6;
+        assert_eq!(bytes[pair..pair + 3], [PAIR, 0, 0]);
+        bytes[pair + 2] = 7;
+        assert_eq!(
+            Ast::deserialize(&bytes).err(),
+            Some(DeserializeError::Invalid { offset: pair })
+        );
+    }
+}
@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock, RwLock};
+
+// Interns symbol names as numbers, so that symbols compare and hash as cheaply as integers. A
+// name's number never changes. The table owns the text of its names, handing out shared references
+// to it so that `resolve` doesn't hold the table's lock, and frees it when it's dropped.
+//
+// It also makes symbols that aren't interned, for renaming the symbols macros introduce. No name
+// gives one of those, so they can't be confused with any symbol a program reads.
+#[derive(Debug, Default)]
+pub struct SymbolTable {
+    inner: RwLock<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    ids: HashMap<Arc<str>, u64>,
+    names: Vec<Arc<str>>,
+    // The interned symbol each symbol that isn't was made from.
+    origins: HashMap<u64, u64>,
+    // How many symbols `gensym` has numbered.
+    numbered: u64,
+}
+
+impl Inner {
+    fn origin(&self, id: u64) -> u64 {
+        self.origins.get(&id).copied().unwrap_or(id)
+    }
+
+    fn add_uninterned(&mut self, origin: u64, name: Arc<str>) -> u64 {
+        let id = self.names.len() as u64;
+        self.names.push(name);
+        self.origins.insert(id, origin);
+        id
+    }
+}
+
+impl SymbolTable {
+    pub fn new() -> SymbolTable {
+        Self::default()
+    }
+
+    // The table the `Ast` interns symbols in.
+    pub fn global() -> &'static SymbolTable {
+        static TABLE: OnceLock<SymbolTable> = OnceLock::new();
+        TABLE.get_or_init(SymbolTable::new)
+    }
+
+    pub fn intern(&self, name: &str) -> u64 {
+        if let Some(&id) = self.inner.read().unwrap().ids.get(name) {
+            return id;
+        }
+
+        // Another thread may have interned the name since the read lock was dropped.
+        let mut inner = self.inner.write().unwrap();
+        if let Some(&id) = inner.ids.get(name) {
+            return id;
+        }
+
+        let name: Arc<str> = name.into();
+        let id = inner.names.len() as u64;
+        inner.names.push(name.clone());
+        inner.ids.insert(name, id);
+        id
+    }
+
+    // The name `id` was interned from. Panics if it wasn't interned in this table.
+    pub fn resolve(&self, id: u64) -> Arc<str> {
+        self.inner.read().unwrap().names[id as usize].clone()
+    }
+
+    // The number `name` was interned as, without interning it if it hasn't been.
+    pub fn get(&self, name: &str) -> Option<u64> {
+        self.inner.read().unwrap().ids.get(name).copied()
+    }
+
+    // A new symbol that isn't interned, named after `id` with a number no other has.
+    pub fn gensym(&self, id: u64) -> u64 {
+        let mut inner = self.inner.write().unwrap();
+        let origin = inner.origin(id);
+        let name = format!("{}%{}", inner.names[origin as usize], inner.numbered);
+        inner.numbered += 1;
+        inner.add_uninterned(origin, name.into())
+    }
+
+    // A new symbol that isn't interned, with the same name as `id`.
+    pub fn alias(&self, id: u64) -> u64 {
+        let mut inner = self.inner.write().unwrap();
+        let origin = inner.origin(id);
+        let name = inner.names[origin as usize].clone();
+        inner.add_uninterned(origin, name)
+    }
+
+    // The interned symbol `id` was made from by `gensym` or `alias`, or `id` if it's interned.
+    pub fn origin(&self, id: u64) -> u64 {
+        self.inner.read().unwrap().origin(id)
+    }
+
+    pub fn is_interned(&self, id: u64) -> bool {
+        !self.inner.read().unwrap().origins.contains_key(&id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.read().unwrap().names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_intern() {
+        let table = SymbolTable::new();
+        assert!(table.is_empty());
+
+        let a = table.intern("a");
+        let b = table.intern("b");
+        assert_ne!(a, b);
+        assert_eq!(table.intern("a"), a);
+        assert_eq!(&*table.resolve(b), "b");
+        assert_eq!(table.get("b"), Some(b));
+        assert_eq!(table.get("c"), None);
+        assert_eq!(table.len(), 2);
+
+        // Names are freed with the table.
+        let name = table.resolve(a);
+        assert_eq!(Arc::strong_count(&name), 3);
+        drop(table);
+        assert_eq!(Arc::strong_count(&name), 1);
+    }
+
+    #[test]
+    fn test_uninterned() {
+        let table = SymbolTable::new();
+        let a = table.intern("a");
+
+        let gensym = table.gensym(a);
+        assert_eq!(&*table.resolve(gensym), "a%0");
+        assert_eq!(&*table.resolve(table.gensym(gensym)), "a%1");
+        assert_ne!(table.intern("a%0"), gensym);
+
+        let alias = table.alias(gensym);
+        assert_ne!(alias, a);
+        assert_eq!(&*table.resolve(alias), "a");
+        assert_eq!(table.get("a"), Some(a));
+
+        assert_eq!(table.origin(gensym), a);
+        assert_eq!(table.origin(alias), a);
+        assert_eq!(table.origin(a), a);
+        assert!(table.is_interned(a));
+        assert!(!table.is_interned(alias));
+    }
+
+    #[test]
+    fn test_threads() {
+        let table = SymbolTable::new();
+
+        let ids = std::thread::scope(|scope| {
+            let threads = (0..4)
+                .map(|_| {
+                    scope.spawn(|| {
+                        (0..100)
+                            .map(|i| table.intern(&format!("s{}", i)))
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            threads
+                .into_iter()
+                .map(|thread| thread.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+
+        // Every thread got the same number for each name.
+        assert!(ids.iter().all(|thread| *thread == ids[0]));
+        assert_eq!(table.len(), 100);
+        assert_eq!(&*table.resolve(ids[0][42]), "s42");
+    }
+}
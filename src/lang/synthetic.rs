@@ -0,0 +1,216 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::Arc;
+
+use crate::lang::ast::{Ast, AstRef, FileId, Origin};
+use crate::lang::compiler::lexer_def;
+use crate::lang::reader::{ParseError, Parser};
+use crate::lex::lexer::Span;
+use crate::lex::source::SourceMap;
+
+// Code a transformation makes as text rather than reads, with each piece linked to the node it
+// was made for. It is read under a `FileId` of its own like any other source, and the nodes read
+// are given the `Origin` of the innermost piece they were read from, so a diagnostic in it can
+// also point at what was written.
+#[derive(Debug, Clone)]
+pub struct SyntheticSource {
+    name: String,
+    text: String,
+    // The text written for each node, in the order the pieces were started.
+    links: Vec<(Span, AstRef)>,
+}
+
+impl SyntheticSource {
+    // `name` is what diagnostics in the text call it, e.g. `<expansion of swap!>`.
+    pub fn new(name: &str) -> SyntheticSource {
+        SyntheticSource {
+            name: name.to_string(),
+            text: String::new(),
+            links: vec![],
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    // Text that wasn't made for any node in particular, like the `let` around a rewritten body.
+    pub fn push_str(&mut self, text: &str) {
+        self.text.push_str(text);
+    }
+
+    pub fn push_linked(&mut self, text: &str, from: AstRef) {
+        self.link(from, |source| source.push_str(text));
+    }
+
+    // `id` written out as it would be read.
+    pub fn push_node(&mut self, ast: &Ast, id: AstRef) {
+        self.push_linked(&ast.display(id).to_string(), id);
+    }
+
+    // Link everything `f` writes to `from`. Pieces it links to other nodes are inside this one.
+    pub fn link<F>(&mut self, from: AstRef, f: F)
+    where
+        F: FnOnce(&mut Self),
+    {
+        let start = self.text.len();
+        let index = self.links.len();
+        self.links.push((Span { start, end: start }, from));
+        f(self);
+        self.links[index].0.end = self.text.len();
+    }
+
+    // The node the text in `span` was made for: that of the innermost piece around it.
+    pub fn origin(&self, span: Span) -> Option<AstRef> {
+        self.links
+            .iter()
+            .filter(|(link, _)| link.start <= span.start && span.end <= link.end)
+            .min_by_key(|(link, _)| link.end - link.start)
+            .map(|&(_, from)| from)
+    }
+
+    pub fn source_map(&self) -> SourceMap {
+        SourceMap::new(&self.name, &self.text)
+    }
+
+    // Read the text into `ast` as the file `file`, leaving the forms out of the root list. Each
+    // node read from a piece linked to a node is given an `Origin` of it and `by`.
+    pub fn read(
+        &self,
+        ast: &mut Ast,
+        file: FileId,
+        by: Option<Arc<str>>,
+    ) -> Result<Vec<AstRef>, ParseError> {
+        let mut run = lexer_def().run();
+        run.put_str(&self.text);
+        run.finish();
+        if let Some(error) = run.get_error() {
+            let position = error.position();
+            return Err(ParseError::InvalidToken {
+                span: Span {
+                    start: position,
+                    end: position,
+                },
+            });
+        }
+
+        let mut parser = Parser::with_ast(std::mem::take(ast));
+        parser.set_file(file);
+        parser.set_detached(true);
+        std::iter::from_fn(|| run.get()).for_each(|lexeme| parser.put(lexeme));
+        parser.finish();
+
+        let forms = match parser.get_error() {
+            Some(&error) => Err(error),
+            None => Ok(std::iter::from_fn(|| parser.get()).collect::<Vec<_>>()),
+        };
+        *ast = parser.into_ast();
+        let forms = forms?;
+
+        let mut seen = HashSet::new();
+        let mut stack = forms.clone();
+        while let Some(id) = stack.pop() {
+            if !seen.insert(id) {
+                continue;
+            }
+            ast.push_children(id, &mut stack);
+
+            let Some(syntax) = ast.get_syntax(id).filter(|syntax| syntax.file == file) else {
+                continue;
+            };
+            if let Some(from) = self.origin(syntax.span) {
+                let by = by.clone();
+                ast.set_origin(id, Origin { from, by });
+            }
+        }
+
+        Ok(forms)
+    }
+
+    // `message` reported at `span` of the text, followed by where the node it was made for was
+    // written, if that node has syntax in one of `sources`, which are indexed by `FileId`.
+    pub fn report(
+        &self,
+        span: Span,
+        message: impl fmt::Display,
+        ast: &Ast,
+        sources: &[SourceMap],
+    ) -> String {
+        let mut report = self.source_map().report(span, message);
+
+        let written = self
+            .origin(span)
+            .and_then(|from| ast.get_syntax(from))
+            .and_then(|syntax| Some((sources.get(syntax.file as usize)?, syntax.span)));
+        if let Some((source, span)) = written {
+            report.push('\n');
+            report.push_str(&source.report(span, "note: generated from here"));
+        }
+
+        report
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lang::reader::parse_str;
+
+    #[test]
+    fn test_synthetic_source() {
+        let written = SourceMap::new("a.scm", "(swap! x y)");
+        let mut ast = Ast::new();
+        let forms = parse_str(written.text(), &mut ast).unwrap();
+        let mut items = ast.list_iter(forms[0]);
+        let (swap, x, y) = (forms[0], items.nth(1).unwrap(), items.next().unwrap());
+
+        // (let ((tmp x)) (set! x y) (set! y tmp)), with the variables linked to what they were
+        // made from and the rest to the use.
+        let mut source = SyntheticSource::new("<expansion of swap!>");
+        source.link(swap, |source| {
+            source.push_str("(let ((tmp ");
+            source.push_node(&ast, x);
+            source.push_str(")) (set! ");
+            source.push_node(&ast, x);
+            source.push_str(" ");
+            source.push_linked("y", y);
+            source.push_str(") (set! y tmp))");
+        });
+        assert_eq!(source.text(), "(let ((tmp x)) (set! x y) (set! y tmp))");
+        assert_eq!(source.origin(Span { start: 11, end: 12 }), Some(x));
+        assert_eq!(source.origin(Span { start: 0, end: 4 }), Some(swap));
+
+        let root = ast.root();
+        let forms = source.read(&mut ast, 1, Some("swap!".into())).unwrap();
+        assert_eq!(ast.root(), root);
+        let form = forms[0];
+        assert_eq!(ast.get_syntax(form).unwrap().file, 1);
+
+        let set = ast.list_iter(form).nth(2).unwrap();
+        let target = ast.list_iter(set).nth(1).unwrap();
+        assert_eq!(
+            ast.origin_chain(target),
+            vec![&Origin {
+                from: x,
+                by: Some("swap!".into())
+            }]
+        );
+        assert_eq!(ast.get_origin(form).unwrap().from, swap);
+
+        // A diagnostic in the text points at what was written too, if that can be found.
+        let span = ast.get_syntax(target).unwrap().span;
+        assert_eq!(
+            source.report(span, "x is immutable", &ast, &[written]),
+            "<expansion of swap!>:1:22: x is immutable\n1 | (let ((tmp x)) (set! x y) (set! y tmp))\n  |                      ^\n\
+             a.scm:1:8: note: generated from here\n1 | (swap! x y)\n  |        ^"
+        );
+        assert_eq!(
+            source.report(span, "x is immutable", &ast, &[]),
+            "<expansion of swap!>:1:22: x is immutable\n1 | (let ((tmp x)) (set! x y) (set! y tmp))\n  |                      ^"
+        );
+    }
+}
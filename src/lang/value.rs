@@ -0,0 +1,257 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use num::{BigInt, BigRational};
+
+use crate::lang::ast::{Ast, AstNode, AstRef, CHAR_NAMES};
+use crate::lang::interpreter::Procedure;
+use crate::lang::symbol::SymbolTable;
+use crate::lang::vm::machine::Closure;
+
+// A procedure written in Rust. It fails with a message, which whatever called it reports along
+// with where the call was.
+pub type BuiltinFn = dyn Fn(&[Value]) -> Result<Value, String>;
+
+pub struct Builtin {
+    name: Arc<str>,
+    function: Box<BuiltinFn>,
+}
+
+impl Builtin {
+    pub fn new<F>(name: &str, function: F) -> Builtin
+    where
+        F: Fn(&[Value]) -> Result<Value, String> + 'static,
+    {
+        let table = SymbolTable::global();
+        Builtin {
+            name: table.resolve(table.intern(name)),
+            function: Box::new(function),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn call(&self, args: &[Value]) -> Result<Value, String> {
+        (self.function)(args)
+    }
+}
+
+// What evaluating a form gives. Lists, vectors and strings are immutable, so are shared rather
+// than copied.
+#[derive(Clone)]
+pub enum Value {
+    // What forms evaluated only for their effects, like `define`, give.
+    Unspecified,
+    Nil,
+    Bool(bool),
+    Integer(BigInt),
+    Rational(BigRational),
+    Float(f64),
+    String(Rc<str>),
+    Char(char),
+    // A name interned in the global `SymbolTable`.
+    Symbol(u64),
+    Bytes(Rc<[u8]>),
+    Pair(Rc<(Value, Value)>),
+    Vector(Rc<[Value]>),
+    Builtin(Rc<Builtin>),
+    Procedure(Rc<Procedure>),
+    // A procedure compiled for the `Vm`.
+    Closure(Rc<Closure>),
+}
+
+impl Value {
+    pub fn list<I>(items: I) -> Value
+    where
+        I: IntoIterator<Item = Value>,
+        I::IntoIter: DoubleEndedIterator,
+    {
+        items
+            .into_iter()
+            .rev()
+            .fold(Value::Nil, |tail, head| Value::Pair(Rc::new((head, tail))))
+    }
+
+    // Everything but `#f` is true.
+    pub fn is_true(&self) -> bool {
+        !matches!(self, Value::Bool(false))
+    }
+
+    // The value of the form `id` as data, as `quote` gives it. Shared nodes become separate
+    // values. `None` if the form is cyclic, which values can't be.
+    pub fn from_datum(ast: &Ast, id: AstRef) -> Option<Value> {
+        datum(ast, id, &mut HashSet::new())
+    }
+}
+
+// `open` holds the pairs and vectors that `id` is inside, which reaching again means a cycle.
+fn datum(ast: &Ast, id: AstRef, open: &mut HashSet<AstRef>) -> Option<Value> {
+    let value = match ast.get(id) {
+        AstNode::Pair(..) => {
+            // Lists are walked without recursing on their tails, so can be of any length.
+            let mut pairs = vec![];
+            let mut items = vec![];
+            let mut tail = id;
+            while let Some((head, rest)) = ast.get_pair(tail) {
+                if !open.insert(tail) {
+                    return None;
+                }
+                pairs.push(tail);
+                items.push(datum(ast, head, open)?);
+                tail = rest;
+            }
+            let tail = datum(ast, tail, open)?;
+            for pair in pairs {
+                open.remove(&pair);
+            }
+            items
+                .into_iter()
+                .rev()
+                .fold(tail, |tail, head| Value::Pair(Rc::new((head, tail))))
+        }
+        AstNode::Vector(elements) => {
+            if !open.insert(id) {
+                return None;
+            }
+            let elements = elements
+                .iter()
+                .map(|&element| datum(ast, element, open))
+                .collect::<Option<Vec<_>>>()?;
+            open.remove(&id);
+            Value::Vector(elements.into())
+        }
+        AstNode::Nil => Value::Nil,
+        // A symbol a macro introduced is the one it was made from, as data.
+        &AstNode::Symbol(symbol) => Value::Symbol(SymbolTable::global().origin(symbol)),
+        &AstNode::Bool(value) => Value::Bool(value),
+        AstNode::Integer(value) => Value::Integer(value.clone()),
+        AstNode::Rational(value) => Value::Rational(value.clone()),
+        &AstNode::Float(value) => Value::Float(value),
+        AstNode::String(value) => Value::String(value.as_str().into()),
+        &AstNode::Char(value) => Value::Char(value),
+        AstNode::Bytes(value) => Value::Bytes(value.as_slice().into()),
+    };
+    Some(value)
+}
+
+// Values are written the way the reader reads them, where they can be.
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Unspecified => write!(f, "#<unspecified>"),
+            Value::Nil => write!(f, "()"),
+            Value::Bool(true) => write!(f, "#t"),
+            Value::Bool(false) => write!(f, "#f"),
+            Value::Integer(value) => write!(f, "{}", value),
+            Value::Rational(value) => write!(f, "{}/{}", value.numer(), value.denom()),
+            Value::Float(value) => write!(f, "{:?}", value),
+            Value::String(value) => {
+                write!(f, "\"")?;
+                for c in value.chars() {
+                    match c {
+                        '"' => write!(f, "\\\"")?,
+                        '\\' => write!(f, "\\\\")?,
+                        '$' => write!(f, "\\$")?,
+                        '\n' => write!(f, "\\n")?,
+                        '\t' => write!(f, "\\t")?,
+                        '\r' => write!(f, "\\r")?,
+                        '\0' => write!(f, "\\0")?,
+                        c => write!(f, "{}", c)?,
+                    }
+                }
+                write!(f, "\"")
+            }
+            Value::Char(value) => match CHAR_NAMES.iter().find(|(_, c)| c == value) {
+                Some((name, _)) => write!(f, "#\\{}", name),
+                None if value.is_control() => write!(f, "#\\x{:x}", *value as u32),
+                None => write!(f, "#\\{}", value),
+            },
+            Value::Symbol(symbol) => write!(f, "{}", SymbolTable::global().resolve(*symbol)),
+            Value::Bytes(value) => {
+                let value = value.iter().map(|b| b.to_string()).collect::<Vec<_>>();
+                write!(f, "#u8({})", value.join(" "))
+            }
+            Value::Pair(pair) => {
+                write!(f, "({}", pair.0)?;
+                let mut tail = &pair.1;
+                while let Value::Pair(pair) = tail {
+                    write!(f, " {}", pair.0)?;
+                    tail = &pair.1;
+                }
+                if !matches!(tail, Value::Nil) {
+                    write!(f, " . {}", tail)?;
+                }
+                write!(f, ")")
+            }
+            Value::Vector(elements) => {
+                write!(f, "#(")?;
+                for (i, element) in elements.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{}", element)?;
+                }
+                write!(f, ")")
+            }
+            Value::Builtin(builtin) => write!(f, "#<builtin {}>", builtin.name),
+            Value::Procedure(procedure) => match procedure.name() {
+                Some(name) => write!(f, "#<procedure {}>", name),
+                None => write!(f, "#<procedure>"),
+            },
+            Value::Closure(closure) => match closure.name() {
+                Some(name) => write!(f, "#<procedure {}>", name),
+                None => write!(f, "#<procedure>"),
+            },
+        }
+    }
+}
+
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lang::reader::parse_str;
+
+    #[test]
+    fn test_from_datum() {
+        let mut ast = Ast::new();
+        let source = "(a \"b\\n\" #\\space (1/2 . 1.5) #(#t ()) #u8(7))";
+        let id = parse_str(source, &mut ast).unwrap()[0];
+        let value = Value::from_datum(&ast, id).unwrap();
+        assert_eq!(value.to_string(), source);
+
+        let cycle = parse_str("#0=(a #0#)", &mut ast).unwrap()[0];
+        assert!(Value::from_datum(&ast, cycle).is_none());
+
+        // Shared nodes that aren't cycles are fine.
+        let shared = parse_str("(#0=(a) #0#)", &mut ast).unwrap()[0];
+        let value = Value::from_datum(&ast, shared).unwrap();
+        assert_eq!(value.to_string(), "((a) (a))");
+    }
+
+    #[test]
+    fn test_value() {
+        let list = Value::list([1, 2].map(|n| Value::Integer(n.into())));
+        assert_eq!(list.to_string(), "(1 2)");
+        assert_eq!(Value::list([]).to_string(), "()");
+
+        assert!(Value::Nil.is_true());
+        assert!(!Value::Bool(false).is_true());
+
+        let builtin = Builtin::new("first", |args| Ok(args[0].clone()));
+        assert_eq!(builtin.call(&[list]).unwrap().to_string(), "(1 2)");
+        assert_eq!(
+            Value::Builtin(Rc::new(builtin)).to_string(),
+            "#<builtin first>"
+        );
+    }
+}
@@ -0,0 +1,278 @@
+use std::collections::HashSet;
+
+use num::{BigInt, BigRational};
+
+use crate::lang::ast::{Ast, AstNode, AstRef};
+
+// Hooks called by `Ast::walk` for each node it reaches, which do nothing unless overridden. A pair
+// is visited before its head and tail, unless `pre_pair` returns false, and after them, and
+// likewise a vector and its elements.
+pub trait AstVisitor {
+    fn pre_pair(&mut self, _ast: &Ast, _id: AstRef, _head: AstRef, _tail: AstRef) -> bool {
+        true
+    }
+
+    fn post_pair(&mut self, _ast: &Ast, _id: AstRef, _head: AstRef, _tail: AstRef) {}
+
+    fn pre_vector(&mut self, _ast: &Ast, _id: AstRef, _elements: &[AstRef]) -> bool {
+        true
+    }
+
+    fn post_vector(&mut self, _ast: &Ast, _id: AstRef, _elements: &[AstRef]) {}
+
+    fn visit_nil(&mut self, _ast: &Ast, _id: AstRef) {}
+
+    fn visit_symbol(&mut self, _ast: &Ast, _id: AstRef, _name: &str) {}
+
+    fn visit_bool(&mut self, _ast: &Ast, _id: AstRef, _value: bool) {}
+
+    fn visit_integer(&mut self, _ast: &Ast, _id: AstRef, _value: &BigInt) {}
+
+    fn visit_rational(&mut self, _ast: &Ast, _id: AstRef, _value: &BigRational) {}
+
+    fn visit_float(&mut self, _ast: &Ast, _id: AstRef, _value: f64) {}
+
+    fn visit_string(&mut self, _ast: &Ast, _id: AstRef, _value: &str) {}
+
+    fn visit_char(&mut self, _ast: &Ast, _id: AstRef, _value: char) {}
+
+    fn visit_bytes(&mut self, _ast: &Ast, _id: AstRef, _value: &[u8]) {}
+}
+
+// Builds a value for each node `Ast::fold` reaches from the values of the nodes it refers to.
+// The `Ast` can be changed while folding, e.g. to build a transformed copy of the forms.
+pub trait AstFold {
+    type Value;
+
+    // Any node other than a pair or vector.
+    fn fold_atom(&mut self, ast: &mut Ast, id: AstRef) -> Self::Value;
+
+    fn fold_pair(
+        &mut self,
+        ast: &mut Ast,
+        id: AstRef,
+        head: Self::Value,
+        tail: Self::Value,
+    ) -> Self::Value;
+
+    fn fold_vector(&mut self, ast: &mut Ast, id: AstRef, elements: Vec<Self::Value>)
+        -> Self::Value;
+}
+
+enum Step {
+    Enter(AstRef),
+    Exit(AstRef, AstRef, AstRef),
+    // A vector and its number of elements.
+    ExitVector(AstRef, usize),
+}
+
+impl Ast {
+    // Visit each node reachable from `root` once, head first. Shared nodes are only visited the
+    // first time they're reached, so cycles end. Lists are walked without recursing, so can be of
+    // any length.
+    pub fn walk<V: AstVisitor>(&self, root: AstRef, visitor: &mut V) {
+        let mut visited = HashSet::new();
+        let mut steps = vec![Step::Enter(root)];
+
+        while let Some(step) = steps.pop() {
+            let id = match step {
+                Step::Enter(id) => id,
+                Step::Exit(id, head, tail) => {
+                    visitor.post_pair(self, id, head, tail);
+                    continue;
+                }
+                Step::ExitVector(id, _) => {
+                    visitor.post_vector(self, id, self.get_vector(id).unwrap());
+                    continue;
+                }
+            };
+            if !visited.insert(id) {
+                continue;
+            }
+
+            match self.get(id) {
+                &AstNode::Pair(head, tail) => {
+                    if visitor.pre_pair(self, id, head, tail) {
+                        steps.push(Step::Exit(id, head, tail));
+                        steps.push(Step::Enter(tail));
+                        steps.push(Step::Enter(head));
+                    }
+                }
+                AstNode::Vector(elements) => {
+                    if visitor.pre_vector(self, id, elements) {
+                        steps.push(Step::ExitVector(id, elements.len()));
+                        steps.extend(elements.iter().rev().map(|&element| Step::Enter(element)));
+                    }
+                }
+                AstNode::Nil => visitor.visit_nil(self, id),
+                &AstNode::Symbol(_) => {
+                    visitor.visit_symbol(self, id, &self.get_symbol(id).unwrap())
+                }
+                &AstNode::Bool(value) => visitor.visit_bool(self, id, value),
+                AstNode::Integer(value) => visitor.visit_integer(self, id, value),
+                AstNode::Rational(value) => visitor.visit_rational(self, id, value),
+                &AstNode::Float(value) => visitor.visit_float(self, id, value),
+                AstNode::String(value) => visitor.visit_string(self, id, value),
+                &AstNode::Char(value) => visitor.visit_char(self, id, value),
+                AstNode::Bytes(value) => visitor.visit_bytes(self, id, value),
+            }
+        }
+    }
+
+    // Fold the nodes reachable from `root`, heads before tails. A node reached more than once is
+    // folded each time. Returns `None` if `root` reaches a cycle, which has no value. The elements
+    // of a vector are read when it's reached, so changes to it while they're folded are ignored.
+    pub fn fold<F: AstFold>(&mut self, root: AstRef, folder: &mut F) -> Option<F::Value> {
+        // The pairs being folded, which reaching again means a cycle.
+        let mut open = HashSet::new();
+        let mut values = vec![];
+        let mut steps = vec![Step::Enter(root)];
+
+        while let Some(step) = steps.pop() {
+            match step {
+                Step::Enter(id) => match self.get(id) {
+                    &AstNode::Pair(head, tail) => {
+                        if !open.insert(id) {
+                            return None;
+                        }
+                        steps.push(Step::Exit(id, head, tail));
+                        steps.push(Step::Enter(tail));
+                        steps.push(Step::Enter(head));
+                    }
+                    AstNode::Vector(elements) => {
+                        if !open.insert(id) {
+                            return None;
+                        }
+                        steps.push(Step::ExitVector(id, elements.len()));
+                        steps.extend(elements.iter().rev().map(|&element| Step::Enter(element)));
+                    }
+                    _ => values.push(folder.fold_atom(self, id)),
+                },
+                Step::Exit(id, _, _) => {
+                    open.remove(&id);
+                    let tail = values.pop().unwrap();
+                    let head = values.pop().unwrap();
+                    values.push(folder.fold_pair(self, id, head, tail));
+                }
+                Step::ExitVector(id, len) => {
+                    open.remove(&id);
+                    let elements = values.split_off(values.len() - len);
+                    values.push(folder.fold_vector(self, id, elements));
+                }
+            }
+        }
+
+        values.pop()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Writes down what it visits.
+    #[derive(Default)]
+    struct Trace(Vec<String>);
+
+    impl AstVisitor for Trace {
+        fn pre_pair(&mut self, ast: &Ast, _id: AstRef, head: AstRef, _tail: AstRef) -> bool {
+            self.0.push("(".to_string());
+            // Don't look inside quotes.
+            ast.get_symbol(head).as_deref() != Some("quote")
+        }
+
+        fn post_pair(&mut self, _ast: &Ast, _id: AstRef, _head: AstRef, _tail: AstRef) {
+            self.0.push(")".to_string());
+        }
+
+        fn visit_nil(&mut self, _ast: &Ast, _id: AstRef) {
+            self.0.push("nil".to_string());
+        }
+
+        fn visit_symbol(&mut self, _ast: &Ast, _id: AstRef, name: &str) {
+            self.0.push(name.to_string());
+        }
+
+        fn visit_integer(&mut self, _ast: &Ast, _id: AstRef, value: &BigInt) {
+            self.0.push(value.to_string());
+        }
+    }
+
+    #[test]
+    fn test_walk() {
+        let mut ast = Ast::new();
+        let [f, quote, x] = ["f", "quote", "x"].map(|name| ast.create_symbol(name));
+        let one = ast.create_integer(1.into());
+        let quoted = ast.create_list(&[quote, x]);
+        let call = ast.create_list(&[f, one, quoted]);
+
+        let mut trace = Trace::default();
+        ast.walk(call, &mut trace);
+        assert_eq!(trace.0.join(" "), "( f ( 1 ( ( nil ) ) )");
+
+        // Shared nodes are visited once, so cycles end.
+        let cycle = ast.create_list(&[one]);
+        ast.set(cycle, AstNode::Pair(one, cycle));
+        let mut trace = Trace::default();
+        ast.walk(cycle, &mut trace);
+        assert_eq!(trace.0.join(" "), "( 1 )");
+
+        // Long lists don't overflow the stack.
+        let items = vec![one; 100_000];
+        let list = ast.create_list(&items);
+        let mut trace = Trace::default();
+        ast.walk(list, &mut trace);
+        assert_eq!(trace.0.len(), 200_002);
+    }
+
+    // Doubles every integer, building a new copy of the forms.
+    struct Double;
+
+    impl AstFold for Double {
+        type Value = AstRef;
+
+        fn fold_atom(&mut self, ast: &mut Ast, id: AstRef) -> AstRef {
+            match ast.get(id) {
+                AstNode::Integer(value) => {
+                    let value = value * 2;
+                    ast.create_integer(value)
+                }
+                _ => id,
+            }
+        }
+
+        fn fold_pair(&mut self, ast: &mut Ast, _id: AstRef, head: AstRef, tail: AstRef) -> AstRef {
+            ast.create_pair(head, tail)
+        }
+
+        fn fold_vector(&mut self, ast: &mut Ast, _id: AstRef, elements: Vec<AstRef>) -> AstRef {
+            ast.create_vector(&elements)
+        }
+    }
+
+    #[test]
+    fn test_fold() {
+        let mut ast = Ast::new();
+        let plus = ast.create_symbol("+");
+        let [one, two] = [1, 2].map(|n| ast.create_integer(n.into()));
+        let inner = ast.create_list(&[plus, two, two]);
+        let outer = ast.create_list(&[plus, one, inner]);
+
+        let doubled = ast.fold(outer, &mut Double).unwrap();
+        assert_eq!(ast.display(doubled).to_string(), "(+ 2 (+ 4 4))");
+        assert_eq!(ast.display(outer).to_string(), "(+ 1 (+ 2 2))");
+
+        let vector = ast.create_vector(&[one, inner]);
+        let doubled = ast.fold(vector, &mut Double).unwrap();
+        assert_eq!(ast.display(doubled).to_string(), "#(2 (+ 4 4))");
+
+        // Shared nodes that aren't cycles are folded each time they're reached.
+        let shared = ast.create_list(&[inner, inner]);
+        let doubled = ast.fold(shared, &mut Double).unwrap();
+        assert_eq!(ast.display(doubled).to_string(), "((+ 4 4) (+ 4 4))");
+
+        let cycle = ast.create_list(&[one]);
+        ast.set(cycle, AstNode::Pair(one, cycle));
+        assert_eq!(ast.fold(cycle, &mut Double), None);
+    }
+}
@@ -0,0 +1,310 @@
+use std::fmt;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use crate::lang::ast::AstRef;
+use crate::lang::value::Value;
+use crate::lex::lexer::Span;
+
+// One instruction. Each is written as a byte for its opcode followed by its operand, if it has
+// one, as four bytes, little endian.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    // Push a constant.
+    Const(u32),
+    // Push the value of a local slot of the running function.
+    Local(u32),
+    // Pop a value into a local slot.
+    SetLocal(u32),
+    // Push the value of a variable the running closure captured.
+    Capture(u32),
+    // Push the value of the global variable named by the symbol constant.
+    Global(u32),
+    // Pop a value into the global variable named by the symbol constant.
+    DefineGlobal(u32),
+    // Push a closure of a function of the chunk, capturing what it refers to.
+    Closure(u32),
+    // Call the procedure under the given number of arguments, replacing them with its result.
+    Call(u32),
+    // Call as `Call`, returning its result from the running function.
+    TailCall(u32),
+    // Go to an offset in the code.
+    Jump(u32),
+    // Pop a value, going to an offset in the code if it's `#f`.
+    JumpIfFalse(u32),
+    Pop,
+    // Pop a value and return it from the running function.
+    Return,
+}
+
+const CONST: u8 = 0;
+const LOCAL: u8 = 1;
+const SET_LOCAL: u8 = 2;
+const CAPTURE: u8 = 3;
+const GLOBAL: u8 = 4;
+const DEFINE_GLOBAL: u8 = 5;
+const CLOSURE: u8 = 6;
+const CALL: u8 = 7;
+const TAIL_CALL: u8 = 8;
+const JUMP: u8 = 9;
+const JUMP_IF_FALSE: u8 = 10;
+const POP: u8 = 11;
+const RETURN: u8 = 12;
+
+impl Op {
+    fn encode(self) -> (u8, Option<u32>) {
+        match self {
+            Op::Const(i) => (CONST, Some(i)),
+            Op::Local(i) => (LOCAL, Some(i)),
+            Op::SetLocal(i) => (SET_LOCAL, Some(i)),
+            Op::Capture(i) => (CAPTURE, Some(i)),
+            Op::Global(i) => (GLOBAL, Some(i)),
+            Op::DefineGlobal(i) => (DEFINE_GLOBAL, Some(i)),
+            Op::Closure(i) => (CLOSURE, Some(i)),
+            Op::Call(n) => (CALL, Some(n)),
+            Op::TailCall(n) => (TAIL_CALL, Some(n)),
+            Op::Jump(to) => (JUMP, Some(to)),
+            Op::JumpIfFalse(to) => (JUMP_IF_FALSE, Some(to)),
+            Op::Pop => (POP, None),
+            Op::Return => (RETURN, None),
+        }
+    }
+}
+
+// Where a captured variable comes from in the function a closure is made in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capture {
+    Local(u32),
+    Capture(u32),
+}
+
+// The code of a function and what it refers to.
+#[derive(Debug, Default)]
+pub struct Chunk {
+    code: Vec<u8>,
+    constants: Vec<Value>,
+    functions: Vec<Rc<Function>>,
+    // The form each op that can fail was compiled from, by offset, for errors.
+    sources: Vec<(usize, AstRef, Option<Span>)>,
+}
+
+impl Chunk {
+    pub fn code(&self) -> &[u8] {
+        &self.code
+    }
+
+    pub fn constants(&self) -> &[Value] {
+        &self.constants
+    }
+
+    pub fn functions(&self) -> &[Rc<Function>] {
+        &self.functions
+    }
+
+    // Write `op`, returning its offset.
+    pub(crate) fn emit(&mut self, op: Op) -> usize {
+        let offset = self.code.len();
+        let (opcode, operand) = op.encode();
+        self.code.push(opcode);
+        if let Some(operand) = operand {
+            self.code.extend_from_slice(&operand.to_le_bytes());
+        }
+        offset
+    }
+
+    // Write `op`, which fails as the form `node`.
+    pub(crate) fn emit_from(&mut self, op: Op, node: AstRef, span: Option<Span>) -> usize {
+        let offset = self.emit(op);
+        self.sources.push((offset, node, span));
+        offset
+    }
+
+    // Set the target of the jump at `offset` to the end of the code.
+    pub(crate) fn patch(&mut self, offset: usize) {
+        let target = self.code.len() as u32;
+        self.code[offset + 1..offset + 5].copy_from_slice(&target.to_le_bytes());
+    }
+
+    pub(crate) fn add_constant(&mut self, value: Value) -> u32 {
+        self.constants.push(value);
+        (self.constants.len() - 1) as u32
+    }
+
+    pub(crate) fn add_function(&mut self, function: Function) -> u32 {
+        self.functions.push(Rc::new(function));
+        (self.functions.len() - 1) as u32
+    }
+
+    // The op at `offset` and the offset of the next.
+    pub fn decode(&self, offset: usize) -> (Op, usize) {
+        let operand = || {
+            let bytes = self.code[offset + 1..offset + 5].try_into().unwrap();
+            u32::from_le_bytes(bytes)
+        };
+        let op = match self.code[offset] {
+            CONST => Op::Const(operand()),
+            LOCAL => Op::Local(operand()),
+            SET_LOCAL => Op::SetLocal(operand()),
+            CAPTURE => Op::Capture(operand()),
+            GLOBAL => Op::Global(operand()),
+            DEFINE_GLOBAL => Op::DefineGlobal(operand()),
+            CLOSURE => Op::Closure(operand()),
+            CALL => Op::Call(operand()),
+            TAIL_CALL => Op::TailCall(operand()),
+            JUMP => Op::Jump(operand()),
+            JUMP_IF_FALSE => Op::JumpIfFalse(operand()),
+            POP => return (Op::Pop, offset + 1),
+            RETURN => return (Op::Return, offset + 1),
+            opcode => panic!("invalid opcode {} at {}", opcode, offset),
+        };
+        (op, offset + 5)
+    }
+
+    // The form the op at `offset` was compiled from, if it can fail.
+    pub fn source(&self, offset: usize) -> Option<(AstRef, Option<Span>)> {
+        let i = self
+            .sources
+            .binary_search_by_key(&offset, |&(offset, _, _)| offset)
+            .ok()?;
+        let (_, node, span) = self.sources[i];
+        Some((node, span))
+    }
+}
+
+// A compiled `lambda`, or a program, which is a function of no parameters. Its parameters are its
+// first local slots, followed by the one the rest of the arguments are passed to as a list if it's
+// variadic, and then the variables it defines.
+#[derive(Debug, Default)]
+pub struct Function {
+    pub(crate) name: Option<Arc<str>>,
+    pub(crate) params: usize,
+    pub(crate) variadic: bool,
+    pub(crate) locals: usize,
+    pub(crate) captures: Vec<Capture>,
+    pub(crate) chunk: Chunk,
+}
+
+impl Function {
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    pub fn chunk(&self) -> &Chunk {
+        &self.chunk
+    }
+
+    // A listing of the function's code, followed by those of the functions it makes closures of.
+    pub fn disassemble(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "function {}: {}{} params, {} locals",
+            self.name.as_deref().unwrap_or("<anonymous>"),
+            self.params,
+            if self.variadic { "+" } else { "" },
+            self.locals
+        )?;
+        if !self.captures.is_empty() {
+            let captures = self
+                .captures
+                .iter()
+                .map(|capture| match capture {
+                    Capture::Local(i) => format!("local {}", i),
+                    Capture::Capture(i) => format!("capture {}", i),
+                })
+                .collect::<Vec<_>>();
+            write!(f, ", captures {}", captures.join(", "))?;
+        }
+        writeln!(f)?;
+
+        let chunk = &self.chunk;
+        let mut offset = 0;
+        while offset < chunk.code.len() {
+            let (op, next) = chunk.decode(offset);
+            write!(f, "  {:04} ", offset)?;
+            match op {
+                Op::Const(i) => write!(f, "const {} ; {}", i, chunk.constants[i as usize])?,
+                Op::Local(i) => write!(f, "local {}", i)?,
+                Op::SetLocal(i) => write!(f, "set-local {}", i)?,
+                Op::Capture(i) => write!(f, "capture {}", i)?,
+                Op::Global(i) => write!(f, "global {} ; {}", i, chunk.constants[i as usize])?,
+                Op::DefineGlobal(i) => {
+                    write!(f, "define-global {} ; {}", i, chunk.constants[i as usize])?
+                }
+                Op::Closure(i) => {
+                    let name = &chunk.functions[i as usize].name;
+                    write!(
+                        f,
+                        "closure {} ; {}",
+                        i,
+                        name.as_deref().unwrap_or("<anonymous>")
+                    )?
+                }
+                Op::Call(n) => write!(f, "call {}", n)?,
+                Op::TailCall(n) => write!(f, "tail-call {}", n)?,
+                Op::Jump(to) => write!(f, "jump {}", to)?,
+                Op::JumpIfFalse(to) => write!(f, "jump-if-false {}", to)?,
+                Op::Pop => write!(f, "pop")?,
+                Op::Return => write!(f, "return")?,
+            }
+            writeln!(f)?;
+            offset = next;
+        }
+
+        for function in &chunk.functions {
+            write!(f, "{}", function)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        let ops = [
+            Op::Const(0),
+            Op::Local(1),
+            Op::SetLocal(2),
+            Op::Capture(3),
+            Op::Global(4),
+            Op::DefineGlobal(5),
+            Op::Closure(6),
+            Op::Call(7),
+            Op::TailCall(8),
+            Op::Jump(0x12345678),
+            Op::JumpIfFalse(10),
+            Op::Pop,
+            Op::Return,
+        ];
+
+        let mut chunk = Chunk::default();
+        let offsets = ops.map(|op| chunk.emit(op));
+        assert_eq!(chunk.code().len(), 11 * 5 + 2);
+        assert_eq!(
+            chunk.code()[offsets[9]..offsets[10]],
+            [JUMP, 0x78, 0x56, 0x34, 0x12]
+        );
+
+        let mut offset = 0;
+        for (i, op) in ops.into_iter().enumerate() {
+            assert_eq!(offset, offsets[i]);
+            let (decoded, next) = chunk.decode(offset);
+            assert_eq!(decoded, op);
+            offset = next;
+        }
+        assert_eq!(offset, chunk.code().len());
+
+        let jump = chunk.emit(Op::JumpIfFalse(0));
+        chunk.emit(Op::Pop);
+        chunk.patch(jump);
+        assert_eq!(chunk.decode(jump).0, Op::JumpIfFalse(jump as u32 + 6));
+    }
+}
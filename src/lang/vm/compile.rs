@@ -0,0 +1,423 @@
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use std::sync::Arc;
+
+use crate::lang::ast::{Ast, AstNode, AstRef};
+use crate::lang::interpreter::EvalError;
+use crate::lang::symbol::SymbolTable;
+use crate::lang::value::Value;
+use crate::lang::vm::bytecode::{Capture, Chunk, Function, Op};
+use crate::lex::lexer::Span;
+
+// A function being compiled.
+#[derive(Default)]
+struct Builder {
+    function: Function,
+    // The local slot of each variable the function binds.
+    locals: HashMap<u64, u32>,
+    // The variable each of the function's captures is of.
+    captured: Vec<u64>,
+    // The constant each symbol the function names a global by is in.
+    symbols: HashMap<u64, u32>,
+}
+
+impl Builder {
+    fn chunk(&mut self) -> &mut Chunk {
+        &mut self.function.chunk
+    }
+
+    fn add_local(&mut self, symbol: u64) -> u32 {
+        let locals = &mut self.function.locals;
+        *self.locals.entry(symbol).or_insert_with(|| {
+            *locals += 1;
+            *locals as u32 - 1
+        })
+    }
+
+    fn symbol(&mut self, symbol: u64) -> u32 {
+        let chunk = &mut self.function.chunk;
+        *self
+            .symbols
+            .entry(symbol)
+            .or_insert_with(|| chunk.add_constant(Value::Symbol(symbol)))
+    }
+}
+
+struct Compiler<'a> {
+    ast: &'a Ast,
+    // The function being compiled and those it's inside, outermost first. The first is the
+    // program, whose variables are globals.
+    builders: Vec<Builder>,
+}
+
+// Compile the program whose top-level forms are the list `root`, like `Ast::root`, into a function
+// of no parameters that runs them in order and returns the value of the last. The forms are those
+// `Interpreter` evaluates, except that keywords are only variables where they're bound by a
+// `lambda` or a `define` in one, since which globals will be defined isn't known until the program
+// runs.
+pub fn compile(ast: &Ast, root: AstRef) -> Result<Rc<Function>, EvalError> {
+    if !ast.is_list(root) {
+        return Err(malformed(ast, root));
+    }
+
+    let mut compiler = Compiler {
+        ast,
+        builders: vec![Builder::default()],
+    };
+    let forms = ast.list_iter(root).collect::<Vec<_>>();
+    compiler.body(&forms)?;
+    compiler.builder().chunk().emit(Op::Return);
+    Ok(Rc::new(compiler.builders.pop().unwrap().function))
+}
+
+impl Compiler<'_> {
+    fn builder(&mut self) -> &mut Builder {
+        self.builders.last_mut().unwrap()
+    }
+
+    fn emit_from(&mut self, op: Op, node: AstRef) -> usize {
+        let span = span(self.ast, node);
+        self.builder().chunk().emit_from(op, node, span)
+    }
+
+    fn constant(&mut self, value: Value) {
+        let chunk = self.builder().chunk();
+        let i = chunk.add_constant(value);
+        chunk.emit(Op::Const(i));
+    }
+
+    // Forms in order, leaving the value of the last, which is in tail position.
+    fn body(&mut self, forms: &[AstRef]) -> Result<(), EvalError> {
+        let Some((&last, forms)) = forms.split_last() else {
+            self.constant(Value::Unspecified);
+            return Ok(());
+        };
+        for &form in forms {
+            self.form(form, false)?;
+            self.builder().chunk().emit(Op::Pop);
+        }
+        self.form(last, true)
+    }
+
+    fn form(&mut self, id: AstRef, tail: bool) -> Result<(), EvalError> {
+        let ast = self.ast;
+        let head = match *ast.get(id) {
+            AstNode::Pair(head, _) => head,
+            AstNode::Symbol(symbol) => {
+                match self.resolve(self.builders.len() - 1, symbol) {
+                    Some(Capture::Local(slot)) => self.builder().chunk().emit(Op::Local(slot)),
+                    Some(Capture::Capture(i)) => self.builder().chunk().emit(Op::Capture(i)),
+                    // A symbol a macro introduced refers to the global it was made from.
+                    None => {
+                        let i = self.builder().symbol(SymbolTable::global().origin(symbol));
+                        self.emit_from(Op::Global(i), id)
+                    }
+                };
+                return Ok(());
+            }
+            AstNode::Nil => return Err(malformed(ast, id)),
+            _ => {
+                let value = Value::from_datum(ast, id).ok_or_else(|| malformed(ast, id))?;
+                self.constant(value);
+                return Ok(());
+            }
+        };
+
+        let items = list(ast, id)?;
+        match self.keyword(head).as_deref() {
+            Some("quote") => {
+                let [_, datum] = items[..] else {
+                    return Err(malformed(ast, id));
+                };
+                let value = Value::from_datum(ast, datum).ok_or_else(|| malformed(ast, id))?;
+                self.constant(value);
+            }
+            Some("if") => {
+                let (test, then, otherwise) = match items[..] {
+                    [_, test, then] => (test, then, None),
+                    [_, test, then, otherwise] => (test, then, Some(otherwise)),
+                    _ => return Err(malformed(ast, id)),
+                };
+                self.form(test, false)?;
+                let jump_if_false = self.builder().chunk().emit(Op::JumpIfFalse(0));
+                self.form(then, tail)?;
+                let jump = self.builder().chunk().emit(Op::Jump(0));
+                self.builder().chunk().patch(jump_if_false);
+                match otherwise {
+                    Some(otherwise) => self.form(otherwise, tail)?,
+                    None => self.constant(Value::Unspecified),
+                }
+                self.builder().chunk().patch(jump);
+            }
+            Some("define") => {
+                self.define(id, &items)?;
+                self.constant(Value::Unspecified);
+            }
+            Some("lambda") => {
+                let [_, params, ref body @ ..] = items[..] else {
+                    return Err(malformed(ast, id));
+                };
+                self.lambda(id, params, body, None)?;
+            }
+            _ => {
+                for &item in &items {
+                    self.form(item, false)?;
+                }
+                let argc = items.len() as u32 - 1;
+                match tail {
+                    true => self.emit_from(Op::TailCall(argc), id),
+                    false => self.emit_from(Op::Call(argc), id),
+                };
+            }
+        }
+        Ok(())
+    }
+
+    // `(define name value)`, or `(define (name params ..) body ..)` for `lambda`. Variables
+    // defined in the program are globals, and in a function are its locals.
+    fn define(&mut self, id: AstRef, items: &[AstRef]) -> Result<(), EvalError> {
+        let ast = self.ast;
+        let Some(name) = definition(ast, items) else {
+            return Err(malformed(ast, id));
+        };
+
+        // The slot is added first, so that the value can refer to itself.
+        let symbol = ast.get_symbol_id(name).unwrap();
+        let slot = match self.builders.len() {
+            1 => None,
+            _ => Some(self.builder().add_local(symbol)),
+        };
+
+        if name == items[1] {
+            let value = items[2];
+            match ast.get_pair(value) {
+                // A procedure is named after the variable it's defined as.
+                Some((head, _)) if self.keyword(head).as_deref() == Some("lambda") => {
+                    let [_, params, ref body @ ..] = list(ast, value)?[..] else {
+                        return Err(malformed(ast, value));
+                    };
+                    self.lambda(value, params, body, ast.get_symbol(name))?;
+                }
+                _ => self.form(value, false)?,
+            }
+        } else {
+            let (_, params) = ast.get_pair(items[1]).unwrap();
+            self.lambda(id, params, &items[2..], ast.get_symbol(name))?;
+        }
+
+        match slot {
+            Some(slot) => self.builder().chunk().emit(Op::SetLocal(slot)),
+            None => {
+                let i = self.builder().symbol(symbol);
+                self.builder().chunk().emit(Op::DefineGlobal(i))
+            }
+        };
+        Ok(())
+    }
+
+    // Compile a function taking `params`, which are a symbol taking every argument as a list, or
+    // a list of symbols ending in one taking the rest, and push a closure of it.
+    fn lambda(
+        &mut self,
+        id: AstRef,
+        params: AstRef,
+        body: &[AstRef],
+        name: Option<Arc<str>>,
+    ) -> Result<(), EvalError> {
+        let ast = self.ast;
+        let cyclic = ast.get_pair(params).is_some()
+            && ast.list_len(params).is_none()
+            && !ast.is_dotted_list(params);
+        if body.is_empty() || cyclic {
+            return Err(malformed(ast, id));
+        }
+
+        let mut iter = ast.list_iter(params);
+        let mut names = vec![];
+        for param in iter.by_ref() {
+            names.push(
+                ast.get_symbol_id(param)
+                    .ok_or_else(|| malformed(ast, param))?,
+            );
+        }
+        let rest = match *ast.get(iter.tail()) {
+            AstNode::Nil => None,
+            AstNode::Symbol(symbol) => Some(symbol),
+            _ => return Err(malformed(ast, iter.tail())),
+        };
+        let mut seen = HashSet::new();
+        if !names.iter().chain(&rest).all(|&name| seen.insert(name)) {
+            return Err(malformed(ast, params));
+        }
+
+        let mut builder = Builder::default();
+        builder.function.name = name;
+        builder.function.params = names.len();
+        builder.function.variadic = rest.is_some();
+        for name in names.into_iter().chain(rest) {
+            builder.add_local(name);
+        }
+        self.builders.push(builder);
+
+        // Variables defined in the body are in scope throughout it, so that procedures it defines
+        // can call each other.
+        for &form in body {
+            if let Some((head, _)) = ast.get_pair(form) {
+                if self.keyword(head).as_deref() == Some("define") {
+                    if let Some(name) = definition(ast, &list(ast, form)?) {
+                        self.builder().add_local(ast.get_symbol_id(name).unwrap());
+                    }
+                }
+            }
+        }
+
+        let compiled = self.body(body);
+        let mut builder = self.builders.pop().unwrap();
+        compiled?;
+        builder.chunk().emit(Op::Return);
+
+        let i = self.builder().chunk().add_function(builder.function);
+        self.builder().chunk().emit(Op::Closure(i));
+        Ok(())
+    }
+
+    // Where the function at `level` finds `symbol`, capturing it from the functions it's inside
+    // if need be. `None` if it's a global.
+    fn resolve(&mut self, level: usize, symbol: u64) -> Option<Capture> {
+        let builder = &self.builders[level];
+        if let Some(&slot) = builder.locals.get(&symbol) {
+            return Some(Capture::Local(slot));
+        }
+        if let Some(i) = builder.captured.iter().position(|&s| s == symbol) {
+            return Some(Capture::Capture(i as u32));
+        }
+        if level == 0 {
+            return None;
+        }
+
+        let outer = self.resolve(level - 1, symbol)?;
+        let builder = &mut self.builders[level];
+        builder.function.captures.push(outer);
+        builder.captured.push(symbol);
+        Some(Capture::Capture(builder.captured.len() as u32 - 1))
+    }
+
+    // The keyword `id` is, if it's a symbol that no function it's in binds.
+    fn keyword(&self, id: AstRef) -> Option<Arc<str>> {
+        let symbol = self.ast.get_symbol_id(id)?;
+        match self.builders.iter().any(|b| b.locals.contains_key(&symbol)) {
+            true => None,
+            false => self.ast.get_symbol(id),
+        }
+    }
+}
+
+// The name a `define` form with `items` defines, if it's of the right shape.
+fn definition(ast: &Ast, items: &[AstRef]) -> Option<AstRef> {
+    match *items {
+        [_, name, _] if ast.get_symbol(name).is_some() => Some(name),
+        [_, signature, _, ..] => {
+            let (name, _) = ast.get_pair(signature)?;
+            ast.get_symbol(name).map(|_| name)
+        }
+        _ => None,
+    }
+}
+
+fn span(ast: &Ast, node: AstRef) -> Option<Span> {
+    ast.get_syntax(node).map(|syntax| syntax.span)
+}
+
+fn malformed(ast: &Ast, node: AstRef) -> EvalError {
+    EvalError::Malformed {
+        node,
+        span: span(ast, node),
+    }
+}
+
+// The items of the form `id`, which has to be a proper list.
+fn list(ast: &Ast, id: AstRef) -> Result<Vec<AstRef>, EvalError> {
+    match ast.is_list(id) {
+        true => Ok(ast.list_iter(id).collect()),
+        false => Err(malformed(ast, id)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lang::reader::parse_str;
+
+    fn compile_str(source: &str) -> Result<Rc<Function>, EvalError> {
+        let mut ast = Ast::new();
+        parse_str(source, &mut ast).unwrap();
+        let root = ast.root().unwrap();
+        compile(&ast, root)
+    }
+
+    #[test]
+    fn test_compile() {
+        let source = "(define (adder n) (lambda (x) (if x (+ x n) 'none))) ((adder 1) 2)";
+        assert_eq!(
+            compile_str(source).unwrap().disassemble(),
+            "\
+function <anonymous>: 0 params, 0 locals
+  0000 closure 0 ; adder
+  0005 define-global 0 ; adder
+  0010 const 1 ; #<unspecified>
+  0015 pop
+  0016 global 0 ; adder
+  0021 const 2 ; 1
+  0026 call 1
+  0031 const 3 ; 2
+  0036 tail-call 1
+  0041 return
+function adder: 1 params, 1 locals
+  0000 closure 0 ; <anonymous>
+  0005 return
+function <anonymous>: 1 params, 1 locals, captures local 0
+  0000 local 0
+  0005 jump-if-false 35
+  0010 global 0 ; +
+  0015 local 0
+  0020 capture 0
+  0025 tail-call 2
+  0030 jump 40
+  0035 const 1 ; none
+  0040 return
+"
+        );
+    }
+
+    #[test]
+    fn test_compile_locals() {
+        // Procedures defined in a body can refer to each other, and captures are passed down
+        // through the functions in between.
+        let source = "(lambda (a)
+              (define (f) (g))
+              (define (g) (lambda () a))
+              f)";
+        let program = compile_str(source).unwrap();
+        let outer = &program.chunk().functions()[0];
+        assert_eq!(outer.locals, 3);
+        let [f, g] = outer.chunk().functions() else {
+            panic!();
+        };
+        assert_eq!(f.captures, vec![Capture::Local(2)]);
+        assert_eq!(g.captures, vec![Capture::Local(0)]);
+        assert_eq!(g.chunk().functions()[0].captures, vec![Capture::Capture(0)]);
+
+        // Keywords bound by a function aren't special in it.
+        let program = compile_str("(lambda (if) (if 1))").unwrap();
+        let function = &program.chunk().functions()[0];
+        assert_eq!(function.chunk().decode(10).0, Op::TailCall(1));
+
+        for source in ["(if)", "(lambda (x x) x)", "(define)", "(f . x)", "()"] {
+            assert!(
+                matches!(compile_str(source), Err(EvalError::Malformed { .. })),
+                "{}",
+                source
+            );
+        }
+    }
+}
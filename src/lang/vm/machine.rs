@@ -0,0 +1,439 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::mem;
+use std::rc::Rc;
+
+use crate::lang::ast::AstRef;
+use crate::lang::interpreter::EvalError;
+use crate::lang::symbol::SymbolTable;
+use crate::lang::value::{Builtin, Value};
+use crate::lang::vm::bytecode::{Capture, Function, Op};
+use crate::lex::lexer::Span;
+
+// A variable, which closures share with the function that made them, so that they see it once
+// it's defined even if it wasn't when they were made.
+type Cell = Rc<RefCell<Value>>;
+
+// A function with the variables it captured from where it was made.
+pub struct Closure {
+    function: Rc<Function>,
+    captures: Vec<Cell>,
+}
+
+impl Closure {
+    pub fn name(&self) -> Option<&str> {
+        self.function.name()
+    }
+
+    pub fn function(&self) -> &Rc<Function> {
+        &self.function
+    }
+}
+
+// A call being run.
+struct Frame {
+    closure: Rc<Closure>,
+    // The offset of the next op.
+    ip: usize,
+    locals: Vec<Cell>,
+    // Where the call's values start on the stack.
+    base: usize,
+}
+
+// Runs compiled programs on a stack of values. Calls are kept on a stack of their own rather than
+// Rust's, and tail calls replace the call they're in, so loops written as tail recursion run in
+// constant space. Procedures are given by builtins, as for `Interpreter`.
+//
+// Globals are kept from one program to the next, e.g. between the lines of a REPL.
+pub struct Vm {
+    globals: HashMap<u64, Value>,
+    max_depth: usize,
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Vm {
+            globals: HashMap::new(),
+            max_depth: 10_000,
+        }
+    }
+}
+
+impl Vm {
+    pub fn new() -> Vm {
+        Self::default()
+    }
+
+    // The most calls that can be run inside one another, not counting calls in tail position.
+    pub fn set_max_depth(&mut self, depth: usize) {
+        self.max_depth = depth;
+    }
+
+    pub fn with_max_depth(&mut self, depth: usize) -> &mut Self {
+        self.set_max_depth(depth);
+        self
+    }
+
+    pub fn add_builtin<F>(&mut self, name: &str, function: F)
+    where
+        F: Fn(&[Value]) -> Result<Value, String> + 'static,
+    {
+        let builtin = Builtin::new(name, function);
+        self.globals.insert(
+            SymbolTable::global().intern(name),
+            Value::Builtin(Rc::new(builtin)),
+        );
+    }
+
+    pub fn with_builtin<F>(&mut self, name: &str, function: F) -> &mut Self
+    where
+        F: Fn(&[Value]) -> Result<Value, String> + 'static,
+    {
+        self.add_builtin(name, function);
+        self
+    }
+
+    // The value of the global variable `name`, if it's been defined.
+    pub fn global(&self, name: &str) -> Option<Value> {
+        self.globals.get(&SymbolTable::global().get(name)?).cloned()
+    }
+
+    // Run a program made by `compile`, returning the value of its last form.
+    pub fn run(&mut self, program: &Rc<Function>) -> Result<Value, EvalError> {
+        let closure = Rc::new(Closure {
+            function: program.clone(),
+            captures: vec![],
+        });
+        let mut frame = Frame {
+            locals: cells(program.locals),
+            closure,
+            ip: 0,
+            base: 0,
+        };
+        let mut frames = vec![];
+        let mut stack = vec![];
+
+        loop {
+            let closure = frame.closure.clone();
+            let chunk = closure.function.chunk();
+            let offset = frame.ip;
+            let (op, next) = chunk.decode(offset);
+            frame.ip = next;
+
+            // Where the op came from, for errors.
+            let source = || chunk.source(offset).unwrap();
+
+            let returned = match op {
+                Op::Const(i) => {
+                    stack.push(chunk.constants()[i as usize].clone());
+                    None
+                }
+                Op::Local(i) => {
+                    stack.push(frame.locals[i as usize].borrow().clone());
+                    None
+                }
+                Op::SetLocal(i) => {
+                    *frame.locals[i as usize].borrow_mut() = stack.pop().unwrap();
+                    None
+                }
+                Op::Capture(i) => {
+                    stack.push(closure.captures[i as usize].borrow().clone());
+                    None
+                }
+                Op::Global(i) => {
+                    let Value::Symbol(symbol) = chunk.constants()[i as usize] else {
+                        panic!("global {} isn't named by a symbol", i);
+                    };
+                    let value = self.globals.get(&symbol).ok_or_else(|| {
+                        let (node, span) = source();
+                        EvalError::Unbound {
+                            name: SymbolTable::global().resolve(symbol),
+                            node,
+                            span,
+                        }
+                    })?;
+                    stack.push(value.clone());
+                    None
+                }
+                Op::DefineGlobal(i) => {
+                    let Value::Symbol(symbol) = chunk.constants()[i as usize] else {
+                        panic!("global {} isn't named by a symbol", i);
+                    };
+                    self.globals.insert(symbol, stack.pop().unwrap());
+                    None
+                }
+                Op::Closure(i) => {
+                    let function = chunk.functions()[i as usize].clone();
+                    let captures = function
+                        .captures
+                        .iter()
+                        .map(|&capture| match capture {
+                            Capture::Local(i) => frame.locals[i as usize].clone(),
+                            Capture::Capture(i) => closure.captures[i as usize].clone(),
+                        })
+                        .collect();
+                    stack.push(Value::Closure(Rc::new(Closure { function, captures })));
+                    None
+                }
+                Op::Call(argc) | Op::TailCall(argc) => {
+                    let tail = matches!(op, Op::TailCall(_));
+                    let args = stack.split_off(stack.len() - argc as usize);
+                    let (node, span) = source();
+                    match stack.pop().unwrap() {
+                        Value::Builtin(builtin) => {
+                            let value =
+                                builtin.call(&args).map_err(|message| EvalError::Failed {
+                                    name: builtin.name().into(),
+                                    message,
+                                    node,
+                                    span,
+                                })?;
+                            match tail {
+                                true => Some(value),
+                                false => {
+                                    stack.push(value);
+                                    None
+                                }
+                            }
+                        }
+                        Value::Closure(callee) => {
+                            let locals = bind(&callee, args, node, span)?;
+                            if tail {
+                                stack.truncate(frame.base);
+                            } else if frames.len() == self.max_depth {
+                                return Err(EvalError::TooDeep { node, span });
+                            }
+                            let callee = Frame {
+                                closure: callee,
+                                ip: 0,
+                                locals,
+                                base: stack.len(),
+                            };
+                            let caller = mem::replace(&mut frame, callee);
+                            if !tail {
+                                frames.push(caller);
+                            }
+                            None
+                        }
+                        _ => return Err(EvalError::NotProcedure { node, span }),
+                    }
+                }
+                Op::Jump(to) => {
+                    frame.ip = to as usize;
+                    None
+                }
+                Op::JumpIfFalse(to) => {
+                    if !stack.pop().unwrap().is_true() {
+                        frame.ip = to as usize;
+                    }
+                    None
+                }
+                Op::Pop => {
+                    stack.pop();
+                    None
+                }
+                Op::Return => stack.pop(),
+            };
+
+            if let Some(value) = returned {
+                stack.truncate(frame.base);
+                match frames.pop() {
+                    Some(caller) => {
+                        frame = caller;
+                        stack.push(value);
+                    }
+                    None => return Ok(value),
+                }
+            }
+        }
+    }
+}
+
+fn cells(len: usize) -> Vec<Cell> {
+    (0..len)
+        .map(|_| Rc::new(RefCell::new(Value::Unspecified)))
+        .collect()
+}
+
+// The locals of a call `node` of `closure`, starting with its arguments.
+fn bind(
+    closure: &Closure,
+    mut args: Vec<Value>,
+    node: AstRef,
+    span: Option<Span>,
+) -> Result<Vec<Cell>, EvalError> {
+    let function = &closure.function;
+    let expected = function.params;
+    if args.len() < expected || (!function.variadic && args.len() > expected) {
+        return Err(EvalError::Arity {
+            name: function.name.clone(),
+            expected,
+            variadic: function.variadic,
+            given: args.len(),
+            node,
+            span,
+        });
+    }
+
+    let locals = cells(function.locals);
+    let rest = args.split_off(expected);
+    for (local, arg) in locals.iter().zip(args) {
+        *local.borrow_mut() = arg;
+    }
+    if function.variadic {
+        *locals[expected].borrow_mut() = Value::list(rest);
+    }
+    Ok(locals)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lang::ast::Ast;
+    use crate::lang::interpreter::Interpreter;
+    use crate::lang::reader::parse_str;
+    use crate::lang::value::BuiltinFn;
+    use crate::lang::vm::compile::compile;
+    use num::BigInt;
+
+    fn integers(name: &str, args: &[Value]) -> Result<Vec<BigInt>, String> {
+        args.iter()
+            .map(|arg| match arg {
+                Value::Integer(value) => Ok(value.clone()),
+                _ => Err(format!("{} expects integers", name)),
+            })
+            .collect()
+    }
+
+    fn builtins() -> Vec<(&'static str, Box<BuiltinFn>)> {
+        vec![
+            (
+                "+",
+                Box::new(|args| Ok(Value::Integer(integers("+", args)?.into_iter().sum()))),
+            ),
+            (
+                "*",
+                Box::new(|args| Ok(Value::Integer(integers("*", args)?.into_iter().product()))),
+            ),
+            (
+                "-",
+                Box::new(|args| match &integers("-", args)?[..] {
+                    [a, b] => Ok(Value::Integer(a - b)),
+                    _ => Err("expects 2 arguments".to_string()),
+                }),
+            ),
+            (
+                "<",
+                Box::new(|args| match &integers("<", args)?[..] {
+                    [a, b] => Ok(Value::Bool(a < b)),
+                    _ => Err("expects 2 arguments".to_string()),
+                }),
+            ),
+            ("list", Box::new(|args| Ok(Value::list(args.to_vec())))),
+        ]
+    }
+
+    fn vm() -> Vm {
+        let mut vm = Vm::new();
+        for (name, builtin) in builtins() {
+            vm.add_builtin(name, builtin);
+        }
+        vm
+    }
+
+    fn run_with(vm: &mut Vm, source: &str) -> Result<String, EvalError> {
+        let mut ast = Ast::new();
+        parse_str(source, &mut ast).unwrap();
+        let root = match ast.root() {
+            Some(root) => root,
+            None => ast.create_nil(),
+        };
+        Ok(vm.run(&compile(&ast, root)?)?.to_string())
+    }
+
+    fn run(source: &str) -> Result<String, EvalError> {
+        run_with(&mut vm(), source)
+    }
+
+    #[test]
+    fn test_run() {
+        let fact = "(define (fact n) (if (< n 2) 1 (* n (fact (- n 1)))))";
+        let programs = [
+            "1 \"a\" #\\b #(1/2 x) '(a . (b))",
+            "(if #f 1 2)",
+            "(if #f #f)",
+            "(define x 2) (+ x 1)",
+            "((lambda (x y) (* x y)) 6 7)",
+            "((lambda (a . rest) (list a rest)) 1 2 3)",
+            "((lambda args args))",
+            "(define f (lambda () 1)) f",
+            // Closures see the variables of where they were made, not where they're called.
+            "(define x 1) (define (get) x) (define (shadow x) (get)) (shadow 2)",
+            "(define (adder n) (lambda (x) (+ x n))) (list ((adder 2) 1) ((adder 3) 1))",
+            "(define x 1) (define (f) (define x 2) x) (list (f) x)",
+            // Procedures defined in a body can call each other.
+            "((lambda (n)
+               (define (even? n) (if (< n 1) #t (odd? (- n 1))))
+               (define (odd? n) (if (< n 1) #f (even? (- n 1))))
+               (list (even? n) (odd? n))) 7)",
+            "((lambda (if) (if 1 2)) -)",
+        ];
+
+        // The same as tree walking.
+        let mut interpreter = Interpreter::new();
+        for (name, builtin) in builtins() {
+            interpreter.add_builtin(name, builtin);
+        }
+        for program in programs
+            .iter()
+            .map(|p| p.to_string())
+            .chain([format!("{} (fact 20)", fact)])
+        {
+            let mut ast = Ast::new();
+            parse_str(&program, &mut ast).unwrap();
+            let root = ast.root().unwrap();
+            let expected = interpreter.run(&ast, root).unwrap().to_string();
+            assert_eq!(run(&program).unwrap(), expected, "{}", program);
+        }
+
+        // Globals last from one program to the next.
+        let mut vm = vm();
+        run_with(&mut vm, "(define y 5)").unwrap();
+        assert_eq!(run_with(&mut vm, "(+ y y)").unwrap(), "10");
+        assert_eq!(vm.global("y").unwrap().to_string(), "5");
+        assert_eq!(run_with(&mut vm, "").unwrap(), "#<unspecified>");
+
+        // Calls in tail position don't nest, so loops don't run out of depth.
+        let source = "(define (count n total)
+              (if (< n 1) total (count (- n 1) (+ total 1))))
+            (count 100000 0)";
+        assert_eq!(run(source).unwrap(), "100000");
+    }
+
+    #[test]
+    fn test_run_errors() {
+        let source = "(define (f x) x) (f y)";
+        assert_eq!(
+            run(source).unwrap_err().to_string(),
+            format!("unbound variable y at {}", source.rfind('y').unwrap())
+        );
+        assert!(matches!(run("(1 2)"), Err(EvalError::NotProcedure { .. })));
+        assert_eq!(
+            run("(define (f x) x) (f)").unwrap_err().to_string(),
+            "f expects 1 argument, given 0 at 17"
+        );
+        assert_eq!(
+            run("((lambda (x y . z) x) 1)").unwrap_err().to_string(),
+            "procedure expects at least 2 arguments, given 1 at 0"
+        );
+        assert_eq!(
+            run("(list (+ 1 'a))").unwrap_err().to_string(),
+            "+: + expects integers at 6"
+        );
+
+        let mut vm = vm();
+        vm.set_max_depth(100);
+        let error = run_with(&mut vm, "(define (f n) (+ 1 (f n))) (f 1)").unwrap_err();
+        assert!(matches!(error, EvalError::TooDeep { .. }));
+    }
+}
@@ -0,0 +1,3 @@
+pub mod bytecode;
+pub mod compile;
+pub mod machine;
@@ -0,0 +1,155 @@
+use crate::cancel::CancelToken;
+use crate::lex::dfa::{Dfa, Progress};
+use crate::lex::lazy::{LazyCache, LazyDfa};
+use crate::lex::nfa::Nfa;
+
+// Several NFAs run together with tagged accepts, where the tag is the lowest index of an NFA that
+// accepts. They are determinized into one DFA unless that would take more than `budget` states,
+// in which case a lazy DFA builds only the states the input reaches, caching at most `budget` of
+// them. Either way the behaviour is the same; only the speed and memory use differ.
+#[derive(Debug, Clone)]
+pub enum Automaton {
+    Dfa(Dfa),
+    Lazy(LazyDfa),
+}
+
+// Where a run is in an `Automaton`, kept apart from it so that the automaton never changes and
+// can be shared by any number of runs. The states a lazy DFA builds are the run's as well.
+#[derive(Debug, Default, Clone)]
+pub struct AutomatonState {
+    current: Option<usize>,
+    cache: LazyCache,
+}
+
+impl Automaton {
+    pub fn new(nfas: &[&Nfa], budget: Option<usize>) -> Automaton {
+        let Some(budget) = budget else {
+            return Automaton::Dfa(Dfa::from_nfas(nfas));
+        };
+
+        // Abandon determinization as soon as the states found so far exceed the budget.
+        let cancel = CancelToken::new();
+        let mut progress = |progress| {
+            if let Progress::Determinize {
+                processed,
+                frontier,
+            } = progress
+            {
+                if processed + frontier > budget {
+                    cancel.cancel();
+                }
+            }
+        };
+
+        match Nfa::determinize_with(nfas, Some(&cancel), Some(&mut progress)) {
+            Ok(mut dfa) => {
+                dfa.minimize();
+                Automaton::Dfa(dfa)
+            }
+            Err(_) => Automaton::Lazy(LazyDfa::new(nfas, budget)),
+        }
+    }
+
+    pub fn is_fallback(&self) -> bool {
+        matches!(self, Automaton::Lazy(_))
+    }
+
+    pub fn reset(&self, state: &mut AutomatonState) {
+        state.current = match self {
+            Automaton::Dfa(dfa) => dfa.start(),
+            Automaton::Lazy(lazy) => lazy.start(&mut state.cache),
+        };
+    }
+
+    pub fn put(&self, state: &mut AutomatonState, c: char) {
+        let Some(current) = state.current else {
+            return;
+        };
+
+        state.current = match self {
+            Automaton::Dfa(dfa) => dfa.next_state(current, c),
+            Automaton::Lazy(lazy) => lazy.next_state(&mut state.cache, current, c),
+        };
+    }
+
+    pub fn is_accept(&self, state: &AutomatonState) -> bool {
+        self.accept_tag(state).is_some()
+    }
+
+    pub fn accept_tag(&self, state: &AutomatonState) -> Option<usize> {
+        let current = state.current?;
+
+        match self {
+            Automaton::Dfa(dfa) => dfa.tag(current),
+            Automaton::Lazy(_) => state.cache.tag(current),
+        }
+    }
+}
+
+impl AutomatonState {
+    pub fn new() -> AutomatonState {
+        AutomatonState::default()
+    }
+
+    pub fn is_dead(&self) -> bool {
+        self.current.is_none()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lex::regex::Regex;
+
+    #[test]
+    fn test_automaton_fallback() {
+        // (a|b)*a(a|b){6} needs 2^7 DFA states.
+        let ab = Regex::one_of("ab");
+        let mut regex = ab.star().concat(&Regex::char('a'));
+        for _ in 0..6 {
+            regex = regex.concat(&ab);
+        }
+        let blowup = regex.to_nfa();
+        let word = Regex::range('a', 'z').plus().to_nfa();
+        let nfas = [&blowup, &word];
+
+        let dfa = Automaton::new(&nfas, None);
+        let small = Automaton::new(&nfas, Some(16));
+        let large = Automaton::new(&nfas, Some(1000));
+        // Clears its cache all the time.
+        let tiny = Automaton::new(&nfas, Some(2));
+
+        assert!(!dfa.is_fallback());
+        assert!(small.is_fallback());
+        assert!(!large.is_fallback());
+        assert!(tiny.is_fallback());
+
+        let automata = [&dfa, &small, &large, &tiny];
+        let mut states = [(); 4].map(|_| AutomatonState::new());
+
+        for s in ["", "a", "abbbbbb", "baaaaaaa", "abbbbbbb", "xyz", "ab1"] {
+            for (automaton, state) in automata.iter().zip(states.iter_mut()) {
+                automaton.reset(state);
+            }
+
+            for c in s.chars() {
+                for (automaton, state) in automata.iter().zip(states.iter_mut()) {
+                    automaton.put(state, c);
+                }
+
+                for state in states[1..].iter() {
+                    assert_eq!(state.is_dead(), states[0].is_dead(), "s: {:?}", s);
+                }
+            }
+
+            for (automaton, state) in automata[1..].iter().zip(states[1..].iter()) {
+                assert_eq!(
+                    automaton.accept_tag(state),
+                    dfa.accept_tag(&states[0]),
+                    "s: {:?}",
+                    s
+                );
+            }
+        }
+    }
+}
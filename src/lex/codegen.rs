@@ -0,0 +1,183 @@
+use std::io::{Result, Write};
+
+use crate::lex::dfa::Dfa;
+
+// Emit `pub fn <name>(input: &str) -> Option<usize>` which runs `dfa` over `input` and returns
+// the byte length of the longest accepted prefix. The generated code has no dependencies, so it
+// can be written to `OUT_DIR` from a build script and `include!`d.
+pub fn write_dfa_rust<W>(dfa: &Dfa, name: &str, mut io: W) -> Result<()>
+where
+    W: Write,
+{
+    writeln!(
+        io,
+        "#[allow(clippy::all, unreachable_patterns, unused_mut)]"
+    )?;
+    writeln!(io, "pub fn {}(input: &str) -> Option<usize> {{", name)?;
+
+    let Some(start) = dfa.start() else {
+        writeln!(io, "    let _ = input;")?;
+        writeln!(io, "    None")?;
+        writeln!(io, "}}")?;
+        return Ok(());
+    };
+
+    let accepting = (0..dfa.len())
+        .filter(|&state| dfa.accepts(state))
+        .map(|state| state.to_string())
+        .collect::<Vec<_>>();
+
+    writeln!(io, "    let mut state: usize = {};", start)?;
+    if dfa.accepts(start) {
+        writeln!(io, "    let mut accepted = Some(0);")?;
+    } else {
+        writeln!(io, "    let mut accepted = None;")?;
+    }
+    writeln!(io)?;
+    writeln!(io, "    for (index, c) in input.char_indices() {{")?;
+    writeln!(io, "        state = match (state, c) {{")?;
+
+    for state in 0..dfa.len() {
+        for &(lo, hi, to) in dfa.edges(state).iter() {
+            if lo == hi {
+                writeln!(io, "            ({}, {:?}) => {},", state, lo, to)?;
+            } else {
+                writeln!(
+                    io,
+                    "            ({}, {:?}..={:?}) => {},",
+                    state, lo, hi, to
+                )?;
+            }
+        }
+
+        if let Some(to) = dfa.default_edge(state) {
+            writeln!(io, "            ({}, _) => {},", state, to)?;
+        }
+    }
+
+    writeln!(io, "            _ => return accepted,")?;
+    writeln!(io, "        }};")?;
+
+    if !accepting.is_empty() {
+        writeln!(io)?;
+        writeln!(
+            io,
+            "        if matches!(state, {}) {{",
+            accepting.join(" | ")
+        )?;
+        writeln!(io, "            accepted = Some(index + c.len_utf8());")?;
+        writeln!(io, "        }}")?;
+    }
+
+    writeln!(io, "    }}")?;
+    writeln!(io)?;
+    writeln!(io, "    accepted")?;
+    writeln!(io, "}}")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lex::regex::Regex;
+
+    fn generate(regex: &Regex) -> String {
+        let mut dfa = regex.to_nfa().to_dfa();
+        dfa.minimize();
+
+        let mut out = vec![];
+        write_dfa_rust(&dfa, "scan", &mut out).unwrap();
+
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_write_dfa_rust() {
+        let regex = Regex::char('\n').concat(&Regex::range('a', 'z').star());
+
+        assert_eq!(
+            generate(&regex),
+            r#"#[allow(clippy::all, unreachable_patterns, unused_mut)]
+pub fn scan(input: &str) -> Option<usize> {
+    let mut state: usize = 0;
+    let mut accepted = None;
+
+    for (index, c) in input.char_indices() {
+        state = match (state, c) {
+            (0, '\n') => 1,
+            (1, 'a'..='z') => 1,
+            _ => return accepted,
+        };
+
+        if matches!(state, 1) {
+            accepted = Some(index + c.len_utf8());
+        }
+    }
+
+    accepted
+}
+"#
+        );
+    }
+
+    #[test]
+    fn test_write_dfa_rust_empty() {
+        assert_eq!(
+            generate(&Regex::empty()),
+            r#"#[allow(clippy::all, unreachable_patterns, unused_mut)]
+pub fn scan(input: &str) -> Option<usize> {
+    let _ = input;
+    None
+}
+"#
+        );
+    }
+
+    #[test]
+    fn test_write_dfa_rust_default_edge() {
+        let mut dfa = Dfa::new();
+        let start = dfa.create_state();
+        let end = dfa.create_state();
+        dfa.set_start(start);
+        dfa.add_edge(start, 'a', 'a', start);
+        dfa.add_default_edge(start, end);
+        dfa.add_accept(end);
+
+        let mut out = vec![];
+        write_dfa_rust(&dfa, "scan", &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert!(out.contains("            (0, 'a') => 0,\n            (0, _) => 1,\n"));
+    }
+
+    // A verbatim copy of the output checked in `test_write_dfa_rust`, so that it gets compiled.
+    #[allow(clippy::all, unreachable_patterns, unused_mut)]
+    pub fn scan(input: &str) -> Option<usize> {
+        let mut state: usize = 0;
+        let mut accepted = None;
+
+        for (index, c) in input.char_indices() {
+            state = match (state, c) {
+                (0, '\n') => 1,
+                (1, 'a'..='z') => 1,
+                _ => return accepted,
+            };
+
+            if matches!(state, 1) {
+                accepted = Some(index + c.len_utf8());
+            }
+        }
+
+        accepted
+    }
+
+    #[test]
+    fn test_generated_scan() {
+        assert_eq!(scan("\nabc"), Some(4));
+        assert_eq!(scan("\nab1"), Some(3));
+        assert_eq!(scan("\n"), Some(1));
+        assert_eq!(scan("abc"), None);
+        assert_eq!(scan(""), None);
+    }
+}
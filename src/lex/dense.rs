@@ -0,0 +1,208 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use crate::lex::dfa::Dfa;
+
+const TABLE_SIZE: usize = 256;
+
+// A `Dfa` compiled into a form that is faster to run over mostly-ASCII input. Characters below
+// `TABLE_SIZE` are mapped to equivalence classes (characters that every state treats the same)
+// and looked up in a dense `[state][class]` table; anything else falls back to a binary search
+// over the original ranges. The dead state is a real row that loops back to itself, so stepping
+// never needs to check for it.
+#[derive(Debug, Clone)]
+pub struct DenseDfa {
+    classes: Vec<u8>,
+    class_count: usize,
+    table: Vec<usize>,
+
+    edges: Vec<Vec<(char, char, usize)>>,
+    defaults: Vec<usize>,
+    accept: Vec<Option<usize>>,
+
+    start: usize,
+    dead: usize,
+    current: usize,
+}
+
+impl DenseDfa {
+    pub fn new(dfa: &Dfa) -> DenseDfa {
+        let dead = dfa.len();
+
+        let edges = (0..dfa.len())
+            .map(|state| dfa.edges(state).to_vec())
+            .chain([vec![]])
+            .collect::<Vec<_>>();
+        let defaults = (0..dfa.len())
+            .map(|state| dfa.default_edge(state).unwrap_or(dead))
+            .chain([dead])
+            .collect::<Vec<_>>();
+
+        let mut signatures = HashMap::new();
+        let mut classes = Vec::with_capacity(TABLE_SIZE);
+        let mut representatives = vec![];
+
+        for byte in 0..TABLE_SIZE {
+            let c = char::from(byte as u8);
+            let signature = (0..dfa.len())
+                .map(|state| target(&edges[state], defaults[state], c))
+                .collect::<Vec<_>>();
+
+            let class = *signatures.entry(signature).or_insert_with(|| {
+                representatives.push(c);
+                representatives.len() - 1
+            });
+
+            classes.push(class as u8);
+        }
+
+        let class_count = representatives.len();
+        let mut table = Vec::with_capacity((dfa.len() + 1) * class_count);
+
+        for state in 0..=dfa.len() {
+            for &c in representatives.iter() {
+                table.push(target(&edges[state], defaults[state], c));
+            }
+        }
+
+        let accept = (0..dfa.len())
+            .map(|state| dfa.tag(state))
+            .chain([None])
+            .collect();
+
+        let start = dfa.start().unwrap_or(dead);
+
+        DenseDfa {
+            classes,
+            class_count,
+            table,
+            edges,
+            defaults,
+            accept,
+            start,
+            dead,
+            current: start,
+        }
+    }
+
+    pub fn class_count(&self) -> usize {
+        self.class_count
+    }
+
+    pub fn reset(&mut self) {
+        self.current = self.start;
+    }
+
+    pub fn put(&mut self, c: char) {
+        let index = c as usize;
+
+        self.current = if index < TABLE_SIZE {
+            self.table[self.current * self.class_count + self.classes[index] as usize]
+        } else {
+            target(&self.edges[self.current], self.defaults[self.current], c)
+        };
+    }
+
+    pub fn is_dead(&self) -> bool {
+        self.current == self.dead
+    }
+
+    pub fn is_accept(&self) -> bool {
+        self.accept[self.current].is_some()
+    }
+
+    pub fn accept_tag(&self) -> Option<usize> {
+        self.accept[self.current]
+    }
+}
+
+impl Dfa {
+    pub fn to_dense(&self) -> DenseDfa {
+        DenseDfa::new(self)
+    }
+}
+
+fn target(edges: &[(char, char, usize)], default: usize, c: char) -> usize {
+    let index = edges.binary_search_by(|&(lo, hi, _)| {
+        if hi < c {
+            Ordering::Less
+        } else if lo > c {
+            Ordering::Greater
+        } else {
+            Ordering::Equal
+        }
+    });
+
+    match index {
+        Ok(index) => edges[index].2,
+        Err(_) => default,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lex::regex::Regex;
+
+    #[test]
+    fn test_dense_dfa() {
+        let digit = Regex::range('0', '9');
+        let alpha = Regex::range('a', 'z').union(&Regex::char('λ'));
+        let regex = alpha.concat(&alpha.union(&digit).star());
+
+        let mut dfa = regex.to_nfa().to_dfa();
+        dfa.minimize();
+        let mut dense = dfa.to_dense();
+
+        // Digits, letters and everything else.
+        assert_eq!(dense.class_count(), 3);
+
+        for s in ["", "a", "abc1", "λ", "aλ2", "1a", "a-b", "ab\u{1F600}"] {
+            dfa.reset();
+            dense.reset();
+
+            for c in s.chars() {
+                dfa.put(c);
+                dense.put(c);
+                assert_eq!(dense.is_dead(), dfa.is_dead(), "s: {:?}", s);
+            }
+
+            assert_eq!(dense.is_accept(), dfa.is_accept(), "s: {:?}", s);
+            assert_eq!(dense.accept_tag(), dfa.accept_tag(), "s: {:?}", s);
+        }
+    }
+
+    #[test]
+    fn test_dense_dfa_default_edge() {
+        let mut dfa = Dfa::new();
+        let start = dfa.create_state();
+        let end = dfa.create_state();
+        dfa.set_start(start);
+        dfa.add_edge(start, 'a', 'a', start);
+        dfa.add_default_edge(start, end);
+        dfa.add_accept(end);
+
+        let mut dense = dfa.to_dense();
+
+        for (s, expected) in [("", false), ("aab", true), ("λ", true), ("bb", false)] {
+            dense.reset();
+            for c in s.chars() {
+                dense.put(c);
+            }
+            assert_eq!(dense.is_accept(), expected, "s: {:?}", s);
+        }
+
+        assert!(dense.is_dead());
+    }
+
+    #[test]
+    fn test_dense_dfa_empty() {
+        let mut dense = Dfa::new().to_dense();
+
+        dense.reset();
+        assert!(dense.is_dead());
+        dense.put('a');
+        assert!(dense.is_dead());
+        assert!(!dense.is_accept());
+    }
+}
@@ -0,0 +1,1559 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::io::Write;
+
+use crate::cancel::{CancelToken, Cancelled};
+use crate::lex::dot::{write_dot, DotGraph};
+use crate::lex::nfa::{char_decr, char_incr, Nfa};
+use crate::lex::stats::{count_components, count_intervals, Stats};
+
+#[derive(Debug, Clone)]
+pub struct Dfa {
+    start: Option<usize>,
+    states: Vec<DfaState>,
+
+    current: Option<usize>,
+}
+
+// `edges` is kept sorted by range and the ranges never overlap, so each state has at most one
+// transition on any character. Accepting states carry a tag saying what they accept, e.g. the
+// index of a lexer rule. `default` is taken on any character that no range covers.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct DfaState {
+    edges: Vec<(char, char, usize)>,
+    default: Option<usize>,
+    accept: Option<usize>,
+}
+
+impl DfaState {
+    fn target(&self, c: char) -> Option<usize> {
+        let index = self.edges.binary_search_by(|&(lo, hi, _)| {
+            if hi < c {
+                Ordering::Less
+            } else if lo > c {
+                Ordering::Greater
+            } else {
+                Ordering::Equal
+            }
+        });
+
+        match index {
+            Ok(index) => Some(self.edges[index].2),
+            Err(_) => self.default,
+        }
+    }
+
+    // Replace the default edge with explicit edges over every gap between the ranges.
+    fn expand_default(&mut self) {
+        let Some(default) = self.default.take() else {
+            return;
+        };
+
+        let mut edges: Vec<(char, char, usize)> = Vec::with_capacity(self.edges.len() * 2 + 1);
+        let mut next = Some(char::MIN);
+
+        for &(lo, hi, to) in self.edges.iter() {
+            if let Some(gap) = next {
+                if gap < lo {
+                    edges.push((gap, char_decr(lo), default));
+                }
+            }
+
+            edges.push((lo, hi, to));
+            next = if hi == char::MAX {
+                None
+            } else {
+                Some(char_incr(hi))
+            };
+        }
+
+        if let Some(gap) = next {
+            edges.push((gap, char::MAX, default));
+        }
+
+        self.edges.clear();
+
+        for (lo, hi, to) in edges {
+            match self.edges.last_mut() {
+                Some((_, last_hi, last_to)) if *last_to == to && char_incr(*last_hi) == lo => {
+                    *last_hi = hi;
+                }
+                _ => self.edges.push((lo, hi, to)),
+            }
+        }
+    }
+}
+
+impl Default for Dfa {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Dfa {
+    pub fn new() -> Dfa {
+        Dfa {
+            start: None,
+            states: vec![],
+            current: None,
+        }
+    }
+
+    pub fn from_nfas(nfas: &[&Nfa]) -> Dfa {
+        Nfa::determinize(nfas)
+    }
+
+    pub fn from_nfas_with_cancel(nfas: &[&Nfa], cancel: &CancelToken) -> Result<Dfa, Cancelled> {
+        Nfa::determinize_with(nfas, Some(cancel), None)
+    }
+
+    // Like `from_nfas`, calling `progress` after every state is processed.
+    pub fn from_nfas_with_progress<F>(nfas: &[&Nfa], mut progress: F) -> Dfa
+    where
+        F: FnMut(Progress),
+    {
+        Nfa::determinize_with(nfas, None, Some(&mut progress)).unwrap()
+    }
+
+    pub fn create_state(&mut self) -> usize {
+        let index = self.states.len();
+
+        self.states.push(DfaState::default());
+
+        index
+    }
+
+    pub fn set_start(&mut self, start: usize) {
+        self.try_set_start(start)
+            .unwrap_or_else(|error| panic!("{}", error));
+    }
+
+    pub fn try_set_start(&mut self, start: usize) -> Result<(), DfaBuildError> {
+        self.check_state(start)?;
+
+        self.start = Some(start);
+
+        Ok(())
+    }
+
+    pub fn add_accept(&mut self, accept: usize) {
+        self.set_accept(accept, Some(0));
+    }
+
+    pub fn set_accept(&mut self, state: usize, tag: Option<usize>) {
+        self.states[state].accept = tag;
+    }
+
+    pub fn add_edge(&mut self, from: usize, lo: char, hi: char, to: usize) {
+        self.try_add_edge(from, lo, hi, to)
+            .unwrap_or_else(|error| panic!("{}", error));
+    }
+
+    pub fn try_add_edge(
+        &mut self,
+        from: usize,
+        lo: char,
+        hi: char,
+        to: usize,
+    ) -> Result<(), DfaBuildError> {
+        self.check_state(from)?;
+        self.check_state(to)?;
+
+        if lo > hi {
+            return Err(DfaBuildError::InvalidRange { lo, hi });
+        }
+
+        let edges = &mut self.states[from].edges;
+        let index = edges.partition_point(|&(_, h, _)| h < lo);
+
+        if let Some(&(l, h, _)) = edges.get(index) {
+            if l <= hi {
+                return Err(DfaBuildError::OverlappingEdge {
+                    state: from,
+                    range: (lo, hi),
+                    existing: (l, h),
+                });
+            }
+        }
+
+        edges.insert(index, (lo, hi, to));
+
+        Ok(())
+    }
+
+    pub fn add_default_edge(&mut self, from: usize, to: usize) {
+        self.try_add_default_edge(from, to)
+            .unwrap_or_else(|error| panic!("{}", error));
+    }
+
+    pub fn try_add_default_edge(&mut self, from: usize, to: usize) -> Result<(), DfaBuildError> {
+        self.check_state(from)?;
+        self.check_state(to)?;
+
+        if self.states[from].default.is_some() {
+            return Err(DfaBuildError::DuplicateDefault { state: from });
+        }
+
+        self.states[from].default = Some(to);
+
+        Ok(())
+    }
+
+    fn check_state(&self, state: usize) -> Result<(), DfaBuildError> {
+        if state < self.states.len() {
+            Ok(())
+        } else {
+            Err(DfaBuildError::UnknownState { state })
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.states.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.states.is_empty()
+    }
+
+    pub fn start(&self) -> Option<usize> {
+        self.start
+    }
+
+    pub fn edges(&self, state: usize) -> &[(char, char, usize)] {
+        &self.states[state].edges
+    }
+
+    pub fn default_edge(&self, state: usize) -> Option<usize> {
+        self.states[state].default
+    }
+
+    pub fn accepts(&self, state: usize) -> bool {
+        self.states[state].accept.is_some()
+    }
+
+    pub fn tag(&self, state: usize) -> Option<usize> {
+        self.states[state].accept
+    }
+
+    // The state `state` goes to on `c`, for stepping the DFA from outside without `put`.
+    pub fn next_state(&self, state: usize, c: char) -> Option<usize> {
+        self.states[state].target(c)
+    }
+
+    pub fn reset(&mut self) {
+        self.current = self.start;
+    }
+
+    pub fn put(&mut self, c: char) {
+        self.current = self
+            .current
+            .and_then(|current| self.states[current].target(c));
+    }
+
+    pub fn is_dead(&self) -> bool {
+        self.current.is_none()
+    }
+
+    pub fn is_accept(&self) -> bool {
+        self.accept_tag().is_some()
+    }
+
+    pub fn accept_tag(&self) -> Option<usize> {
+        self.current.and_then(|current| self.states[current].accept)
+    }
+
+    // Every accepted string of at most `max_len` characters, shortest first and in character
+    // order within each length. Ranges are expanded one character at a time, so a broad class
+    // can produce a lot of strings; use `take` to look at the first few.
+    pub fn enumerate(&self, max_len: usize) -> Enumerate {
+        let mut dfa = self.clone();
+        for state in dfa.states.iter_mut() {
+            state.expand_default();
+        }
+
+        // Shortest distance from every state to an accepting state, so branches that cannot
+        // finish in time are never walked.
+        let mut predecessors = vec![vec![]; dfa.states.len()];
+        for (from, state) in dfa.states.iter().enumerate() {
+            for &(_, _, to) in state.edges.iter() {
+                predecessors[to].push(from);
+            }
+        }
+
+        let mut distance = vec![None; dfa.states.len()];
+        let mut queue = VecDeque::new();
+
+        for (state, d) in distance.iter_mut().enumerate() {
+            if dfa.accepts(state) {
+                *d = Some(0);
+                queue.push_back(state);
+            }
+        }
+
+        while let Some(state) = queue.pop_front() {
+            let next = distance[state].map(|d| d + 1);
+
+            for &from in predecessors[state].iter() {
+                if distance[from].is_none() {
+                    distance[from] = next;
+                    queue.push_back(from);
+                }
+            }
+        }
+
+        Enumerate {
+            dfa,
+            distance,
+            max_len,
+            len: None,
+            stack: vec![],
+            prefix: String::new(),
+        }
+    }
+
+    // The longest prefix of `input` that the DFA accepts. Runs until the DFA dies or the input
+    // ends, then backtracks to the last accepting position. `length` is in bytes.
+    pub fn scan(&mut self, input: &str) -> Option<Match> {
+        self.reset();
+
+        let mut last = self.accept_tag().map(|tag| Match { length: 0, tag });
+
+        for (index, c) in input.char_indices() {
+            self.put(c);
+
+            if self.is_dead() {
+                break;
+            }
+
+            if let Some(tag) = self.accept_tag() {
+                last = Some(Match {
+                    length: index + c.len_utf8(),
+                    tag,
+                });
+            }
+        }
+
+        last
+    }
+
+    pub fn stats(&self) -> Stats {
+        let successors = self
+            .states
+            .iter()
+            .map(|state| {
+                state
+                    .edges
+                    .iter()
+                    .map(|&(_, _, to)| to)
+                    .chain(state.default)
+                    .collect()
+            })
+            .collect::<Vec<Vec<usize>>>();
+
+        Stats {
+            states: self.states.len(),
+            edges: self
+                .states
+                .iter()
+                .map(|state| state.edges.len() + state.default.iter().count())
+                .sum(),
+            epsilons: 0,
+            intervals: count_intervals(
+                self.states
+                    .iter()
+                    .flat_map(|state| state.edges.iter())
+                    .map(|&(lo, hi, _)| (lo, hi)),
+            ),
+            components: count_components(&successors),
+        }
+    }
+
+    pub fn write_dot<W>(&self, io: W) -> std::io::Result<()>
+    where
+        W: Write,
+    {
+        self.write_dot_with(|state| state.to_string(), io)
+    }
+
+    pub fn write_dot_with<W, F>(&self, label: F, io: W) -> std::io::Result<()>
+    where
+        W: Write,
+        F: Fn(usize) -> String,
+    {
+        let mut edges = BTreeMap::new();
+
+        for (from, state) in self.states.iter().enumerate() {
+            for &(lo, hi, to) in state.edges.iter() {
+                edges
+                    .entry((from, to))
+                    .or_insert_with(Vec::new)
+                    .push((lo, hi));
+            }
+        }
+
+        let graph = DotGraph {
+            name: "DFA",
+            states: self.states.len(),
+            start: self.start.into_iter().collect(),
+            accept: (0..self.states.len())
+                .filter(|&state| self.accepts(state))
+                .collect(),
+            edges,
+            defaults: (0..self.states.len())
+                .filter_map(|from| self.states[from].default.map(|to| (from, to)))
+                .collect(),
+            epsilons: vec![],
+        };
+
+        write_dot(&graph, label, io)
+    }
+
+    pub fn minimize(&mut self) {
+        self.minimize_with_report();
+    }
+
+    pub fn minimize_with_report(&mut self) -> MinimizeReport {
+        self.minimize_with_progress(|_| {})
+    }
+
+    // Like `minimize_with_report`, calling `progress` after every refinement round.
+    pub fn minimize_with_progress<F>(&mut self, mut progress: F) -> MinimizeReport
+    where
+        F: FnMut(Progress),
+    {
+        for state in self.states.iter_mut() {
+            state.expand_default();
+        }
+
+        let trimmed = self.trim();
+
+        let Some(start) = self.start else {
+            return MinimizeReport { map: trimmed };
+        };
+
+        let mut tags = HashMap::new();
+        let mut class = self
+            .states
+            .iter()
+            .map(|state| {
+                let len = tags.len();
+                *tags.entry(state.accept).or_insert(len)
+            })
+            .collect::<Vec<_>>();
+        let mut count = 0;
+        let mut rounds = 0;
+
+        loop {
+            let mut signatures = HashMap::new();
+            let mut next = vec![0; self.states.len()];
+
+            for state in 0..self.states.len() {
+                let signature = (class[state], self.class_edges(state, &class));
+                let len = signatures.len();
+                next[state] = *signatures.entry(signature).or_insert(len);
+            }
+
+            class = next;
+            rounds += 1;
+
+            progress(Progress::Minimize {
+                rounds,
+                classes: signatures.len(),
+            });
+
+            if signatures.len() == count {
+                break;
+            }
+
+            count = signatures.len();
+        }
+
+        let mut states = vec![None; count];
+
+        for state in 0..self.states.len() {
+            if states[class[state]].is_none() {
+                states[class[state]] = Some(DfaState {
+                    edges: self.class_edges(state, &class),
+                    default: None,
+                    accept: self.states[state].accept,
+                });
+            }
+        }
+
+        self.states = states.into_iter().map(Option::unwrap).collect();
+        self.start = Some(class[start]);
+        self.current = None;
+
+        MinimizeReport {
+            map: trimmed
+                .into_iter()
+                .map(|state| state.map(|state| class[state]))
+                .collect(),
+        }
+    }
+
+    pub fn equivalent(&self, other: &Dfa) -> bool {
+        let mut lhs = self.clone();
+        let mut rhs = other.clone();
+
+        lhs.minimize();
+        rhs.minimize();
+
+        lhs.canonical() == rhs.canonical()
+    }
+
+    // A DFA accepting exactly the strings `self` rejects. Every state that could die is given a
+    // default edge to a rejecting sink, so the result is total.
+    pub fn complement(&self) -> Dfa {
+        let mut dfa = self.clone();
+        let sink = dfa.create_state();
+
+        for state in dfa.states.iter_mut() {
+            state.default.get_or_insert(sink);
+            state.accept = match state.accept {
+                Some(_) => None,
+                None => Some(0),
+            };
+        }
+
+        dfa.start = Some(self.start.unwrap_or(sink));
+        dfa.current = None;
+
+        dfa
+    }
+
+    // A DFA accepting the strings accepted by `self` but not by `other`, keeping the tags of
+    // `self`.
+    pub fn difference(&self, other: &Dfa) -> Dfa {
+        let mut dfa = Dfa::new();
+
+        let Some(start) = self.start else {
+            return dfa;
+        };
+
+        let mut states = HashMap::new();
+        let mut queue = VecDeque::new();
+
+        states.insert((start, other.start), dfa.create_state());
+        queue.push_back((start, other.start));
+
+        while let Some((a, b)) = queue.pop_front() {
+            let from = states[&(a, b)];
+
+            if !b.is_some_and(|b| other.accepts(b)) {
+                dfa.set_accept(from, self.tag(a));
+            }
+
+            for (lo, hi, a, b) in self.product_successors(Some(a), other, b) {
+                let Some(a) = a else {
+                    continue;
+                };
+
+                let to = *states.entry((a, b)).or_insert_with(|| {
+                    queue.push_back((a, b));
+                    dfa.states.push(DfaState::default());
+                    dfa.states.len() - 1
+                });
+
+                dfa.add_edge(from, lo, hi, to);
+            }
+        }
+
+        dfa.set_start(states[&(start, other.start)]);
+
+        dfa
+    }
+
+    // A DFA accepting the strings accepted by both `self` and `other`, keeping the tags of `self`.
+    pub fn intersection(&self, other: &Dfa) -> Dfa {
+        let mut dfa = Dfa::new();
+
+        let (Some(start), Some(other_start)) = (self.start, other.start) else {
+            return dfa;
+        };
+
+        let mut states = HashMap::new();
+        let mut queue = VecDeque::new();
+
+        states.insert((start, other_start), dfa.create_state());
+        queue.push_back((start, other_start));
+
+        while let Some((a, b)) = queue.pop_front() {
+            let from = states[&(a, b)];
+
+            if other.accepts(b) {
+                dfa.set_accept(from, self.tag(a));
+            }
+
+            for (lo, hi, a, b) in self.product_successors(Some(a), other, Some(b)) {
+                let (Some(a), Some(b)) = (a, b) else {
+                    continue;
+                };
+
+                let to = *states.entry((a, b)).or_insert_with(|| {
+                    queue.push_back((a, b));
+                    dfa.states.push(DfaState::default());
+                    dfa.states.len() - 1
+                });
+
+                dfa.add_edge(from, lo, hi, to);
+            }
+        }
+
+        dfa.set_start(states[&(start, other_start)]);
+
+        dfa
+    }
+
+    // Whether every string accepted by `self` is also accepted by `other`, found by searching
+    // the product automaton for a state where only `self` accepts.
+    pub fn is_subset(&self, other: &Dfa) -> bool {
+        let Some(start) = self.start else {
+            return true;
+        };
+
+        let mut visited = HashSet::new();
+        let mut stack = vec![(start, other.start)];
+
+        while let Some((a, b)) = stack.pop() {
+            if !visited.insert((a, b)) {
+                continue;
+            }
+
+            if self.accepts(a) && !b.is_some_and(|b| other.accepts(b)) {
+                return false;
+            }
+
+            for (_, _, a, b) in self.product_successors(Some(a), other, b) {
+                if let Some(a) = a {
+                    stack.push((a, b));
+                }
+            }
+        }
+
+        true
+    }
+
+    // Up to `limit` distinct strings accepted by exactly one of `self` and `other`, shortest
+    // first. Only one character is tried from each run of characters that behave the same, so
+    // the result is the same from run to run.
+    pub fn symmetric_difference(&self, other: &Dfa, limit: usize) -> Vec<Witness> {
+        let accepts = |(a, b): (Option<usize>, Option<usize>)| {
+            (
+                a.is_some_and(|a| self.accepts(a)),
+                b.is_some_and(|b| other.accepts(b)),
+            )
+        };
+
+        // Explore the product automaton, then find the pairs that can still reach a pair where
+        // exactly one side accepts so the search below never wanders down a dead end.
+        let start = (self.start, other.start);
+        let mut successors = HashMap::new();
+        let mut predecessors: HashMap<_, Vec<_>> = HashMap::new();
+        let mut queue = VecDeque::from([start]);
+
+        while let Some(pair) = queue.pop_front() {
+            if successors.contains_key(&pair) {
+                continue;
+            }
+
+            let next = self.product_successors(pair.0, other, pair.1);
+            for &(_, _, a, b) in next.iter() {
+                predecessors.entry((a, b)).or_default().push(pair);
+                queue.push_back((a, b));
+            }
+            successors.insert(pair, next);
+        }
+
+        let mut live = HashSet::new();
+        let mut stack = successors
+            .keys()
+            .copied()
+            .filter(|&pair| {
+                let (lhs, rhs) = accepts(pair);
+                lhs != rhs
+            })
+            .collect::<Vec<_>>();
+
+        while let Some(pair) = stack.pop() {
+            if live.insert(pair) {
+                stack.extend(predecessors.get(&pair).into_iter().flatten().copied());
+            }
+        }
+
+        let mut witnesses = vec![];
+
+        if !live.contains(&start) {
+            return witnesses;
+        }
+
+        // Search the product a length at a time. Strings are kept as their last character and the
+        // index of the string they extend, and a pair is reached by at most `limit` strings of each
+        // length: any more come after those, so everything they lead to comes after the same
+        // extensions of those, and never makes it into the result.
+        let mut strings = vec![];
+        let mut layer = vec![(start, None)];
+
+        while !layer.is_empty() {
+            let mut next = vec![];
+            let mut counts = HashMap::new();
+
+            for &(pair, string) in layer.iter() {
+                let (lhs, rhs) = accepts(pair);
+                if lhs != rhs {
+                    witnesses.push(Witness {
+                        input: spell(&strings, string),
+                        accepted_by_self: lhs,
+                    });
+                    if witnesses.len() >= limit {
+                        return witnesses;
+                    }
+                }
+
+                for &(c, _, a, b) in successors[&pair].iter() {
+                    let count = counts.entry((a, b)).or_insert(0);
+                    if live.contains(&(a, b)) && *count < limit {
+                        *count += 1;
+                        strings.push((string, c));
+                        next.push(((a, b), Some(strings.len() - 1)));
+                    }
+                }
+            }
+
+            layer = next;
+        }
+
+        witnesses
+    }
+
+    // The transitions of the product of state `a` of `self` and state `b` of `other`, split into
+    // the runs of characters that lead to the same pair of targets. Runs on which both sides die
+    // are left out.
+    fn product_successors(
+        &self,
+        a: Option<usize>,
+        other: &Dfa,
+        b: Option<usize>,
+    ) -> Vec<(char, char, Option<usize>, Option<usize>)> {
+        let lhs = a.map(|a| &self.states[a]);
+        let rhs = b.map(|b| &other.states[b]);
+
+        let mut boundaries = vec![char::MIN];
+        for state in lhs.iter().chain(rhs.iter()) {
+            for &(lo, hi, _) in state.edges.iter() {
+                boundaries.push(lo);
+                if hi != char::MAX {
+                    boundaries.push(char_incr(hi));
+                }
+            }
+        }
+        boundaries.sort_unstable();
+        boundaries.dedup();
+
+        let mut successors = vec![];
+
+        for (i, &lo) in boundaries.iter().enumerate() {
+            let hi = boundaries
+                .get(i + 1)
+                .map_or(char::MAX, |&next| char_decr(next));
+            let a = lhs.and_then(|state| state.target(lo));
+            let b = rhs.and_then(|state| state.target(lo));
+
+            if a.is_some() || b.is_some() {
+                successors.push((lo, hi, a, b));
+            }
+        }
+
+        successors
+    }
+
+    // Edges of `state` with every target replaced by its class, merging adjacent ranges that end
+    // up in the same class.
+    fn class_edges(&self, state: usize, class: &[usize]) -> Vec<(char, char, usize)> {
+        let mut edges: Vec<(char, char, usize)> = vec![];
+
+        for &(lo, hi, to) in self.states[state].edges.iter() {
+            match edges.last_mut() {
+                Some((_, last_hi, last_to))
+                    if *last_to == class[to]
+                        && *last_hi != char::MAX
+                        && char_incr(*last_hi) == lo =>
+                {
+                    *last_hi = hi;
+                }
+                _ => edges.push((lo, hi, class[to])),
+            }
+        }
+
+        edges
+    }
+
+    // Renumber the states in breadth-first order from the start state. Two minimal DFAs accept
+    // the same language exactly when their canonical forms are equal.
+    fn canonical(&self) -> Vec<DfaState> {
+        let Some(start) = self.start else {
+            return vec![];
+        };
+
+        let mut order = vec![None; self.states.len()];
+        let mut queue = VecDeque::new();
+        let mut states = vec![];
+        let mut count = 1;
+
+        order[start] = Some(0);
+        queue.push_back(start);
+
+        while let Some(state) = queue.pop_front() {
+            let mut edges = vec![];
+
+            for &(lo, hi, to) in self.states[state].edges.iter() {
+                let index = *order[to].get_or_insert_with(|| {
+                    queue.push_back(to);
+                    count += 1;
+                    count - 1
+                });
+                edges.push((lo, hi, index));
+            }
+
+            states.push(DfaState {
+                edges,
+                default: None,
+                accept: self.states[state].accept,
+            });
+        }
+
+        states
+    }
+
+    // Remove states that are unreachable from the start state or cannot reach an accepting
+    // state. Missing transitions already mean "reject", so this never changes the language.
+    // Default edges must have been expanded. Returns where each old state ended up.
+    fn trim(&mut self) -> Vec<Option<usize>> {
+        let mut reachable = vec![false; self.states.len()];
+        let mut stack = self.start.into_iter().collect::<Vec<_>>();
+
+        while let Some(state) = stack.pop() {
+            if reachable[state] {
+                continue;
+            }
+
+            reachable[state] = true;
+            stack.extend(self.states[state].edges.iter().map(|&(_, _, to)| to));
+        }
+
+        let mut predecessors = vec![vec![]; self.states.len()];
+        for (from, state) in self.states.iter().enumerate() {
+            for &(_, _, to) in state.edges.iter() {
+                predecessors[to].push(from);
+            }
+        }
+
+        let mut live = vec![false; self.states.len()];
+        let mut stack = (0..self.states.len())
+            .filter(|&state| self.accepts(state))
+            .collect::<Vec<_>>();
+
+        while let Some(state) = stack.pop() {
+            if live[state] {
+                continue;
+            }
+
+            live[state] = true;
+            stack.extend(predecessors[state].iter().copied());
+        }
+
+        let mut map = vec![None; self.states.len()];
+        let mut count = 0;
+
+        for state in 0..self.states.len() {
+            if reachable[state] && live[state] {
+                map[state] = Some(count);
+                count += 1;
+            }
+        }
+
+        let states = std::mem::take(&mut self.states);
+        self.states = states
+            .into_iter()
+            .enumerate()
+            .filter(|&(index, _)| map[index].is_some())
+            .map(|(_, mut state)| {
+                state.edges = state
+                    .edges
+                    .iter()
+                    .filter_map(|&(lo, hi, to)| map[to].map(|to| (lo, hi, to)))
+                    .collect();
+                state
+            })
+            .collect();
+
+        self.start = self.start.and_then(|start| map[start]);
+        self.current = None;
+
+        map
+    }
+}
+
+// The string ending at `end` of those `symmetric_difference` keeps, each as its last character
+// and the string it extends. `None` is the empty string.
+fn spell(strings: &[(Option<usize>, char)], mut end: Option<usize>) -> String {
+    let mut chars = vec![];
+    while let Some(string) = end {
+        let (rest, c) = strings[string];
+        chars.push(c);
+        end = rest;
+    }
+    chars.iter().rev().collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DfaBuildError {
+    UnknownState {
+        state: usize,
+    },
+    InvalidRange {
+        lo: char,
+        hi: char,
+    },
+    OverlappingEdge {
+        state: usize,
+        range: (char, char),
+        existing: (char, char),
+    },
+    DuplicateDefault {
+        state: usize,
+    },
+}
+
+impl fmt::Display for DfaBuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DfaBuildError::UnknownState { state } => write!(f, "unknown state: {}", state),
+            DfaBuildError::InvalidRange { lo, hi } => {
+                write!(f, "invalid range: {:?}-{:?}", lo, hi)
+            }
+            DfaBuildError::OverlappingEdge {
+                state,
+                range,
+                existing,
+            } => write!(
+                f,
+                "edge {:?}-{:?} overlaps {:?}-{:?} on state {}",
+                range.0, range.1, existing.0, existing.1, state
+            ),
+            DfaBuildError::DuplicateDefault { state } => {
+                write!(f, "state {} already has a default edge", state)
+            }
+        }
+    }
+}
+
+impl Error for DfaBuildError {}
+
+// How far a long-running construction has got. `frontier` is the number of subset states found
+// but not yet processed; if it keeps growing, the construction is probably blowing up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Progress {
+    Determinize { processed: usize, frontier: usize },
+    Minimize { rounds: usize, classes: usize },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Match {
+    pub length: usize,
+    pub tag: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Witness {
+    pub input: String,
+    pub accepted_by_self: bool,
+}
+
+// Iterative deepening over the accepted strings of each length in turn. Each frame on `stack`
+// is a state together with the next edge and character to try from it; `prefix` holds the
+// characters leading to the top frame.
+#[derive(Debug, Clone)]
+pub struct Enumerate {
+    dfa: Dfa,
+    distance: Vec<Option<usize>>,
+    max_len: usize,
+    len: Option<usize>,
+    stack: Vec<(usize, usize, Option<char>)>,
+    prefix: String,
+}
+
+impl Iterator for Enumerate {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        loop {
+            let depth = self.stack.len();
+            let Some((state, edge, next)) = self.stack.last_mut() else {
+                let len = self.len.map_or(0, |len| len + 1);
+                let start = self.dfa.start?;
+                self.distance[start]?;
+
+                if len > self.max_len {
+                    return None;
+                }
+
+                self.len = Some(len);
+
+                if len == 0 {
+                    if self.dfa.accepts(start) {
+                        return Some(String::new());
+                    }
+                } else {
+                    self.stack.push((start, 0, None));
+                }
+
+                continue;
+            };
+
+            let Some(&(lo, hi, to)) = self.dfa.states[*state].edges.get(*edge) else {
+                self.stack.pop();
+                self.prefix.pop();
+                continue;
+            };
+
+            let remaining = self.len.unwrap() - depth;
+
+            if next.is_none() && self.distance[to].is_none_or(|d| d > remaining) {
+                *edge += 1;
+                continue;
+            }
+
+            let c = next.unwrap_or(lo);
+
+            if c == hi {
+                *edge += 1;
+                *next = None;
+            } else {
+                *next = Some(char_incr(c));
+            }
+
+            if remaining == 0 {
+                let mut input = self.prefix.clone();
+                input.push(c);
+                return Some(input);
+            }
+
+            self.prefix.push(c);
+            self.stack.push((to, 0, None));
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MinimizeReport {
+    // For every state before minimization, the state it was merged into, or `None` if it was
+    // removed because it was unreachable or could never accept.
+    pub map: Vec<Option<usize>>,
+}
+
+impl MinimizeReport {
+    pub fn classes(&self) -> Vec<Vec<usize>> {
+        let len = self
+            .map
+            .iter()
+            .flatten()
+            .map(|&to| to + 1)
+            .max()
+            .unwrap_or(0);
+        let mut classes = vec![vec![]; len];
+
+        for (from, to) in self.map.iter().enumerate() {
+            if let Some(to) = to {
+                classes[*to].push(from);
+            }
+        }
+
+        classes
+    }
+
+    pub fn merged(&self) -> Vec<Vec<usize>> {
+        self.classes()
+            .into_iter()
+            .filter(|class| class.len() > 1)
+            .collect()
+    }
+
+    pub fn removed(&self) -> Vec<usize> {
+        (0..self.map.len())
+            .filter(|&state| self.map[state].is_none())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lex::regex::Regex;
+
+    fn test_dfa(dfa: &mut Dfa, s: &str, expected: bool) {
+        dfa.reset();
+
+        for c in s.chars() {
+            dfa.put(c);
+        }
+
+        assert_eq!(dfa.is_accept(), expected, "s: {:?}", s);
+    }
+
+    #[test]
+    fn test_from_nfa() {
+        let regex = Regex::char('a')
+            .concat(&Regex::range('b', 'd').star())
+            .concat(&Regex::char('e'));
+        let mut dfa = regex.to_nfa().to_dfa();
+
+        test_dfa(&mut dfa, "ae", true);
+        test_dfa(&mut dfa, "abcde", true);
+        test_dfa(&mut dfa, "a", false);
+        test_dfa(&mut dfa, "abf", false);
+        test_dfa(&mut dfa, "", false);
+    }
+
+    #[test]
+    fn test_minimize() {
+        let regex = Regex::one_of("ab")
+            .star()
+            .concat(&Regex::one_of("ab").star());
+        let mut dfa = regex.to_nfa().to_dfa();
+        dfa.minimize();
+
+        assert_eq!(dfa.len(), 1);
+        test_dfa(&mut dfa, "", true);
+        test_dfa(&mut dfa, "abba", true);
+        test_dfa(&mut dfa, "abc", false);
+    }
+
+    #[test]
+    fn test_minimize_empty() {
+        let mut dfa = Regex::empty().to_nfa().to_dfa();
+        dfa.minimize();
+
+        assert!(dfa.is_empty());
+        test_dfa(&mut dfa, "", false);
+        assert!(dfa.is_dead());
+    }
+
+    #[test]
+    fn test_try_add_edge() {
+        let mut dfa = Dfa::new();
+        let a = dfa.create_state();
+        let b = dfa.create_state();
+
+        assert_eq!(dfa.try_add_edge(a, 'a', 'm', b), Ok(()));
+        assert_eq!(
+            dfa.try_add_edge(a, 'k', 'z', b),
+            Err(DfaBuildError::OverlappingEdge {
+                state: a,
+                range: ('k', 'z'),
+                existing: ('a', 'm'),
+            })
+        );
+        assert_eq!(
+            dfa.try_add_edge(a, 'z', 'n', b),
+            Err(DfaBuildError::InvalidRange { lo: 'z', hi: 'n' })
+        );
+        assert_eq!(
+            dfa.try_add_edge(2, 'n', 'z', b),
+            Err(DfaBuildError::UnknownState { state: 2 })
+        );
+        assert_eq!(
+            dfa.try_set_start(5),
+            Err(DfaBuildError::UnknownState { state: 5 })
+        );
+        assert_eq!(dfa.try_add_default_edge(a, b), Ok(()));
+        assert_eq!(
+            dfa.try_add_default_edge(a, a),
+            Err(DfaBuildError::DuplicateDefault { state: a })
+        );
+
+        assert_eq!(dfa.edges(a), &[('a', 'm', b)]);
+        assert_eq!(dfa.start(), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "edge 'k'-'z' overlaps 'a'-'m' on state 0")]
+    fn test_add_overlapping_edge() {
+        let mut dfa = Dfa::new();
+        let a = dfa.create_state();
+        let b = dfa.create_state();
+
+        dfa.add_edge(a, 'a', 'm', b);
+        dfa.add_edge(a, 'k', 'z', b);
+    }
+
+    #[test]
+    fn test_equivalent() {
+        let a = Regex::char('a');
+        let b = Regex::char('b');
+
+        let pairs = [
+            (a.star(), a.star().star(), true),
+            (a.union(&b), Regex::one_of("ab"), true),
+            (a.plus(), a.concat(&a.star()), true),
+            (a.optional(), Regex::epsilon().union(&a), true),
+            (Regex::range('a', 'c'), Regex::one_of("abc"), true),
+            (Regex::none_of("a"), Regex::any(), false),
+            (a.concat(&b), b.concat(&a), false),
+            (a.star(), a.plus(), false),
+            (Regex::empty(), Regex::epsilon(), false),
+        ];
+
+        for (lhs, rhs, expected) in pairs.iter() {
+            assert_eq!(
+                lhs.to_nfa().equivalent(&rhs.to_nfa()),
+                *expected,
+                "lhs: {:?}, rhs: {:?}",
+                lhs,
+                rhs
+            );
+        }
+    }
+
+    #[test]
+    fn test_stats() {
+        let regex = Regex::char('a').concat(&Regex::one_of("bc").star());
+        let mut dfa = regex.to_nfa().to_dfa();
+        dfa.minimize();
+
+        let stats = dfa.stats();
+
+        assert_eq!(stats.states, 2);
+        assert_eq!(stats.edges, 2);
+        assert_eq!(stats.epsilons, 0);
+        assert_eq!(stats.intervals, 2);
+        assert_eq!(stats.components, 2);
+    }
+
+    #[test]
+    fn test_minimize_report() {
+        let mut dfa = Dfa::new();
+        let start = dfa.create_state();
+        let a = dfa.create_state();
+        let b = dfa.create_state();
+        let dead = dfa.create_state();
+        let unreachable = dfa.create_state();
+
+        dfa.set_start(start);
+        dfa.add_edge(start, 'a', 'a', a);
+        dfa.add_edge(start, 'b', 'b', b);
+        dfa.add_edge(start, 'c', 'c', dead);
+        dfa.add_edge(unreachable, 'a', 'a', a);
+        dfa.add_accept(a);
+        dfa.add_accept(b);
+        dfa.add_accept(unreachable);
+
+        let report = dfa.minimize_with_report();
+
+        assert_eq!(dfa.len(), 2);
+        assert_eq!(report.map, vec![Some(0), Some(1), Some(1), None, None]);
+        assert_eq!(report.classes(), vec![vec![start], vec![a, b]]);
+        assert_eq!(report.merged(), vec![vec![a, b]]);
+        assert_eq!(report.removed(), vec![dead, unreachable]);
+    }
+
+    #[test]
+    fn test_write_dot() {
+        let mut dfa = Regex::one_of("ab-").plus().to_nfa().to_dfa();
+        dfa.minimize();
+
+        let mut out = vec![];
+        dfa.write_dot(&mut out).unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            r#"digraph DFA {
+  rankdir=LR;
+  0 [label="0", shape=circle];
+  1 [label="1", shape=circle];
+  _start_point [shape=point];
+  _start_point -> 0;
+  _accept_point [shape=point, style=invis];
+  1 [shape=doublecircle];
+  1 -> _accept_point [style=invis];
+  0 -> 1 [label="[\\-a-b]"];
+  1 -> 1 [label="[\\-a-b]"];
+}
+"#
+        );
+    }
+
+    #[test]
+    fn test_from_nfas() {
+        let keyword = Regex::char('i').concat(&Regex::char('f')).to_nfa();
+        let identifier = Regex::range('a', 'z').plus().to_nfa();
+
+        let cases = [
+            ("if", Some(0)),
+            ("iff", Some(1)),
+            ("i", Some(1)),
+            ("", None),
+        ];
+
+        let mut dfa = Dfa::from_nfas(&[&keyword, &identifier]);
+        dfa.minimize();
+
+        for (s, expected) in cases.iter() {
+            dfa.reset();
+            for c in s.chars() {
+                dfa.put(c);
+            }
+
+            assert_eq!(dfa.accept_tag(), *expected, "s: {:?}", s);
+        }
+
+        let mut dfa = Dfa::from_nfas(&[&identifier, &keyword]);
+        dfa.minimize();
+
+        dfa.reset();
+        dfa.put('i');
+        dfa.put('f');
+        assert_eq!(dfa.accept_tag(), Some(0));
+    }
+
+    #[test]
+    fn test_default_edge() {
+        let mut dfa = Dfa::new();
+        let start = dfa.create_state();
+        let body = dfa.create_state();
+        let end = dfa.create_state();
+
+        dfa.set_start(start);
+        dfa.add_edge(start, '"', '"', body);
+        dfa.add_edge(body, '"', '"', end);
+        dfa.add_default_edge(body, body);
+        dfa.add_accept(end);
+
+        test_dfa(&mut dfa, "\"\"", true);
+        test_dfa(&mut dfa, "\"abc\"", true);
+        test_dfa(&mut dfa, "\"a\nb\"", true);
+        test_dfa(&mut dfa, "\"abc", false);
+        test_dfa(&mut dfa, "abc\"", false);
+
+        assert_eq!(dfa.stats().edges, 3);
+
+        let regex = Regex::char('"')
+            .concat(&Regex::none_of("\"").star())
+            .concat(&Regex::char('"'));
+        assert!(dfa.equivalent(&regex.to_nfa().to_dfa()));
+
+        dfa.minimize();
+
+        assert_eq!(dfa.default_edge(1), None);
+        test_dfa(&mut dfa, "\"abc\"", true);
+        test_dfa(&mut dfa, "\"abc", false);
+    }
+
+    #[test]
+    fn test_is_subset() {
+        let a = Regex::char('a');
+        let b = Regex::char('b');
+
+        let pairs = [
+            (a.clone(), a.union(&b), true),
+            (a.union(&b), a.clone(), false),
+            (a.plus(), a.star(), true),
+            (a.star(), a.plus(), false),
+            (Regex::empty(), a.clone(), true),
+            (Regex::range('b', 'y'), Regex::range('a', 'z'), true),
+            (Regex::range('a', 'z'), Regex::range('b', 'y'), false),
+            (a.concat(&b), a.concat(&b.star()), true),
+        ];
+
+        for (lhs, rhs, expected) in pairs.iter() {
+            assert_eq!(
+                lhs.to_nfa().to_dfa().is_subset(&rhs.to_nfa().to_dfa()),
+                *expected,
+                "lhs: {:?}, rhs: {:?}",
+                lhs,
+                rhs
+            );
+        }
+    }
+
+    #[test]
+    fn test_symmetric_difference() {
+        let a = Regex::char('a');
+        let b = Regex::char('b');
+
+        let lhs = a.star().to_nfa().to_dfa();
+        let rhs = a.concat(&a).star().union(&b).to_nfa().to_dfa();
+
+        assert_eq!(
+            lhs.symmetric_difference(&rhs, 3),
+            vec![
+                Witness {
+                    input: "a".to_string(),
+                    accepted_by_self: true,
+                },
+                Witness {
+                    input: "b".to_string(),
+                    accepted_by_self: false,
+                },
+                Witness {
+                    input: "aaa".to_string(),
+                    accepted_by_self: true,
+                },
+            ]
+        );
+
+        assert!(lhs.symmetric_difference(&lhs.clone(), 3).is_empty());
+        assert_eq!(lhs.symmetric_difference(&rhs, 1).len(), 1);
+        assert_eq!(
+            Dfa::new().symmetric_difference(&lhs, 1),
+            vec![Witness {
+                input: "".to_string(),
+                accepted_by_self: false,
+            }]
+        );
+
+        // Every string of 40 `a`s and `b`s reaches the same state, so the search doesn't follow
+        // each of them.
+        let ab = a.union(&b);
+        let long = (0..40).fold(Regex::char('c'), |regex, _| ab.concat(&regex));
+        let witnesses = long.to_nfa().to_dfa().symmetric_difference(&Dfa::new(), 2);
+        let inputs = witnesses
+            .iter()
+            .map(|w| w.input.as_str())
+            .collect::<Vec<_>>();
+        let aaa = "a".repeat(40);
+        assert_eq!(
+            inputs,
+            vec![format!("{}c", aaa), format!("{}bc", &aaa[1..])]
+        );
+    }
+
+    #[test]
+    fn test_scan() {
+        let integer = Regex::range('0', '9').plus();
+        let float = integer.concat(&Regex::char('.')).concat(&integer);
+        let (integer, float) = (integer.to_nfa(), float.to_nfa());
+
+        let mut dfa = Dfa::from_nfas(&[&float, &integer]);
+
+        assert_eq!(dfa.scan("12.5+"), Some(Match { length: 4, tag: 0 }));
+        assert_eq!(dfa.scan("12.+"), Some(Match { length: 2, tag: 1 }));
+        assert_eq!(dfa.scan("7"), Some(Match { length: 1, tag: 1 }));
+        assert_eq!(dfa.scan(".5"), None);
+        assert_eq!(dfa.scan(""), None);
+
+        let mut dfa = Regex::char('λ').star().to_nfa().to_dfa();
+
+        assert_eq!(dfa.scan("λλx"), Some(Match { length: 4, tag: 0 }));
+        assert_eq!(dfa.scan("x"), Some(Match { length: 0, tag: 0 }));
+    }
+
+    #[test]
+    fn test_complement() {
+        let a = Regex::char('a');
+        let mut dfa = a.plus().to_nfa().to_dfa().complement();
+
+        test_dfa(&mut dfa, "", true);
+        test_dfa(&mut dfa, "a", false);
+        test_dfa(&mut dfa, "aaa", false);
+        test_dfa(&mut dfa, "ab", true);
+        test_dfa(&mut dfa, "b", true);
+
+        let mut dfa = Dfa::new().complement();
+
+        test_dfa(&mut dfa, "", true);
+        test_dfa(&mut dfa, "xyz", true);
+
+        let dfa = a.star().to_nfa().to_dfa();
+        assert!(dfa.complement().complement().equivalent(&dfa));
+    }
+
+    #[test]
+    fn test_difference() {
+        let lower = Regex::range('a', 'z').plus().to_nfa().to_dfa();
+        let keyword = Regex::char('i').concat(&Regex::char('f')).to_nfa().to_dfa();
+
+        let mut dfa = lower.difference(&keyword);
+
+        test_dfa(&mut dfa, "if", false);
+        test_dfa(&mut dfa, "i", true);
+        test_dfa(&mut dfa, "iff", true);
+        test_dfa(&mut dfa, "x", true);
+        test_dfa(&mut dfa, "", false);
+
+        assert!(keyword.difference(&lower).is_subset(&Dfa::new()));
+        assert!(lower.difference(&Dfa::new()).equivalent(&lower));
+    }
+
+    #[test]
+    fn test_intersection() {
+        let lower = Regex::range('a', 'z').plus().to_nfa().to_dfa();
+        let keyword = Regex::char('i').concat(&Regex::char('f'));
+        let tagged = Dfa::from_nfas(&[&keyword.optional().to_nfa()]);
+
+        let mut dfa = tagged.intersection(&lower);
+
+        test_dfa(&mut dfa, "if", true);
+        test_dfa(&mut dfa, "i", false);
+        test_dfa(&mut dfa, "", false);
+        test_dfa(&mut dfa, "iff", false);
+        assert_eq!(dfa.scan("if").unwrap().tag, 0);
+
+        assert!(lower.intersection(&Dfa::new()).is_subset(&Dfa::new()));
+        assert!(lower.intersection(&lower).equivalent(&lower));
+    }
+
+    #[test]
+    fn test_enumerate() {
+        let regex = Regex::char('a').concat(&Regex::one_of("bc").star());
+        let dfa = regex.to_nfa().to_dfa();
+
+        assert_eq!(
+            dfa.enumerate(3).collect::<Vec<_>>(),
+            vec!["a", "ab", "ac", "abb", "abc", "acb", "acc"]
+        );
+        assert_eq!(dfa.enumerate(0).count(), 0);
+
+        let dfa = Regex::char('x').optional().to_nfa().to_dfa();
+        assert_eq!(dfa.enumerate(5).collect::<Vec<_>>(), vec!["", "x"]);
+
+        let dfa = Regex::none_of("a").plus().to_nfa().to_dfa();
+        assert_eq!(
+            dfa.enumerate(2).take(3).collect::<Vec<_>>(),
+            vec!["\u{0}", "\u{1}", "\u{2}"]
+        );
+
+        assert_eq!(Dfa::new().enumerate(3).count(), 0);
+    }
+
+    #[test]
+    fn test_progress() {
+        let regex = Regex::one_of("ab").star().concat(&Regex::char('a'));
+        let nfa = regex.to_nfa();
+
+        let mut reports = vec![];
+        let mut dfa = Dfa::from_nfas_with_progress(&[&nfa], |progress| reports.push(progress));
+
+        assert_eq!(reports.len(), dfa.len());
+        assert_eq!(
+            reports.last(),
+            Some(&Progress::Determinize {
+                processed: dfa.len(),
+                frontier: 0,
+            })
+        );
+
+        reports.clear();
+        dfa.minimize_with_progress(|progress| reports.push(progress));
+
+        assert_eq!(
+            reports.last(),
+            Some(&Progress::Minimize {
+                rounds: reports.len(),
+                classes: dfa.len(),
+            })
+        );
+    }
+}
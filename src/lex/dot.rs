@@ -0,0 +1,134 @@
+use std::collections::BTreeMap;
+use std::io::{Result, Write};
+
+pub(crate) struct DotGraph<'a> {
+    pub name: &'a str,
+    pub states: usize,
+    pub start: Vec<usize>,
+    pub accept: Vec<usize>,
+    pub edges: BTreeMap<(usize, usize), Vec<(char, char)>>,
+    pub defaults: Vec<(usize, usize)>,
+    pub epsilons: Vec<(usize, usize)>,
+}
+
+pub(crate) fn write_dot<W, F>(graph: &DotGraph, label: F, mut io: W) -> Result<()>
+where
+    W: Write,
+    F: Fn(usize) -> String,
+{
+    writeln!(io, "digraph {} {{", graph.name)?;
+    writeln!(io, "  rankdir=LR;")?;
+
+    for state in 0..graph.states {
+        writeln!(
+            io,
+            "  {} [label=\"{}\", shape=circle];",
+            state,
+            escape(&label(state))
+        )?;
+    }
+
+    writeln!(io, "  _start_point [shape=point];")?;
+    for state in graph.start.iter() {
+        writeln!(io, "  _start_point -> {};", state)?;
+    }
+
+    if !graph.accept.is_empty() {
+        writeln!(io, "  _accept_point [shape=point, style=invis];")?;
+    }
+    for state in graph.accept.iter() {
+        writeln!(io, "  {} [shape=doublecircle];", state)?;
+        writeln!(io, "  {} -> _accept_point [style=invis];", state)?;
+    }
+
+    for ((from, to), ranges) in graph.edges.iter() {
+        writeln!(
+            io,
+            "  {} -> {} [label=\"{}\"];",
+            from,
+            to,
+            escape(&format_ranges(ranges))
+        )?;
+    }
+
+    for (from, to) in graph.defaults.iter() {
+        writeln!(
+            io,
+            "  {} -> {} [label=\"otherwise\", style=dashed];",
+            from, to
+        )?;
+    }
+
+    for (from, to) in graph.epsilons.iter() {
+        writeln!(io, "  {} -> {} [style=dotted];", from, to)?;
+    }
+
+    writeln!(io, "}}")?;
+
+    Ok(())
+}
+
+// A single char prints bare; anything else prints as a bracketed class such as `[a-z_]`.
+pub(crate) fn format_ranges(ranges: &[(char, char)]) -> String {
+    if let [(lo, hi)] = ranges {
+        if lo == hi {
+            return format_char(*lo);
+        }
+    }
+
+    let mut label = String::from("[");
+
+    for &(lo, hi) in ranges.iter() {
+        label.push_str(&format_char(lo));
+
+        if lo != hi {
+            label.push('-');
+            label.push_str(&format_char(hi));
+        }
+    }
+
+    label.push(']');
+
+    label
+}
+
+fn format_char(c: char) -> String {
+    match c {
+        '\n' => "\\n".to_string(),
+        '\r' => "\\r".to_string(),
+        '\t' => "\\t".to_string(),
+        '\\' | '-' | '[' | ']' => format!("\\{}", c),
+        c if c.is_ascii_graphic() || (!c.is_ascii() && c.is_alphanumeric()) => c.to_string(),
+        c => format!("U+{:04X}", c as u32),
+    }
+}
+
+pub(crate) fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_format_ranges() {
+        assert_eq!(format_ranges(&[('a', 'a')]), "a");
+        assert_eq!(format_ranges(&[('a', 'z')]), "[a-z]");
+        assert_eq!(format_ranges(&[('a', 'z'), ('_', '_')]), "[a-z_]");
+        assert_eq!(format_ranges(&[('\n', '\n')]), "\\n");
+        assert_eq!(format_ranges(&[(' ', ' ')]), "U+0020");
+        assert_eq!(format_ranges(&[('é', 'é')]), "é");
+        assert_eq!(format_ranges(&[('-', '-'), (']', ']')]), "[\\-\\]]");
+        assert_eq!(
+            format_ranges(&[(char::MIN, char::MAX)]),
+            "[U+0000-U+10FFFF]"
+        );
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("\"a\""), "\\\"a\\\"");
+        assert_eq!(escape("\\n"), "\\\\n");
+    }
+}
@@ -0,0 +1,254 @@
+use std::fmt::Debug;
+use std::fs;
+use std::hash::Hash;
+use std::io;
+use std::path::Path;
+
+use crate::lex::lexer::Lexer;
+
+// A lexer conformance test loaded from a `.lex` file. The file holds the input text, a line
+// containing only `---`, and then one expected lexeme per line as `Kind position length`, where
+// `Kind` is the token's `Debug` output and the position and length are in bytes. Blank lines and
+// lines starting with `#` after the separator are ignored.
+//
+//     (foo)
+//     ---
+//     LParen 0 1
+//     Identifier 1 3
+//     RParen 4 1
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fixture {
+    pub name: String,
+    pub input: String,
+    pub expected: Vec<ExpectedLexeme>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExpectedLexeme {
+    pub kind: String,
+    pub position: usize,
+    pub length: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FixtureFailure {
+    pub name: String,
+    pub message: String,
+}
+
+impl Fixture {
+    pub fn parse(name: &str, text: &str) -> io::Result<Fixture> {
+        let invalid = |line: usize, message: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}:{}: {}", name, line + 1, message),
+            )
+        };
+
+        let lines = text.split('\n').collect::<Vec<_>>();
+        let Some(separator) = lines.iter().position(|line| line.trim_end() == "---") else {
+            return Err(invalid(lines.len() - 1, "missing `---` separator"));
+        };
+
+        let mut expected = vec![];
+
+        for (i, line) in lines.iter().enumerate().skip(separator + 1) {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let [kind, position, length] = fields[..] else {
+                return Err(invalid(i, "expected `Kind position length`"));
+            };
+
+            expected.push(ExpectedLexeme {
+                kind: kind.to_string(),
+                position: position
+                    .parse()
+                    .map_err(|_| invalid(i, "invalid position"))?,
+                length: length.parse().map_err(|_| invalid(i, "invalid length"))?,
+            });
+        }
+
+        Ok(Fixture {
+            name: name.to_string(),
+            input: lines[..separator].join("\n"),
+            expected,
+        })
+    }
+
+    // Lex the input and compare the lexemes with the expected ones, returning a description of
+    // the first difference.
+    pub fn run<M, T>(&self, lexer: &mut Lexer<M, T>) -> Result<(), FixtureFailure>
+    where
+        T: Clone + Debug,
+        M: Copy + Debug + Eq + Hash + Default,
+    {
+        let fail = |message: String| {
+            Err(FixtureFailure {
+                name: self.name.clone(),
+                message,
+            })
+        };
+
+        let mut actual = vec![];
+        for lexeme in lexer.lex_str(&self.input) {
+            match lexeme {
+                Ok(lexeme) => actual.push(ExpectedLexeme {
+                    kind: format!("{:?}", lexeme.token),
+                    position: lexeme.position,
+                    length: lexeme.length,
+                }),
+                Err(error) => return fail(format!("lexer error: {}", error)),
+            }
+        }
+
+        for (i, (actual, expected)) in actual.iter().zip(self.expected.iter()).enumerate() {
+            if actual != expected {
+                return fail(format!(
+                    "lexeme {}: expected {:?}, got {:?}",
+                    i, expected, actual
+                ));
+            }
+        }
+
+        if actual.len() != self.expected.len() {
+            return fail(format!(
+                "expected {} lexemes, got {}",
+                self.expected.len(),
+                actual.len()
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+// Every `.lex` file in `dir`, sorted by file name.
+pub fn load_fixtures(dir: &Path) -> io::Result<Vec<Fixture>> {
+    let mut paths = vec![];
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "lex") {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    paths
+        .iter()
+        .map(|path| {
+            let name = path.file_name().unwrap().to_string_lossy();
+            Fixture::parse(&name, &fs::read_to_string(path)?)
+        })
+        .collect()
+}
+
+// Load every fixture in `dir` and run it against `lexer`, returning the failures.
+pub fn check_fixtures<M, T>(lexer: &mut Lexer<M, T>, dir: &Path) -> io::Result<Vec<FixtureFailure>>
+where
+    T: Clone + Debug,
+    M: Copy + Debug + Eq + Hash + Default,
+{
+    Ok(load_fixtures(dir)?
+        .iter()
+        .filter_map(|fixture| fixture.run(lexer).err())
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lex::regex::Regex;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+    enum Mode {
+        #[default]
+        Default,
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Token {
+        Word,
+        Space,
+    }
+
+    fn word_lexer() -> Lexer<Mode, Token> {
+        let mut lexer = Lexer::new();
+        lexer
+            .def_mut()
+            .with_rule(
+                Token::Word,
+                &Regex::range('a', 'z').plus(),
+                Mode::Default,
+                Mode::Default,
+                false,
+            )
+            .with_rule(
+                Token::Space,
+                &Regex::one_of(" \n").plus(),
+                Mode::Default,
+                Mode::Default,
+                false,
+            );
+        lexer
+    }
+
+    #[test]
+    fn test_parse_fixture() {
+        let fixture =
+            Fixture::parse("a.lex", "ab\ncd\n---\n# comment\nWord 0 2\n\nSpace 2 1\n").unwrap();
+
+        assert_eq!(fixture.input, "ab\ncd");
+        assert_eq!(
+            fixture.expected,
+            vec![
+                ExpectedLexeme {
+                    kind: "Word".to_string(),
+                    position: 0,
+                    length: 2,
+                },
+                ExpectedLexeme {
+                    kind: "Space".to_string(),
+                    position: 2,
+                    length: 1,
+                },
+            ]
+        );
+
+        let error = Fixture::parse("b.lex", "ab\n---\nWord 0\n").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "b.lex:3: expected `Kind position length`"
+        );
+        assert!(Fixture::parse("c.lex", "ab\n").is_err());
+    }
+
+    #[test]
+    fn test_check_fixtures() {
+        let dir = std::env::temp_dir().join(format!("turkey-fixtures-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        fs::write(
+            dir.join("1-pass.lex"),
+            "ab cd\n---\nWord 0 2\nSpace 2 1\nWord 3 2\n",
+        )
+        .unwrap();
+        fs::write(dir.join("2-fail.lex"), "ab\n---\nSpace 0 2\n").unwrap();
+        fs::write(dir.join("3-error.lex"), "a1\n---\nWord 0 1\n").unwrap();
+        fs::write(dir.join("ignored.txt"), "not a fixture").unwrap();
+
+        let failures = check_fixtures(&mut word_lexer(), &dir);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let names = failures
+            .unwrap()
+            .into_iter()
+            .map(|failure| failure.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["2-fail.lex", "3-error.lex"]);
+    }
+}
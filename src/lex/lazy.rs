@@ -0,0 +1,209 @@
+use std::collections::HashMap;
+
+use crate::lex::nfa::Nfa;
+
+// A DFA over several NFAs that is built while it runs instead of up front. Each state is a set
+// of `(nfa, node)` pairs, tagged like `Dfa::from_nfas` with the lowest accepting NFA index.
+// The states built so far live in a `LazyCache` that belongs to whoever is running the DFA, so
+// the DFA itself never changes and can be shared. The cache holds at most `capacity` states,
+// and like the `regex` crate's lazy DFA, it is cleared when it fills up and built again from the
+// current state. That keeps a step to one hash of a char, with no bookkeeping to find what to
+// evict.
+#[derive(Debug, Clone)]
+pub struct LazyDfa {
+    nfas: Vec<Nfa>,
+    capacity: usize,
+}
+
+// The states of a `LazyDfa` built so far and their transitions. State ids are only valid until
+// the next clear, which can happen on any step; the state a step returns is always valid.
+#[derive(Debug, Default, Clone)]
+pub struct LazyCache {
+    ids: HashMap<Vec<(usize, usize)>, usize>,
+    states: Vec<LazyState>,
+    clears: usize,
+}
+
+#[derive(Debug, Clone)]
+struct LazyState {
+    set: Vec<(usize, usize)>,
+    accept: Option<usize>,
+    transitions: HashMap<char, Option<usize>>,
+}
+
+impl LazyDfa {
+    pub fn new(nfas: &[&Nfa], capacity: usize) -> LazyDfa {
+        let nfas = nfas
+            .iter()
+            .map(|&nfa| {
+                let mut nfa = nfa.clone();
+                nfa.reset();
+                nfa
+            })
+            .collect();
+
+        LazyDfa {
+            nfas,
+            capacity: capacity.max(1),
+        }
+    }
+
+    pub fn start(&self, cache: &mut LazyCache) -> Option<usize> {
+        let mut start = self
+            .nfas
+            .iter()
+            .enumerate()
+            .flat_map(|(i, nfa)| nfa.start_nodes().iter().map(move |&node| (i, node)))
+            .collect::<Vec<_>>();
+        start.sort_unstable();
+        start.dedup();
+
+        self.state(cache, start)
+    }
+
+    pub fn next_state(&self, cache: &mut LazyCache, from: usize, c: char) -> Option<usize> {
+        if let Some(&to) = cache.states[from].transitions.get(&c) {
+            return to;
+        }
+
+        let mut target = vec![];
+        let mut nodes = vec![];
+        for &(i, node) in cache.states[from].set.iter() {
+            nodes.clear();
+            self.nfas[i].step_node(node, c, &mut nodes);
+            target.extend(nodes.iter().map(|&to| (i, to)));
+        }
+        target.sort_unstable();
+        target.dedup();
+
+        // If building the target cleared the cache, `from` is gone and there's nothing to record
+        // the transition in.
+        let clears = cache.clears;
+        let to = self.state(cache, target);
+        if cache.clears == clears {
+            cache.states[from].transitions.insert(c, to);
+        }
+
+        to
+    }
+
+    // The id of the state for `set`, building it if it is not cached. An empty set is the dead
+    // state, which is never cached.
+    fn state(&self, cache: &mut LazyCache, set: Vec<(usize, usize)>) -> Option<usize> {
+        if set.is_empty() {
+            return None;
+        }
+
+        if let Some(&id) = cache.ids.get(&set) {
+            return Some(id);
+        }
+
+        if cache.states.len() >= self.capacity {
+            cache.ids.clear();
+            cache.states.clear();
+            cache.clears += 1;
+        }
+
+        let accept = set
+            .iter()
+            .filter(|&&(i, node)| self.nfas[i].accepts_node(node))
+            .map(|&(i, _)| i)
+            .min();
+
+        let id = cache.states.len();
+        cache.ids.insert(set.clone(), id);
+        cache.states.push(LazyState {
+            set,
+            accept,
+            transitions: HashMap::new(),
+        });
+
+        Some(id)
+    }
+}
+
+impl LazyCache {
+    pub fn new() -> LazyCache {
+        LazyCache::default()
+    }
+
+    // Number of states currently cached.
+    pub fn len(&self) -> usize {
+        self.states.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.states.is_empty()
+    }
+
+    // Number of times the cache has filled up and been cleared.
+    pub fn clears(&self) -> usize {
+        self.clears
+    }
+
+    pub fn tag(&self, state: usize) -> Option<usize> {
+        self.states[state].accept
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lex::dfa::Dfa;
+    use crate::lex::regex::Regex;
+
+    #[test]
+    fn test_lazy_dfa() {
+        let integer = Regex::range('0', '9').plus();
+        let float = integer.concat(&Regex::char('.')).concat(&integer);
+        let word = Regex::range('a', 'z').plus();
+        let nfas = [float.to_nfa(), integer.to_nfa(), word.to_nfa()];
+        let nfas = nfas.iter().collect::<Vec<_>>();
+
+        let mut dfa = Dfa::from_nfas(&nfas);
+        let lazy = LazyDfa::new(&nfas, 2);
+        let mut cache = LazyCache::new();
+
+        for s in ["", "1", "12.5", "12.", "abc", "a1", ".5", "123456.789"] {
+            dfa.reset();
+            let mut state = lazy.start(&mut cache);
+
+            for c in s.chars() {
+                dfa.put(c);
+                state = state.and_then(|state| lazy.next_state(&mut cache, state, c));
+                assert_eq!(state.is_none(), dfa.is_dead(), "s: {:?}", s);
+                assert_eq!(
+                    state.and_then(|state| cache.tag(state)),
+                    dfa.accept_tag(),
+                    "s: {:?}",
+                    s
+                );
+            }
+        }
+
+        assert!(cache.len() <= 2);
+        assert!(cache.clears() > 0);
+    }
+
+    #[test]
+    fn test_lazy_dfa_cache() {
+        let nfa = Regex::char('a').star().to_nfa();
+        let lazy = LazyDfa::new(&[&nfa], 16);
+        let mut cache = LazyCache::new();
+
+        let mut state = lazy.start(&mut cache).unwrap();
+        for _ in 0..100 {
+            state = lazy.next_state(&mut cache, state, 'a').unwrap();
+        }
+
+        assert_eq!(cache.tag(state), Some(0));
+        assert!(cache.len() <= 2);
+        assert_eq!(cache.clears(), 0);
+        assert_eq!(lazy.next_state(&mut cache, state, 'b'), None);
+
+        // Another cache starts from nothing.
+        let mut other = LazyCache::new();
+        assert_eq!(lazy.start(&mut other), Some(0));
+        assert_eq!(other.len(), 1);
+    }
+}
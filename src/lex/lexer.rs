@@ -1,27 +1,203 @@
 use std::collections::{HashMap, VecDeque};
-use std::fmt::Debug;
+use std::error::Error;
+use std::fmt::{self, Debug};
 use std::hash::Hash;
+use std::io::{self, Read};
+use std::ops::{Deref, Range};
+use std::sync::{Arc, OnceLock};
 
-use crate::lex::nfa::Nfa;
+use crate::cancel::{CancelToken, Cancelled};
+use crate::input::Decoder;
+use crate::lex::automaton::{Automaton, AutomatonState};
+use crate::lex::dfa::{Dfa, Witness};
+use crate::lex::nfa::{Nfa, NfaCursor};
 use crate::lex::regex::Regex;
+use crate::lex::scanner::{ScannerMode, ScannerState};
 
-pub struct Lexer<M, T> {
+// The compiled rules of a lexer, grouped by mode. Lexing never changes a definition, so one can
+// be shared, e.g. behind an `Arc`, by any number of `LexerRun`s, each lexing its own input.
+//
+// Each mode's rules are compiled into a single minimized DFA the first time a run needs them,
+// so lexing costs one transition per character however many rules there are. A mode whose DFA
+// would need more than the DFA budget's states builds its states lazily from the rules' NFAs as
+// the input reaches them instead, caching at most the budget's worth.
+// `compile` does all of that up front, and clones keep what has been compiled, so a definition
+// built once can be cloned into a lexer per input without compiling its rules again.
+#[derive(Clone)]
+pub struct LexerDef<M, T> {
     modes: Vec<Vec<Rule<T>>>,
     mode_indices: HashMap<M, usize>,
     mode_names: HashMap<usize, M>,
+    compiled: Vec<OnceLock<CompiledMode>>,
+    dfa_budget: Option<usize>,
 
     start_mode: usize,
+    recovery: Option<T>,
+    layout: Option<Layout<T>>,
+}
+
+pub const DEFAULT_DFA_BUDGET: usize = 10_000;
+
+// Lexemes are routed to channels by their rule. `get` and `tokens` only return lexemes on the
+// default channel, so a parser never sees e.g. comments moved to the hidden channel, while a
+// formatter can still collect them with `get_channel`.
+pub const DEFAULT_CHANNEL: usize = 0;
+pub const HIDDEN_CHANNEL: usize = 1;
+
+// The rules of one mode as one automaton. The rules are ordered by priority before compiling, as
+// the automaton prefers lower tags, so `rules` maps its tags back to rule indices. Runs step the
+// automaton with states of their own, so it is shared rather than copied.
+#[derive(Debug, Clone)]
+struct CompiledMode {
+    automaton: Arc<Automaton>,
+    rules: Vec<usize>,
+}
+
+// One input being lexed against a `LexerDef`. A run from `LexerDef::run` borrows the definition
+// and creating one copies nothing from it. A `Lexer` owns its definition instead, so that rules can
+// be added to it through `def_mut`; the run picks them up from its next token.
+pub struct LexerRun<'d, M, T> {
+    def: RunDef<'d, M, T>,
+    state: RunState<T>,
+    document: Option<Document<T>>,
+}
+
+enum RunDef<'d, M, T> {
+    Borrowed(&'d LexerDef<M, T>),
+    Owned(LexerDef<M, T>),
+}
+
+// Everything needed to put a lexer back the way it was: its mode, where it is in the input, the
+// input it has been given but not yet turned into lexemes, and the lexemes not yet taken.
+#[derive(Debug, Clone)]
+pub struct Checkpoint<T> {
+    mode: usize,
+    mode_stack: Vec<usize>,
+    input: VecDeque<char>,
+    cursor: usize,
+    cursor_offset: usize,
+    position: usize,
+    last_accepted: Option<(usize, usize)>,
+    output: VecDeque<Lexeme<T>>,
+    channels: HashMap<usize, VecDeque<Lexeme<T>>>,
+    error: Option<LexerError<T>>,
+    errors: Vec<LexerError<T>>,
+    skipped: Option<(usize, String)>,
+    layout: LayoutState,
+    nested: Option<(Lexeme<T>, usize)>,
+    heredoc: Option<Heredoc<T>>,
+}
+
+// The tokens `LexerDef::set_layout` makes up. Tabs indent to the next multiple of `tab_width`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Layout<T> {
+    pub newline: T,
+    pub indent: T,
+    pub dedent: T,
+    pub tab_width: usize,
+}
+
+// Where lexing is in the layout of the input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct LayoutState {
+    // The indentation of every open block.
+    indents: Vec<usize>,
+    // Whether a lexeme has been output yet, and whether a newline has been read since the last.
+    started: bool,
+    new_line: bool,
+    // The indentation of the current line so far, and whether its leading whitespace is still
+    // being read.
+    indent: usize,
+    leading: bool,
+}
+
+impl LayoutState {
+    fn new() -> Self {
+        LayoutState {
+            indents: vec![],
+            started: false,
+            new_line: true,
+            indent: 0,
+            leading: true,
+        }
+    }
+}
+
+// The lexeme of a rule added with `add_heredoc_rule` so far, while looking for its terminator.
+#[derive(Debug, Clone)]
+struct Heredoc<T> {
+    lexeme: Lexeme<T>,
+    terminator: Vec<char>,
+    skip: bool,
+    channel: usize,
+}
+
+// A whole input kept with its lexemes so that it can be edited and relexed in place.
+struct Document<T> {
+    text: String,
+    lexemes: Vec<Lexeme<T>>,
+    starts: Vec<TokenStart<T>>,
+}
+
+// The lexer's state where a token started, and how far lexing that token read. Lexing from a
+// token start depends only on its mode and mode stack and the text from there on. `scan_end` is
+// `usize::MAX` if the token was ended by the end of the input.
+#[derive(Debug, Clone)]
+struct TokenStart<T> {
+    position: usize,
+    mode: usize,
+    mode_stack: Vec<usize>,
+    // Number of lexemes before this one.
+    lexemes: usize,
+    scan_end: usize,
+    // Part way through a run of skipped characters, which carries over to the next token.
+    skipping: bool,
+    layout: LayoutState,
+    nested: Option<(Lexeme<T>, usize)>,
+    heredoc: Option<Heredoc<T>>,
+}
+
+// A definition together with a single run over it, for when inputs are lexed one at a time.
+pub type Lexer<M, T> = LexerRun<'static, M, T>;
+
+// Everything that changes while lexing. `automata` holds the automaton of each mode entered so
+// far, shared with the definition, and where lexing is in it.
+struct RunState<T> {
+    automata: Vec<Option<(Arc<Automaton>, AutomatonState)>>,
+
     current_mode: usize,
+    mode_stack: Vec<usize>,
 
     input: VecDeque<char>,
+    // Characters of `input` fed to the current mode's automaton, and their length in bytes.
     cursor: usize,
+    cursor_offset: usize,
+    // Byte offset of the start of `input`.
     position: usize,
     last_accepted: Option<(usize, usize)>,
 
     output: VecDeque<Lexeme<T>>,
-    error: Option<LexerError>,
+    // Lexemes on channels other than the default one.
+    channels: HashMap<usize, VecDeque<Lexeme<T>>>,
+    error: Option<LexerError<T>>,
+
+    errors: Vec<LexerError<T>>,
+    skipped: Option<(usize, String)>,
+    layout: LayoutState,
+    // The lexeme of a nested rule so far, and the depth of the mode stack it ends at.
+    nested: Option<(Lexeme<T>, usize)>,
+    heredoc: Option<Heredoc<T>>,
+
+    tracking: Option<Tracking>,
+    trace: Option<Trace<T>>,
+    cancel: Option<CancelToken>,
+
+    // Every token start, recorded while a document is lexed, and the lexemes output so far.
+    starts: Option<Vec<TokenStart<T>>>,
+    emitted: usize,
 }
 
+// `position` and `length` are in bytes, so the lexeme's text is `&input[lexeme.extent()]`.
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Lexeme<T> {
     pub token: T,
@@ -30,292 +206,2981 @@ pub struct Lexeme<T> {
     pub span: Option<String>,
 }
 
-#[derive(Debug, Clone)]
-pub struct LexerError {
-    pub message: String,
-    pub position: usize,
+impl<T> Lexeme<T> {
+    pub fn extent(&self) -> Span {
+        Span {
+            start: self.position,
+            end: self.position + self.length,
+        }
+    }
 }
 
-pub struct Rule<T> {
-    token: T,
-    nfa: Nfa,
-    mode_to: usize,
-    keep_span: bool,
+// The bytes `start..end` of the input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
 }
 
-impl<M, T> Lexer<M, T>
-where
-    T: Clone + Debug,
-    M: Copy + Debug + Eq + Hash + Default,
-{
-    pub fn new() -> Self {
-        Lexer {
-            modes: vec![],
-            mode_indices: HashMap::new(),
-            mode_names: HashMap::new(),
-            start_mode: 0,
-            current_mode: 0,
-            input: VecDeque::new(),
-            cursor: 0,
-            position: 0,
-            last_accepted: None,
-            output: VecDeque::new(),
-            error: None,
-        }
+impl Span {
+    pub fn len(&self) -> usize {
+        self.end - self.start
     }
 
-    fn get_mode_index(&mut self, mode: M) -> usize {
-        *self.mode_indices.entry(mode).or_insert_with(|| {
-            let index = self.modes.len();
-            self.modes.push(vec![]);
-            self.mode_names.insert(index, mode);
-            index
-        })
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
     }
 
-    pub fn set_start_mode(&mut self, mode: M) {
-        self.start_mode = self.get_mode_index(mode);
+    pub fn range(&self) -> Range<usize> {
+        self.start..self.end
     }
+}
 
-    pub fn add_rule(&mut self, token: T, regex: &Regex, mode_from: M, mode_to: M, keep_span: bool) {
-        let mode_from = self.get_mode_index(mode_from);
-        let mode_to = self.get_mode_index(mode_to);
-        let nfa = regex.to_nfa();
-        self.modes[mode_from].push(Rule {
-            token,
-            nfa,
-            mode_to,
-            keep_span,
-        });
+impl From<Span> for Range<usize> {
+    fn from(span: Span) -> Range<usize> {
+        span.range()
     }
+}
 
-    pub fn with_rule(
-        &mut self,
+// Why lexing stopped, or, with recovery on, what was skipped. Positions are byte offsets from
+// the start of the input. `alive` lists the rules that could still have matched had the input
+// gone on differently, in the order they were added.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LexerError<T> {
+    // `c` at `position` can't continue any rule's match.
+    UnexpectedChar {
+        position: usize,
+        c: char,
+        alive: Vec<T>,
+    },
+    // The input ended part way through a lexeme.
+    UnexpectedEof {
+        position: usize,
+        alive: Vec<T>,
+    },
+    // A `token` lexeme at `position` popped an empty mode stack.
+    ModeUnderflow {
+        position: usize,
         token: T,
-        regex: &Regex,
-        mode_from: M,
-        mode_to: M,
-        keep_span: bool,
-    ) -> &mut Self {
-        self.add_rule(token, regex, mode_from, mode_to, keep_span);
-        self
+    },
+    // The cancel token was cancelled before the character at `position`.
+    Cancelled {
+        position: usize,
+    },
+    // With a layout, the line starting at `position` is indented less than the line before, but
+    // not as little as any enclosing block.
+    InconsistentIndent {
+        position: usize,
+    },
+}
+
+impl<T> LexerError<T> {
+    fn position_mut(&mut self) -> &mut usize {
+        match self {
+            LexerError::UnexpectedChar { position, .. }
+            | LexerError::UnexpectedEof { position, .. }
+            | LexerError::ModeUnderflow { position, .. }
+            | LexerError::Cancelled { position }
+            | LexerError::InconsistentIndent { position } => position,
+        }
     }
 
-    pub fn reset(&mut self) {
-        self.current_mode = self.start_mode;
-        self.cursor = 0;
-        self.position = 0;
-        self.input.clear();
-        self.last_accepted = None;
-        self.output.clear();
-        self.error = None;
+    pub fn position(&self) -> usize {
+        match *self {
+            LexerError::UnexpectedChar { position, .. }
+            | LexerError::UnexpectedEof { position, .. }
+            | LexerError::ModeUnderflow { position, .. }
+            | LexerError::Cancelled { position }
+            | LexerError::InconsistentIndent { position } => position,
+        }
     }
+}
 
-    pub fn put(&mut self, c: char) {
-        if self.is_error() {
-            return;
+impl<T> fmt::Display for LexerError<T>
+where
+    T: Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let alive = match self {
+            LexerError::UnexpectedChar { position, c, alive } => {
+                write!(f, "unexpected {:?} at {}", c, position)?;
+                alive
+            }
+            LexerError::UnexpectedEof { position, alive } => {
+                write!(f, "unexpected end of input at {}", position)?;
+                alive
+            }
+            LexerError::ModeUnderflow { position, token } => {
+                return write!(f, "{:?} at {} pops an empty mode stack", token, position);
+            }
+            LexerError::Cancelled { position } => {
+                return write!(f, "{} at {}", Cancelled, position);
+            }
+            LexerError::InconsistentIndent { position } => {
+                return write!(f, "inconsistent indentation at {}", position);
+            }
+        };
+
+        for (i, token) in alive.iter().enumerate() {
+            let separator = if i == 0 { ", expected one of" } else { "," };
+            write!(f, "{} {:?}", separator, token)?;
         }
 
-        self.input.push_back(c);
-        self.lex();
+        Ok(())
     }
+}
 
-    pub fn finish(&mut self) {
-        if self.is_error() {
-            return;
+impl<T> Error for LexerError<T> where T: Debug {}
+
+// What a rule does to the lexer's mode once it matches. `Push` remembers the current mode on a
+// stack so that a later `Pop` can return to it, which is what nested constructs like block
+// comments or string interpolations need. Popping an empty stack is an error, except with `PopOr`,
+// which switches to its mode instead; a closing bracket that may or may not end an interpolation
+// uses it so that unbalanced brackets outside one still lex.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ModeAction<M> {
+    Stay,
+    Switch(M),
+    Push(M),
+    Pop,
+    PopOr(M),
+}
+
+impl ModeAction<usize> {
+    // The mode to lex in next, given the mode the rule matched in, or `None` if the stack
+    // underflowed.
+    pub(crate) fn apply(self, current: usize, stack: &mut Vec<usize>) -> Option<usize> {
+        match self {
+            ModeAction::Stay => Some(current),
+            ModeAction::Switch(mode) => Some(mode),
+            ModeAction::Push(mode) => {
+                stack.push(current);
+                Some(mode)
+            }
+            ModeAction::Pop => stack.pop(),
+            ModeAction::PopOr(mode) => Some(stack.pop().unwrap_or(mode)),
         }
+    }
+}
 
-        self.emit();
+type Normalizer = Arc<dyn Fn(&str) -> String + Send + Sync>;
+type Builder<T> = Arc<dyn Fn(&str, Span) -> T + Send + Sync>;
+type Terminator = Arc<dyn Fn(&str) -> String + Send + Sync>;
 
-        if self.cursor < self.input.len() {
-            self.error = Some(LexerError {
-                message: "unexpected end of input".to_string(),
-                position: self.cursor,
-            });
+// Everything about a rule besides its token, regex and mode, for `add_rule_with_options`. The
+// defaults are those of `add_rule` with `ModeAction::Stay`.
+pub struct RuleOptions<M, T> {
+    action: ModeAction<M>,
+    keep_span: bool,
+    skip: bool,
+    priority: i32,
+    builder: Option<Builder<T>>,
+    context: Option<Regex>,
+}
+
+impl<M, T> RuleOptions<M, T> {
+    pub fn new() -> Self {
+        RuleOptions {
+            action: ModeAction::Stay,
+            keep_span: false,
+            skip: false,
+            priority: 0,
+            builder: None,
+            context: None,
         }
     }
 
-    pub fn get(&mut self) -> Option<Lexeme<T>> {
-        self.output.pop_front()
+    // What the rule does to the lexer's mode once it matches.
+    pub fn set_action(&mut self, action: ModeAction<M>) {
+        self.action = action;
     }
 
-    pub fn is_error(&self) -> bool {
-        self.get_error().is_some()
+    pub fn with_action(&mut self, action: ModeAction<M>) -> &mut Self {
+        self.set_action(action);
+        self
     }
 
-    pub fn get_error(&self) -> Option<&LexerError> {
-        self.error.as_ref()
+    // Whether the rule's lexemes keep the text they matched.
+    pub fn set_keep_span(&mut self, keep_span: bool) {
+        self.keep_span = keep_span;
     }
 
-    fn lex(&mut self) {
-        let mut all_dead = true;
-        let mut last_accepted = None;
-        while self.cursor < self.input.len() {
-            if self.is_error() {
-                return;
-            }
-            let c = self.input[self.cursor];
+    pub fn with_keep_span(&mut self, keep_span: bool) -> &mut Self {
+        self.set_keep_span(keep_span);
+        self
+    }
 
-            for (i, rule) in self.modes[self.current_mode].iter_mut().enumerate() {
-                rule.nfa.put(c);
-                all_dead &= rule.nfa.is_dead();
+    // Whether the rule's matches are consumed without producing a lexeme, as with
+    // `add_skip_rule`.
+    pub fn set_skip(&mut self, skip: bool) {
+        self.skip = skip;
+    }
 
-                if rule.nfa.is_accept() {
-                    last_accepted = Some((i, self.cursor + 1));
-                }
-            }
+    pub fn with_skip(&mut self, skip: bool) -> &mut Self {
+        self.set_skip(skip);
+        self
+    }
 
-            if all_dead {
-                self.emit();
-                continue;
-            }
+    // Lexing always takes the longest match. When several rules match that same longest input,
+    // the one with the highest `priority` wins, and among equal priorities the one added first.
+    pub fn set_priority(&mut self, priority: i32) {
+        self.priority = priority;
+    }
 
-            if last_accepted.is_some() {
-                self.last_accepted = last_accepted;
-            }
+    pub fn with_priority(&mut self, priority: i32) -> &mut Self {
+        self.set_priority(priority);
+        self
+    }
 
-            self.cursor += 1;
-        }
+    // Make the rule's lexemes get their token from `builder`, called with the matched text, after
+    // any normalizer, and where it is. This lets tokens carry values parsed from the text, like
+    // `Token::Integer(BigInt)`. The rule's own token stands for it wherever a single token is
+    // needed, e.g. in `ambiguities` and errors.
+    pub fn set_builder<F>(&mut self, builder: F)
+    where
+        F: Fn(&str, Span) -> T + Send + Sync + 'static,
+    {
+        self.builder = Some(Arc::new(builder));
     }
 
-    fn emit(&mut self) {
-        if self.last_accepted.is_none() {
-            self.error = Some(LexerError {
-                message: format!(
-                    "mode: {:?}, input: {:?}, cursor: {:?}",
-                    self.current_mode, self.input, self.cursor
-                ),
-                position: self.cursor,
-            });
-            return;
-        }
+    pub fn with_builder<F>(&mut self, builder: F) -> &mut Self
+    where
+        F: Fn(&str, Span) -> T + Send + Sync + 'static,
+    {
+        self.set_builder(builder);
+        self
+    }
 
-        let (rule, length) = self.last_accepted.unwrap();
+    // Make the rule a flex style `regex / context` rule: it only matches its regex when `context`
+    // follows, and the lexeme ends where the regex does, leaving the context to be lexed again.
+    // The context counts towards the length of the match when choosing the longest one. If the
+    // regex could end in more than one place, the lexeme is as long as possible. The regex must
+    // not match the empty string.
+    pub fn set_context(&mut self, context: &Regex) {
+        self.context = Some(context.clone());
+    }
 
-        let token = self.modes[self.current_mode][rule].token.clone();
-        let position = self.position;
-        let span = if self.modes[self.current_mode][rule].keep_span {
-            Some(
-                self.input
-                    .iter()
-                    .take(length)
-                    .collect::<String>()
-                    .to_string(),
-            )
-        } else {
-            None
+    pub fn with_context(&mut self, context: &Regex) -> &mut Self {
+        self.set_context(context);
+        self
+    }
+}
+
+impl<M, T> Default for RuleOptions<M, T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone)]
+pub struct Rule<T> {
+    token: T,
+    nfa: Nfa,
+    action: ModeAction<usize>,
+    keep_span: bool,
+    skip: bool,
+    channel: usize,
+    priority: i32,
+    normalizer: Option<Normalizer>,
+    builder: Option<Builder<T>>,
+    // Sorted by text, for looking up matched text with a binary search.
+    keywords: Vec<(String, T)>,
+    // The head and trailing context of a rule added with `RuleOptions::set_context`, whose `nfa`
+    // matches both.
+    context: Option<(Nfa, Nfa)>,
+    // Part of a rule added with `add_nested_rule`, whose lexemes are joined into one.
+    nested: bool,
+    terminator: Option<Terminator>,
+}
+
+impl<T> Rule<T> {
+    // How much of a match of the first `length` characters of `input` is the lexeme. For a
+    // trailing context rule that is the longest non-empty prefix the head matches with the rest
+    // matched by the context.
+    fn lexeme_length(&self, input: &VecDeque<char>, length: usize) -> usize {
+        let Some((head, context)) = self.context.as_ref() else {
+            return length;
         };
 
-        self.output.push_back(Lexeme {
-            token,
-            position,
-            length,
-            span,
-        });
+        let text = input.range(..length).copied().collect::<Vec<_>>();
+        let mut cursor = NfaCursor::default();
 
-        self.position += length;
-        self.current_mode = self.modes[self.current_mode][rule].mode_to;
-        self.cursor = 0;
-        self.last_accepted = None;
-        self.input.drain(..length);
+        let ends = match_ends(head, &text, &mut cursor);
 
-        for rule in self.modes[self.current_mode].iter_mut() {
-            rule.nfa.reset();
-        }
+        ends.into_iter()
+            .rev()
+            .filter(|&end| end > 0)
+            .find(|&end| {
+                match_ends(context, &text[end..], &mut cursor)
+                    .last()
+                    .is_some_and(|&rest| end + rest == length)
+            })
+            .unwrap_or(length)
     }
 }
 
-#[cfg(test)]
-mod test {
-    use super::*;
+// The lengths of the prefixes of `text` that `nfa` matches, shortest first.
+fn match_ends(nfa: &Nfa, text: &[char], cursor: &mut NfaCursor) -> Vec<usize> {
+    cursor.reset(nfa);
 
-    #[derive(Debug, Clone, PartialEq, Eq, Hash)]
-    enum Token {
-        LParen,
-        RParen,
-        Semicolon,
-        Whitespace,
-        Newline,
-        Comment,
+    let mut ends = vec![];
+    if cursor.is_accept(nfa) {
+        ends.push(0);
     }
 
-    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Hash)]
-    enum Mode {
-        #[default]
-        Default,
-        Comment,
+    for (i, &c) in text.iter().enumerate() {
+        cursor.put(nfa, c);
+        if cursor.is_dead() {
+            break;
+        }
+        if cursor.is_accept(nfa) {
+            ends.push(i + 1);
+        }
     }
 
-    fn small_lexer() -> Lexer<Mode, Token> {
-        let lparen = Regex::char('(');
-        let rparen = Regex::char(')');
-        let semicolon = Regex::char(';');
-        let whitespace = Regex::one_of(" \t").star();
-        let newline = Regex::char('\n');
-        let comment = Regex::none_of("\n").star();
+    ends
+}
 
-        let mut lexer = Lexer::new();
+pub struct Tokens<'a, T> {
+    state: &'a mut RunState<T>,
+    done: bool,
+}
 
-        lexer.set_start_mode(Mode::Default);
-        lexer.add_rule(Token::LParen, &lparen, Mode::Default, Mode::Default, false);
-        lexer.add_rule(Token::RParen, &rparen, Mode::Default, Mode::Default, false);
-        lexer.add_rule(
-            Token::Semicolon,
-            &semicolon,
-            Mode::Default,
-            Mode::Comment,
-            false,
-        );
-        lexer.add_rule(
-            Token::Whitespace,
-            &whitespace,
-            Mode::Default,
-            Mode::Default,
-            false,
-        );
-        lexer.add_rule(
-            Token::Newline,
-            &newline,
-            Mode::Default,
-            Mode::Default,
-            false,
-        );
+#[derive(Debug, Clone)]
+pub struct Coverage<M, T> {
+    pub rules: Vec<RuleCoverage<M, T>>,
+    pub samples: usize,
+    pub errors: usize,
+}
 
-        lexer.add_rule(Token::Comment, &comment, Mode::Comment, Mode::Default, true);
+#[derive(Debug, Clone)]
+pub struct RuleCoverage<M, T> {
+    pub mode: M,
+    pub token: T,
+    pub hits: usize,
+    pub states: usize,
+    pub unvisited_states: Vec<usize>,
+}
 
-        lexer
+impl<M, T> Coverage<M, T> {
+    pub fn unused_rules(&self) -> impl Iterator<Item = &RuleCoverage<M, T>> {
+        self.rules.iter().filter(|rule| rule.hits == 0)
     }
+}
 
-    fn test_lexer<M, T>(lexer: &mut Lexer<M, T>, input: &str, expected: &[Lexeme<T>])
-    where
-        T: Clone + Debug + Eq,
-        M: Copy + Debug + Eq + Hash + Default,
-    {
-        lexer.reset();
-        for c in input.chars() {
-            lexer.put(c);
-        }
-        lexer.finish();
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LanguageChange {
+    Equal,
+    Superset,
+    Subset,
+    Incomparable,
+    Added,
+    Removed,
+}
 
-        let mut actual = Vec::new();
-        while let Some(lexeme) = lexer.get() {
+#[derive(Debug, Clone)]
+pub struct RuleComparison<M, T> {
+    pub mode: M,
+    pub token: T,
+    pub change: LanguageChange,
+    // A few of the shortest strings only one version accepts, where `accepted_by_self` means the
+    // old version.
+    pub witnesses: Vec<Witness>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Ambiguity<M, T> {
+    pub mode: M,
+    pub first: T,
+    pub second: T,
+    pub examples: Vec<String>,
+}
+
+// A problem with a definition found by `LexerDef::validate`.
+#[derive(Debug, Clone)]
+pub enum LexerIssue<M, T> {
+    // A rule that never produces a lexeme, as every non-empty string it matches is taken by
+    // `shadowed_by`, rules of the same mode that come before it by priority and then order.
+    // `examples` are some of the strings it matches.
+    Unreachable {
+        mode: M,
+        token: T,
+        shadowed_by: Vec<T>,
+        examples: Vec<String>,
+    },
+    // Two reachable rules that both match the strings in `examples`, which go to `first`.
+    Overlap(Ambiguity<M, T>),
+}
+
+impl<M, T> fmt::Display for LexerIssue<M, T>
+where
+    M: Debug,
+    T: Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LexerIssue::Unreachable {
+                mode,
+                token,
+                shadowed_by,
+                examples,
+            } => {
+                write!(f, "{:?} in mode {:?} can never match", token, mode)?;
+                if !shadowed_by.is_empty() {
+                    write!(f, ", shadowed by {:?}", shadowed_by)?;
+                }
+                if !examples.is_empty() {
+                    write!(f, " (e.g. {:?})", examples)?;
+                }
+                Ok(())
+            }
+            LexerIssue::Overlap(ambiguity) => write!(
+                f,
+                "{:?} and {:?} in mode {:?} both match {:?}",
+                ambiguity.first, ambiguity.second, ambiguity.mode, ambiguity.examples
+            ),
+        }
+    }
+}
+
+// Per-rule counters collected while `coverage` runs, indexed like `LexerDef::modes`. The compiled
+// automata don't say which rule states were visited, so the rules' NFAs are stepped alongside.
+struct Tracking {
+    nfas: Vec<Vec<Nfa>>,
+    hits: Vec<Vec<usize>>,
+    visited: Vec<Vec<Vec<bool>>>,
+}
+
+impl Tracking {
+    fn reset(&mut self, mode: usize) {
+        let Some(nfas) = self.nfas.get_mut(mode) else {
+            return;
+        };
+
+        for (rule, nfa) in nfas.iter_mut().enumerate() {
+            nfa.reset();
+            Self::visit(&mut self.visited[mode][rule], nfa);
+        }
+    }
+
+    fn put(&mut self, mode: usize, c: char) {
+        let Some(nfas) = self.nfas.get_mut(mode) else {
+            return;
+        };
+
+        for (rule, nfa) in nfas.iter_mut().enumerate() {
+            nfa.put(c);
+            Self::visit(&mut self.visited[mode][rule], nfa);
+        }
+    }
+
+    fn visit(visited: &mut [bool], nfa: &Nfa) {
+        for node in nfa.current() {
+            visited[node] = true;
+        }
+    }
+}
+
+// What a traced lexer did, for working out why it lexed something the way it did. Positions are
+// byte offsets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TraceEvent<M, T> {
+    // `c` at `position` was read in `mode`. `alive` are the rules that can still match the token
+    // read so far, in the order they were added, and `accepted` is the rule that matches it as it
+    // stands, if any.
+    Step {
+        mode: M,
+        position: usize,
+        c: char,
+        alive: Vec<T>,
+        accepted: Option<T>,
+    },
+    // A `token` lexeme over `span` was the longest match in `mode`, as no rule could go on to
+    // match `next`, or the input ended if it is `None`.
+    Emit {
+        mode: M,
+        token: T,
+        span: Span,
+        next: Option<char>,
+    },
+    // The rule just emitted changed the mode, leaving `depth` modes on the stack.
+    Mode {
+        from: M,
+        to: M,
+        depth: usize,
+    },
+}
+
+impl<T> TraceEvent<usize, T> {
+    fn with_modes<M>(self, names: &HashMap<usize, M>) -> TraceEvent<M, T>
+    where
+        M: Copy,
+    {
+        match self {
+            TraceEvent::Step {
+                mode,
+                position,
+                c,
+                alive,
+                accepted,
+            } => TraceEvent::Step {
+                mode: names[&mode],
+                position,
+                c,
+                alive,
+                accepted,
+            },
+            TraceEvent::Emit {
+                mode,
+                token,
+                span,
+                next,
+            } => TraceEvent::Emit {
+                mode: names[&mode],
+                token,
+                span,
+                next,
+            },
+            TraceEvent::Mode { from, to, depth } => TraceEvent::Mode {
+                from: names[&from],
+                to: names[&to],
+                depth,
+            },
+        }
+    }
+}
+
+impl<M, T> fmt::Display for TraceEvent<M, T>
+where
+    M: Debug,
+    T: Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TraceEvent::Step {
+                mode,
+                position,
+                c,
+                alive,
+                accepted,
+            } => {
+                write!(f, "{} {:?} in {:?}: alive {:?}, ", position, c, mode, alive)?;
+                match accepted {
+                    Some(token) => write!(f, "accepts {:?}", token),
+                    None => write!(f, "accepts nothing"),
+                }
+            }
+            TraceEvent::Emit {
+                mode,
+                token,
+                span,
+                next,
+            } => {
+                write!(
+                    f,
+                    "emit {:?} at {}..{} in {:?}, the longest match before ",
+                    token, span.start, span.end, mode
+                )?;
+                match next {
+                    Some(c) => write!(f, "{:?}", c),
+                    None => write!(f, "the end of the input"),
+                }
+            }
+            TraceEvent::Mode { from, to, depth } => {
+                write!(f, "mode {:?} -> {:?}, stack depth {}", from, to, depth)
+            }
+        }
+    }
+}
+
+// The current mode's rules, stepped alongside its automaton while tracing as the automaton can't
+// say which of them are alive, and the events not yet taken.
+struct Trace<T> {
+    cursors: Vec<NfaCursor>,
+    events: Vec<TraceEvent<usize, T>>,
+}
+
+impl<T> Trace<T>
+where
+    T: Clone,
+{
+    fn reset(&mut self, rules: &[Rule<T>]) {
+        self.cursors.resize_with(rules.len(), NfaCursor::default);
+        for (cursor, rule) in self.cursors.iter_mut().zip(rules) {
+            cursor.reset(&rule.nfa);
+        }
+    }
+
+    // Step every rule on `c` without recording it.
+    fn put(&mut self, rules: &[Rule<T>], c: char) {
+        for (cursor, rule) in self.cursors.iter_mut().zip(rules) {
+            cursor.put(&rule.nfa, c);
+        }
+    }
+
+    fn step(
+        &mut self,
+        rules: &[Rule<T>],
+        mode: usize,
+        position: usize,
+        c: char,
+        accepted: Option<usize>,
+    ) {
+        self.put(rules, c);
+
+        let alive = self
+            .cursors
+            .iter()
+            .zip(rules)
+            .filter(|(cursor, _)| !cursor.is_dead())
+            .map(|(_, rule)| rule.token.clone())
+            .collect();
+
+        self.events.push(TraceEvent::Step {
+            mode,
+            position,
+            c,
+            alive,
+            accepted: accepted.map(|rule| rules[rule].token.clone()),
+        });
+    }
+}
+
+impl<M, T> Default for LexerDef<M, T>
+where
+    T: Clone + Debug,
+    M: Copy + Debug + Eq + Hash + Default,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<M, T> LexerDef<M, T>
+where
+    T: Clone + Debug,
+    M: Copy + Debug + Eq + Hash + Default,
+{
+    pub fn new() -> Self {
+        LexerDef {
+            modes: vec![],
+            mode_indices: HashMap::new(),
+            mode_names: HashMap::new(),
+            compiled: vec![],
+            dfa_budget: Some(DEFAULT_DFA_BUDGET),
+            start_mode: 0,
+            recovery: None,
+            layout: None,
+        }
+    }
+
+    fn get_mode_index(&mut self, mode: M) -> usize {
+        *self.mode_indices.entry(mode).or_insert_with(|| {
+            let index = self.modes.len();
+            self.modes.push(vec![]);
+            self.compiled.push(OnceLock::new());
+            self.mode_names.insert(index, mode);
+            index
+        })
+    }
+
+    // The mode every run begins in, after every `reset`. A `Lexer` that hasn't been given any
+    // input yet begins in it straight away.
+    pub fn set_start_mode(&mut self, mode: M) {
+        self.start_mode = self.get_mode_index(mode);
+    }
+
+    pub fn with_start_mode(&mut self, mode: M) -> &mut Self {
+        self.set_start_mode(mode);
+        self
+    }
+
+    pub fn add_rule(&mut self, token: T, regex: &Regex, mode_from: M, mode_to: M, keep_span: bool) {
+        let mut options = RuleOptions::new();
+        options
+            .with_action(ModeAction::Switch(mode_to))
+            .with_keep_span(keep_span);
+        self.add_rule_with_options(token, regex, mode_from, &options);
+    }
+
+    // Any kind of rule at once: see `RuleOptions`.
+    pub fn add_rule_with_options(
+        &mut self,
+        token: T,
+        regex: &Regex,
+        mode_from: M,
+        options: &RuleOptions<M, T>,
+    ) {
+        let mode_from = self.get_mode_index(mode_from);
+        let action = match options.action {
+            ModeAction::Stay => ModeAction::Stay,
+            ModeAction::Switch(mode) => ModeAction::Switch(self.get_mode_index(mode)),
+            ModeAction::Push(mode) => ModeAction::Push(self.get_mode_index(mode)),
+            ModeAction::Pop => ModeAction::Pop,
+            ModeAction::PopOr(mode) => ModeAction::PopOr(self.get_mode_index(mode)),
+        };
+
+        let (nfa, context) = match options.context.as_ref() {
+            Some(context) => {
+                let head = regex.to_nfa();
+                assert!(
+                    !head.is_accept(),
+                    "the head of a trailing context rule must not match the empty string"
+                );
+                (
+                    regex.concat(context).to_nfa(),
+                    Some((head, context.to_nfa())),
+                )
+            }
+            None => (regex.to_nfa(), None),
+        };
+
+        self.compiled[mode_from] = OnceLock::new();
+        self.modes[mode_from].push(Rule {
+            token,
+            nfa,
+            action,
+            keep_span: options.keep_span,
+            skip: options.skip,
+            channel: DEFAULT_CHANNEL,
+            priority: options.priority,
+            normalizer: None,
+            builder: options.builder.clone(),
+            keywords: vec![],
+            context,
+            nested: false,
+            terminator: None,
+        });
+    }
+
+    // A rule whose matches are consumed, and can switch modes, but never produce a lexeme. Useful
+    // for whitespace and comments.
+    pub fn add_skip_rule(&mut self, token: T, regex: &Regex, mode_from: M, mode_to: M) {
+        let mut options = RuleOptions::new();
+        options
+            .with_action(ModeAction::Switch(mode_to))
+            .with_skip(true);
+        self.add_rule_with_options(token, regex, mode_from, &options);
+    }
+
+    // A rule whose lexeme goes on past its match up to and including the first text that
+    // `terminator`, given the matched text, returns. This makes heredocs like `<<END ... END` one
+    // lexeme, with the terminator taken from the match of e.g. `<<[A-Z]+\n`. The input after the
+    // match isn't lexed until the terminator is found.
+    pub fn add_heredoc_rule<F>(
+        &mut self,
+        token: T,
+        open: &Regex,
+        mode_from: M,
+        mode_to: M,
+        keep_span: bool,
+        terminator: F,
+    ) where
+        F: Fn(&str) -> String + Send + Sync + 'static,
+    {
+        self.add_rule(token, open, mode_from, mode_to, keep_span);
+
+        let mode_from = self.mode_indices[&mode_from];
+        self.modes[mode_from].last_mut().unwrap().terminator = Some(Arc::new(terminator));
+    }
+
+    // A construct that opens with `open`, closes with `close` and can nest, like the block
+    // comment `#| a #| b |# c |#`. Everything from the outermost `open` to its `close` becomes one
+    // lexeme of `token`. The inside is lexed in `inner_mode`, using the mode stack to count how
+    // deep it is, so `inner_mode` needs no other rules.
+    pub fn add_nested_rule(
+        &mut self,
+        token: T,
+        open: &Regex,
+        close: &Regex,
+        mode_from: M,
+        inner_mode: M,
+        keep_span: bool,
+    ) {
+        assert!(
+            mode_from != inner_mode,
+            "a nested rule needs a mode of its own"
+        );
+
+        let mut options = RuleOptions::new();
+        options
+            .with_action(ModeAction::Push(inner_mode))
+            .with_keep_span(keep_span);
+        self.add_rule_with_options(token.clone(), open, mode_from, &options);
+        self.add_rule_with_options(token.clone(), open, inner_mode, &options);
+        options.set_action(ModeAction::Pop);
+        self.add_rule_with_options(token.clone(), close, inner_mode, &options);
+        // Anything else, a character at a time, losing to `open` and `close`.
+        options.with_action(ModeAction::Stay).with_priority(-1);
+        self.add_rule_with_options(token, &Regex::any(), inner_mode, &options);
+
+        let mode_from = self.mode_indices[&mode_from];
+        let inner_mode = self.mode_indices[&inner_mode];
+        self.modes[mode_from].last_mut().unwrap().nested = true;
+        self.modes[inner_mode]
+            .iter_mut()
+            .rev()
+            .take(3)
+            .for_each(|rule| rule.nested = true);
+    }
+
+    pub fn with_skip_rule(
+        &mut self,
+        token: T,
+        regex: &Regex,
+        mode_from: M,
+        mode_to: M,
+    ) -> &mut Self {
+        self.add_skip_rule(token, regex, mode_from, mode_to);
+        self
+    }
+
+    pub fn with_rule_options(
+        &mut self,
+        token: T,
+        regex: &Regex,
+        mode_from: M,
+        options: &RuleOptions<M, T>,
+    ) -> &mut Self {
+        self.add_rule_with_options(token, regex, mode_from, options);
+        self
+    }
+
+    pub fn with_rule(
+        &mut self,
+        token: T,
+        regex: &Regex,
+        mode_from: M,
+        mode_to: M,
+        keep_span: bool,
+    ) -> &mut Self {
+        self.add_rule(token, regex, mode_from, mode_to, keep_span);
+        self
+    }
+
+    // Rewrite the text of every lexeme produced for `token`, e.g. to lowercase identifiers or
+    // strip digit separators. The result is stored as the lexeme's span, so these rules always
+    // keep their span.
+    pub fn set_normalizer<F>(&mut self, token: T, normalizer: F)
+    where
+        T: PartialEq,
+        F: Fn(&str) -> String + Send + Sync + 'static,
+    {
+        let normalizer: Normalizer = Arc::new(normalizer);
+
+        for rule in self.modes.iter_mut().flatten() {
+            if rule.token == token {
+                rule.keep_span = true;
+                rule.normalizer = Some(normalizer.clone());
+            }
+        }
+    }
+
+    // Send lexemes of `token` to `channel`, e.g. `HIDDEN_CHANNEL` for comments and whitespace.
+    pub fn set_channel(&mut self, token: T, channel: usize)
+    where
+        T: PartialEq,
+    {
+        for rule in self.modes.iter_mut().flatten() {
+            if rule.token == token {
+                rule.channel = channel;
+            }
+        }
+    }
+
+    // Reclassify lexemes of `token` whose text, after any normalizer, is one of `keywords`, so
+    // that one identifier rule can stand in for a rule per keyword. A keyword added again
+    // replaces the earlier one.
+    pub fn add_keywords(&mut self, token: T, keywords: &[(&str, T)])
+    where
+        T: PartialEq,
+    {
+        for rule in self.modes.iter_mut().flatten() {
+            if rule.token != token {
+                continue;
+            }
+
+            for (keyword, keyword_token) in keywords {
+                let entry = (keyword.to_string(), keyword_token.clone());
+
+                match rule
+                    .keywords
+                    .binary_search_by(|(other, _)| other.as_str().cmp(keyword))
+                {
+                    Ok(i) => rule.keywords[i] = entry,
+                    Err(i) => rule.keywords.insert(i, entry),
+                }
+            }
+        }
+    }
+
+    // Instead of stopping at the first character no rule can match, emit a lexeme of
+    // `error_token` covering each run of such characters, record an error for it and carry on.
+    pub fn set_recovery(&mut self, error_token: Option<T>) {
+        self.recovery = error_token;
+    }
+
+    // Lex indentation sensitive syntax, as in Python: the first lexeme of each line is preceded
+    // by `newline` if it isn't the first line, then by `indent` if the line is indented further
+    // than the one before, or by a `dedent` for every block it closes. The end of the input ends
+    // the last line and closes every block. Only lexemes on the default channel count, so
+    // whitespace, newlines and comments need skip rules or another channel, and blank lines are
+    // ignored.
+    pub fn set_layout(&mut self, layout: Option<Layout<T>>) {
+        self.layout = layout;
+    }
+
+    // The most DFA states a mode may compile to before falling back to a lazy DFA, or `None` for
+    // no limit. Defaults to `DEFAULT_DFA_BUDGET`.
+    pub fn set_dfa_budget(&mut self, budget: Option<usize>) {
+        self.dfa_budget = budget;
+        self.compiled
+            .iter_mut()
+            .for_each(|compiled| *compiled = OnceLock::new());
+    }
+
+    // Compile every mode now rather than as runs first enter them. Adding rules to a mode or
+    // changing the DFA budget discards what was compiled.
+    pub fn compile(&self) {
+        (0..self.modes.len()).for_each(|mode| {
+            self.compiled(mode);
+        });
+    }
+
+    // The modes that exceeded the DFA budget and are lexed with a lazy DFA. Compiles every mode.
+    pub fn fallback_modes(&self) -> Vec<M> {
+        (0..self.modes.len())
+            .filter(|&mode| self.compiled(mode).automaton.is_fallback())
+            .map(|mode| self.mode_names[&mode])
+            .collect()
+    }
+
+    // Start lexing a new input.
+    pub fn run(&self) -> LexerRun<'_, M, T> {
+        LexerRun {
+            def: RunDef::Borrowed(self),
+            state: RunState::new(self),
+            document: None,
+        }
+    }
+
+    // The rules compiled into one DFA per mode, for driving by hand.
+    pub fn scanner(&self) -> ScannerState<M, T> {
+        let modes = self
+            .modes
+            .iter()
+            .enumerate()
+            .map(|(mode, rules)| {
+                let compiled = self.compiled(mode);
+
+                ScannerMode {
+                    automaton: compiled.automaton.clone(),
+                    state: AutomatonState::new(),
+                    rules: compiled
+                        .rules
+                        .iter()
+                        .map(|&i| (rules[i].token.clone(), rules[i].action))
+                        .collect(),
+                }
+            })
+            .collect();
+        let mode_names = (0..self.modes.len())
+            .map(|mode| self.mode_names[&mode])
+            .collect();
+
+        ScannerState::new(modes, mode_names, self.start_mode)
+    }
+
+    // Pairs of rules in the same mode that both match some non-empty string, with up to `limit` of
+    // the shortest such strings. Which rule wins these strings is decided by priority, then order.
+    pub fn ambiguities(&self, limit: usize) -> Vec<Ambiguity<M, T>> {
+        let mut ambiguities = vec![];
+
+        for (mode, rules) in self.modes.iter().enumerate() {
+            let dfas = rules
+                .iter()
+                .map(|rule| non_empty(&rule.nfa))
+                .collect::<Vec<_>>();
+
+            for i in 0..rules.len() {
+                for j in i + 1..rules.len() {
+                    let examples = examples(&dfas[i].intersection(&dfas[j]), limit);
+
+                    if !examples.is_empty() {
+                        ambiguities.push(Ambiguity {
+                            mode: self.mode_names[&mode],
+                            first: rules[i].token.clone(),
+                            second: rules[j].token.clone(),
+                            examples,
+                        });
+                    }
+                }
+            }
+        }
+
+        ambiguities
+    }
+
+    // Rules that can never match and pairs of rules that match the same strings, in the order the
+    // rules take precedence in each mode.
+    pub fn validate(&self) -> Vec<LexerIssue<M, T>> {
+        let is_empty = |dfa: &Dfa| dfa.is_subset(&Dfa::new());
+
+        let mut issues = vec![];
+
+        for (mode, rules) in self.modes.iter().enumerate() {
+            let mode_name = self.mode_names[&mode];
+            let mut order = (0..rules.len()).collect::<Vec<_>>();
+            order.sort_by_key(|&i| std::cmp::Reverse(rules[i].priority));
+
+            let dfas = order
+                .iter()
+                .map(|&i| non_empty(&rules[i].nfa))
+                .collect::<Vec<_>>();
+            let mut reachable = vec![];
+
+            for (i, dfa) in dfas.iter().enumerate() {
+                let earlier = order[..i]
+                    .iter()
+                    .map(|&j| &rules[j].nfa)
+                    .collect::<Vec<_>>();
+
+                if !dfa.is_subset(&Dfa::from_nfas(&earlier)) {
+                    reachable.push(i);
+                    continue;
+                }
+
+                issues.push(LexerIssue::Unreachable {
+                    mode: mode_name,
+                    token: rules[order[i]].token.clone(),
+                    shadowed_by: (0..i)
+                        .filter(|&j| !is_empty(&dfa.intersection(&dfas[j])))
+                        .map(|j| rules[order[j]].token.clone())
+                        .collect(),
+                    examples: examples(dfa, VALIDATION_EXAMPLES),
+                });
+            }
+
+            for (n, &i) in reachable.iter().enumerate() {
+                for &j in reachable[n + 1..].iter() {
+                    let both = dfas[i].intersection(&dfas[j]);
+
+                    if !is_empty(&both) {
+                        issues.push(LexerIssue::Overlap(Ambiguity {
+                            mode: mode_name,
+                            first: rules[order[i]].token.clone(),
+                            second: rules[order[j]].token.clone(),
+                            examples: examples(&both, VALIDATION_EXAMPLES),
+                        }));
+                    }
+                }
+            }
+        }
+
+        issues
+    }
+
+    // The language of every token in every mode, as a DFA over the union of its rules.
+    fn token_languages(&self) -> Vec<(M, T, Dfa)>
+    where
+        T: PartialEq,
+    {
+        let mut languages = vec![];
+
+        for (mode, rules) in self.modes.iter().enumerate() {
+            let mut tokens: Vec<(&T, Vec<&Nfa>)> = vec![];
+
+            for rule in rules.iter() {
+                match tokens.iter_mut().find(|(token, _)| **token == rule.token) {
+                    Some((_, nfas)) => nfas.push(&rule.nfa),
+                    None => tokens.push((&rule.token, vec![&rule.nfa])),
+                }
+            }
+
+            for (token, nfas) in tokens {
+                languages.push((self.mode_names[&mode], token.clone(), Dfa::from_nfas(&nfas)));
+            }
+        }
+
+        languages
+    }
+}
+
+impl<M, T> LexerDef<M, T> {
+    fn compiled(&self, mode: usize) -> &CompiledMode {
+        self.compiled[mode].get_or_init(|| {
+            let rules = &self.modes[mode];
+            let mut order = (0..rules.len()).collect::<Vec<_>>();
+            order.sort_by_key(|&i| std::cmp::Reverse(rules[i].priority));
+
+            let nfas = order.iter().map(|&i| &rules[i].nfa).collect::<Vec<_>>();
+
+            CompiledMode {
+                automaton: Arc::new(Automaton::new(&nfas, self.dfa_budget)),
+                rules: order,
+            }
+        })
+    }
+}
+
+impl<T> RunState<T>
+where
+    T: Clone + Debug,
+{
+    fn new<M>(def: &LexerDef<M, T>) -> Self {
+        let mut state = RunState {
+            automata: vec![],
+            current_mode: def.start_mode,
+            mode_stack: vec![],
+            input: VecDeque::new(),
+            cursor: 0,
+            cursor_offset: 0,
+            position: 0,
+            last_accepted: None,
+            output: VecDeque::new(),
+            channels: HashMap::new(),
+            error: None,
+            errors: vec![],
+            skipped: None,
+            layout: LayoutState::new(),
+            nested: None,
+            heredoc: None,
+            tracking: None,
+            trace: None,
+            cancel: None,
+            starts: None,
+            emitted: 0,
+        };
+
+        state.reset_rules(def);
+
+        state
+    }
+
+    // Nothing has been put since the last reset.
+    fn is_idle(&self) -> bool {
+        self.position == 0 && self.input.is_empty()
+    }
+
+    fn reset<M>(&mut self, def: &LexerDef<M, T>) {
+        self.current_mode = def.start_mode;
+        self.mode_stack.clear();
+        self.cursor = 0;
+        self.cursor_offset = 0;
+        self.position = 0;
+        self.input.clear();
+        self.last_accepted = None;
+        self.output.clear();
+        self.channels.clear();
+        self.error = None;
+        self.errors.clear();
+        self.skipped = None;
+        self.layout = LayoutState::new();
+        self.nested = None;
+        self.heredoc = None;
+        self.emitted = 0;
+
+        if let Some(starts) = self.starts.as_mut() {
+            starts.clear();
+        }
+
+        self.reset_rules(def);
+        self.token_start();
+    }
+
+    // Carry on lexing from `start`, as if everything before it had just been lexed.
+    fn restart_at<M>(&mut self, def: &LexerDef<M, T>, start: &TokenStart<T>) {
+        self.current_mode = start.mode;
+        self.mode_stack = start.mode_stack.clone();
+        self.cursor = 0;
+        self.cursor_offset = 0;
+        self.position = start.position;
+        self.input.clear();
+        self.last_accepted = None;
+        self.output.clear();
+        self.error = None;
+        self.skipped = None;
+        self.emitted = start.lexemes;
+        self.layout = start.layout.clone();
+        self.nested = start.nested.clone();
+        self.heredoc = start.heredoc.clone();
+
+        self.reset_rules(def);
+        self.token_start();
+    }
+
+    fn checkpoint(&self) -> Checkpoint<T> {
+        Checkpoint {
+            mode: self.current_mode,
+            mode_stack: self.mode_stack.clone(),
+            input: self.input.clone(),
+            cursor: self.cursor,
+            cursor_offset: self.cursor_offset,
+            position: self.position,
+            last_accepted: self.last_accepted,
+            output: self.output.clone(),
+            channels: self.channels.clone(),
+            error: self.error.clone(),
+            errors: self.errors.clone(),
+            skipped: self.skipped.clone(),
+            layout: self.layout.clone(),
+            nested: self.nested.clone(),
+            heredoc: self.heredoc.clone(),
+        }
+    }
+
+    fn restore<M>(&mut self, def: &LexerDef<M, T>, checkpoint: Checkpoint<T>) {
+        self.current_mode = checkpoint.mode;
+        self.mode_stack = checkpoint.mode_stack;
+        self.input = checkpoint.input;
+        self.cursor = checkpoint.cursor;
+        self.cursor_offset = checkpoint.cursor_offset;
+        self.position = checkpoint.position;
+        self.last_accepted = checkpoint.last_accepted;
+        self.output = checkpoint.output;
+        self.channels = checkpoint.channels;
+        self.error = checkpoint.error;
+        self.errors = checkpoint.errors;
+        self.skipped = checkpoint.skipped;
+        self.layout = checkpoint.layout;
+        self.nested = checkpoint.nested;
+        self.heredoc = checkpoint.heredoc;
+
+        // Bring the automaton back to where it was by feeding it the current token again.
+        self.reset_rules(def);
+        if let Some((automaton, state)) = self
+            .automata
+            .get_mut(self.current_mode)
+            .and_then(Option::as_mut)
+        {
+            for &c in self.input.range(..self.cursor) {
+                automaton.put(state, c);
+            }
+        }
+        self.refeed_trace(def);
+    }
+
+    fn set_trace<M>(&mut self, def: &LexerDef<M, T>, trace: bool) {
+        if !trace {
+            self.trace = None;
+        } else if self.trace.is_none() {
+            let mut trace = Trace {
+                cursors: vec![],
+                events: vec![],
+            };
+            trace.reset(def.modes.get(self.current_mode).map_or(&[], Vec::as_slice));
+            self.trace = Some(trace);
+            self.refeed_trace(def);
+        }
+    }
+
+    // Bring the traced rules up to the cursor, without recording the steps again.
+    fn refeed_trace<M>(&mut self, def: &LexerDef<M, T>) {
+        let rules: &[Rule<T>] = def.modes.get(self.current_mode).map_or(&[], Vec::as_slice);
+        if let Some(trace) = self.trace.as_mut() {
+            for &c in self.input.range(..self.cursor) {
+                trace.put(rules, c);
+            }
+        }
+    }
+
+    fn take_trace<M>(&mut self, def: &LexerDef<M, T>) -> Vec<TraceEvent<M, T>>
+    where
+        M: Copy,
+    {
+        let Some(trace) = self.trace.as_mut() else {
+            return vec![];
+        };
+
+        std::mem::take(&mut trace.events)
+            .into_iter()
+            .map(|event| event.with_modes(&def.mode_names))
+            .collect()
+    }
+
+    fn token_start(&mut self) {
+        let Some(starts) = self.starts.as_mut() else {
+            return;
+        };
+
+        if self.error.is_none() {
+            starts.push(TokenStart {
+                position: self.position,
+                mode: self.current_mode,
+                mode_stack: self.mode_stack.clone(),
+                lexemes: self.emitted,
+                scan_end: self.position,
+                skipping: self.skipped.is_some(),
+                layout: self.layout.clone(),
+                nested: self.nested.clone(),
+                heredoc: self.heredoc.clone(),
+            });
+        }
+    }
+
+    // Take the current mode's automaton from `def` if it hasn't been yet, or if the mode has been
+    // compiled again since, e.g. after rules were added to it. A new automaton starts a new token.
+    fn sync<M>(&mut self, def: &LexerDef<M, T>) {
+        let mode = self.current_mode;
+        if mode >= def.modes.len() {
+            return;
+        }
+
+        if self.automata.len() < def.modes.len() {
+            self.automata.resize_with(def.modes.len(), || None);
+        }
+
+        let compiled = def.compiled(mode);
+        if self.automata[mode]
+            .as_ref()
+            .is_none_or(|(automaton, _)| !Arc::ptr_eq(automaton, &compiled.automaton))
+        {
+            let automaton = compiled.automaton.clone();
+            let mut state = AutomatonState::new();
+            automaton.reset(&mut state);
+            self.automata[mode] = Some((automaton, state));
+        }
+    }
+
+    // Begin in the definition's start mode until anything is put, even if it changed since the
+    // last reset.
+    fn follow_start_mode<M>(&mut self, def: &LexerDef<M, T>) {
+        if self.is_idle() && (self.current_mode != def.start_mode || !self.mode_stack.is_empty()) {
+            self.current_mode = def.start_mode;
+            self.mode_stack.clear();
+            self.reset_rules(def);
+        }
+    }
+
+    fn reset_rules<M>(&mut self, def: &LexerDef<M, T>) {
+        self.sync(def);
+
+        if let Some((automaton, state)) = self
+            .automata
+            .get_mut(self.current_mode)
+            .and_then(Option::as_mut)
+        {
+            automaton.reset(state);
+        }
+
+        if let Some(tracking) = self.tracking.as_mut() {
+            tracking.reset(self.current_mode);
+        }
+
+        if let Some(trace) = self.trace.as_mut() {
+            trace.reset(def.modes.get(self.current_mode).map_or(&[], Vec::as_slice));
+        }
+    }
+
+    fn put<M>(&mut self, def: &LexerDef<M, T>, c: char) {
+        if self.is_error() {
+            return;
+        }
+
+        self.follow_start_mode(def);
+        self.input.push_back(c);
+        self.lex(def);
+    }
+
+    fn put_str<M>(&mut self, def: &LexerDef<M, T>, input: &str) {
+        if self.is_error() {
+            return;
+        }
+
+        self.follow_start_mode(def);
+        self.input.extend(input.chars());
+        self.lex(def);
+    }
+
+    fn put_reader<M, R>(&mut self, def: &LexerDef<M, T>, mut reader: R) -> io::Result<()>
+    where
+        R: Read,
+    {
+        let mut buf = [0; 4096];
+        let mut pending = vec![];
+
+        loop {
+            let n = match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+
+            pending.extend_from_slice(&buf[..n]);
+
+            let valid = match std::str::from_utf8(&pending) {
+                Ok(s) => s.len(),
+                Err(e) if e.error_len().is_none() => e.valid_up_to(),
+                Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+            };
+
+            self.put_str(def, std::str::from_utf8(&pending[..valid]).unwrap());
+            pending.drain(..valid);
+
+            if self.is_error() {
+                return Ok(());
+            }
+        }
+
+        if !pending.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "incomplete UTF-8 sequence at end of input",
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn put_encoded<M, R>(
+        &mut self,
+        def: &LexerDef<M, T>,
+        mut reader: R,
+        decoder: &mut Decoder,
+    ) -> io::Result<()>
+    where
+        R: Read,
+    {
+        let mut buf = [0; 4096];
+        let mut text = String::new();
+
+        loop {
+            let n = match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+
+            decoder.decode(&buf[..n], &mut text)?;
+            self.put_str(def, &text);
+            text.clear();
+
+            if self.is_error() {
+                return Ok(());
+            }
+        }
+
+        decoder.finish(&mut text)?;
+        self.put_str(def, &text);
+
+        Ok(())
+    }
+
+    fn finish<M>(&mut self, def: &LexerDef<M, T>) {
+        while !self.is_error() && !self.input.is_empty() && self.heredoc.is_none() {
+            if let Some(start) = self.starts.as_mut().and_then(|starts| starts.last_mut()) {
+                start.scan_end = usize::MAX;
+            }
+
+            self.emit(def);
+            self.lex(def);
+        }
+
+        self.flush_skipped(def);
+
+        if let (Some((nested, _)), false) = (self.nested.as_ref(), self.is_error()) {
+            self.error = Some(LexerError::UnexpectedEof {
+                position: self.position,
+                alive: vec![nested.token.clone()],
+            });
+        }
+
+        if let (Some(heredoc), false) = (self.heredoc.as_ref(), self.is_error()) {
+            self.error = Some(LexerError::UnexpectedEof {
+                position: self.position + self.cursor_offset,
+                alive: vec![heredoc.lexeme.token.clone()],
+            });
+        }
+
+        if let (Some(layout), false) = (def.layout.as_ref(), self.is_error()) {
+            self.end_layout(layout);
+        }
+    }
+
+    fn lex_str<M>(&mut self, def: &LexerDef<M, T>, input: &str) -> Tokens<'_, T> {
+        self.reset(def);
+        self.put_str(def, input);
+        self.finish(def);
+
+        self.tokens()
+    }
+
+    fn tokens(&mut self) -> Tokens<'_, T> {
+        Tokens {
+            state: self,
+            done: false,
+        }
+    }
+
+    fn is_error(&self) -> bool {
+        self.error.is_some()
+    }
+
+    fn get_channel(&mut self, channel: usize) -> Option<Lexeme<T>> {
+        if channel == DEFAULT_CHANNEL {
+            return self.output.pop_front();
+        }
+
+        self.channels.get_mut(&channel)?.pop_front()
+    }
+
+    // Feed the input from the cursor to the current mode's automaton. `input` starts at the current
+    // token and doubles as the backtracking buffer: once the automaton dies, the token ends at the
+    // last accept and `emit` drains only that much, resets the automaton and rewinds the cursor,
+    // so whatever was read past the accept is rescanned from the start of the next token.
+    fn lex<M>(&mut self, def: &LexerDef<M, T>) {
+        loop {
+            if self.heredoc.is_some() {
+                if !self.scan_heredoc(def) {
+                    return;
+                }
+                continue;
+            }
+
+            if self.cursor == self.input.len() || self.is_error() {
+                return;
+            }
+
+            // Rules added to the definition since take part from the next token.
+            if self.cursor == 0 {
+                self.sync(def);
+            }
+
+            let Some((automaton, state)) = self
+                .automata
+                .get_mut(self.current_mode)
+                .and_then(Option::as_mut)
+            else {
+                self.emit(def);
+                continue;
+            };
+            let rules = &def.compiled(self.current_mode).rules;
+
+            // Step the current token through as much of the input as there is.
+            while let Some(&c) = self.input.get(self.cursor) {
+                if self
+                    .cancel
+                    .as_ref()
+                    .is_some_and(|cancel| cancel.is_cancelled())
+                {
+                    self.error = Some(LexerError::Cancelled {
+                        position: self.position + self.cursor_offset,
+                    });
+                    return;
+                }
+
+                automaton.put(state, c);
+
+                if let Some(start) = self.starts.as_mut().and_then(|starts| starts.last_mut()) {
+                    let end = self.position + self.cursor_offset + c.len_utf8();
+                    start.scan_end = start.scan_end.max(end);
+                }
+
+                if let Some(tracking) = self.tracking.as_mut() {
+                    tracking.put(self.current_mode, c);
+                }
+
+                if let Some(trace) = self.trace.as_mut() {
+                    trace.step(
+                        &def.modes[self.current_mode],
+                        self.current_mode,
+                        self.position + self.cursor_offset,
+                        c,
+                        automaton.accept_tag(state).map(|tag| rules[tag]),
+                    );
+                }
+
+                if state.is_dead() {
+                    break;
+                }
+
+                if let Some(tag) = automaton.accept_tag(state) {
+                    self.last_accepted = Some((rules[tag], self.cursor + 1));
+                }
+
+                self.cursor += 1;
+                self.cursor_offset += c.len_utf8();
+            }
+
+            if self.cursor < self.input.len() {
+                self.emit(def);
+            }
+        }
+    }
+
+    fn emit<M>(&mut self, def: &LexerDef<M, T>) {
+        let Some((rule, length)) = self.last_accepted else {
+            if def.recovery.is_some() {
+                self.skip(def);
+            } else {
+                let position = self.position + self.cursor_offset;
+                let alive = self.alive(def, self.cursor);
+
+                self.error = Some(match self.input.get(self.cursor) {
+                    Some(&c) => LexerError::UnexpectedChar { position, c, alive },
+                    None => LexerError::UnexpectedEof { position, alive },
+                });
+            }
+            return;
+        };
+
+        self.flush_skipped(def);
+
+        if let Some(tracking) = self.tracking.as_mut() {
+            tracking.hits[self.current_mode][rule] += 1;
+        }
+
+        let rule = &def.modes[self.current_mode][rule];
+        let length = rule.lexeme_length(&self.input, length);
+        let position = self.position;
+        let bytes = self
+            .input
+            .range(..length)
+            .map(|c| c.len_utf8())
+            .sum::<usize>();
+        let terminator = rule.terminator.as_ref().map(|terminator| {
+            terminator(&self.input.range(..length).collect::<String>())
+                .chars()
+                .collect::<Vec<_>>()
+        });
+        let text =
+            (rule.keep_span || rule.builder.is_some() || !rule.keywords.is_empty()).then(|| {
+                let text = self.input.range(..length).collect::<String>();
+
+                match rule.normalizer.as_ref() {
+                    Some(normalizer) => normalizer(&text),
+                    None => text,
+                }
+            });
+        let keyword = text.as_deref().and_then(|text| {
+            rule.keywords
+                .binary_search_by(|(keyword, _)| keyword.as_str().cmp(text))
+                .ok()
+        });
+        let token = match (keyword, rule.builder.as_ref(), text.as_deref()) {
+            (Some(keyword), _, _) => rule.keywords[keyword].1.clone(),
+            (None, Some(builder), Some(text)) => builder(
+                text,
+                Span {
+                    start: position,
+                    end: position + bytes,
+                },
+            ),
+            _ => rule.token.clone(),
+        };
+        let span = text.filter(|_| rule.keep_span);
+
+        if let Some(trace) = self.trace.as_mut() {
+            trace.events.push(TraceEvent::Emit {
+                mode: self.current_mode,
+                token: token.clone(),
+                span: Span {
+                    start: position,
+                    end: position + bytes,
+                },
+                next: self.input.get(self.cursor).copied(),
+            });
+        }
+
+        if let Some(layout) = def.layout.as_ref() {
+            if rule.skip || rule.channel != DEFAULT_CHANNEL {
+                self.read_layout(layout, length);
+            } else if !self.start_line(layout, position) {
+                return;
+            }
+        }
+
+        let mut lexeme = Some(Lexeme {
+            token,
+            position,
+            length: bytes,
+            span,
+        });
+
+        if rule.nested {
+            let piece = lexeme.take().unwrap();
+            match self.nested.as_mut() {
+                Some((nested, _)) => {
+                    nested.length += piece.length;
+                    if let (Some(span), Some(text)) = (nested.span.as_mut(), piece.span) {
+                        span.push_str(&text);
+                    }
+                }
+                None => self.nested = Some((piece, self.mode_stack.len())),
+            }
+        }
+
+        self.position += bytes;
+        let (from, depth) = (self.current_mode, self.mode_stack.len());
+        match rule.action.apply(self.current_mode, &mut self.mode_stack) {
+            Some(mode) => self.current_mode = mode,
+            None => {
+                self.error = Some(LexerError::ModeUnderflow {
+                    position,
+                    token: rule.token.clone(),
+                })
+            }
+        }
+
+        if let Some(trace) = self.trace.as_mut() {
+            if self.current_mode != from || self.mode_stack.len() != depth {
+                trace.events.push(TraceEvent::Mode {
+                    from,
+                    to: self.current_mode,
+                    depth: self.mode_stack.len(),
+                });
+            }
+        }
+
+        if let Some((_, depth)) = self.nested {
+            if self.mode_stack.len() == depth {
+                lexeme = self.nested.take().map(|(nested, _)| nested);
+            }
+        }
+
+        if let Some(terminator) = terminator {
+            self.heredoc = Some(Heredoc {
+                lexeme: lexeme.take().unwrap(),
+                terminator,
+                skip: rule.skip,
+                channel: rule.channel,
+            });
+        }
+
+        if let Some(lexeme) = lexeme {
+            self.output_lexeme(lexeme, rule.skip, rule.channel);
+        }
+        self.cursor = 0;
+        self.cursor_offset = 0;
+        self.last_accepted = None;
+        self.input.drain(..length);
+
+        self.reset_rules(def);
+        self.token_start();
+    }
+
+    fn output_lexeme(&mut self, lexeme: Lexeme<T>, skip: bool, channel: usize) {
+        match channel {
+            _ if skip => {}
+            DEFAULT_CHANNEL => {
+                self.output.push_back(lexeme);
+                self.emitted += 1;
+            }
+            channel => self.channels.entry(channel).or_default().push_back(lexeme),
+        }
+    }
+
+    // Read on through the input until it ends with the heredoc's terminator, then output the
+    // heredoc. Returns false if the input ran out first.
+    fn scan_heredoc<M>(&mut self, def: &LexerDef<M, T>) -> bool {
+        let terminator = &self.heredoc.as_ref().unwrap().terminator;
+
+        loop {
+            let read = self.input.range(..self.cursor);
+            if self.cursor >= terminator.len()
+                && read.skip(self.cursor - terminator.len()).eq(terminator)
+            {
+                break;
+            }
+
+            let Some(&c) = self.input.get(self.cursor) else {
+                return false;
+            };
+
+            self.cursor += 1;
+            self.cursor_offset += c.len_utf8();
+
+            if let Some(start) = self.starts.as_mut().and_then(|starts| starts.last_mut()) {
+                start.scan_end = start.scan_end.max(self.position + self.cursor_offset);
+            }
+        }
+
+        let mut heredoc = self.heredoc.take().unwrap();
+        heredoc.lexeme.length += self.cursor_offset;
+        if let Some(span) = heredoc.lexeme.span.as_mut() {
+            span.extend(self.input.range(..self.cursor));
+        }
+
+        if let Some(layout) = def.layout.as_ref() {
+            if heredoc.skip || heredoc.channel != DEFAULT_CHANNEL {
+                self.read_layout(layout, self.cursor);
+            }
+        }
+
+        self.position += self.cursor_offset;
+        self.input.drain(..self.cursor);
+        self.cursor = 0;
+        self.cursor_offset = 0;
+        self.output_lexeme(heredoc.lexeme, heredoc.skip, heredoc.channel);
+
+        self.reset_rules(def);
+        self.token_start();
+
+        true
+    }
+
+    // The rules of the current mode that are still alive after the first `length` characters of
+    // the input. Only used for errors, so the rules' NFAs are simply run again.
+    fn alive<M>(&self, def: &LexerDef<M, T>, length: usize) -> Vec<T> {
+        let Some(rules) = def.modes.get(self.current_mode) else {
+            return vec![];
+        };
+
+        let mut cursor = NfaCursor::default();
+        rules
+            .iter()
+            .filter(|rule| {
+                cursor.reset(&rule.nfa);
+                self.input
+                    .iter()
+                    .take(length)
+                    .for_each(|&c| cursor.put(&rule.nfa, c));
+                !cursor.is_dead()
+            })
+            .map(|rule| rule.token.clone())
+            .collect()
+    }
+
+    // Drop the first character of the input, which no rule can start a lexeme with, adding it to
+    // the run of skipped characters.
+    fn skip<M>(&mut self, def: &LexerDef<M, T>) {
+        let Some(c) = self.input.pop_front() else {
+            return;
+        };
+
+        self.skipped
+            .get_or_insert_with(|| (self.position, String::new()))
+            .1
+            .push(c);
+
+        self.position += c.len_utf8();
+        self.cursor = 0;
+        self.cursor_offset = 0;
+        self.reset_rules(def);
+        self.token_start();
+    }
+
+    fn flush_skipped<M>(&mut self, def: &LexerDef<M, T>) {
+        let Some((position, text)) = self.skipped.take() else {
+            return;
+        };
+
+        let Some(token) = def.recovery.clone() else {
+            return;
+        };
+
+        self.errors.push(LexerError::UnexpectedChar {
+            position,
+            c: text.chars().next().unwrap(),
+            alive: self.alive(def, 0),
+        });
+
+        if let Some(layout) = def.layout.as_ref() {
+            if !self.start_line(layout, position) {
+                return;
+            }
+        }
+
+        self.output.push_back(Lexeme {
+            token,
+            position,
+            length: text.len(),
+            span: Some(text),
+        });
+        self.emitted += 1;
+    }
+
+    // Follow the layout through the first `length` characters of the input, which make up a
+    // lexeme that isn't output.
+    fn read_layout(&mut self, layout: &Layout<T>, length: usize) {
+        let state = &mut self.layout;
+
+        for &c in self.input.range(..length) {
+            match c {
+                '\n' => {
+                    state.new_line = true;
+                    state.indent = 0;
+                    state.leading = true;
+                }
+                ' ' if state.leading => state.indent += 1,
+                '\t' if state.leading => {
+                    state.indent = (state.indent / layout.tab_width + 1) * layout.tab_width
+                }
+                _ => state.leading = false,
+            }
+        }
+    }
+
+    // Output the layout lexemes due before a lexeme at `position`. Returns false if the line is
+    // dedented to an indentation no open block has.
+    fn start_line(&mut self, layout: &Layout<T>, position: usize) -> bool {
+        let state = &mut self.layout;
+        let new_line = std::mem::replace(&mut state.new_line, false);
+        let started = std::mem::replace(&mut state.started, true);
+        state.leading = false;
+
+        if !new_line {
+            return true;
+        }
+
+        let made_up = |token: &T| Lexeme {
+            token: token.clone(),
+            position,
+            length: 0,
+            span: None,
+        };
+
+        if started {
+            self.output.push_back(made_up(&layout.newline));
+            self.emitted += 1;
+        }
+
+        let indent = state.indent;
+        if indent > state.indents.last().copied().unwrap_or(0) {
+            state.indents.push(indent);
+            self.output.push_back(made_up(&layout.indent));
+            self.emitted += 1;
+        }
+
+        while indent < state.indents.last().copied().unwrap_or(0) {
+            state.indents.pop();
+            self.output.push_back(made_up(&layout.dedent));
+            self.emitted += 1;
+        }
+
+        if indent != state.indents.last().copied().unwrap_or(0) {
+            self.error = Some(LexerError::InconsistentIndent { position });
+            return false;
+        }
+
+        true
+    }
+
+    // End the last line and close every block at the end of the input.
+    fn end_layout(&mut self, layout: &Layout<T>) {
+        let state = &mut self.layout;
+        let made_up = |token: &T| Lexeme {
+            token: token.clone(),
+            position: self.position,
+            length: 0,
+            span: None,
+        };
+
+        if std::mem::replace(&mut state.started, false) {
+            self.output.push_back(made_up(&layout.newline));
+            self.emitted += 1;
+        }
+
+        while state.indents.pop().is_some() {
+            self.output.push_back(made_up(&layout.dedent));
+            self.emitted += 1;
+        }
+    }
+
+    fn coverage<'a, M, I>(&mut self, def: &LexerDef<M, T>, corpus: I) -> Coverage<M, T>
+    where
+        M: Copy + Eq + Hash,
+        I: IntoIterator<Item = &'a str>,
+    {
+        self.tracking = Some(Tracking {
+            nfas: def
+                .modes
+                .iter()
+                .map(|rules| rules.iter().map(|rule| rule.nfa.clone()).collect())
+                .collect(),
+            hits: def.modes.iter().map(|rules| vec![0; rules.len()]).collect(),
+            visited: def
+                .modes
+                .iter()
+                .map(|rules| {
+                    rules
+                        .iter()
+                        .map(|rule| vec![false; rule.nfa.len()])
+                        .collect()
+                })
+                .collect(),
+        });
+
+        let mut samples = 0;
+        let mut errors = 0;
+
+        for sample in corpus {
+            self.reset(def);
+
+            for c in sample.chars() {
+                self.put(def, c);
+            }
+            self.finish(def);
+
+            samples += 1;
+            if self.is_error() || !self.errors.is_empty() {
+                errors += 1;
+            }
+        }
+
+        self.reset(def);
+
+        let tracking = self.tracking.take().unwrap();
+        let mut rules = vec![];
+
+        for (mode, mode_rules) in def.modes.iter().enumerate() {
+            for (i, rule) in mode_rules.iter().enumerate() {
+                let visited = &tracking.visited[mode][i];
+
+                rules.push(RuleCoverage {
+                    mode: def.mode_names[&mode],
+                    token: rule.token.clone(),
+                    hits: tracking.hits[mode][i],
+                    states: visited.len(),
+                    unvisited_states: (0..visited.len()).filter(|&node| !visited[node]).collect(),
+                });
+            }
+        }
+
+        Coverage {
+            rules,
+            samples,
+            errors,
+        }
+    }
+}
+
+impl<M, T> LexerRun<'_, M, T>
+where
+    T: Clone + Debug,
+    M: Copy + Debug + Eq + Hash + Default,
+{
+    pub fn def(&self) -> &LexerDef<M, T> {
+        &self.def
+    }
+
+    // The definition, to change it. A run that borrows its definition is given a copy of its own
+    // first, which the definition it came from doesn't see.
+    pub fn def_mut(&mut self) -> &mut LexerDef<M, T> {
+        if let RunDef::Borrowed(def) = self.def {
+            self.def = RunDef::Owned(def.clone());
+        }
+
+        match &mut self.def {
+            RunDef::Borrowed(_) => unreachable!(),
+            RunDef::Owned(def) => def,
+        }
+    }
+
+    // The definition, copied if the run borrows it.
+    pub fn into_def(self) -> LexerDef<M, T> {
+        match self.def {
+            RunDef::Borrowed(def) => def.clone(),
+            RunDef::Owned(def) => def,
+        }
+    }
+
+    // Stop lexing with an error once `cancel` is cancelled. It is checked for every character, so
+    // a host can abandon a huge input part way through.
+    pub fn set_cancel_token(&mut self, cancel: Option<CancelToken>) {
+        self.state.cancel = cancel;
+    }
+
+    // Record each character read, with the rules still alive after it, and each lexeme emitted and
+    // mode changed, until turned off. Every rule of the current mode is stepped on its own as well
+    // as through the compiled automaton, so tracing is slow.
+    pub fn set_trace(&mut self, trace: bool) {
+        self.state.set_trace(&self.def, trace);
+    }
+
+    // The events recorded since tracing was turned on or the trace was last taken.
+    pub fn take_trace(&mut self) -> Vec<TraceEvent<M, T>> {
+        self.state.take_trace(&self.def)
+    }
+
+    // The errors recovered from so far.
+    pub fn errors(&self) -> &[LexerError<T>] {
+        &self.state.errors
+    }
+
+    pub fn reset(&mut self) {
+        self.document = None;
+        self.state.reset(&self.def);
+    }
+
+    // Save the lexer's state, e.g. before lexing speculatively.
+    pub fn checkpoint(&self) -> Checkpoint<T> {
+        self.state.checkpoint()
+    }
+
+    // Go back to a state saved by `checkpoint` on this run. Input put since is forgotten and
+    // lexemes taken since are given out again.
+    pub fn restore(&mut self, checkpoint: Checkpoint<T>) {
+        self.state.restore(&self.def, checkpoint);
+    }
+
+    pub fn put(&mut self, c: char) {
+        self.state.put(&self.def, c);
+    }
+
+    // Like calling `put` for every character of `input`, but all of them are stepped through at
+    // once.
+    pub fn put_str(&mut self, input: &str) {
+        self.state.put_str(&self.def, input);
+    }
+
+    // Feed everything `reader` produces, decoding UTF-8 as it goes so that a character split
+    // across two reads is still put whole. Stops early if lexing fails. Does not call `finish`.
+    pub fn put_reader<R>(&mut self, reader: R) -> io::Result<()>
+    where
+        R: Read,
+    {
+        self.state.put_reader(&self.def, reader)
+    }
+
+    // Like `put_reader`, but the bytes are decoded by `decoder`, which by default drops a byte
+    // order mark and decodes UTF-16 if the mark says so. Positions are still in bytes of the
+    // decoded text; `decoder.source_offset` maps them back to the bytes read.
+    pub fn put_encoded<R>(&mut self, reader: R, decoder: &mut Decoder) -> io::Result<()>
+    where
+        R: Read,
+    {
+        self.state.put_encoded(&self.def, reader, decoder)
+    }
+
+    pub fn finish(&mut self) {
+        self.state.finish(&self.def);
+    }
+
+    pub fn get(&mut self) -> Option<Lexeme<T>> {
+        self.state.output.pop_front()
+    }
+
+    // The next lexeme on `channel`. Each channel is queued separately, in input order.
+    pub fn get_channel(&mut self, channel: usize) -> Option<Lexeme<T>> {
+        self.state.get_channel(channel)
+    }
+
+    // Drain the lexemes produced so far, followed by the error if lexing stopped on one.
+    pub fn tokens(&mut self) -> Tokens<'_, T> {
+        self.state.tokens()
+    }
+
+    // Lex the whole of `input` from a fresh start.
+    pub fn lex_str(&mut self, input: &str) -> Tokens<'_, T> {
+        self.document = None;
+        self.state.lex_str(&self.def, input)
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.state.is_error()
+    }
+
+    pub fn get_error(&self) -> Option<&LexerError<T>> {
+        self.state.error.as_ref()
+    }
+
+    pub fn coverage<'a, I>(&mut self, corpus: I) -> Coverage<M, T>
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        self.state.coverage(&self.def, corpus)
+    }
+
+    // Lex the whole of `text` and keep it, with its lexemes, as a document for `edit`. Errors are
+    // reported through `get_error` and `errors` as usual. `reset` and `lex_str` close the
+    // document.
+    pub fn open(&mut self, text: &str) {
+        self.state.starts = Some(vec![]);
+        self.state.reset(&self.def);
+
+        self.state.put_str(&self.def, text);
+        self.state.finish(&self.def);
+
+        self.document = Some(Document {
+            text: text.to_string(),
+            lexemes: self.state.output.drain(..).collect(),
+            starts: self.state.starts.take().unwrap(),
+        });
+    }
+
+    // The lexemes of the open document.
+    pub fn lexemes(&self) -> &[Lexeme<T>] {
+        self.document
+            .as_ref()
+            .map_or(&[], |document| &document.lexemes[..])
+    }
+
+    // Replace the bytes in `range` of the open document with `replacement` and relex it,
+    // returning the range of `lexemes` that were relexed. Lexing restarts at the last token that read
+    // nothing at or after the edit, and stops once it reaches a token start past the edit in the
+    // same mode as a token start of the old text, whose lexemes are then reused with their
+    // positions moved. Tokens made by a rule's builder are reused as they are.
+    pub fn edit(&mut self, range: Range<usize>, replacement: &str) -> Range<usize> {
+        let document = self
+            .document
+            .take()
+            .expect("`edit` needs a document from `open`");
+        assert!(
+            range.start <= range.end && range.end <= document.text.len(),
+            "edit {:?} is outside the document",
+            range
+        );
+        assert!(
+            document.text.is_char_boundary(range.start)
+                && document.text.is_char_boundary(range.end),
+            "edit {:?} splits a character",
+            range
+        );
+
+        let mut text = document.text;
+        text.replace_range(range.clone(), replacement);
+        let edit_end = range.start + replacement.len();
+        let delta = edit_end as isize - range.end as isize;
+        let moved = |position: usize| position.checked_add_signed(delta).unwrap();
+
+        // The first token that read anything at or after the edit, or the last one if lexing
+        // stopped on an error before it. A run of skipped characters isn't recorded in a token
+        // start, so restart before any that is under way.
+        let mut restart = document
+            .starts
+            .iter()
+            .position(|start| start.scan_end >= range.start)
+            .unwrap_or(document.starts.len() - 1);
+        while document.starts[restart].skipping {
+            restart -= 1;
+        }
+        let from = document.starts[restart].clone();
+
+        let old_errors = std::mem::take(&mut self.state.errors);
+        let old_error = self.state.error.take();
+        let mut lexemes = document.lexemes;
+        let old_lexemes = lexemes.split_off(from.lexemes);
+
+        self.state.starts = Some(document.starts[..restart].to_vec());
+        self.state.restart_at(&self.def, &from);
+        self.state.errors = old_errors
+            .iter()
+            .filter(|error| error.position() < from.position)
+            .cloned()
+            .collect();
+
+        // A new token start past the edit that lines up with an old one, as indices into both.
+        let mut resync = None;
+        let mut examined = restart;
+
+        for c in text[from.position..].chars() {
+            self.state.put(&self.def, c);
+
+            let starts = self.state.starts.as_ref().unwrap();
+            resync = (examined..starts.len()).find_map(|new| {
+                let start = &starts[new];
+                if start.position < edit_end || start.skipping {
+                    return None;
+                }
+
+                let position = start.position.checked_add_signed(-delta)?;
+                let old = document
+                    .starts
+                    .binary_search_by_key(&position, |old| old.position)
+                    .ok()?;
+                let old_start = &document.starts[old];
+
+                let same = !old_start.skipping
+                    && old_start.mode == start.mode
+                    && old_start.mode_stack == start.mode_stack
+                    && old_start.layout == start.layout
+                    && old_start.nested.is_none()
+                    && start.nested.is_none()
+                    && old_start.heredoc.is_none()
+                    && start.heredoc.is_none();
+                same.then_some((new, old))
+            });
+            examined = starts.len();
+
+            if resync.is_some() || self.state.is_error() {
+                break;
+            }
+        }
+
+        let mut starts = self.state.starts.take().unwrap();
+
+        let Some((new, old)) = resync else {
+            if !self.state.is_error() {
+                self.state.starts = Some(starts);
+                self.state.finish(&self.def);
+                starts = self.state.starts.take().unwrap();
+            }
+
+            let changed = from.lexemes..from.lexemes + self.state.output.len();
+            lexemes.extend(self.state.output.drain(..));
+            self.document = Some(Document {
+                text,
+                lexemes,
+                starts,
+            });
+            return changed;
+        };
+
+        let new_start = starts[new].clone();
+        let old_start = &document.starts[old];
+        let lexemes_moved = new_start.lexemes as isize - old_start.lexemes as isize;
+
+        lexemes.extend(self.state.output.drain(..new_start.lexemes - from.lexemes));
+        let changed = from.lexemes..lexemes.len();
+        lexemes.extend(
+            old_lexemes[old_start.lexemes - from.lexemes..]
+                .iter()
+                .cloned()
+                .map(|mut lexeme| {
+                    lexeme.position = moved(lexeme.position);
+                    lexeme
+                }),
+        );
+
+        starts.truncate(new);
+        starts.extend(document.starts[old..].iter().cloned().map(|mut start| {
+            start.position = moved(start.position);
+            if start.scan_end != usize::MAX {
+                start.scan_end = moved(start.scan_end);
+            }
+            start.lexemes = start.lexemes.checked_add_signed(lexemes_moved).unwrap();
+            start
+        }));
+
+        let move_error = |mut error: LexerError<T>| {
+            let position = error.position_mut();
+            *position = moved(*position);
+            error
+        };
+        self.state
+            .errors
+            .retain(|error| error.position() < new_start.position);
+        self.state.errors.extend(
+            old_errors
+                .into_iter()
+                .filter(|error| error.position() >= old_start.position)
+                .map(move_error),
+        );
+        self.state.error = old_error.map(move_error);
+
+        // Whatever was read past the token start is already lexed in the reused lexemes.
+        self.state.input.clear();
+        self.state.output.clear();
+        self.state.cursor = 0;
+        self.state.cursor_offset = 0;
+        self.state.last_accepted = None;
+        self.state.skipped = None;
+
+        self.document = Some(Document {
+            text,
+            lexemes,
+            starts,
+        });
+        changed
+    }
+}
+
+impl<M, T> Default for LexerRun<'static, M, T>
+where
+    T: Clone + Debug,
+    M: Copy + Debug + Eq + Hash + Default,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<M, T> LexerRun<'static, M, T>
+where
+    T: Clone + Debug,
+    M: Copy + Debug + Eq + Hash + Default,
+{
+    pub fn new() -> Self {
+        Self::from_def(LexerDef::new())
+    }
+
+    pub fn from_def(def: LexerDef<M, T>) -> Self {
+        LexerRun {
+            state: RunState::new(&def),
+            def: RunDef::Owned(def),
+            document: None,
+        }
+    }
+}
+
+impl<M, T> Deref for RunDef<'_, M, T> {
+    type Target = LexerDef<M, T>;
+
+    fn deref(&self) -> &LexerDef<M, T> {
+        match self {
+            RunDef::Borrowed(def) => def,
+            RunDef::Owned(def) => def,
+        }
+    }
+}
+
+impl<M, T> Deref for LexerRun<'_, M, T> {
+    type Target = LexerDef<M, T>;
+
+    fn deref(&self) -> &LexerDef<M, T> {
+        &self.def
+    }
+}
+
+const COMPARISON_WITNESSES: usize = 3;
+const VALIDATION_EXAMPLES: usize = 3;
+
+// The non-empty strings `nfa` matches, which are all a rule can match as lexemes are never empty.
+fn non_empty(nfa: &Nfa) -> Dfa {
+    nfa.to_dfa()
+        .intersection(&Regex::any().plus().to_nfa().to_dfa())
+}
+
+// Up to `limit` of the shortest strings `dfa` accepts. A DFA that accepts anything accepts a
+// string shorter than its number of states, so there's no need to look at longer ones.
+fn examples(dfa: &Dfa, limit: usize) -> Vec<String> {
+    let mut dfa = dfa.clone();
+    dfa.minimize();
+    dfa.enumerate(dfa.len()).take(limit).collect()
+}
+
+impl<T> Iterator for Tokens<'_, T>
+where
+    T: Clone,
+{
+    type Item = Result<Lexeme<T>, LexerError<T>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(lexeme) = self.state.output.pop_front() {
+            return Some(Ok(lexeme));
+        }
+
+        if self.done {
+            return None;
+        }
+
+        self.done = true;
+        self.state.error.clone().map(Err)
+    }
+}
+
+// Compare each (mode, token) pair of `new` against the same pair in `old`, reporting whether the
+// language the token matches grew, shrank, changed incomparably or stayed the same.
+pub fn compare_lexers<M, T>(old: &LexerDef<M, T>, new: &LexerDef<M, T>) -> Vec<RuleComparison<M, T>>
+where
+    T: Clone + Debug + PartialEq,
+    M: Copy + Debug + Eq + Hash + Default,
+{
+    let old = old.token_languages();
+    let mut new = new.token_languages();
+    let mut comparisons = vec![];
+
+    for (mode, token, old_dfa) in old {
+        let index = new
+            .iter()
+            .position(|(new_mode, new_token, _)| *new_mode == mode && *new_token == token);
+
+        let Some((_, _, new_dfa)) = index.map(|index| new.remove(index)) else {
+            comparisons.push(RuleComparison {
+                mode,
+                token,
+                change: LanguageChange::Removed,
+                witnesses: vec![],
+            });
+            continue;
+        };
+
+        let change = match (old_dfa.is_subset(&new_dfa), new_dfa.is_subset(&old_dfa)) {
+            (true, true) => LanguageChange::Equal,
+            (true, false) => LanguageChange::Superset,
+            (false, true) => LanguageChange::Subset,
+            (false, false) => LanguageChange::Incomparable,
+        };
+
+        comparisons.push(RuleComparison {
+            mode,
+            token,
+            change,
+            witnesses: old_dfa.symmetric_difference(&new_dfa, COMPARISON_WITNESSES),
+        });
+    }
+
+    for (mode, token, _) in new {
+        comparisons.push(RuleComparison {
+            mode,
+            token,
+            change: LanguageChange::Added,
+            witnesses: vec![],
+        });
+    }
+
+    comparisons
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lex::scanner::{ScanMatch, StepResult};
+
+    #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+    enum Token {
+        LParen,
+        RParen,
+        Semicolon,
+        Whitespace,
+        Newline,
+        Comment,
+        Integer,
+        Float,
+        Range,
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Hash)]
+    enum Mode {
+        #[default]
+        Default,
+        Comment,
+    }
+
+    fn small_lexer() -> Lexer<Mode, Token> {
+        let lparen = Regex::char('(');
+        let rparen = Regex::char(')');
+        let semicolon = Regex::char(';');
+        let whitespace = Regex::one_of(" \t").star();
+        let newline = Regex::char('\n');
+        let comment = Regex::none_of("\n").star();
+
+        let mut lexer = Lexer::new();
+
+        let def = lexer.def_mut();
+        def.set_start_mode(Mode::Default);
+        def.add_rule(Token::LParen, &lparen, Mode::Default, Mode::Default, false);
+        def.add_rule(Token::RParen, &rparen, Mode::Default, Mode::Default, false);
+        def.add_rule(
+            Token::Semicolon,
+            &semicolon,
+            Mode::Default,
+            Mode::Comment,
+            false,
+        );
+        def.add_rule(
+            Token::Whitespace,
+            &whitespace,
+            Mode::Default,
+            Mode::Default,
+            false,
+        );
+        def.add_rule(
+            Token::Newline,
+            &newline,
+            Mode::Default,
+            Mode::Default,
+            false,
+        );
+
+        def.add_rule(Token::Comment, &comment, Mode::Comment, Mode::Default, true);
+
+        lexer
+    }
+
+    fn test_lexer<M, T>(lexer: &mut Lexer<M, T>, input: &str, expected: &[Lexeme<T>])
+    where
+        T: Clone + Debug + Eq,
+        M: Copy + Debug + Eq + Hash + Default,
+    {
+        lexer.reset();
+        for c in input.chars() {
+            lexer.put(c);
+        }
+        lexer.finish();
+
+        let mut actual = Vec::new();
+        while let Some(lexeme) = lexer.get() {
             actual.push(lexeme);
         }
 
-        for (a, e) in actual.iter().zip(expected.iter()) {
-            assert_eq!(a, e);
-        }
+        for (a, e) in actual.iter().zip(expected.iter()) {
+            assert_eq!(a, e);
+        }
+
+        assert!(lexer.get_error().is_none());
+        assert!(!lexer.is_error());
+    }
+
+    #[test]
+    fn test_simple_tokens() {
+        let mut lexer = small_lexer();
+        test_lexer(
+            &mut lexer,
+            "()",
+            &[
+                Lexeme {
+                    token: Token::LParen,
+                    position: 0,
+                    length: 1,
+                    span: None,
+                },
+                Lexeme {
+                    token: Token::RParen,
+                    position: 1,
+                    length: 1,
+                    span: None,
+                },
+            ],
+        );
+    }
+
+    #[test]
+    fn test_whitespace() {
+        let mut lexer = small_lexer();
+        test_lexer(
+            &mut lexer,
+            "  (  )  ",
+            &[
+                Lexeme {
+                    token: Token::Whitespace,
+                    position: 0,
+                    length: 2,
+                    span: None,
+                },
+                Lexeme {
+                    token: Token::LParen,
+                    position: 2,
+                    length: 1,
+                    span: None,
+                },
+                Lexeme {
+                    token: Token::Whitespace,
+                    position: 3,
+                    length: 2,
+                    span: None,
+                },
+                Lexeme {
+                    token: Token::RParen,
+                    position: 5,
+                    length: 1,
+                    span: None,
+                },
+                Lexeme {
+                    token: Token::Whitespace,
+                    position: 6,
+                    length: 2,
+                    span: None,
+                },
+            ],
+        );
+    }
+
+    #[test]
+    fn test_comment() {
+        let mut lexer = small_lexer();
+        test_lexer(
+            &mut lexer,
+            "(; this is a comment\n)",
+            &[
+                Lexeme {
+                    token: Token::LParen,
+                    position: 0,
+                    length: 1,
+                    span: None,
+                },
+                Lexeme {
+                    token: Token::Semicolon,
+                    position: 1,
+                    length: 1,
+                    span: None,
+                },
+                Lexeme {
+                    token: Token::Comment,
+                    position: 2,
+                    length: 18,
+                    span: Some(" this is a comment".to_string()),
+                },
+                Lexeme {
+                    token: Token::Newline,
+                    position: 20,
+                    length: 1,
+                    span: None,
+                },
+                Lexeme {
+                    token: Token::RParen,
+                    position: 21,
+                    length: 1,
+                    span: None,
+                },
+            ],
+        );
+    }
+
+    #[test]
+    fn test_coverage() {
+        let mut lexer = small_lexer();
+
+        let coverage = lexer.coverage(["()", "( )", "(; comment"]);
+
+        assert_eq!(coverage.samples, 3);
+        assert_eq!(coverage.errors, 0);
+
+        let hits = coverage
+            .rules
+            .iter()
+            .map(|rule| (rule.mode, rule.token.clone(), rule.hits))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            hits,
+            vec![
+                (Mode::Default, Token::LParen, 3),
+                (Mode::Default, Token::RParen, 2),
+                (Mode::Default, Token::Semicolon, 1),
+                (Mode::Default, Token::Whitespace, 1),
+                (Mode::Default, Token::Newline, 0),
+                (Mode::Comment, Token::Comment, 1),
+            ]
+        );
+
+        let unused = coverage
+            .unused_rules()
+            .map(|rule| rule.token.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(unused, vec![Token::Newline]);
+
+        for rule in coverage.rules.iter() {
+            if rule.hits > 0 {
+                assert!(rule.unvisited_states.len() < rule.states);
+            }
+        }
+    }
+
+    #[test]
+    fn test_compare_lexers() {
+        let old = small_lexer();
+
+        let mut new = Lexer::new();
+        let def = new.def_mut();
+        def.set_start_mode(Mode::Default);
+        def.add_rule(
+            Token::LParen,
+            &Regex::char('('),
+            Mode::Default,
+            Mode::Default,
+            false,
+        );
+        def.add_rule(
+            Token::RParen,
+            &Regex::one_of(")]"),
+            Mode::Default,
+            Mode::Default,
+            false,
+        );
+        def.add_rule(
+            Token::Whitespace,
+            &Regex::one_of(" \r").plus(),
+            Mode::Default,
+            Mode::Default,
+            false,
+        );
+        def.add_rule(
+            Token::Newline,
+            &Regex::one_of("\r\n"),
+            Mode::Default,
+            Mode::Default,
+            false,
+        );
+        def.add_rule(
+            Token::Newline,
+            &Regex::char('\r').concat(&Regex::char('\n')),
+            Mode::Default,
+            Mode::Default,
+            false,
+        );
+        def.add_rule(
+            Token::Comment,
+            &Regex::none_of("\n").star(),
+            Mode::Default,
+            Mode::Default,
+            true,
+        );
+
+        let changes = compare_lexers(old.def(), new.def())
+            .into_iter()
+            .map(|rule| (rule.mode, rule.token, rule.change))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            changes,
+            vec![
+                (Mode::Default, Token::LParen, LanguageChange::Equal),
+                (Mode::Default, Token::RParen, LanguageChange::Superset),
+                (Mode::Default, Token::Semicolon, LanguageChange::Removed),
+                (
+                    Mode::Default,
+                    Token::Whitespace,
+                    LanguageChange::Incomparable
+                ),
+                (Mode::Default, Token::Newline, LanguageChange::Superset),
+                (Mode::Comment, Token::Comment, LanguageChange::Removed),
+                (Mode::Default, Token::Comment, LanguageChange::Added),
+            ]
+        );
+
+        let comparisons = compare_lexers(old.def(), new.def());
+
+        assert!(comparisons[0].witnesses.is_empty());
+        assert_eq!(
+            comparisons[1].witnesses,
+            vec![Witness {
+                input: "]".to_string(),
+                accepted_by_self: false,
+            }]
+        );
+        assert_eq!(
+            comparisons[3]
+                .witnesses
+                .iter()
+                .map(|witness| (witness.input.as_str(), witness.accepted_by_self))
+                .collect::<Vec<_>>(),
+            vec![("", true), ("\t", true), ("\r", false)]
+        );
+
+        let changes = compare_lexers(new.def(), old.def())
+            .into_iter()
+            .map(|rule| rule.change)
+            .collect::<Vec<_>>();
+
+        assert_eq!(changes[1], LanguageChange::Subset);
+    }
+
+    #[test]
+    fn test_normalizer() {
+        let mut lexer = small_lexer();
+        lexer.def_mut().add_rule(
+            Token::Comment,
+            &Regex::range('A', 'Z').plus(),
+            Mode::Default,
+            Mode::Default,
+            false,
+        );
+        lexer
+            .def_mut()
+            .set_normalizer(Token::Comment, |text| text.to_lowercase());
+
+        test_lexer(
+            &mut lexer,
+            "ABC;DEF",
+            &[
+                Lexeme {
+                    token: Token::Comment,
+                    position: 0,
+                    length: 3,
+                    span: Some("abc".to_string()),
+                },
+                Lexeme {
+                    token: Token::Semicolon,
+                    position: 3,
+                    length: 1,
+                    span: None,
+                },
+                Lexeme {
+                    token: Token::Comment,
+                    position: 4,
+                    length: 3,
+                    span: Some("def".to_string()),
+                },
+            ],
+        );
+    }
 
-        assert!(lexer.get_error().is_none());
+    #[test]
+    fn test_ambiguities() {
+        let mut lexer = small_lexer();
+
+        assert!(lexer.ambiguities(3).is_empty());
+
+        lexer.def_mut().add_rule(
+            Token::Comment,
+            &Regex::one_of("()").plus(),
+            Mode::Default,
+            Mode::Default,
+            false,
+        );
+
+        let ambiguities = lexer
+            .ambiguities(3)
+            .into_iter()
+            .map(|ambiguity| {
+                (
+                    ambiguity.mode,
+                    ambiguity.first,
+                    ambiguity.second,
+                    ambiguity.examples,
+                )
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            ambiguities,
+            vec![
+                (
+                    Mode::Default,
+                    Token::LParen,
+                    Token::Comment,
+                    vec!["(".to_string()]
+                ),
+                (
+                    Mode::Default,
+                    Token::RParen,
+                    Token::Comment,
+                    vec![")".to_string()]
+                ),
+            ]
+        );
+
+        // Rules that both match the empty string don't overlap on it, as no lexeme is empty.
+        let mut def = LexerDef::<Mode, Token>::new();
+        def.with_rule(
+            Token::Integer,
+            &Regex::char('a').star(),
+            Mode::Default,
+            Mode::Default,
+            false,
+        )
+        .with_rule(
+            Token::Float,
+            &Regex::char('b').star(),
+            Mode::Default,
+            Mode::Default,
+            false,
+        );
+        assert!(def.ambiguities(3).is_empty());
+        assert!(def.validate().is_empty());
+    }
+
+    #[test]
+    fn test_cancel() {
+        let mut lexer = small_lexer();
+        let cancel = CancelToken::new();
+        lexer.set_cancel_token(Some(cancel.clone()));
+
+        lexer.reset();
+        lexer.put('(');
+        lexer.put(')');
         assert!(!lexer.is_error());
+
+        cancel.cancel();
+        lexer.put('(');
+
+        assert_eq!(
+            lexer.get_error(),
+            Some(&LexerError::Cancelled { position: 2 })
+        );
     }
 
     #[test]
-    fn test_simple_tokens() {
+    fn test_recovery() {
         let mut lexer = small_lexer();
+        lexer.def_mut().set_recovery(Some(Token::Comment));
+
         test_lexer(
             &mut lexer,
-            "()",
+            "(xy)z",
             &[
                 Lexeme {
                     token: Token::LParen,
@@ -324,49 +3189,710 @@ mod test {
                     span: None,
                 },
                 Lexeme {
-                    token: Token::RParen,
+                    token: Token::Comment,
                     position: 1,
+                    length: 2,
+                    span: Some("xy".to_string()),
+                },
+                Lexeme {
+                    token: Token::RParen,
+                    position: 3,
                     length: 1,
                     span: None,
                 },
+                Lexeme {
+                    token: Token::Comment,
+                    position: 4,
+                    length: 1,
+                    span: Some("z".to_string()),
+                },
             ],
         );
+
+        let positions = lexer
+            .errors()
+            .iter()
+            .map(|error| error.position())
+            .collect::<Vec<_>>();
+        assert_eq!(positions, vec![1, 4]);
+    }
+
+    #[test]
+    fn test_no_recovery() {
+        let mut lexer = small_lexer();
+
+        lexer.reset();
+        for c in "(x)".chars() {
+            lexer.put(c);
+        }
+        lexer.finish();
+
+        assert!(lexer.is_error());
+        assert_eq!(lexer.get().map(|lexeme| lexeme.token), Some(Token::LParen));
+        assert_eq!(lexer.get(), None);
+    }
+
+    #[test]
+    fn test_scanner() {
+        let lexer = small_lexer();
+        let mut scanner = lexer.scanner();
+
+        assert_eq!(scanner.mode(), Mode::Default);
+        assert_eq!(scanner.step('('), StepResult::Accept(Token::LParen));
+        assert_eq!(scanner.step(' '), StepResult::Dead);
+        assert_eq!(
+            scanner.accept(),
+            Some(ScanMatch {
+                token: Token::LParen,
+                length: 1,
+            })
+        );
+
+        assert_eq!(scanner.step(';'), StepResult::Accept(Token::Semicolon));
+        assert_eq!(scanner.accept().map(|m| m.token), Some(Token::Semicolon));
+        assert_eq!(scanner.mode(), Mode::Comment);
+
+        assert_eq!(scanner.step('a'), StepResult::Accept(Token::Comment));
+        assert_eq!(scanner.step('b'), StepResult::Accept(Token::Comment));
+        assert_eq!(scanner.length(), 2);
+        assert_eq!(scanner.step('\n'), StepResult::Dead);
+        assert_eq!(scanner.step('c'), StepResult::Dead);
+        assert_eq!(
+            scanner.accept(),
+            Some(ScanMatch {
+                token: Token::Comment,
+                length: 2,
+            })
+        );
+        assert_eq!(scanner.mode(), Mode::Default);
+
+        assert_eq!(scanner.step('x'), StepResult::Dead);
+        assert_eq!(scanner.accept(), None);
+
+        scanner.set_mode(Mode::Comment);
+        assert_eq!(scanner.step('x'), StepResult::Accept(Token::Comment));
+        scanner.reset();
+        assert_eq!(scanner.mode(), Mode::Default);
+        assert_eq!(scanner.length(), 0);
+    }
+
+    #[test]
+    fn test_first_rule_wins_tie() {
+        let mut lexer = Lexer::new();
+        let word = Regex::range('a', 'z').plus();
+        let def = lexer.def_mut();
+        def.add_rule(Token::LParen, &word, Mode::Default, Mode::Default, false);
+        def.add_rule(Token::RParen, &word, Mode::Default, Mode::Default, false);
+
+        test_lexer(
+            &mut lexer,
+            "ab",
+            &[Lexeme {
+                token: Token::LParen,
+                position: 0,
+                length: 2,
+                span: None,
+            }],
+        );
+
+        let mut scanner = lexer.scanner();
+        scanner.step('a');
+        assert_eq!(scanner.accept().map(|m| m.token), Some(Token::LParen));
+    }
+
+    #[test]
+    fn test_lex_str() {
+        let mut lexer = small_lexer();
+
+        let tokens = lexer
+            .lex_str("( )")
+            .map(|lexeme| lexeme.map(|lexeme| lexeme.token))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            tokens,
+            vec![Ok(Token::LParen), Ok(Token::Whitespace), Ok(Token::RParen)]
+        );
+
+        let tokens = lexer.lex_str("(x").collect::<Vec<_>>();
+        assert_eq!(tokens.len(), 2);
+        assert_eq!(tokens[0].as_ref().unwrap().token, Token::LParen);
+        assert_eq!(tokens[1].as_ref().unwrap_err().position(), 1);
+
+        assert_eq!(lexer.tokens().count(), 1);
+        assert_eq!(lexer.lex_str("").count(), 0);
+    }
+
+    // Hands out one byte per read, to split every multi-byte character.
+    struct ByteReader<'a>(&'a [u8]);
+
+    impl Read for ByteReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let Some((&first, rest)) = self.0.split_first() else {
+                return Ok(0);
+            };
+
+            buf[0] = first;
+            self.0 = rest;
+            Ok(1)
+        }
+    }
+
+    #[test]
+    fn test_put_reader() {
+        let mut lexer = small_lexer();
+
+        lexer.reset();
+        lexer
+            .put_reader(ByteReader("(;é€😀\n)".as_bytes()))
+            .unwrap();
+        lexer.finish();
+
+        let lexemes = lexer.tokens().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(lexemes.len(), 5);
+        assert_eq!(lexemes[2].span.as_deref(), Some("é€😀"));
+        // Positions are in bytes.
+        assert_eq!(lexemes[2].extent(), Span { start: 2, end: 11 });
+        assert_eq!(lexemes[4].position, 12);
+        assert_eq!(&"(;é€😀\n)"[lexemes[4].extent().range()], ")");
+
+        lexer.reset();
+        let error = lexer.put_reader(ByteReader(&[b'(', 0xff])).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        lexer.reset();
+        let error = lexer.put_reader(ByteReader(&[b'(', 0xc3])).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_put_encoded() {
+        let mut lexer = small_lexer();
+
+        // UTF-16LE with a byte order mark.
+        let mut bytes = vec![0xff, 0xfe];
+        "(;é😀\n)"
+            .encode_utf16()
+            .for_each(|unit| bytes.extend(unit.to_le_bytes()));
+
+        let mut decoder = Decoder::new();
+        lexer.put_encoded(ByteReader(&bytes), &mut decoder).unwrap();
+        lexer.finish();
+
+        let lexemes = lexer.tokens().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(lexemes[2].span.as_deref(), Some("é😀"));
+        assert_eq!(lexemes[2].extent(), Span { start: 2, end: 8 });
+        assert_eq!(decoder.source_offset(lexemes[2].position), 6);
+        assert_eq!(decoder.source_offset(lexemes[4].position), 14);
+
+        lexer.reset();
+        let error = lexer
+            .put_encoded(ByteReader(&[0xfe, 0xff, 0, b'(', 0]), &mut Decoder::new())
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_skip_rule() {
+        let mut lexer = Lexer::new();
+        lexer
+            .def_mut()
+            .with_rule(
+                Token::LParen,
+                &Regex::char('('),
+                Mode::Default,
+                Mode::Default,
+                false,
+            )
+            .with_skip_rule(
+                Token::Whitespace,
+                &Regex::one_of(" \n").plus(),
+                Mode::Default,
+                Mode::Default,
+            )
+            .with_skip_rule(
+                Token::Semicolon,
+                &Regex::char(';'),
+                Mode::Default,
+                Mode::Comment,
+            )
+            .with_skip_rule(
+                Token::Comment,
+                &Regex::none_of("\n").plus(),
+                Mode::Comment,
+                Mode::Default,
+            );
+
+        let lexemes = lexer
+            .lex_str(" ( ;comment\n(")
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        assert_eq!(
+            lexemes,
+            vec![
+                Lexeme {
+                    token: Token::LParen,
+                    position: 1,
+                    length: 1,
+                    span: None,
+                },
+                Lexeme {
+                    token: Token::LParen,
+                    position: 12,
+                    length: 1,
+                    span: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rule_priority() {
+        let mut lexer = Lexer::new();
+        let word = Regex::range('a', 'z').plus();
+        let keyword = Regex::char('i').concat(&Regex::char('f'));
+        let def = lexer.def_mut();
+        def.add_rule(Token::Comment, &word, Mode::Default, Mode::Default, false);
+        def.add_rule_with_options(
+            Token::Semicolon,
+            &keyword,
+            Mode::Default,
+            RuleOptions::new()
+                .with_action(ModeAction::Switch(Mode::Default))
+                .with_priority(1),
+        );
+        def.add_rule(
+            Token::Whitespace,
+            &Regex::char(' '),
+            Mode::Default,
+            Mode::Default,
+            false,
+        );
+
+        // Longest match first, then priority.
+        let tokens = lexer
+            .lex_str("if iff i")
+            .map(|lexeme| lexeme.unwrap().token)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Semicolon,
+                Token::Whitespace,
+                Token::Comment,
+                Token::Whitespace,
+                Token::Comment,
+            ]
+        );
+
+        let mut scanner = lexer.scanner();
+        scanner.step('i');
+        scanner.step('f');
+        assert_eq!(scanner.accept().map(|m| m.token), Some(Token::Semicolon));
+    }
+
+    #[test]
+    fn test_mode_stack() {
+        let mut lexer = Lexer::new();
+        lexer
+            .def_mut()
+            .with_rule_options(
+                Token::LParen,
+                &Regex::char('('),
+                Mode::Default,
+                RuleOptions::new().with_action(ModeAction::Push(Mode::Comment)),
+            )
+            .with_rule_options(
+                Token::LParen,
+                &Regex::char('('),
+                Mode::Comment,
+                RuleOptions::new().with_action(ModeAction::Push(Mode::Comment)),
+            )
+            .with_rule_options(
+                Token::RParen,
+                &Regex::char(')'),
+                Mode::Comment,
+                RuleOptions::new().with_action(ModeAction::Pop),
+            )
+            .with_rule_options(
+                Token::Comment,
+                &Regex::range('a', 'z').plus(),
+                Mode::Comment,
+                RuleOptions::new().with_action(ModeAction::Stay),
+            );
+
+        let tokens = lexer
+            .lex_str("(a(b)c)(")
+            .map(|lexeme| lexeme.unwrap().token)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::LParen,
+                Token::Comment,
+                Token::LParen,
+                Token::Comment,
+                Token::RParen,
+                Token::Comment,
+                Token::RParen,
+                Token::LParen,
+            ]
+        );
+
+        // A closing paren with nothing left to close.
+        assert!(lexer.lex_str("(a))").last().unwrap().is_err());
+
+        let mut scanner = lexer.scanner();
+        for c in "((".chars() {
+            scanner.step(c);
+            scanner.accept();
+        }
+        scanner.step(')');
+        scanner.accept();
+        assert_eq!(scanner.mode(), Mode::Comment);
+        scanner.step(')');
+        scanner.accept();
+        assert_eq!(scanner.mode(), Mode::Default);
+
+        // `PopOr` falls back to its mode instead of underflowing.
+        lexer.def_mut().add_rule_with_options(
+            Token::RParen,
+            &Regex::char(')'),
+            Mode::Default,
+            RuleOptions::new().with_action(ModeAction::PopOr(Mode::Default)),
+        );
+        let tokens = lexer
+            .lex_str("(a)))")
+            .map(|lexeme| lexeme.unwrap().token)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::LParen,
+                Token::Comment,
+                Token::RParen,
+                Token::RParen,
+                Token::RParen,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_with_start_mode() {
+        let mut lexer = Lexer::new();
+        lexer
+            .def_mut()
+            .with_rule(
+                Token::Semicolon,
+                &Regex::char(';'),
+                Mode::Default,
+                Mode::Comment,
+                false,
+            )
+            .with_rule(
+                Token::Comment,
+                &Regex::none_of("\n").plus(),
+                Mode::Comment,
+                Mode::Default,
+                false,
+            )
+            .with_start_mode(Mode::Comment);
+
+        // Without a reset first.
+        for c in "ab;".chars() {
+            lexer.put(c);
+        }
+        lexer.finish();
+        assert_eq!(lexer.get().map(|lexeme| lexeme.token), Some(Token::Comment));
+        assert!(lexer.get().is_none());
+
+        let tokens = lexer
+            .lex_str("x;y")
+            .map(|lexeme| lexeme.unwrap().token)
+            .collect::<Vec<_>>();
+        assert_eq!(tokens, vec![Token::Comment]);
+    }
+
+    #[test]
+    fn test_shared_def() {
+        let def = Arc::new(small_lexer().into_def());
+
+        // Two runs over the same definition, interleaved.
+        let mut first = def.run();
+        let mut second = def.run();
+        for (a, b) in "(;x".chars().zip(") (".chars()) {
+            first.put(a);
+            second.put(b);
+        }
+        first.finish();
+        second.finish();
+
+        let tokens = |run: &mut LexerRun<Mode, Token>| {
+            run.tokens()
+                .map(|lexeme| lexeme.unwrap().token)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            tokens(&mut first),
+            vec![Token::LParen, Token::Semicolon, Token::Comment]
+        );
+        assert_eq!(
+            tokens(&mut second),
+            vec![Token::RParen, Token::Whitespace, Token::LParen]
+        );
+
+        // Both step the definition's automaton rather than copies of it.
+        let automaton = &def.compiled(0).automaton;
+        for run in [&first, &second] {
+            let (shared, _) = run.state.automata[0].as_ref().unwrap();
+            assert!(Arc::ptr_eq(shared, automaton));
+        }
+
+        let handles = (0..4)
+            .map(|i| {
+                let def = def.clone();
+                std::thread::spawn(move || def.run().lex_str(&"()".repeat(i)).count())
+            })
+            .collect::<Vec<_>>();
+        let counts = handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(counts, vec![0, 2, 4, 6]);
+    }
+
+    #[test]
+    fn test_run_def_copy() {
+        let def = small_lexer().into_def();
+        let rules = def.modes[0].len();
+
+        // Adding a rule through a run that borrows the definition gives the run a copy.
+        let mut run = def.run();
+        run.def_mut().add_rule(
+            Token::Semicolon,
+            &Regex::char('?'),
+            Mode::Default,
+            Mode::Default,
+            false,
+        );
+        assert_eq!(def.modes[0].len(), rules);
+        assert_eq!(run.modes[0].len(), rules + 1);
+
+        let tokens = run
+            .lex_str("(?)")
+            .map(|lexeme| lexeme.unwrap().token)
+            .collect::<Vec<_>>();
+        assert_eq!(tokens, vec![Token::LParen, Token::Semicolon, Token::RParen]);
+
+        assert!(def.run().lex_str("?").last().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_compile() {
+        let def = small_lexer().into_def();
+        assert!(def.compiled.iter().all(|compiled| compiled.get().is_none()));
+
+        def.compile();
+        assert!(def.compiled.iter().all(|compiled| compiled.get().is_some()));
+
+        // Clones share nothing with the original but keep its compiled modes.
+        let mut copy = def.clone();
+        assert!(copy
+            .compiled
+            .iter()
+            .all(|compiled| compiled.get().is_some()));
+
+        let tokens = |def: &LexerDef<Mode, Token>| {
+            def.run()
+                .lex_str("(;x")
+                .map(|lexeme| lexeme.unwrap().token)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(tokens(&copy), tokens(&def));
+
+        copy.add_rule(
+            Token::Integer,
+            &Regex::range('0', '9').plus(),
+            Mode::Default,
+            Mode::Default,
+            false,
+        );
+        assert!(copy.compiled[0].get().is_none());
+        assert!(def.compiled[0].get().is_some());
+        assert!(def.run().lex_str("1").next().unwrap().is_err());
+        assert_eq!(
+            copy.run().lex_str("1").next().unwrap().unwrap().token,
+            Token::Integer
+        );
+    }
+
+    #[test]
+    fn test_dfa_budget() {
+        // (a|b)*a(a|b){6} needs 2^7 DFA states.
+        let ab = Regex::one_of("ab");
+        let mut blowup = ab.star().concat(&Regex::char('a'));
+        for _ in 0..6 {
+            blowup = blowup.concat(&ab);
+        }
+
+        let mut lexer = Lexer::new();
+        lexer
+            .def_mut()
+            .with_rule(Token::Comment, &blowup, Mode::Default, Mode::Default, false)
+            .with_rule(
+                Token::LParen,
+                &Regex::one_of("ab").plus(),
+                Mode::Default,
+                Mode::Default,
+                false,
+            )
+            .with_rule(
+                Token::Whitespace,
+                &Regex::char(' '),
+                Mode::Default,
+                Mode::Default,
+                false,
+            );
+
+        let input = "abbbbbb ab baaaaaaa";
+        let tokens = |lexer: &mut Lexer<Mode, Token>| {
+            lexer
+                .lex_str(input)
+                .map(|lexeme| lexeme.unwrap().token)
+                .collect::<Vec<_>>()
+        };
+        let expected = vec![
+            Token::Comment,
+            Token::Whitespace,
+            Token::LParen,
+            Token::Whitespace,
+            Token::Comment,
+        ];
+
+        assert!(lexer.fallback_modes().is_empty());
+        assert_eq!(tokens(&mut lexer), expected);
+
+        lexer.def_mut().set_dfa_budget(Some(16));
+        assert_eq!(lexer.fallback_modes(), vec![Mode::Default]);
+        assert_eq!(tokens(&mut lexer), expected);
+
+        // The run picked up the recompiled mode.
+        let (automaton, _) = lexer.state.automata[0].as_ref().unwrap();
+        assert!(automaton.is_fallback());
+    }
+
+    #[test]
+    fn test_lexer_errors() {
+        let digits = Regex::range('0', '9').plus();
+        let float = digits.concat(&Regex::char('.')).concat(&digits);
+
+        let mut lexer = Lexer::new();
+        lexer
+            .def_mut()
+            .with_rule(Token::Comment, &float, Mode::Default, Mode::Default, false)
+            .with_rule_options(
+                Token::RParen,
+                &Regex::char(')'),
+                Mode::Default,
+                RuleOptions::new().with_action(ModeAction::Pop),
+            );
+
+        let error = lexer.lex_str("1.x").last().unwrap().unwrap_err();
+        assert_eq!(
+            error,
+            LexerError::UnexpectedChar {
+                position: 2,
+                c: 'x',
+                alive: vec![Token::Comment],
+            }
+        );
+        assert_eq!(
+            error.to_string(),
+            "unexpected 'x' at 2, expected one of Comment"
+        );
+
+        let error = lexer.lex_str("1.5 ").last().unwrap().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "unexpected ' ' at 3, expected one of Comment, RParen"
+        );
+
+        let error = lexer.lex_str("1.").last().unwrap().unwrap_err();
+        assert_eq!(
+            error,
+            LexerError::UnexpectedEof {
+                position: 2,
+                alive: vec![Token::Comment],
+            }
+        );
+
+        let tokens = lexer.lex_str("1.5)").collect::<Vec<_>>();
+        assert_eq!(
+            tokens[1],
+            Ok(Lexeme {
+                token: Token::RParen,
+                position: 3,
+                length: 1,
+                span: None,
+            })
+        );
+        let error = tokens[2].clone().unwrap_err();
+        assert_eq!(
+            error,
+            LexerError::ModeUnderflow {
+                position: 3,
+                token: Token::RParen,
+            }
+        );
+        assert_eq!(error.to_string(), "RParen at 3 pops an empty mode stack");
     }
 
     #[test]
-    fn test_whitespace() {
-        let mut lexer = small_lexer();
+    fn test_trailing_context() {
+        let digits = Regex::range('0', '9').plus();
+        let dot = Regex::char('.');
+        let range = dot.concat(&dot);
+
+        let mut lexer = Lexer::new();
+        let def = lexer.def_mut();
+        def.add_rule(
+            Token::Float,
+            &digits.concat(&dot).concat(&Regex::range('0', '9').star()),
+            Mode::Default,
+            Mode::Default,
+            false,
+        );
+        def.add_rule_with_options(
+            Token::Integer,
+            &digits,
+            Mode::Default,
+            RuleOptions::new()
+                .with_action(ModeAction::Switch(Mode::Default))
+                .with_keep_span(true)
+                .with_context(&range),
+        );
+        def.add_rule(Token::Integer, &digits, Mode::Default, Mode::Default, true);
+        def.add_rule(Token::Range, &range, Mode::Default, Mode::Default, false);
+
         test_lexer(
             &mut lexer,
-            "  (  )  ",
+            "12..3.",
             &[
                 Lexeme {
-                    token: Token::Whitespace,
+                    token: Token::Integer,
                     position: 0,
                     length: 2,
-                    span: None,
+                    span: Some("12".to_string()),
                 },
                 Lexeme {
-                    token: Token::LParen,
+                    token: Token::Range,
                     position: 2,
-                    length: 1,
-                    span: None,
-                },
-                Lexeme {
-                    token: Token::Whitespace,
-                    position: 3,
                     length: 2,
                     span: None,
                 },
                 Lexeme {
-                    token: Token::RParen,
-                    position: 5,
-                    length: 1,
-                    span: None,
-                },
-                Lexeme {
-                    token: Token::Whitespace,
-                    position: 6,
+                    token: Token::Float,
+                    position: 4,
                     length: 2,
                     span: None,
                 },
@@ -375,43 +3901,720 @@ mod test {
     }
 
     #[test]
-    fn test_comment() {
-        let mut lexer = small_lexer();
-        test_lexer(
-            &mut lexer,
-            "(; this is a comment\n)",
-            &[
+    #[should_panic(expected = "must not match the empty string")]
+    fn test_trailing_context_empty_head() {
+        let mut lexer = Lexer::<Mode, Token>::new();
+        lexer.def_mut().add_rule_with_options(
+            Token::Integer,
+            &Regex::range('0', '9').star(),
+            Mode::Default,
+            RuleOptions::new()
+                .with_action(ModeAction::Switch(Mode::Default))
+                .with_context(&Regex::char('.')),
+        );
+    }
+
+    #[test]
+    fn test_rule_builder() {
+        #[derive(Debug, Clone, PartialEq)]
+        enum Value {
+            Integer(u64),
+            Word(Span),
+        }
+
+        let mut lexer = Lexer::new();
+        let def = lexer.def_mut();
+        def.add_rule_with_options(
+            Value::Integer(0),
+            &Regex::range('0', '9').plus(),
+            Mode::Default,
+            RuleOptions::new()
+                .with_action(ModeAction::Switch(Mode::Default))
+                .with_builder(|text, _| Value::Integer(text.parse().unwrap())),
+        );
+        def.add_rule_with_options(
+            Value::Word(Span { start: 0, end: 0 }),
+            &Regex::range('a', 'z').plus(),
+            Mode::Default,
+            RuleOptions::new()
+                .with_action(ModeAction::Switch(Mode::Default))
+                .with_keep_span(true)
+                .with_builder(|_, span| Value::Word(span)),
+        );
+
+        let lexemes = lexer
+            .lex_str("42ab7")
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            lexemes,
+            vec![
                 Lexeme {
-                    token: Token::LParen,
+                    token: Value::Integer(42),
                     position: 0,
-                    length: 1,
-                    span: None,
-                },
-                Lexeme {
-                    token: Token::Semicolon,
-                    position: 1,
-                    length: 1,
+                    length: 2,
                     span: None,
                 },
                 Lexeme {
-                    token: Token::Comment,
+                    token: Value::Word(Span { start: 2, end: 4 }),
                     position: 2,
-                    length: 18,
-                    span: Some(" this is a comment".to_string()),
-                },
-                Lexeme {
-                    token: Token::Newline,
-                    position: 20,
-                    length: 1,
-                    span: None,
+                    length: 2,
+                    span: Some("ab".to_string()),
                 },
                 Lexeme {
-                    token: Token::RParen,
-                    position: 21,
+                    token: Value::Integer(7),
+                    position: 4,
                     length: 1,
                     span: None,
                 },
-            ],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_keywords() {
+        let mut lexer = Lexer::new();
+        lexer
+            .def_mut()
+            .with_rule(
+                Token::Comment,
+                &Regex::range('a', 'z').plus(),
+                Mode::Default,
+                Mode::Default,
+                true,
+            )
+            .with_rule(
+                Token::Whitespace,
+                &Regex::char(' '),
+                Mode::Default,
+                Mode::Default,
+                false,
+            );
+        lexer.def_mut().add_keywords(
+            Token::Comment,
+            &[("let", Token::Integer), ("in", Token::Range)],
+        );
+        lexer
+            .def_mut()
+            .add_keywords(Token::Comment, &[("let", Token::Float)]);
+
+        let tokens = lexer
+            .lex_str("let x in lets")
+            .map(|lexeme| lexeme.unwrap().token)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Float,
+                Token::Whitespace,
+                Token::Comment,
+                Token::Whitespace,
+                Token::Range,
+                Token::Whitespace,
+                Token::Comment,
+            ]
+        );
+    }
+
+    fn document_def() -> LexerDef<Mode, Token> {
+        let digits = Regex::range('0', '9').plus();
+
+        let mut def = LexerDef::new();
+        def.with_rule(
+            Token::LParen,
+            &Regex::char('('),
+            Mode::Default,
+            Mode::Default,
+            false,
+        )
+        .with_rule(
+            Token::RParen,
+            &Regex::char(')'),
+            Mode::Default,
+            Mode::Default,
+            false,
+        )
+        .with_rule(
+            Token::Comment,
+            &Regex::range('a', 'z').plus(),
+            Mode::Default,
+            Mode::Default,
+            true,
+        )
+        .with_rule(Token::Integer, &digits, Mode::Default, Mode::Default, true)
+        .with_rule(
+            Token::Float,
+            &digits.concat(&Regex::char('.')).concat(&digits),
+            Mode::Default,
+            Mode::Default,
+            true,
+        )
+        .with_rule(
+            Token::Whitespace,
+            &Regex::char(' ').plus(),
+            Mode::Default,
+            Mode::Default,
+            false,
+        )
+        .with_rule_options(
+            Token::Semicolon,
+            &Regex::char('"'),
+            Mode::Default,
+            RuleOptions::new().with_action(ModeAction::Push(Mode::Comment)),
+        )
+        .with_rule_options(
+            Token::Comment,
+            &Regex::none_of("\"").plus(),
+            Mode::Comment,
+            RuleOptions::new()
+                .with_action(ModeAction::Stay)
+                .with_keep_span(true),
+        )
+        .with_rule_options(
+            Token::Semicolon,
+            &Regex::char('"'),
+            Mode::Comment,
+            RuleOptions::new().with_action(ModeAction::Pop),
+        );
+        def
+    }
+
+    #[test]
+    fn test_edit() {
+        let def = document_def();
+        let mut run = def.run();
+
+        run.open("(ab 12) (cd 3.5)");
+        assert_eq!(run.lexemes().len(), 11);
+
+        // Only the lexemes next to the edit are relexed; the rest are reused.
+        let changed = run.edit(1..3, "xyz");
+        assert_eq!(changed, 0..2);
+        assert_eq!(run.lexemes()[1].span.as_deref(), Some("xyz"));
+        assert_eq!(run.lexemes()[10].position, 16);
+
+        // Opening a string changes the mode of everything after it.
+        let changed = run.edit(0..0, "\"");
+        assert_eq!(changed, 0..2);
+        assert_eq!(run.lexemes().len(), 2);
+
+        let changed = run.edit(18..18, "\"");
+        assert_eq!(changed, 1..3);
+
+        let mut fresh = def.run();
+        let expected = fresh
+            .lex_str("\"(xyz 12) (cd 3.5)\"")
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(run.lexemes(), &expected[..]);
+    }
+
+    #[test]
+    fn test_edit_matches_relex() {
+        use rand::rngs::StdRng;
+        use rand::{Rng, SeedableRng};
+
+        let mut def = document_def();
+        def.set_recovery(Some(Token::Range));
+
+        let alphabet = ['a', 'b', '1', '.', ' ', '(', ')', '"', '!'];
+        let mut rng = StdRng::seed_from_u64(1565);
+        let mut run = def.run();
+        let mut fresh = def.run();
+
+        for _ in 0..20 {
+            let mut text = (0..rng.gen_range(0..30))
+                .map(|_| alphabet[rng.gen_range(0..alphabet.len())])
+                .collect::<Vec<_>>();
+            run.open(&text.iter().collect::<String>());
+
+            for _ in 0..20 {
+                let start = rng.gen_range(0..=text.len());
+                let end = rng.gen_range(start..=text.len().min(start + 4));
+                let replacement = (0..rng.gen_range(0..4))
+                    .map(|_| alphabet[rng.gen_range(0..alphabet.len())])
+                    .collect::<Vec<_>>();
+
+                run.edit(start..end, &replacement.iter().collect::<String>());
+                text.splice(start..end, replacement);
+
+                let input = text.iter().collect::<String>();
+                let expected = fresh
+                    .lex_str(&input)
+                    .filter_map(Result::ok)
+                    .collect::<Vec<_>>();
+                assert_eq!(run.lexemes(), &expected[..], "input: {:?}", input);
+                assert_eq!(run.errors(), fresh.errors(), "input: {:?}", input);
+                assert_eq!(run.get_error(), fresh.get_error(), "input: {:?}", input);
+            }
+        }
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let mut lexer = small_lexer();
+        let tokens = |lexer: &mut Lexer<Mode, Token>| {
+            lexer
+                .tokens()
+                .map(|lexeme| lexeme.map(|lexeme| (lexeme.token, lexeme.position)))
+                .collect::<Vec<_>>()
+        };
+
+        lexer.reset();
+        for c in "( ;a".chars() {
+            lexer.put(c);
+        }
+        let checkpoint = lexer.checkpoint();
+
+        for c in "b\n)".chars() {
+            lexer.put(c);
+        }
+        lexer.finish();
+        assert_eq!(
+            tokens(&mut lexer),
+            vec![
+                Ok((Token::LParen, 0)),
+                Ok((Token::Whitespace, 1)),
+                Ok((Token::Semicolon, 2)),
+                Ok((Token::Comment, 3)),
+                Ok((Token::Newline, 5)),
+                Ok((Token::RParen, 6)),
+            ]
+        );
+
+        // Back in the comment, part way through "a".
+        lexer.restore(checkpoint.clone());
+        for c in "x".chars() {
+            lexer.put(c);
+        }
+        lexer.finish();
+        let lexemes = lexer.tokens().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(lexemes.len(), 4);
+        assert_eq!(lexemes[3].span.as_deref(), Some("ax"));
+
+        lexer.restore(checkpoint);
+        lexer.put('\n');
+        lexer.put('?');
+        lexer.finish();
+        assert_eq!(
+            tokens(&mut lexer).last(),
+            Some(&Err(LexerError::UnexpectedChar {
+                position: 5,
+                c: '?',
+                alive: vec![
+                    Token::LParen,
+                    Token::RParen,
+                    Token::Semicolon,
+                    Token::Whitespace,
+                    Token::Newline,
+                ],
+            }))
+        );
+    }
+
+    #[test]
+    fn test_maximal_munch() {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        enum Op {
+            Integer,
+            Float,
+            Dot,
+            Less,
+            LessEqual,
+        }
+
+        let digits = Regex::range('0', '9').plus();
+        let dot = Regex::char('.');
+        let less = Regex::char('<');
+
+        let mut lexer = Lexer::<Mode, Op>::new();
+        lexer
+            .def_mut()
+            .with_rule(
+                Op::Float,
+                &digits.concat(&dot).concat(&digits),
+                Mode::Default,
+                Mode::Default,
+                false,
+            )
+            .with_rule(Op::Integer, &digits, Mode::Default, Mode::Default, false)
+            .with_rule(Op::Dot, &dot, Mode::Default, Mode::Default, false)
+            .with_rule(
+                Op::LessEqual,
+                &less.concat(&Regex::char('=')),
+                Mode::Default,
+                Mode::Default,
+                false,
+            )
+            .with_rule(Op::Less, &less, Mode::Default, Mode::Default, false);
+
+        type Expected = &'static [(Op, usize, usize)];
+        let cases: &[(&str, Expected)] = &[
+            ("1.", &[(Op::Integer, 0, 1), (Op::Dot, 1, 1)]),
+            ("1.5", &[(Op::Float, 0, 3)]),
+            (
+                "12.<",
+                &[(Op::Integer, 0, 2), (Op::Dot, 2, 1), (Op::Less, 3, 1)],
+            ),
+            (
+                "1.2.3",
+                &[(Op::Float, 0, 3), (Op::Dot, 3, 1), (Op::Integer, 4, 1)],
+            ),
+            (
+                "1..5",
+                &[
+                    (Op::Integer, 0, 1),
+                    (Op::Dot, 1, 1),
+                    (Op::Dot, 2, 1),
+                    (Op::Integer, 3, 1),
+                ],
+            ),
+            ("<", &[(Op::Less, 0, 1)]),
+            ("<=", &[(Op::LessEqual, 0, 2)]),
+            (
+                "<<=<",
+                &[(Op::Less, 0, 1), (Op::LessEqual, 1, 2), (Op::Less, 3, 1)],
+            ),
+        ];
+
+        for &(input, expected) in cases {
+            let actual = lexer
+                .lex_str(input)
+                .map(|lexeme| lexeme.map(|lexeme| (lexeme.token, lexeme.position, lexeme.length)))
+                .collect::<Result<Vec<_>, _>>();
+            assert_eq!(actual.as_deref(), Ok(expected), "input: {:?}", input);
+        }
+    }
+
+    #[test]
+    fn test_channels() {
+        let mut lexer = small_lexer();
+        let def = lexer.def_mut();
+        def.set_channel(Token::Whitespace, HIDDEN_CHANNEL);
+        def.set_channel(Token::Comment, HIDDEN_CHANNEL);
+        def.set_channel(Token::Newline, 2);
+
+        let tokens = lexer
+            .lex_str("( ;a\n)")
+            .map(|lexeme| lexeme.map(|lexeme| (lexeme.token, lexeme.position)))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                (Token::LParen, 0),
+                (Token::Semicolon, 2),
+                (Token::RParen, 5)
+            ]
+        );
+
+        let mut hidden = vec![];
+        while let Some(lexeme) = lexer.get_channel(HIDDEN_CHANNEL) {
+            hidden.push((lexeme.token, lexeme.position));
+        }
+        assert_eq!(hidden, vec![(Token::Whitespace, 1), (Token::Comment, 3)]);
+
+        assert_eq!(lexer.get_channel(2).map(|lexeme| lexeme.position), Some(4));
+        assert_eq!(lexer.get_channel(2), None);
+        assert_eq!(lexer.get_channel(3), None);
+    }
+
+    #[test]
+    fn test_validate() {
+        let word = Regex::range('a', 'z').plus();
+        let keyword = Regex::char('i').concat(&Regex::char('f'));
+
+        let mut lexer = Lexer::<Mode, Token>::new();
+        lexer
+            .def_mut()
+            .with_rule(
+                Token::Comment,
+                &keyword,
+                Mode::Default,
+                Mode::Default,
+                false,
+            )
+            .with_rule(Token::Integer, &word, Mode::Default, Mode::Default, false)
+            // Only "if", which `Comment` takes.
+            .with_rule(Token::Float, &keyword, Mode::Default, Mode::Default, false)
+            // Only the empty string.
+            .with_rule(
+                Token::Range,
+                &Regex::epsilon(),
+                Mode::Default,
+                Mode::Default,
+                false,
+            );
+        // Wins over `Integer` despite coming after it.
+        let def = lexer.def_mut();
+        def.add_rule_with_options(
+            Token::Semicolon,
+            &Regex::char('x'),
+            Mode::Default,
+            RuleOptions::new()
+                .with_action(ModeAction::Switch(Mode::Default))
+                .with_priority(1),
+        );
+        def.add_rule_with_options(
+            Token::Newline,
+            &Regex::char('\n'),
+            Mode::Comment,
+            RuleOptions::new()
+                .with_action(ModeAction::Switch(Mode::Default))
+                .with_priority(1),
+        );
+
+        let issues = lexer
+            .validate()
+            .iter()
+            .map(|issue| issue.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            issues,
+            vec![
+                "Float in mode Default can never match, shadowed by [Comment, Integer] (e.g. [\"if\"])",
+                "Range in mode Default can never match",
+                "Semicolon and Integer in mode Default both match [\"x\"]",
+                "Comment and Integer in mode Default both match [\"if\"]",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_put_str() {
+        let mut lexer = small_lexer();
+        let input = "( ;ab\n)(;c\n;\n) ?";
+        let expected = lexer.lex_str(input).collect::<Vec<_>>();
+        assert!(expected.last().unwrap().is_err());
+
+        // Every way of splitting the input in two, including mid-token.
+        for split in 0..=input.len() {
+            lexer.reset();
+            lexer.put_str(&input[..split]);
+            lexer.put_str(&input[split..]);
+            lexer.finish();
+
+            assert_eq!(
+                lexer.tokens().collect::<Vec<_>>(),
+                expected,
+                "split: {}",
+                split
+            );
+        }
+    }
+
+    #[test]
+    fn test_layout() {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        enum Line {
+            Word,
+            Comment,
+            Space,
+            Newline,
+            Indent,
+            Dedent,
+        }
+        use Line::*;
+
+        let mut lexer: Lexer<Mode, Line> = crate::lexer! {
+            mode Mode::Default {
+                re "[a-z]+" => Word;
+                re "#[^\n]*" => Comment;
+                re "[ \t]+" => Space, skip;
+                "\n" => Newline, skip;
+            }
+        };
+        let def = lexer.def_mut();
+        def.set_channel(Comment, HIDDEN_CHANNEL);
+        def.set_layout(Some(Layout {
+            newline: Newline,
+            indent: Indent,
+            dedent: Dedent,
+            tab_width: 4,
+        }));
+
+        let layout = |lexer: &mut Lexer<Mode, Line>, input: &str| {
+            lexer
+                .lex_str(input)
+                .map(|lexeme| lexeme.map(|lexeme| lexeme.token))
+                .collect::<Result<Vec<_>, _>>()
+        };
+
+        assert_eq!(
+            layout(&mut lexer, "a b\n  c\n\n      # x\n  d\n\te f\ng\n"),
+            Ok(vec![
+                Word, Word, Newline, Indent, Word, Newline, Word, Newline, Indent, Word, Word,
+                Newline, Dedent, Dedent, Word, Newline,
+            ])
+        );
+        assert_eq!(
+            layout(&mut lexer, "  a\n    b"),
+            Ok(vec![
+                Indent, Word, Newline, Indent, Word, Newline, Dedent, Dedent
+            ])
+        );
+        assert_eq!(layout(&mut lexer, "\n # x\n"), Ok(vec![]));
+        assert_eq!(
+            layout(&mut lexer, "a\n    b\n  c"),
+            Err(LexerError::InconsistentIndent { position: 10 })
+        );
+
+        // Relexing after an edit picks up the layout where it was.
+        let mut edited = lexer.def().run();
+        edited.open("a\n  b\n  c\nd\n");
+        edited.edit(7..7, "  ");
+        let mut fresh = lexer.def().run();
+        fresh.open("a\n  b\n    c\nd\n");
+        assert_eq!(edited.lexemes(), fresh.lexemes());
+    }
+
+    #[test]
+    fn test_nested_rule() {
+        let mut lexer = Lexer::new();
+        lexer
+            .def_mut()
+            .with_rule(
+                Token::Integer,
+                &Regex::range('a', 'z').plus(),
+                Mode::Default,
+                Mode::Default,
+                false,
+            )
+            .with_skip_rule(
+                Token::Whitespace,
+                &Regex::char(' '),
+                Mode::Default,
+                Mode::Default,
+            );
+        lexer.def_mut().add_nested_rule(
+            Token::Comment,
+            &Regex::literal("#|"),
+            &Regex::literal("|#"),
+            Mode::Default,
+            Mode::Comment,
+            true,
+        );
+
+        let lexemes = lexer
+            .lex_str("a #| x #| y |# z| |# b #||#")
+            .map(|lexeme| lexeme.map(|lexeme| (lexeme.token, lexeme.position, lexeme.span)))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            lexemes,
+            vec![
+                (Token::Integer, 0, None),
+                (Token::Comment, 2, Some("#| x #| y |# z| |#".to_string())),
+                (Token::Integer, 21, None),
+                (Token::Comment, 23, Some("#||#".to_string())),
+            ]
+        );
+
+        assert_eq!(
+            lexer.lex_str("a #| #| |#").last(),
+            Some(Err(LexerError::UnexpectedEof {
+                position: 10,
+                alive: vec![Token::Comment],
+            }))
+        );
+    }
+
+    #[test]
+    fn test_heredoc_rule() {
+        let mut lexer = Lexer::new();
+        lexer
+            .def_mut()
+            .with_rule(
+                Token::Integer,
+                &Regex::range('a', 'z').plus(),
+                Mode::Default,
+                Mode::Default,
+                false,
+            )
+            .with_skip_rule(
+                Token::Whitespace,
+                &Regex::char(' '),
+                Mode::Default,
+                Mode::Default,
+            );
+        lexer.def_mut().add_heredoc_rule(
+            Token::Comment,
+            &Regex::parse("<<[A-Z]+\n").unwrap(),
+            Mode::Default,
+            Mode::Default,
+            true,
+            |open| format!("\n{}", open[2..].trim_end()),
+        );
+
+        let expected = vec![
+            Ok((Token::Integer, 0, None)),
+            Ok((
+                Token::Comment,
+                2,
+                Some("<<END\nx <<EOF\n END y\nEND".to_string()),
+            )),
+            Ok((Token::Integer, 27, None)),
+        ];
+
+        // Put all at once and a character at a time.
+        let input = "a <<END\nx <<EOF\n END y\nEND b";
+        let lexemes = lexer
+            .lex_str(input)
+            .map(|lexeme| lexeme.map(|lexeme| (lexeme.token, lexeme.position, lexeme.span)))
+            .collect::<Vec<_>>();
+        assert_eq!(lexemes, expected);
+
+        lexer.reset();
+        input.chars().for_each(|c| lexer.put(c));
+        lexer.finish();
+        let lexemes = lexer
+            .tokens()
+            .map(|lexeme| lexeme.map(|lexeme| (lexeme.token, lexeme.position, lexeme.span)))
+            .collect::<Vec<_>>();
+        assert_eq!(lexemes, expected);
+
+        assert_eq!(
+            lexer.lex_str("<<END\nEN").last(),
+            Some(Err(LexerError::UnexpectedEof {
+                position: 8,
+                alive: vec![Token::Comment],
+            }))
+        );
+    }
+
+    #[test]
+    fn test_trace() {
+        let mut lexer = small_lexer();
+        lexer.put('(');
+        lexer.set_trace(true);
+        lexer.put_str(";x");
+        lexer.finish();
+
+        let trace = lexer
+            .take_trace()
+            .iter()
+            .map(|event| event.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            trace,
+            vec![
+                "1 ';' in Default: alive [], accepts nothing",
+                "emit LParen at 0..1 in Default, the longest match before ';'",
+                "1 ';' in Default: alive [Semicolon], accepts Semicolon",
+                "2 'x' in Default: alive [], accepts nothing",
+                "emit Semicolon at 1..2 in Default, the longest match before 'x'",
+                "mode Default -> Comment, stack depth 0",
+                "2 'x' in Comment: alive [Comment], accepts Comment",
+                "emit Comment at 2..3 in Comment, the longest match before the end of the input",
+                "mode Comment -> Default, stack depth 0",
+            ]
         );
+        assert!(lexer.take_trace().is_empty());
+
+        lexer.set_trace(false);
+        lexer.reset();
+        lexer.put_str("()");
+        lexer.finish();
+        assert!(lexer.take_trace().is_empty());
     }
 }
@@ -0,0 +1,167 @@
+// Declare a `Lexer` in one block of modes and rules instead of a chain of `add_rule` calls. The
+// first mode declared is the start mode. Each rule is a pattern, its token and any options:
+//
+//     let lexer = lexer! {
+//         mode Mode::Default {
+//             "(" => LParen;
+//             re "[0-9]+" => Integer(0), keep, build(|text, _| Integer(text.parse().unwrap()));
+//             re "[ \t]+" => Whitespace, skip;
+//             ";" => Semicolon, to(Mode::Comment);
+//         }
+//         mode Mode::Comment {
+//             re "[^\n]*" => Comment;
+//             "\n" => Newline, to(Mode::Default);
+//         }
+//     };
+//
+// A plain string matches itself and `re` marks a pattern for `Regex::parse`, which panics if it
+// is invalid. The options are `keep`, `skip`, `priority(p)`, `to(mode)`, `push(mode)`, `pop`,
+// `pop_or(mode)` and `build(f)`, as in `RuleOptions`; without `to`, `push`, `pop` or `pop_or` the
+// rule stays in its mode.
+#[macro_export]
+macro_rules! lexer {
+    (@start $lexer:ident,) => {};
+    (@start $lexer:ident, $first:path $(, $rest:path)*) => {
+        $lexer.set_start_mode($first);
+    };
+
+    (@rules $lexer:ident, $mode:path,) => {};
+    (@rules $lexer:ident, $mode:path,
+        re $pattern:literal => $token:expr $(, $option:ident $(($($arg:tt)*))?)*; $($rest:tt)*
+    ) => {
+        let regex = $crate::lex::regex::Regex::parse($pattern)
+            .unwrap_or_else(|error| panic!("invalid pattern {:?}: {}", $pattern, error));
+        $crate::lexer!(@rule $lexer, $mode, regex, $token $(, $option $(($($arg)*))?)*);
+        $crate::lexer!(@rules $lexer, $mode, $($rest)*);
+    };
+    (@rules $lexer:ident, $mode:path,
+        $text:literal => $token:expr $(, $option:ident $(($($arg:tt)*))?)*; $($rest:tt)*
+    ) => {
+        let regex = $crate::lex::regex::Regex::literal($text);
+        $crate::lexer!(@rule $lexer, $mode, regex, $token $(, $option $(($($arg)*))?)*);
+        $crate::lexer!(@rules $lexer, $mode, $($rest)*);
+    };
+
+    (@rule $lexer:ident, $mode:path, $regex:ident, $token:expr
+        $(, $option:ident $(($($arg:tt)*))?)*
+    ) => {{
+        #[allow(unused_mut)]
+        let mut options = $crate::lex::lexer::RuleOptions::new();
+        $($crate::lexer!(@option options, $option $(($($arg)*))?);)*
+        $lexer.add_rule_with_options($token, &$regex, $mode, &options);
+    }};
+
+    (@option $options:ident, keep) => {
+        $options.set_keep_span(true);
+    };
+    (@option $options:ident, skip) => {
+        $options.set_skip(true);
+    };
+    (@option $options:ident, priority($priority:expr)) => {
+        $options.set_priority($priority);
+    };
+    (@option $options:ident, to($mode:expr)) => {
+        $options.set_action($crate::lex::lexer::ModeAction::Switch($mode));
+    };
+    (@option $options:ident, push($mode:expr)) => {
+        $options.set_action($crate::lex::lexer::ModeAction::Push($mode));
+    };
+    (@option $options:ident, pop) => {
+        $options.set_action($crate::lex::lexer::ModeAction::Pop);
+    };
+    (@option $options:ident, pop_or($mode:expr)) => {
+        $options.set_action($crate::lex::lexer::ModeAction::PopOr($mode));
+    };
+    (@option $options:ident, build($builder:expr)) => {
+        $options.set_builder($builder);
+    };
+
+    ($(mode $mode:path { $($rules:tt)* })*) => {{
+        let mut def = $crate::lex::lexer::LexerDef::new();
+        $crate::lexer!(@start def, $($mode),*);
+        $($crate::lexer!(@rules def, $mode, $($rules)*);)*
+        $crate::lex::lexer::Lexer::from_def(def)
+    }};
+}
+
+#[cfg(test)]
+mod test {
+    use crate::lex::lexer::Lexer;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+    enum Mode {
+        #[default]
+        Comment,
+        Code,
+        Block,
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Token {
+        LParen,
+        RParen,
+        Integer(u32),
+        Identifier,
+        Whitespace,
+        Comment,
+        Open,
+        Close,
+    }
+
+    #[test]
+    fn test_lexer_macro() {
+        use Token::*;
+
+        let mut lexer: Lexer<Mode, Token> = lexer! {
+            mode Mode::Code {
+                "(" => LParen;
+                ")" => RParen;
+                re "[0-9]+" => Integer(0), build(|text, _| Integer(text.parse().unwrap()));
+                re "[a-z]+" => Identifier, keep;
+                re r"[ \t\n]+" => Whitespace, skip;
+                re "in|if" => Identifier, priority(1);
+                "/*" => Open, push(Mode::Block);
+                "//" => Comment, to(Mode::Comment);
+            }
+            mode Mode::Block {
+                re r"([^*]|\*[^/])*" => Comment;
+                "*/" => Close, pop;
+            }
+            mode Mode::Comment {
+                re "[^\n]*" => Comment;
+            }
+        };
+
+        let lexemes = lexer
+            .lex_str("(foo 42) /* a */if// b")
+            .map(|lexeme| lexeme.map(|lexeme| (lexeme.token, lexeme.span)))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        assert_eq!(
+            lexemes,
+            vec![
+                (LParen, None),
+                (Identifier, Some("foo".to_string())),
+                (Integer(42), None),
+                (RParen, None),
+                (Open, None),
+                (Comment, None),
+                (Close, None),
+                (Identifier, None),
+                (Comment, None),
+                (Comment, None),
+            ]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid pattern \"[a-\"")]
+    fn test_lexer_macro_invalid_pattern() {
+        let _: Lexer<Mode, Token> = lexer! {
+            mode Mode::Code {
+                re "[a-" => Token::Identifier;
+            }
+        };
+    }
+}
@@ -1,3 +1,15 @@
+pub mod automaton;
+pub mod codegen;
+pub mod dense;
+pub mod dfa;
+pub(crate) mod dot;
+pub mod fixture;
+pub mod lazy;
 pub mod lexer;
+mod macros;
 pub mod nfa;
+pub mod recording;
 pub mod regex;
+pub mod scanner;
+pub mod source;
+pub mod stats;
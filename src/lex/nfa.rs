@@ -1,22 +1,210 @@
-use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
 
+use crate::cancel::{CancelToken, Cancelled};
+use crate::lex::dfa::{Dfa, Progress};
+use crate::lex::dot::{write_dot, DotGraph};
+use crate::lex::stats::{count_components, count_intervals, Stats};
+
+#[derive(Debug, Clone)]
 pub struct Nfa {
     start: Vec<usize>,
     accept: Vec<usize>,
     nodes: Vec<NfaNode>,
 
     optimized: bool,
+    accepting: Vec<bool>,
+
+    cursor: NfaCursor,
+}
 
-    current: Vec<usize>,
-    next: Vec<usize>,
+// Where a simulation of an NFA is, kept apart from the NFA so that one NFA can be simulated by
+// any number of cursors at once without cloning it. The NFA must be optimized, which `Nfa::reset`
+// and `Regex::to_nfa` do. `Nfa::put` and friends step the NFA's own cursor.
+#[derive(Debug, Default, Clone)]
+pub struct NfaCursor {
+    current: StateSet,
+    next: StateSet,
 }
 
+// A sparse set over node indices: constant time insert, membership and clear, and iteration in
+// insertion order. Stepping the simulation therefore never sorts or allocates.
+#[derive(Debug, Default, Clone)]
+struct StateSet {
+    dense: Vec<usize>,
+    sparse: Vec<usize>,
+}
+
+impl StateSet {
+    fn resize(&mut self, len: usize) {
+        self.dense.clear();
+        self.dense.reserve(len);
+        self.sparse.resize(len, 0);
+    }
+
+    fn insert(&mut self, x: usize) {
+        if !self.contains(x) {
+            self.sparse[x] = self.dense.len();
+            self.dense.push(x);
+        }
+    }
+
+    fn contains(&self, x: usize) -> bool {
+        let index = self.sparse[x];
+        index < self.dense.len() && self.dense[index] == x
+    }
+
+    fn clear(&mut self) {
+        self.dense.clear();
+    }
+
+    fn is_empty(&self) -> bool {
+        self.dense.is_empty()
+    }
+
+    fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.dense.iter().copied()
+    }
+}
+
+// `edges` is an interval map: the ranges are sorted, never overlap, and adjacent ranges always
+// lead to different target sets.
 #[derive(Debug, Default, Clone)]
 pub struct NfaNode {
-    edges: Vec<(char, char, usize)>,
+    edges: Vec<(char, char, Vec<usize>)>,
     epsilons: Vec<usize>,
 }
 
+impl NfaNode {
+    fn targets(&self, c: char) -> &[usize] {
+        let index = self.edges.binary_search_by(|&(lo, hi, _)| {
+            if hi < c {
+                Ordering::Less
+            } else if lo > c {
+                Ordering::Greater
+            } else {
+                Ordering::Equal
+            }
+        });
+
+        match index {
+            Ok(index) => &self.edges[index].2,
+            Err(_) => &[],
+        }
+    }
+
+    fn insert_edge(&mut self, lo: char, hi: char, to: usize) {
+        assert!(lo <= hi, "invalid range: {:?}-{:?}", lo, hi);
+
+        let mut edges = Vec::with_capacity(self.edges.len() + 2);
+        let mut remaining = Some(lo);
+
+        for (l, h, targets) in self.edges.drain(..) {
+            let rest = match remaining {
+                Some(rest) if h >= rest => rest,
+                _ => {
+                    edges.push((l, h, targets));
+                    continue;
+                }
+            };
+
+            if l > hi {
+                edges.push((rest, hi, vec![to]));
+                edges.push((l, h, targets));
+                remaining = None;
+                continue;
+            }
+
+            let mut l = l;
+
+            match l.cmp(&rest) {
+                Ordering::Less => {
+                    edges.push((l, char_decr(rest), targets.clone()));
+                    l = rest;
+                }
+                Ordering::Greater => {
+                    edges.push((rest, char_decr(l), vec![to]));
+                }
+                Ordering::Equal => {}
+            }
+
+            let end = h.min(hi);
+
+            let mut overlap = targets.clone();
+            overlap.push(to);
+            overlap.sort_unstable();
+            overlap.dedup();
+            edges.push((l, end, overlap));
+
+            if end < h {
+                edges.push((char_incr(end), h, targets));
+            }
+
+            remaining = if end < hi { Some(char_incr(end)) } else { None };
+        }
+
+        if let Some(rest) = remaining {
+            edges.push((rest, hi, vec![to]));
+        }
+
+        self.edges = edges;
+        self.coalesce();
+    }
+
+    fn coalesce(&mut self) {
+        let mut edges: Vec<(char, char, Vec<usize>)> = Vec::with_capacity(self.edges.len());
+
+        for (lo, hi, mut targets) in self.edges.drain(..) {
+            targets.sort_unstable();
+            targets.dedup();
+
+            if targets.is_empty() {
+                continue;
+            }
+
+            match edges.last_mut() {
+                Some((_, last_hi, last_targets))
+                    if *last_hi != char::MAX
+                        && char_incr(*last_hi) == lo
+                        && *last_targets == targets =>
+                {
+                    *last_hi = hi;
+                }
+                _ => edges.push((lo, hi, targets)),
+            }
+        }
+
+        self.edges = edges;
+    }
+}
+
+pub(crate) fn char_decr(c: char) -> char {
+    assert!(c != char::MIN);
+
+    if c == '\u{E000}' {
+        return '\u{D7FF}';
+    }
+
+    char::from_u32(c as u32 - 1).unwrap()
+}
+
+pub(crate) fn char_incr(c: char) -> char {
+    assert!(c != char::MAX);
+
+    if c == '\u{D7FF}' {
+        return '\u{E000}';
+    }
+
+    char::from_u32(c as u32 + 1).unwrap()
+}
+
+impl Default for Nfa {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Nfa {
     pub fn new() -> Nfa {
         Nfa {
@@ -24,11 +212,23 @@ impl Nfa {
             accept: vec![],
             nodes: vec![],
             optimized: true,
-            current: vec![],
-            next: vec![],
+            accepting: vec![],
+            cursor: NfaCursor::default(),
         }
     }
 
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn current(&self) -> impl Iterator<Item = usize> + '_ {
+        self.cursor.current()
+    }
+
     pub fn add_start(&mut self, start: usize) {
         self.optimized = false;
         self.start.push(start);
@@ -50,19 +250,7 @@ impl Nfa {
 
     pub fn add_edge(&mut self, from: usize, lo: char, hi: char, to: usize) {
         self.optimized = false;
-
-        let overlaps = self.nodes[from]
-            .edges
-            .iter()
-            .any(|&(l, h, _)| (l..h).contains(&lo) || (l..h).contains(&hi));
-
-        if overlaps {
-            let intermediate = self.create_node();
-            self.add_epsilon(from, intermediate);
-            self.add_edge(intermediate, lo, hi, to);
-        } else {
-            self.nodes[from].edges.push((lo, hi, to));
-        }
+        self.nodes[from].insert_edge(lo, hi, to);
     }
 
     pub fn add_epsilon(&mut self, from: usize, to: usize) {
@@ -88,10 +276,12 @@ impl Nfa {
         }
 
         for from in 0..other.nodes.len() {
-            for &(lo, hi, to) in other.nodes[from].edges.iter() {
+            for (lo, hi, targets) in other.nodes[from].edges.iter() {
                 let from = *map.get(&from).unwrap();
-                let to = *map.get(&to).unwrap();
-                self.add_edge(from, lo, hi, to);
+                for to in targets.iter() {
+                    let to = *map.get(to).unwrap();
+                    self.add_edge(from, *lo, *hi, to);
+                }
             }
         }
 
@@ -122,16 +312,25 @@ impl Nfa {
             self.optimize();
         }
 
-        self.current.clear();
-        self.current.extend(self.start.iter());
+        self.cursor.start(self.nodes.len(), &self.start);
     }
 
     fn optimize(&mut self) {
+        self.optimize_with(None).unwrap();
+    }
+
+    // Run the optimizations that `reset` would run, checking `cancel` as it goes. Large NFAs
+    // spend most of this time computing epsilon closures.
+    pub fn optimize_with_cancel(&mut self, cancel: &CancelToken) -> Result<(), Cancelled> {
+        self.optimize_with(Some(cancel))
+    }
+
+    fn optimize_with(&mut self, cancel: Option<&CancelToken>) -> Result<(), Cancelled> {
         if self.optimized {
-            return;
+            return Ok(());
         }
 
-        self.epsilon_closure();
+        self.epsilon_closure(cancel)?;
         self.remove_unreachable_nodes();
         self.remove_dead_nodes();
 
@@ -141,23 +340,33 @@ impl Nfa {
         self.accept.sort_unstable();
         self.accept.dedup();
 
-        for node in 0..self.nodes.len() {
-            self.nodes[node].edges.sort_unstable();
-            self.nodes[node].edges.dedup();
+        for node in self.nodes.iter_mut() {
+            node.coalesce();
 
-            self.nodes[node].epsilons.sort_unstable();
-            self.nodes[node].epsilons.dedup();
+            node.epsilons.sort_unstable();
+            node.epsilons.dedup();
+        }
+
+        self.accepting = vec![false; self.nodes.len()];
+        for &node in self.accept.iter() {
+            self.accepting[node] = true;
         }
 
         self.optimized = true;
+
+        Ok(())
     }
 
-    fn epsilon_closure(&mut self) {
+    fn epsilon_closure(&mut self, cancel: Option<&CancelToken>) -> Result<(), Cancelled> {
         let mut eps = vec![];
         let mut stack = vec![];
         let mut visited = vec![false; self.nodes.len()];
 
         for a in 0..self.nodes.len() {
+            if let Some(cancel) = cancel {
+                cancel.check()?;
+            }
+
             visited.fill(false);
             stack.clear();
             stack.extend(self.nodes[a].epsilons.iter().copied());
@@ -196,6 +405,8 @@ impl Nfa {
         self.start.extend(eps.iter().copied());
         self.start.sort_unstable();
         self.start.dedup();
+
+        Ok(())
     }
 
     pub fn remove_unreachable_nodes(&mut self) {
@@ -209,8 +420,8 @@ impl Nfa {
 
             visited[node] = true;
 
-            for &(_, _, to) in self.nodes[node].edges.iter() {
-                stack.push(to);
+            for (_, _, targets) in self.nodes[node].edges.iter() {
+                stack.extend(targets.iter().copied());
             }
 
             for &to in self.nodes[node].epsilons.iter() {
@@ -218,14 +429,26 @@ impl Nfa {
             }
         }
 
-        for node in 0..self.nodes.len() {
-            if !visited[node] {
-                self.remove_node(node);
-            }
-        }
+        self.retain_nodes(&visited);
     }
 
+    // Keep only the nodes that can still reach an accepting node. Since every node left in the
+    // automaton is live, an empty `current` set is exactly the condition for `is_dead`.
     fn remove_dead_nodes(&mut self) {
+        let mut predecessors = vec![vec![]; self.nodes.len()];
+
+        for (from, node) in self.nodes.iter().enumerate() {
+            for (_, _, targets) in node.edges.iter() {
+                for &to in targets.iter() {
+                    predecessors[to].push(from);
+                }
+            }
+
+            for &to in node.epsilons.iter() {
+                predecessors[to].push(from);
+            }
+        }
+
         let mut stack = self.accept.clone();
         let mut visited = vec![false; self.nodes.len()];
 
@@ -235,105 +458,430 @@ impl Nfa {
             }
 
             visited[node] = true;
+            stack.extend(predecessors[node].iter().copied());
+        }
 
-            for from in 0..self.nodes.len() {
-                for &(_, _, to) in self.nodes[from].edges.iter() {
-                    if to == node {
-                        stack.push(from);
-                    }
-                }
+        self.retain_nodes(&visited);
+    }
 
-                for &to in self.nodes[from].epsilons.iter() {
-                    if to == node {
-                        stack.push(from);
-                    }
-                }
+    fn retain_nodes(&mut self, keep: &[bool]) {
+        if keep.iter().all(|&k| k) {
+            return;
+        }
+
+        self.optimized = false;
+
+        let mut map = vec![None; self.nodes.len()];
+        let mut count = 0;
+
+        for (node, &k) in keep.iter().enumerate() {
+            if k {
+                map[node] = Some(count);
+                count += 1;
             }
         }
 
-        for node in 0..self.nodes.len() {
-            if !visited[node] {
-                self.remove_node(node);
+        let nodes = std::mem::take(&mut self.nodes);
+        self.nodes = nodes
+            .into_iter()
+            .zip(keep.iter())
+            .filter_map(|(node, &k)| k.then_some(node))
+            .collect();
+
+        for node in self.nodes.iter_mut() {
+            for (_, _, targets) in node.edges.iter_mut() {
+                *targets = targets.iter().filter_map(|&to| map[to]).collect();
             }
+            node.coalesce();
+
+            node.epsilons = node.epsilons.iter().filter_map(|&to| map[to]).collect();
         }
+
+        self.start = self.start.iter().filter_map(|&x| map[x]).collect();
+        self.accept = self.accept.iter().filter_map(|&x| map[x]).collect();
     }
 
     pub fn remove_node(&mut self, deleted: usize) {
-        self.optimized = false;
+        let mut keep = vec![true; self.nodes.len()];
+        keep[deleted] = false;
 
-        let old_index = self.nodes.len() - 1;
-        let new_index = deleted;
+        self.retain_nodes(&keep);
+    }
 
-        self.nodes.swap_remove(deleted);
+    pub fn to_dfa(&self) -> Dfa {
+        Nfa::determinize(&[self])
+    }
 
-        for from in 0..self.nodes.len() {
-            self.nodes[from].edges.retain(|&(_, _, to)| to != deleted);
-            self.nodes[from].epsilons.retain(|&x| x != deleted);
+    pub fn to_dfa_with_cancel(&self, cancel: &CancelToken) -> Result<Dfa, Cancelled> {
+        Nfa::determinize_with(&[self], Some(cancel), None)
+    }
+
+    // Subset construction over several NFAs at once. A DFA state is a set of `(nfa, node)` pairs
+    // and is tagged with the lowest index of an NFA that accepts in it, so earlier NFAs win
+    // conflicts.
+    pub(crate) fn determinize(nfas: &[&Nfa]) -> Dfa {
+        Nfa::determinize_with(nfas, None, None).unwrap()
+    }
+
+    pub(crate) fn determinize_with(
+        nfas: &[&Nfa],
+        cancel: Option<&CancelToken>,
+        mut progress: Option<&mut dyn FnMut(Progress)>,
+    ) -> Result<Dfa, Cancelled> {
+        // The construction relies on each node's epsilons being its closure, so NFAs that haven't
+        // been optimized since they were changed are optimized as copies.
+        let copies = nfas
+            .iter()
+            .filter(|nfa| !nfa.optimized)
+            .map(|&nfa| {
+                let mut copy = nfa.clone();
+                copy.optimize_with(cancel)?;
+                Ok(copy)
+            })
+            .collect::<Result<Vec<_>, Cancelled>>()?;
+        let mut copies = copies.iter();
+        let nfas = nfas
+            .iter()
+            .map(|&nfa| match nfa.optimized {
+                true => nfa,
+                false => copies.next().unwrap(),
+            })
+            .collect::<Vec<_>>();
+
+        let mut dfa = Dfa::new();
+        let mut states = HashMap::new();
+        let mut stack = vec![];
+
+        let mut start = nfas
+            .iter()
+            .enumerate()
+            .flat_map(|(i, nfa)| nfa.start.iter().map(move |&node| (i, node)))
+            .collect::<Vec<_>>();
+        start.sort_unstable();
+        start.dedup();
+
+        if start.is_empty() {
+            return Ok(dfa);
         }
 
-        self.start.retain(|&x| x != deleted);
-        self.accept.retain(|&x| x != deleted);
+        let index = dfa.create_state();
+        dfa.set_start(index);
+        states.insert(start.clone(), index);
+        stack.push(start);
 
-        for node in self.start.iter_mut() {
-            if *node == old_index {
-                *node = new_index;
+        let mut boundaries = vec![];
+        let mut processed = 0;
+
+        while let Some(set) = stack.pop() {
+            if let Some(cancel) = cancel {
+                cancel.check()?;
             }
-        }
 
-        for node in self.accept.iter_mut() {
-            if *node == old_index {
-                *node = new_index;
+            let from = states[&set];
+
+            let tag = set
+                .iter()
+                .filter(|&&(i, node)| nfas[i].accepting[node])
+                .map(|&(i, _)| i)
+                .min();
+            dfa.set_accept(from, tag);
+
+            // Split the alphabet at every range boundary of every node in the set. Within each
+            // resulting segment all nodes agree on their targets.
+            boundaries.clear();
+            for &(i, node) in set.iter() {
+                for &(lo, hi, _) in nfas[i].nodes[node].edges.iter() {
+                    boundaries.push(lo);
+                    if hi != char::MAX {
+                        boundaries.push(char_incr(hi));
+                    }
+                }
             }
-        }
+            boundaries.sort_unstable();
+            boundaries.dedup();
+
+            for (index, &lo) in boundaries.iter().enumerate() {
+                let hi = match boundaries.get(index + 1) {
+                    Some(&next) => char_decr(next),
+                    None => char::MAX,
+                };
+
+                let mut target = vec![];
+                for &(i, node) in set.iter() {
+                    let nodes = &nfas[i].nodes;
+                    for &to in nodes[node].targets(lo) {
+                        target.push((i, to));
+                        target.extend(nodes[to].epsilons.iter().map(|&e| (i, e)));
+                    }
+                }
 
-        for from in 0..self.nodes.len() {
-            for (_, _, to) in self.nodes[from].edges.iter_mut() {
-                if *to == old_index {
-                    *to = new_index;
+                if target.is_empty() {
+                    continue;
                 }
+
+                target.sort_unstable();
+                target.dedup();
+
+                let to = match states.get(&target) {
+                    Some(&to) => to,
+                    None => {
+                        let to = dfa.create_state();
+                        states.insert(target.clone(), to);
+                        stack.push(target);
+                        to
+                    }
+                };
+
+                dfa.add_edge(from, lo, hi, to);
             }
 
-            for to in self.nodes[from].epsilons.iter_mut() {
-                if *to == old_index {
-                    *to = new_index;
+            processed += 1;
+
+            if let Some(progress) = progress.as_mut() {
+                progress(Progress::Determinize {
+                    processed,
+                    frontier: stack.len(),
+                });
+            }
+        }
+
+        dfa.reset();
+
+        Ok(dfa)
+    }
+
+    pub fn stats(&self) -> Stats {
+        let successors = self
+            .nodes
+            .iter()
+            .map(|node| {
+                node.edges
+                    .iter()
+                    .flat_map(|(_, _, targets)| targets.iter().copied())
+                    .chain(node.epsilons.iter().copied())
+                    .collect()
+            })
+            .collect::<Vec<Vec<usize>>>();
+
+        Stats {
+            states: self.nodes.len(),
+            edges: self
+                .nodes
+                .iter()
+                .flat_map(|node| node.edges.iter())
+                .map(|(_, _, targets)| targets.len())
+                .sum(),
+            epsilons: self.nodes.iter().map(|node| node.epsilons.len()).sum(),
+            intervals: count_intervals(
+                self.nodes
+                    .iter()
+                    .flat_map(|node| node.edges.iter())
+                    .map(|&(lo, hi, _)| (lo, hi)),
+            ),
+            components: count_components(&successors),
+        }
+    }
+
+    pub fn write_dot<W>(&self, io: W) -> std::io::Result<()>
+    where
+        W: Write,
+    {
+        self.write_dot_with(|node| node.to_string(), io)
+    }
+
+    pub fn write_dot_with<W, F>(&self, label: F, io: W) -> std::io::Result<()>
+    where
+        W: Write,
+        F: Fn(usize) -> String,
+    {
+        let mut edges = BTreeMap::new();
+        let mut epsilons = vec![];
+
+        for (from, node) in self.nodes.iter().enumerate() {
+            for (lo, hi, targets) in node.edges.iter() {
+                for &to in targets.iter() {
+                    edges
+                        .entry((from, to))
+                        .or_insert_with(Vec::new)
+                        .push((*lo, *hi));
                 }
             }
+
+            for &to in node.epsilons.iter() {
+                epsilons.push((from, to));
+            }
         }
+
+        let graph = DotGraph {
+            name: "NFA",
+            states: self.nodes.len(),
+            start: self.start.clone(),
+            accept: self.accept.clone(),
+            edges,
+            defaults: vec![],
+            epsilons,
+        };
+
+        write_dot(&graph, label, io)
+    }
+
+    pub fn equivalent(&self, other: &Nfa) -> bool {
+        self.to_dfa().equivalent(&other.to_dfa())
     }
 
     pub fn put(&mut self, c: char) {
         assert!(self.optimized, "must be optimized before simulating");
 
-        self.next.clear();
+        self.cursor.step(&self.nodes, c);
+    }
 
-        for &from in self.current.iter() {
-            for &(c1, c2, to) in self.nodes[from].edges.iter() {
-                if c1 <= c && c <= c2 {
-                    self.next.push(to);
+    pub(crate) fn start_nodes(&self) -> &[usize] {
+        &self.start
+    }
 
-                    for &e in self.nodes[to].epsilons.iter() {
-                        self.next.push(e);
-                    }
-                }
-            }
-        }
+    pub(crate) fn accepts_node(&self, node: usize) -> bool {
+        self.accepting[node]
+    }
 
-        self.next.sort_unstable();
-        self.next.dedup();
+    // Push every node reachable from `node` on `c`, including through trailing epsilons.
+    pub(crate) fn step_node(&self, node: usize, c: char, out: &mut Vec<usize>) {
+        for &to in self.nodes[node].targets(c) {
+            out.push(to);
+            out.extend(self.nodes[to].epsilons.iter().copied());
+        }
+    }
 
-        std::mem::swap(&mut self.current, &mut self.next);
+    // Simulate the automaton from its start state over `iter`, yielding the number of chars
+    // consumed every time the input read so far is accepted, along with the accepting nodes it
+    // ended in, in order. An NFA with an accept node per alternative, like one made by hand from
+    // several patterns, tells which matched. Stops as soon as the automaton dies.
+    pub fn run<I>(&mut self, iter: I) -> NfaRun<'_, I::IntoIter>
+    where
+        I: IntoIterator<Item = char>,
+    {
+        NfaRun {
+            nfa: self,
+            iter: iter.into_iter(),
+            offset: 0,
+            started: false,
+        }
     }
 
     pub fn is_dead(&self) -> bool {
         assert!(self.optimized, "must be optimized before simulating");
 
-        self.current.is_empty()
+        self.cursor.is_dead()
     }
 
     pub fn is_accept(&self) -> bool {
         assert!(self.optimized, "must be optimized before simulating");
 
-        self.current.iter().any(|&from| self.accept.contains(&from))
+        self.cursor.is_accept(self)
+    }
+}
+
+impl NfaCursor {
+    pub fn new(nfa: &Nfa) -> NfaCursor {
+        let mut cursor = NfaCursor::default();
+        cursor.reset(nfa);
+        cursor
+    }
+
+    pub fn reset(&mut self, nfa: &Nfa) {
+        assert!(nfa.optimized, "must be optimized before simulating");
+
+        self.start(nfa.nodes.len(), &nfa.start);
+    }
+
+    pub fn put(&mut self, nfa: &Nfa, c: char) {
+        self.step(&nfa.nodes, c);
+    }
+
+    pub fn is_dead(&self) -> bool {
+        self.current.is_empty()
+    }
+
+    pub fn is_accept(&self, nfa: &Nfa) -> bool {
+        self.current.iter().any(|from| nfa.accepting[from])
+    }
+
+    pub fn current(&self) -> impl Iterator<Item = usize> + '_ {
+        self.current.iter()
+    }
+
+    fn start(&mut self, len: usize, start: &[usize]) {
+        self.current.resize(len);
+        self.next.resize(len);
+
+        for &node in start.iter() {
+            self.current.insert(node);
+        }
+    }
+
+    fn step(&mut self, nodes: &[NfaNode], c: char) {
+        self.next.clear();
+
+        for from in self.current.iter() {
+            for &to in nodes[from].targets(c) {
+                self.next.insert(to);
+
+                for &e in nodes[to].epsilons.iter() {
+                    self.next.insert(e);
+                }
+            }
+        }
+
+        std::mem::swap(&mut self.current, &mut self.next);
+    }
+}
+
+pub struct NfaRun<'a, I> {
+    nfa: &'a mut Nfa,
+    iter: I,
+    offset: usize,
+    started: bool,
+}
+
+impl<I> Iterator for NfaRun<'_, I>
+where
+    I: Iterator<Item = char>,
+{
+    type Item = (usize, Vec<usize>);
+
+    fn next(&mut self) -> Option<(usize, Vec<usize>)> {
+        if !self.started {
+            self.started = true;
+            self.nfa.reset();
+
+            if self.nfa.is_accept() {
+                return Some((0, self.accepts()));
+            }
+        }
+
+        while !self.nfa.is_dead() {
+            let c = self.iter.next()?;
+
+            self.nfa.put(c);
+            self.offset += 1;
+
+            if self.nfa.is_accept() {
+                return Some((self.offset, self.accepts()));
+            }
+        }
+
+        None
+    }
+}
+
+impl<I> NfaRun<'_, I> {
+    fn accepts(&self) -> Vec<usize> {
+        let mut accepts = self
+            .nfa
+            .current()
+            .filter(|&node| self.nfa.accepts_node(node))
+            .collect::<Vec<_>>();
+        accepts.sort_unstable();
+        accepts
     }
 }
 
@@ -370,14 +918,10 @@ mod tests {
         nfa.add_accept(accept);
 
         let dirpath = "target/debug/dotfiles";
-        match std::fs::create_dir(dirpath) {
-            Err(e) => {
-                if !matches!(e.kind(), std::io::ErrorKind::AlreadyExists) {
-                    panic!("Failed to create directory: {}", e);
-                }
+        if let Err(e) = std::fs::create_dir(dirpath) {
+            if !matches!(e.kind(), std::io::ErrorKind::AlreadyExists) {
+                panic!("Failed to create directory: {}", e);
             }
-
-            _ => {}
         }
 
         write_nfa_dot_file(&nfa, &format!("{}/before.dot", dirpath)).unwrap();
@@ -395,46 +939,7 @@ mod tests {
         }
 
         let mut io = std::fs::File::create(path)?;
-        write_nfa_dot(&nfa, &mut io)?;
-        Ok(())
-    }
-
-    fn write_nfa_dot<W>(nfa: &Nfa, mut io: W) -> std::io::Result<()>
-    where
-        W: std::io::Write,
-    {
-        writeln!(io, "digraph NFA {{")?;
-        writeln!(io, "  rankdir=LR;")?;
-
-        for node in 0..nfa.nodes.len() {
-            writeln!(io, "  {} [label=\"{}\", shape=circle];", node, node)?;
-        }
-
-        writeln!(io, "  _start_point [shape=point];")?;
-        for node in nfa.start.iter() {
-            writeln!(io, "  _start_point -> {};", node)?;
-        }
-
-        if !nfa.accept.is_empty() {
-            writeln!(io, "  _accept_point [shape=point, style=invis];")?;
-        }
-        for node in nfa.accept.iter() {
-            writeln!(io, "  {} [shape=doublecircle];", node)?;
-            writeln!(io, "  {} -> _accept_point [style=invis];", node)?;
-        }
-
-        for node in 0..nfa.nodes.len() {
-            for &(c1, c2, to) in nfa.nodes[node].edges.iter() {
-                writeln!(io, "  {} -> {} [label=\"[{}-{}]\"];", node, to, c1, c2)?;
-            }
-
-            for &to in nfa.nodes[node].epsilons.iter() {
-                writeln!(io, "  {} -> {} [style=dotted];", node, to)?;
-            }
-        }
-
-        writeln!(io, "}}")?;
-
+        nfa.write_dot(&mut io)?;
         Ok(())
     }
 
@@ -448,7 +953,7 @@ mod tests {
             is_dead,
             "is_dead failure. input: {:?}, current: {:?}, accept: {:?}",
             input,
-            nfa.current,
+            nfa.cursor.current,
             nfa.accept
         );
         assert_eq!(
@@ -456,7 +961,7 @@ mod tests {
             is_accept,
             "is_accept failure. input: {:?}, current: {:?}, accept: {:?}",
             input,
-            nfa.current,
+            nfa.cursor.current,
             nfa.accept
         );
 
@@ -489,7 +994,7 @@ mod tests {
         assert!(
             nfa.is_accept(),
             "current: {:?}, accept: {:?}",
-            nfa.current,
+            nfa.cursor.current,
             nfa.accept
         );
 
@@ -596,4 +1101,268 @@ mod tests {
         test_nfa(&mut nfa, "aba", false, false, "test_nfa_abstar");
         test_nfa(&mut nfa, "abab", false, true, "test_nfa_abstar");
     }
+
+    #[test]
+    fn test_nfa_overlapping_edges() {
+        let mut nfa = build_nfa(
+            0,
+            3,
+            &[
+                (0, 'a', 'm', 1),
+                (0, 'h', 'z', 2),
+                (1, 'x', 'x', 3),
+                (2, 'y', 'y', 3),
+            ],
+            &[],
+        );
+
+        test_nfa(&mut nfa, "ax", false, true, "test_nfa_overlapping_edges");
+        test_nfa(&mut nfa, "zy", false, true, "test_nfa_overlapping_edges");
+        test_nfa(&mut nfa, "hx", false, true, "test_nfa_overlapping_edges");
+        test_nfa(&mut nfa, "hy", false, true, "test_nfa_overlapping_edges");
+        test_nfa(&mut nfa, "ay", true, false, "test_nfa_overlapping_edges");
+        test_nfa(&mut nfa, "zx", true, false, "test_nfa_overlapping_edges");
+    }
+
+    #[test]
+    fn test_nfa_node_interval_map() {
+        let mut node = NfaNode::default();
+
+        node.insert_edge('a', 'c', 1);
+        node.insert_edge('d', 'f', 1);
+        assert_eq!(node.edges, vec![('a', 'f', vec![1])]);
+
+        node.insert_edge('c', 'e', 2);
+        assert_eq!(
+            node.edges,
+            vec![
+                ('a', 'b', vec![1]),
+                ('c', 'e', vec![1, 2]),
+                ('f', 'f', vec![1]),
+            ]
+        );
+
+        node.insert_edge('a', 'z', 1);
+        assert_eq!(
+            node.edges,
+            vec![
+                ('a', 'b', vec![1]),
+                ('c', 'e', vec![1, 2]),
+                ('f', 'z', vec![1]),
+            ]
+        );
+
+        assert_eq!(node.targets('d'), &[1, 2]);
+        assert_eq!(node.targets('q'), &[1]);
+        assert!(node.targets('0').is_empty());
+    }
+
+    #[test]
+    fn test_state_set() {
+        let mut set = StateSet::default();
+        set.resize(8);
+
+        assert!(set.is_empty());
+
+        set.insert(5);
+        set.insert(2);
+        set.insert(5);
+
+        assert!(set.contains(5));
+        assert!(set.contains(2));
+        assert!(!set.contains(0));
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![5, 2]);
+
+        set.clear();
+
+        assert!(set.is_empty());
+        assert!(!set.contains(5));
+    }
+
+    #[test]
+    fn test_nfa_dead_branch() {
+        let mut nfa = build_nfa(
+            0,
+            3,
+            &[
+                (0, 'a', 'a', 1),
+                (1, 'b', 'b', 2),
+                (0, 'c', 'c', 4),
+                (4, 'd', 'd', 3),
+            ],
+            &[],
+        );
+
+        test_nfa(&mut nfa, "a", true, false, "test_nfa_dead_branch");
+        test_nfa(&mut nfa, "c", false, false, "test_nfa_dead_branch");
+        test_nfa(&mut nfa, "cd", false, true, "test_nfa_dead_branch");
+    }
+
+    #[test]
+    fn test_nfa_no_accept_is_dead() {
+        let mut nfa = Nfa::new();
+        let start = nfa.create_node();
+        let other = nfa.create_node();
+        nfa.add_start(start);
+        nfa.add_edge(start, 'a', 'z', other);
+        nfa.reset();
+
+        assert!(nfa.is_dead());
+        assert!(!nfa.is_accept());
+    }
+
+    #[test]
+    fn test_nfa_stats() {
+        let nfa = build_nfa(
+            0,
+            2,
+            &[(0, 'a', 'm', 1), (0, 'h', 'z', 2), (1, 'b', 'b', 2)],
+            &[(2, 0)],
+        );
+
+        let stats = nfa.stats();
+
+        assert_eq!(stats.states, 3);
+        assert_eq!(stats.edges, 5);
+        assert_eq!(stats.epsilons, 1);
+        assert_eq!(stats.intervals, 5);
+        assert_eq!(stats.components, 1);
+    }
+
+    #[test]
+    fn test_nfa_run() {
+        let mut nfa = build_nfa(0, 1, &[(0, 'a', 'a', 1), (1, 'a', 'a', 1)], &[]);
+
+        let accepts = nfa.run("aaab".chars()).collect::<Vec<_>>();
+        assert_eq!(accepts, vec![(1, vec![1]), (2, vec![1]), (3, vec![1])]);
+
+        let accepts = nfa.run("baaa".chars()).collect::<Vec<_>>();
+        assert!(accepts.is_empty());
+
+        let mut nfa = build_nfa(0, 1, &[(0, 'a', 'a', 1)], &[(0, 1)]);
+
+        let accepts = nfa.run("aa".chars()).collect::<Vec<_>>();
+        assert_eq!(accepts, vec![(0, vec![1]), (1, vec![1])]);
+
+        // "ab" and "a" followed by any number of "b", each with an accept node of its own.
+        let mut nfa = Nfa::new();
+        let nodes = (0..4).map(|_| nfa.create_node()).collect::<Vec<_>>();
+        nfa.add_start(nodes[0]);
+        nfa.add_edge(nodes[0], 'a', 'a', nodes[1]);
+        nfa.add_edge(nodes[1], 'b', 'b', nodes[2]);
+        nfa.add_edge(nodes[0], 'a', 'a', nodes[3]);
+        nfa.add_edge(nodes[3], 'b', 'b', nodes[3]);
+        nfa.add_accept(nodes[2]);
+        nfa.add_accept(nodes[3]);
+        nfa.reset();
+
+        let accepts = nfa.run("abbc".chars()).collect::<Vec<_>>();
+        assert_eq!(accepts, vec![(1, vec![3]), (2, vec![2, 3]), (3, vec![3])]);
+    }
+
+    #[test]
+    fn test_nfa_cursor() {
+        let mut nfa = build_nfa(0, 2, &[(0, 'a', 'a', 1), (1, 'b', 'b', 2)], &[(2, 0)]);
+        nfa.reset();
+
+        let mut first = NfaCursor::new(&nfa);
+        let mut second = NfaCursor::new(&nfa);
+
+        for c in "abab".chars() {
+            first.put(&nfa, c);
+        }
+        second.put(&nfa, 'a');
+
+        assert!(first.is_accept(&nfa));
+        assert!(!second.is_accept(&nfa));
+        assert!(!second.is_dead());
+
+        second.put(&nfa, 'a');
+        assert!(second.is_dead());
+
+        // The NFA's own simulation is left where `reset` put it.
+        assert!(!nfa.is_accept());
+        assert!(!nfa.is_dead());
+
+        first.reset(&nfa);
+        assert!(!first.is_accept(&nfa));
+    }
+
+    #[test]
+    fn test_nfa_determinize_unoptimized() {
+        // (a|b)c, built by hand and never reset.
+        let mut nfa = Nfa::new();
+        let [start, middle, end] = [(); 3].map(|_| nfa.create_node());
+        let other = nfa.create_node();
+        nfa.add_edge(start, 'a', 'b', middle);
+        nfa.add_epsilon(middle, other);
+        nfa.add_edge(other, 'c', 'c', end);
+        nfa.add_start(start);
+        nfa.add_accept(end);
+
+        let dfa = nfa.to_dfa();
+        assert_eq!(dfa.enumerate(3).collect::<Vec<_>>(), vec!["ac", "bc"]);
+
+        let copy = nfa.clone();
+        assert!(nfa.equivalent(&copy));
+        let cancel = CancelToken::new();
+        assert!(nfa.to_dfa_with_cancel(&cancel).unwrap().equivalent(&dfa));
+
+        // The NFA itself is left as it was.
+        assert!(!nfa.optimized);
+        assert_eq!(nfa.len(), 4);
+    }
+
+    #[test]
+    fn test_nfa_write_dot() {
+        let nfa = build_nfa(
+            0,
+            1,
+            &[(0, 'a', 'z', 1), (0, '"', '"', 1), (0, '\n', '\n', 0)],
+            &[],
+        );
+
+        let mut out = vec![];
+        nfa.write_dot_with(|node| format!("q{}", node), &mut out)
+            .unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            r#"digraph NFA {
+  rankdir=LR;
+  0 [label="q0", shape=circle];
+  1 [label="q1", shape=circle];
+  _start_point [shape=point];
+  _start_point -> 0;
+  _accept_point [shape=point, style=invis];
+  1 [shape=doublecircle];
+  1 -> _accept_point [style=invis];
+  0 -> 0 [label="\\n"];
+  0 -> 1 [label="[\"a-z]"];
+}
+"#
+        );
+    }
+
+    #[test]
+    fn test_nfa_cancel() {
+        let cancel = CancelToken::new();
+        let mut nfa = Nfa::new();
+        let a = nfa.create_node();
+        let b = nfa.create_node();
+        let c = nfa.create_node();
+        nfa.add_start(a);
+        nfa.add_accept(c);
+        nfa.add_edge(a, 'a', 'a', b);
+        nfa.add_epsilon(b, c);
+
+        cancel.cancel();
+        assert_eq!(nfa.optimize_with_cancel(&cancel), Err(Cancelled));
+
+        nfa.reset();
+        assert_eq!(nfa.to_dfa_with_cancel(&cancel).unwrap_err(), Cancelled);
+
+        let dfa = nfa.to_dfa_with_cancel(&CancelToken::new()).unwrap();
+        assert!(dfa.equivalent(&nfa.to_dfa()));
+    }
 }
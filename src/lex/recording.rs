@@ -0,0 +1,277 @@
+use std::fmt::Debug;
+use std::hash::Hash;
+
+use crate::lex::lexer::{LexerRun, Span, TraceEvent};
+
+// A traced lexing of one whole input, kept so it can be stepped through a character at a time in
+// either direction. The cursor is always on a `TraceEvent::Step`, the reading of one character, and
+// everything recorded before that step is what the lexer had done by then.
+#[derive(Debug, Clone)]
+pub struct Recording<M, T> {
+    input: String,
+    events: Vec<TraceEvent<M, T>>,
+    // The index in `events` of every step, and which of them the cursor is on.
+    steps: Vec<usize>,
+    at: usize,
+}
+
+impl<M, T> Recording<M, T>
+where
+    T: Clone + Debug,
+    M: Copy + Debug + Eq + Hash + Default,
+{
+    // Lex the whole of `input` with `run` from a fresh start, tracing every step. The run is left
+    // at the end of the input, with tracing off.
+    pub fn new(run: &mut LexerRun<M, T>, input: &str) -> Recording<M, T> {
+        run.reset();
+        run.set_trace(true);
+        run.put_str(input);
+        run.finish();
+        let events = run.take_trace();
+        run.set_trace(false);
+
+        let steps = events
+            .iter()
+            .enumerate()
+            .filter(|(_, event)| matches!(event, TraceEvent::Step { .. }))
+            .map(|(i, _)| i)
+            .collect();
+
+        Recording {
+            input: input.to_string(),
+            events,
+            steps,
+            at: 0,
+        }
+    }
+}
+
+impl<M, T> Recording<M, T>
+where
+    M: Copy,
+{
+    pub fn input(&self) -> &str {
+        &self.input
+    }
+
+    pub fn events(&self) -> &[TraceEvent<M, T>] {
+        &self.events
+    }
+
+    // The number of characters read, counting those read again after a lexeme ended before them.
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    // Which step the cursor is on.
+    pub fn position(&self) -> usize {
+        self.at
+    }
+
+    // The step the cursor is on, or `None` if nothing was read.
+    pub fn step(&self) -> Option<&TraceEvent<M, T>> {
+        self.steps.get(self.at).map(|&event| &self.events[event])
+    }
+
+    // The mode the character at the cursor was read in.
+    pub fn mode(&self) -> Option<M> {
+        match self.step()? {
+            TraceEvent::Step { mode, .. } => Some(*mode),
+            _ => unreachable!(),
+        }
+    }
+
+    // The rules still alive after reading the character at the cursor.
+    pub fn alive(&self) -> &[T] {
+        match self.step() {
+            Some(TraceEvent::Step { alive, .. }) => alive,
+            _ => &[],
+        }
+    }
+
+    // The text the lexer holds at the cursor: from the end of the last lexeme up to and including
+    // the character just read.
+    pub fn buffer(&self) -> &str {
+        let Some(TraceEvent::Step { position, c, .. }) = self.step() else {
+            return "";
+        };
+        let start = self.emitted().last().map_or(0, |(_, span)| span.end);
+
+        &self.input[start..position + c.len_utf8()]
+    }
+
+    // The lexemes emitted before the cursor, including skipped ones.
+    pub fn emitted(&self) -> Vec<(&T, Span)> {
+        let end = self
+            .steps
+            .get(self.at)
+            .copied()
+            .unwrap_or(self.events.len());
+
+        self.events[..end]
+            .iter()
+            .filter_map(|event| match event {
+                TraceEvent::Emit { token, span, .. } => Some((token, *span)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // Move to the next character read, returning whether there was one.
+    pub fn next_char(&mut self) -> bool {
+        if self.at + 1 >= self.steps.len() {
+            return false;
+        }
+
+        self.at += 1;
+        true
+    }
+
+    // Move back to the character read before, returning whether there was one.
+    pub fn prev_char(&mut self) -> bool {
+        if self.at == 0 {
+            return false;
+        }
+
+        self.at -= 1;
+        true
+    }
+
+    // Move to the first character read for the `n`th lexeme, counting skipped ones, returning
+    // whether there was such a lexeme.
+    pub fn jump_to_token(&mut self, n: usize) -> bool {
+        let emits = self
+            .events
+            .iter()
+            .enumerate()
+            .filter(|(_, event)| matches!(event, TraceEvent::Emit { .. }))
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        if n >= emits.len() {
+            return false;
+        }
+
+        // Every lexeme is at least a character long, so its first step comes after the lexeme
+        // before it and no later than its own emit.
+        let after = n.checked_sub(1).map_or(0, |previous| emits[previous]);
+        self.at = self.steps.partition_point(|&step| step < after);
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lex::lexer::LexerDef;
+    use crate::lex::regex::Regex;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+    enum Mode {
+        #[default]
+        Default,
+        String,
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Token {
+        Word,
+        Space,
+        Quote,
+        Text,
+    }
+
+    fn quote_lexer() -> LexerDef<Mode, Token> {
+        let mut def = LexerDef::new();
+        def.set_start_mode(Mode::Default);
+        def.add_rule(
+            Token::Word,
+            &Regex::range('a', 'z').plus(),
+            Mode::Default,
+            Mode::Default,
+            false,
+        );
+        def.add_skip_rule(
+            Token::Space,
+            &Regex::char(' '),
+            Mode::Default,
+            Mode::Default,
+        );
+        def.add_rule(
+            Token::Quote,
+            &Regex::char('"'),
+            Mode::Default,
+            Mode::String,
+            false,
+        );
+        def.add_rule(
+            Token::Text,
+            &Regex::none_of("\"").plus(),
+            Mode::String,
+            Mode::String,
+            false,
+        );
+        def.add_rule(
+            Token::Quote,
+            &Regex::char('"'),
+            Mode::String,
+            Mode::Default,
+            false,
+        );
+        def
+    }
+
+    #[test]
+    fn test_recording() {
+        let def = quote_lexer();
+        let mut lexer = def.run();
+        let mut recording = Recording::new(&mut lexer, "ab \"x\"");
+
+        // Each character is read once, and those after a lexeme are read again to start the next.
+        assert_eq!(recording.len(), 10);
+        assert_eq!(recording.buffer(), "a");
+        assert_eq!(recording.alive(), &[Token::Word]);
+        assert!(!recording.prev_char());
+
+        assert!(recording.next_char());
+        assert!(recording.next_char());
+        assert_eq!(recording.buffer(), "ab ");
+        assert!(recording.alive().is_empty());
+        assert!(recording.emitted().is_empty());
+
+        assert!(recording.next_char());
+        assert_eq!(recording.buffer(), " ");
+        assert_eq!(
+            recording.emitted(),
+            vec![(&Token::Word, Span { start: 0, end: 2 })]
+        );
+
+        // The skipped space counts as a lexeme, and the quote switches to the string mode.
+        assert!(recording.jump_to_token(3));
+        assert_eq!(recording.buffer(), "x");
+        assert_eq!(recording.mode(), Some(Mode::String));
+        assert_eq!(recording.alive(), &[Token::Text]);
+
+        assert!(recording.prev_char());
+        assert_eq!(recording.buffer(), "\"x");
+        assert_eq!(recording.mode(), Some(Mode::Default));
+
+        assert!(recording.jump_to_token(0));
+        assert_eq!(recording.position(), 0);
+        assert!(!recording.jump_to_token(5));
+
+        while recording.next_char() {}
+        assert_eq!(recording.position(), 9);
+        assert_eq!(recording.buffer(), "\"");
+        assert_eq!(recording.emitted().len(), 4);
+
+        // Nothing is read from an empty input.
+        let recording = Recording::new(&mut lexer, "");
+        assert!(recording.is_empty());
+        assert_eq!(recording.step(), None);
+        assert_eq!(recording.buffer(), "");
+    }
+}
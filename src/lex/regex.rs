@@ -1,7 +1,10 @@
-use std::fmt::Debug;
+use std::error::Error;
+use std::fmt::{self, Debug};
+use std::iter::Peekable;
 use std::rc::Rc;
+use std::str::CharIndices;
 
-use crate::lex::nfa::Nfa;
+use crate::lex::nfa::{char_decr, char_incr, Nfa};
 
 #[derive(Debug, Clone)]
 pub struct Regex(Rc<RegexInner>);
@@ -14,6 +17,7 @@ enum RegexInner {
     Range(char, char),
     OneOf(String),
     NoneOf(String),
+    NoneIn(Vec<(char, char)>),
     Any,
     Concat(Regex, Regex),
     Union(Regex, Regex),
@@ -47,6 +51,35 @@ impl Regex {
         Regex(Rc::new(RegexInner::NoneOf(chars.to_string())))
     }
 
+    // Any character outside all of `ranges`.
+    pub fn none_in(ranges: &[(char, char)]) -> Self {
+        Regex(Rc::new(RegexInner::NoneIn(ranges.to_vec())))
+    }
+
+    // Exactly `text`.
+    pub fn literal(text: &str) -> Self {
+        text.chars()
+            .map(Regex::char)
+            .reduce(|lhs, rhs| lhs.concat(&rhs))
+            .unwrap_or_else(Regex::epsilon)
+    }
+
+    // A regex written in the usual syntax: `|`, `*`, `+`, `?`, groups, classes like `[a-z_]` and
+    // `[^"\\]`, and the escapes `\n`, `\t`, `\r`, `\0`, `\d`, `\s` and `\w`. Any other escaped
+    // character stands for itself. Unlike most regex engines, `.` also matches newlines, like
+    // `Regex::any`.
+    pub fn parse(pattern: &str) -> Result<Regex, RegexError> {
+        let mut parser = Parser {
+            chars: pattern.char_indices().peekable(),
+        };
+
+        let regex = parser.union()?;
+        match parser.chars.next() {
+            None => Ok(regex),
+            Some((position, c)) => Err(RegexError::UnexpectedChar { position, c }),
+        }
+    }
+
     pub fn any() -> Self {
         Regex(Rc::new(RegexInner::Any))
     }
@@ -79,6 +112,7 @@ impl Regex {
             RegexInner::Range(lo, hi) => to_nfa_range(*lo, *hi),
             RegexInner::OneOf(chars) => to_nfa_one_of(chars),
             RegexInner::NoneOf(chars) => to_nfa_none_of(chars),
+            RegexInner::NoneIn(ranges) => to_nfa_none_in(ranges),
             RegexInner::Any => to_nfa_any(),
             RegexInner::Concat(lhs, rhs) => to_nfa_concat(&lhs.to_nfa(), &rhs.to_nfa()),
             RegexInner::Union(lhs, rhs) => to_nfa_union(&lhs.to_nfa(), &rhs.to_nfa()),
@@ -93,6 +127,187 @@ impl Regex {
     }
 }
 
+// Why `Regex::parse` rejected a pattern. Positions are byte offsets into the pattern.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegexError {
+    UnexpectedChar { position: usize, c: char },
+    // The pattern ended inside a group, class or escape.
+    UnexpectedEnd,
+    // A class range like `z-a` whose end comes before its start.
+    InvalidRange { position: usize, lo: char, hi: char },
+}
+
+impl fmt::Display for RegexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RegexError::UnexpectedChar { position, c } => {
+                write!(f, "unexpected {:?} at {}", c, position)
+            }
+            RegexError::UnexpectedEnd => write!(f, "unexpected end of pattern"),
+            RegexError::InvalidRange { position, lo, hi } => {
+                write!(f, "invalid range {:?}-{:?} at {}", lo, hi, position)
+            }
+        }
+    }
+}
+
+impl Error for RegexError {}
+
+// A recursive descent parser for `Regex::parse`, one method per precedence level.
+struct Parser<'a> {
+    chars: Peekable<CharIndices<'a>>,
+}
+
+impl Parser<'_> {
+    fn peek(&mut self) -> Option<char> {
+        self.chars.peek().map(|&(_, c)| c)
+    }
+
+    fn next(&mut self) -> Result<(usize, char), RegexError> {
+        self.chars.next().ok_or(RegexError::UnexpectedEnd)
+    }
+
+    fn union(&mut self) -> Result<Regex, RegexError> {
+        let mut regex = self.concat()?;
+
+        while self.peek() == Some('|') {
+            self.chars.next();
+            regex = regex.union(&self.concat()?);
+        }
+
+        Ok(regex)
+    }
+
+    fn concat(&mut self) -> Result<Regex, RegexError> {
+        let mut regex: Option<Regex> = None;
+
+        while !matches!(self.peek(), None | Some('|') | Some(')')) {
+            let next = self.repeat()?;
+            regex = Some(match regex {
+                Some(regex) => regex.concat(&next),
+                None => next,
+            });
+        }
+
+        Ok(regex.unwrap_or_else(Regex::epsilon))
+    }
+
+    fn repeat(&mut self) -> Result<Regex, RegexError> {
+        let mut regex = self.atom()?;
+
+        loop {
+            regex = match self.peek() {
+                Some('*') => regex.star(),
+                Some('+') => regex.plus(),
+                Some('?') => regex.optional(),
+                _ => return Ok(regex),
+            };
+            self.chars.next();
+        }
+    }
+
+    fn atom(&mut self) -> Result<Regex, RegexError> {
+        let (position, c) = self.next()?;
+
+        match c {
+            '(' => {
+                let regex = self.union()?;
+                match self.next()? {
+                    (_, ')') => Ok(regex),
+                    (position, c) => Err(RegexError::UnexpectedChar { position, c }),
+                }
+            }
+            '[' => self.class(),
+            '.' => Ok(Regex::any()),
+            '\\' => Ok(ranges_regex(&self.escape()?)),
+            '*' | '+' | '?' | ')' => Err(RegexError::UnexpectedChar { position, c }),
+            c => Ok(Regex::char(c)),
+        }
+    }
+
+    // The ranges an escape stands for, just after its backslash.
+    fn escape(&mut self) -> Result<Vec<(char, char)>, RegexError> {
+        let c = match self.next()?.1 {
+            'n' => '\n',
+            't' => '\t',
+            'r' => '\r',
+            '0' => '\0',
+            'd' => return Ok(vec![('0', '9')]),
+            's' => return Ok(vec![(' ', ' '), ('\t', '\n'), ('\r', '\r')]),
+            'w' => return Ok(vec![('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')]),
+            c => c,
+        };
+
+        Ok(vec![(c, c)])
+    }
+
+    // A class, just after its `[`. A `]` first in the class and a `-` first or last in it stand
+    // for themselves.
+    fn class(&mut self) -> Result<Regex, RegexError> {
+        let negated = self.peek() == Some('^');
+        if negated {
+            self.chars.next();
+        }
+
+        let mut ranges = vec![];
+        let mut first = true;
+
+        loop {
+            let (position, c) = self.next()?;
+
+            let lo = match c {
+                ']' if !first => break,
+                '\\' => {
+                    let escaped = self.escape()?;
+                    if escaped.len() > 1 || escaped[0].0 != escaped[0].1 {
+                        ranges.extend(escaped);
+                        first = false;
+                        continue;
+                    }
+                    escaped[0].0
+                }
+                c => c,
+            };
+            first = false;
+
+            let mut lookahead = self.chars.clone();
+            if !matches!(lookahead.next(), Some((_, '-')))
+                || matches!(lookahead.next(), Some((_, ']')) | None)
+            {
+                ranges.push((lo, lo));
+                continue;
+            }
+
+            self.chars.next();
+            let hi = match self.next()? {
+                (_, '\\') => match self.escape()?[..] {
+                    [(hi, same)] if hi == same => hi,
+                    _ => return Err(RegexError::UnexpectedChar { position, c: '\\' }),
+                },
+                (_, hi) => hi,
+            };
+
+            if hi < lo {
+                return Err(RegexError::InvalidRange { position, lo, hi });
+            }
+            ranges.push((lo, hi));
+        }
+
+        Ok(match negated {
+            true => Regex::none_in(&ranges),
+            false => ranges_regex(&ranges),
+        })
+    }
+}
+
+fn ranges_regex(ranges: &[(char, char)]) -> Regex {
+    ranges
+        .iter()
+        .map(|&(lo, hi)| Regex::range(lo, hi))
+        .reduce(|lhs, rhs| lhs.union(&rhs))
+        .unwrap_or_else(Regex::empty)
+}
+
 fn to_nfa_empty() -> Nfa {
     Nfa::new()
 }
@@ -147,26 +362,6 @@ fn to_nfa_one_of(chars: &str) -> Nfa {
     nfa
 }
 
-fn char_decr(c: char) -> char {
-    assert!(c != char::MIN);
-
-    if c == '\u{E000}' {
-        return '\u{D7FF}';
-    }
-
-    char::from_u32(c as u32 - 1).unwrap()
-}
-
-fn char_incr(c: char) -> char {
-    assert!(c != char::MAX);
-
-    if c == '\u{D7FF}' {
-        return '\u{E000}';
-    }
-
-    char::from_u32(c as u32 + 1).unwrap()
-}
-
 fn to_nfa_none_of(chars: &str) -> Nfa {
     let mut chars = chars.chars().collect::<Vec<_>>();
     chars.sort_unstable();
@@ -213,6 +408,37 @@ fn to_nfa_none_of(chars: &str) -> Nfa {
         .unwrap()
 }
 
+fn to_nfa_none_in(ranges: &[(char, char)]) -> Nfa {
+    let mut ranges = ranges.to_vec();
+    ranges.sort_unstable();
+
+    // The gaps between the ranges, starting from the lowest character.
+    let mut next = Some(char::MIN);
+    let mut sub_nfas = vec![];
+
+    for (lo, hi) in ranges {
+        let Some(gap_start) = next else {
+            break;
+        };
+
+        if gap_start < lo {
+            sub_nfas.push(to_nfa_range(gap_start, char_decr(lo)));
+        }
+        if hi >= gap_start {
+            next = (hi != char::MAX).then(|| char_incr(hi));
+        }
+    }
+
+    if let Some(gap_start) = next {
+        sub_nfas.push(to_nfa_range(gap_start, char::MAX));
+    }
+
+    sub_nfas
+        .into_iter()
+        .reduce(|a, b| to_nfa_union(&a, &b))
+        .unwrap_or_else(to_nfa_empty)
+}
+
 fn to_nfa_any() -> Nfa {
     to_nfa_range(char::MIN, char::MAX)
 }
@@ -226,8 +452,8 @@ fn to_nfa_concat(lhs: &Nfa, rhs: &Nfa) -> Nfa {
     nfa.add_start(start);
     nfa.add_accept(accept);
 
-    let (lhs_start, lhs_accept) = nfa.merge(&lhs);
-    let (rhs_start, rhs_accept) = nfa.merge(&rhs);
+    let (lhs_start, lhs_accept) = nfa.merge(lhs);
+    let (rhs_start, rhs_accept) = nfa.merge(rhs);
 
     nfa.add_epsilon(start, lhs_start);
     nfa.add_epsilon(lhs_accept, rhs_start);
@@ -245,8 +471,8 @@ fn to_nfa_union(lhs: &Nfa, rhs: &Nfa) -> Nfa {
     nfa.add_start(start);
     nfa.add_accept(accept);
 
-    let (lhs_start, lhs_accept) = nfa.merge(&lhs);
-    let (rhs_start, rhs_accept) = nfa.merge(&rhs);
+    let (lhs_start, lhs_accept) = nfa.merge(lhs);
+    let (rhs_start, rhs_accept) = nfa.merge(rhs);
 
     nfa.add_epsilon(start, lhs_start);
     nfa.add_epsilon(start, rhs_start);
@@ -265,7 +491,7 @@ fn to_nfa_star(nfa: &Nfa) -> Nfa {
     nfa_star.add_start(start);
     nfa_star.add_accept(accept);
 
-    let (inner_start, inner_accept) = nfa_star.merge(&nfa);
+    let (inner_start, inner_accept) = nfa_star.merge(nfa);
 
     nfa_star.add_epsilon(start, accept);
     nfa_star.add_epsilon(start, inner_start);
@@ -276,11 +502,11 @@ fn to_nfa_star(nfa: &Nfa) -> Nfa {
 }
 
 fn to_nfa_plus(nfa: &Nfa) -> Nfa {
-    to_nfa_concat(&nfa, &to_nfa_star(nfa))
+    to_nfa_concat(nfa, &to_nfa_star(nfa))
 }
 
 fn to_nfa_optional(nfa: &Nfa) -> Nfa {
-    to_nfa_union(&to_nfa_epsilon(), &nfa)
+    to_nfa_union(&to_nfa_epsilon(), nfa)
 }
 
 #[cfg(test)]
@@ -392,4 +618,72 @@ mod test {
         test_regex(&regex, "`", false);
         test_regex(&regex, "&", true);
     }
+
+    #[test]
+    fn test_parse() {
+        let float = Regex::parse(r"[+-]?[0-9]+(\.[0-9]+)?([eE][+-]?\d+)?").unwrap();
+        test_regex(&float, "1", true);
+        test_regex(&float, "-1.5", true);
+        test_regex(&float, "+2e-10", true);
+        test_regex(&float, "1.", false);
+        test_regex(&float, "e1", false);
+
+        let string = Regex::parse(r#""([^"\\]|\\.)*""#).unwrap();
+        test_regex(&string, r#""""#, true);
+        test_regex(&string, r#""a\"b\\""#, true);
+        test_regex(&string, "\"a\nb\"", true);
+        test_regex(&string, r#""a\""#, false);
+
+        let alternatives = Regex::parse("ab|c|").unwrap();
+        test_regex(&alternatives, "ab", true);
+        test_regex(&alternatives, "c", true);
+        test_regex(&alternatives, "", true);
+        test_regex(&alternatives, "a", false);
+
+        let class = Regex::parse(r"[]a\-\w-][^\s\]]").unwrap();
+        test_regex(&class, "]x", true);
+        test_regex(&class, "-]", false);
+        test_regex(&class, "_-", true);
+        test_regex(&class, "a\t", false);
+
+        let escaped = Regex::parse(r"\(\*\n").unwrap();
+        test_regex(&escaped, "(*\n", true);
+        test_regex(&Regex::literal("(*\n"), "(*\n", true);
+        test_regex(&Regex::literal(""), "", true);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            Regex::parse("a)").unwrap_err(),
+            RegexError::UnexpectedChar {
+                position: 1,
+                c: ')'
+            }
+        );
+        assert_eq!(Regex::parse("(a").unwrap_err(), RegexError::UnexpectedEnd);
+        assert_eq!(Regex::parse("[a").unwrap_err(), RegexError::UnexpectedEnd);
+        assert_eq!(Regex::parse("a\\").unwrap_err(), RegexError::UnexpectedEnd);
+        assert_eq!(
+            Regex::parse("*").unwrap_err(),
+            RegexError::UnexpectedChar {
+                position: 0,
+                c: '*'
+            }
+        );
+        assert_eq!(
+            Regex::parse("[z-a]").unwrap_err().to_string(),
+            "invalid range 'z'-'a' at 1"
+        );
+    }
+
+    #[test]
+    fn test_none_in() {
+        let regex = Regex::none_in(&[('0', '9'), ('a', 'z'), ('c', 'e')]);
+        test_regex(&regex, "A", true);
+        test_regex(&regex, "5", false);
+        test_regex(&regex, "f", false);
+        test_regex(&regex, "\u{10FFFF}", true);
+        test_regex(&Regex::none_in(&[(char::MIN, char::MAX)]), "a", false);
+    }
 }
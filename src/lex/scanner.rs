@@ -0,0 +1,153 @@
+use std::sync::Arc;
+
+use crate::lex::automaton::{Automaton, AutomatonState};
+use crate::lex::lexer::ModeAction;
+
+// The lexer's rules compiled into one automaton per mode, stepped by hand. `step` feeds one
+// character of the current token and says whether it can go on; `accept` ends the token at the
+// longest match seen so far and applies the rule's mode action. Rescanning whatever followed
+// the match is up to the caller, which makes this suitable for custom scanning loops. Ties
+// between rules are broken by priority and then order, as in `Lexer`. Matches of trailing context
+// rules include the context, since the scanner doesn't keep the input to rescan it, nested
+// rules match a piece at a time and heredoc rules match only their opening.
+#[derive(Debug, Clone)]
+pub struct ScannerState<M, T> {
+    modes: Vec<ScannerMode<T>>,
+    mode_names: Vec<M>,
+    start_mode: usize,
+
+    mode: usize,
+    mode_stack: Vec<usize>,
+    length: usize,
+    last_accept: Option<(usize, usize)>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct ScannerMode<T> {
+    pub automaton: Arc<Automaton>,
+    pub state: AutomatonState,
+    // The token and mode action of every rule, indexed by the automaton's accept tags.
+    pub rules: Vec<(T, ModeAction<usize>)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StepResult<T> {
+    // The input so far is not a token, but could still become one.
+    Pending,
+    // The input so far is a `T`, and a longer match may still follow.
+    Accept(T),
+    // No rule can continue with this character. The caller should `accept` the longest match,
+    // if there is one, and rescan the input after it.
+    Dead,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScanMatch<T> {
+    pub token: T,
+    pub length: usize,
+}
+
+impl<M, T> ScannerState<M, T>
+where
+    M: Copy + PartialEq,
+    T: Clone,
+{
+    pub(crate) fn new(modes: Vec<ScannerMode<T>>, mode_names: Vec<M>, start_mode: usize) -> Self {
+        let mut scanner = ScannerState {
+            modes,
+            mode_names,
+            start_mode,
+            mode: start_mode,
+            mode_stack: vec![],
+            length: 0,
+            last_accept: None,
+        };
+
+        scanner.reset();
+
+        scanner
+    }
+
+    // Go back to the start mode with an empty token and mode stack.
+    pub fn reset(&mut self) {
+        self.mode = self.start_mode;
+        self.mode_stack.clear();
+        self.restart();
+    }
+
+    pub fn mode(&self) -> M {
+        self.mode_names[self.mode]
+    }
+
+    // Switch modes, discarding the current token. Does nothing if no rule uses `mode`.
+    pub fn set_mode(&mut self, mode: M) {
+        if let Some(index) = self.mode_names.iter().position(|&name| name == mode) {
+            self.mode = index;
+            self.restart();
+        }
+    }
+
+    // Number of characters stepped since the current token started.
+    pub fn length(&self) -> usize {
+        self.length
+    }
+
+    // The longest match of the current token so far.
+    pub fn last_accept(&self) -> Option<ScanMatch<T>> {
+        self.last_accept.map(|(rule, length)| ScanMatch {
+            token: self.modes[self.mode].rules[rule].0.clone(),
+            length,
+        })
+    }
+
+    pub fn step(&mut self, c: char) -> StepResult<T> {
+        let Some(mode) = self.modes.get_mut(self.mode) else {
+            return StepResult::Dead;
+        };
+
+        if mode.state.is_dead() {
+            return StepResult::Dead;
+        }
+
+        mode.automaton.put(&mut mode.state, c);
+
+        if mode.state.is_dead() {
+            return StepResult::Dead;
+        }
+
+        self.length += 1;
+
+        match mode.automaton.accept_tag(&mode.state) {
+            Some(rule) => {
+                self.last_accept = Some((rule, self.length));
+                StepResult::Accept(mode.rules[rule].0.clone())
+            }
+            None => StepResult::Pending,
+        }
+    }
+
+    // End the current token at its longest match, apply the matching rule's mode action and start
+    // a new token. Returns `None`, changing nothing, if nothing has matched yet.
+    pub fn accept(&mut self) -> Option<ScanMatch<T>> {
+        let token = self.last_accept()?;
+        let (rule, _) = self.last_accept.unwrap();
+
+        let action = self.modes[self.mode].rules[rule].1;
+        // There is nowhere to report a mode stack underflow, so go back to the start mode.
+        self.mode = action
+            .apply(self.mode, &mut self.mode_stack)
+            .unwrap_or(self.start_mode);
+        self.restart();
+
+        Some(token)
+    }
+
+    fn restart(&mut self) {
+        self.length = 0;
+        self.last_accept = None;
+
+        if let Some(mode) = self.modes.get_mut(self.mode) {
+            mode.automaton.reset(&mut mode.state);
+        }
+    }
+}
@@ -0,0 +1,162 @@
+use std::fmt;
+
+use crate::lex::lexer::Span;
+
+// A source text together with the byte offset of each of its lines, for turning the byte offsets
+// of lexemes and errors into line and column numbers and quoting the text around them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceMap {
+    name: String,
+    text: String,
+    lines: Vec<usize>,
+}
+
+// A line and column, both counted from 1. Columns count characters, not bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Location {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+impl SourceMap {
+    pub fn new(name: &str, text: &str) -> SourceMap {
+        let lines = std::iter::once(0)
+            .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+
+        SourceMap {
+            name: name.to_string(),
+            text: text.to_string(),
+            lines,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn line_count(&self) -> usize {
+        self.lines.len()
+    }
+
+    // The text covered by `span`.
+    pub fn slice(&self, span: Span) -> &str {
+        &self.text[span.range()]
+    }
+
+    // Where the byte at `offset` is. `offset` may be the length of the text, for the end of the
+    // input.
+    pub fn location(&self, offset: usize) -> Location {
+        assert!(
+            offset <= self.text.len(),
+            "offset {} is past the end of {}",
+            offset,
+            self.name
+        );
+
+        let line = self.lines.partition_point(|&start| start <= offset) - 1;
+
+        Location {
+            line: line + 1,
+            column: self.text[self.lines[line]..offset].chars().count() + 1,
+        }
+    }
+
+    // The text of line `line`, counted from 1, without its newline.
+    pub fn line(&self, line: usize) -> &str {
+        let start = self.lines[line - 1];
+        let end = self
+            .lines
+            .get(line)
+            .map_or(self.text.len(), |&next| next - 1);
+
+        &self.text[start..end]
+    }
+
+    // The first line of `span` with the spanned part underlined, e.g.
+    //
+    //     2 | (foo bar)
+    //       |      ^^^
+    pub fn snippet(&self, span: Span) -> String {
+        let start = self.location(span.start);
+        let line = self.line(start.line);
+        let end = span.end.min(self.lines[start.line - 1] + line.len());
+        let width = self.text[span.start..end].chars().count().max(1);
+
+        let number = start.line.to_string();
+        format!(
+            "{} | {}\n{} | {}{}",
+            number,
+            line,
+            " ".repeat(number.len()),
+            " ".repeat(start.column - 1),
+            "^".repeat(width)
+        )
+    }
+
+    // `message` prefixed by where `span` starts, followed by its snippet.
+    pub fn report(&self, span: Span, message: impl fmt::Display) -> String {
+        format!(
+            "{}:{}: {}\n{}",
+            self.name,
+            self.location(span.start),
+            message,
+            self.snippet(span)
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_location() {
+        let source = SourceMap::new("a.scm", "ab\nλc\n\nd");
+
+        assert_eq!(source.line_count(), 4);
+        assert_eq!(source.location(0), Location { line: 1, column: 1 });
+        assert_eq!(source.location(2), Location { line: 1, column: 3 });
+        assert_eq!(source.location(3), Location { line: 2, column: 1 });
+        assert_eq!(source.location(5), Location { line: 2, column: 2 });
+        assert_eq!(source.location(7), Location { line: 3, column: 1 });
+        assert_eq!(source.location(9), Location { line: 4, column: 2 });
+
+        assert_eq!(source.line(2), "λc");
+        assert_eq!(source.line(3), "");
+        assert_eq!(source.line(4), "d");
+        assert_eq!(source.slice(Span { start: 3, end: 6 }), "λc");
+    }
+
+    #[test]
+    fn test_report() {
+        let source = SourceMap::new("a.scm", "(a)\n(λ foo\n bar)");
+
+        assert_eq!(
+            source.report(Span { start: 8, end: 11 }, "unbound variable"),
+            "a.scm:2:4: unbound variable\n2 | (λ foo\n  |    ^^^"
+        );
+
+        // Spans running past their first line are underlined to its end.
+        assert_eq!(
+            source.snippet(Span { start: 5, end: 17 }),
+            "2 | (λ foo\n  |  ^^^^^"
+        );
+
+        // The end of the input gets one caret.
+        assert_eq!(
+            source.snippet(Span { start: 17, end: 17 }),
+            "3 |  bar)\n  |      ^"
+        );
+    }
+}
@@ -0,0 +1,140 @@
+use crate::lex::nfa::char_incr;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    pub states: usize,
+    pub edges: usize,
+    pub epsilons: usize,
+    pub intervals: usize,
+    pub components: usize,
+}
+
+// Number of disjoint character intervals that the ranges split the alphabet into, ignoring the
+// gaps that no range covers.
+pub(crate) fn count_intervals<I>(ranges: I) -> usize
+where
+    I: IntoIterator<Item = (char, char)>,
+{
+    let ranges = ranges.into_iter().collect::<Vec<_>>();
+
+    let mut boundaries = vec![];
+    for &(lo, hi) in ranges.iter() {
+        boundaries.push(lo);
+        if hi != char::MAX {
+            boundaries.push(char_incr(hi));
+        }
+    }
+    boundaries.sort_unstable();
+    boundaries.dedup();
+
+    let mut coverage = vec![0isize; boundaries.len() + 1];
+    for &(lo, hi) in ranges.iter() {
+        coverage[boundaries.binary_search(&lo).unwrap()] += 1;
+
+        let end = if hi == char::MAX {
+            boundaries.len()
+        } else {
+            boundaries.binary_search(&char_incr(hi)).unwrap()
+        };
+        coverage[end] -= 1;
+    }
+
+    let mut count = 0;
+    let mut depth = 0;
+    for delta in coverage.iter().take(boundaries.len()) {
+        depth += delta;
+        if depth > 0 {
+            count += 1;
+        }
+    }
+
+    count
+}
+
+// Number of strongly connected components, using an iterative version of Tarjan's algorithm so
+// that long chains of states cannot overflow the stack.
+pub(crate) fn count_components(successors: &[Vec<usize>]) -> usize {
+    let unvisited = usize::MAX;
+
+    let mut index = vec![unvisited; successors.len()];
+    let mut lowlink = vec![0; successors.len()];
+    let mut on_stack = vec![false; successors.len()];
+    let mut stack = vec![];
+    let mut calls = vec![];
+    let mut next_index = 0;
+    let mut count = 0;
+
+    for root in 0..successors.len() {
+        if index[root] != unvisited {
+            continue;
+        }
+
+        index[root] = next_index;
+        lowlink[root] = next_index;
+        next_index += 1;
+        stack.push(root);
+        on_stack[root] = true;
+        calls.push((root, 0));
+
+        while let Some((node, child)) = calls.pop() {
+            if let Some(&to) = successors[node].get(child) {
+                calls.push((node, child + 1));
+
+                if index[to] == unvisited {
+                    index[to] = next_index;
+                    lowlink[to] = next_index;
+                    next_index += 1;
+                    stack.push(to);
+                    on_stack[to] = true;
+                    calls.push((to, 0));
+                } else if on_stack[to] {
+                    lowlink[node] = lowlink[node].min(index[to]);
+                }
+
+                continue;
+            }
+
+            if lowlink[node] == index[node] {
+                while let Some(member) = stack.pop() {
+                    on_stack[member] = false;
+                    if member == node {
+                        break;
+                    }
+                }
+                count += 1;
+            }
+
+            if let Some(&(parent, _)) = calls.last() {
+                lowlink[parent] = lowlink[parent].min(lowlink[node]);
+            }
+        }
+    }
+
+    count
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_count_intervals() {
+        assert_eq!(count_intervals([]), 0);
+        assert_eq!(count_intervals([('a', 'z')]), 1);
+        assert_eq!(count_intervals([('a', 'm'), ('h', 'z')]), 3);
+        assert_eq!(count_intervals([('a', 'c'), ('x', 'z')]), 2);
+        assert_eq!(count_intervals([('a', 'z'), ('a', 'z')]), 1);
+        assert_eq!(count_intervals([(char::MIN, char::MAX), ('a', 'a')]), 3);
+    }
+
+    #[test]
+    fn test_count_components() {
+        assert_eq!(count_components(&[]), 0);
+        assert_eq!(count_components(&[vec![1], vec![2], vec![]]), 3);
+        assert_eq!(count_components(&[vec![1], vec![2], vec![0]]), 1);
+        assert_eq!(
+            count_components(&[vec![1], vec![0, 2], vec![3], vec![2], vec![4]]),
+            3
+        );
+    }
+}
@@ -1,2 +1,8 @@
+pub mod cancel;
+pub mod input;
 pub mod lang;
 pub mod lex;
+pub mod parse;
+
+pub use lang::reader::parse_str;
+pub use lang::run::run_source;
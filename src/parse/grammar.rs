@@ -0,0 +1,586 @@
+use std::collections::HashSet;
+use std::fmt::{self, Debug};
+use std::hash::Hash;
+
+// Terminals are token kinds; nonterminals are indices into the grammar's names.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Symbol<T> {
+    Terminal(T),
+    Nonterminal(usize),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Production<T> {
+    pub lhs: usize,
+    pub rhs: Vec<Symbol<T>>,
+}
+
+// A context-free grammar over token kinds `T`. The first nonterminal created is the start symbol
+// unless another is set. Other entry points can be added for parsing just part of the language,
+// e.g. a single expression, with the same grammar.
+#[derive(Debug, Clone)]
+pub struct Grammar<T> {
+    names: Vec<String>,
+    productions: Vec<Production<T>>,
+    start: usize,
+    entries: Vec<usize>,
+}
+
+// What `analyze` works out about a grammar, indexed by nonterminal. A lookahead of `None` is the
+// end of the input.
+#[derive(Debug, Clone)]
+pub struct Analysis<T> {
+    pub nullable: Vec<bool>,
+    pub first: Vec<HashSet<T>>,
+    pub follow: Vec<HashSet<Option<T>>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GrammarIssue<T> {
+    // A nonterminal used but never given a production.
+    Undefined {
+        nonterminal: String,
+    },
+    // A nonterminal that can't derive any string of terminals.
+    Unproductive {
+        nonterminal: String,
+    },
+    // A nonterminal no entry point derives.
+    Unreachable {
+        nonterminal: String,
+    },
+    // Each nonterminal in `cycle` can derive the next as its leftmost symbol, and the last the
+    // first, which top-down parsers loop on.
+    LeftRecursion {
+        cycle: Vec<String>,
+    },
+    // Two productions of `nonterminal` that both apply with any of `lookahead` next, so one token
+    // of lookahead can't choose between them. Every ambiguous grammar has one of these.
+    Conflict {
+        nonterminal: String,
+        productions: (usize, usize),
+        lookahead: Vec<Option<T>>,
+    },
+}
+
+impl<T> fmt::Display for GrammarIssue<T>
+where
+    T: Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GrammarIssue::Undefined { nonterminal } => {
+                write!(f, "{} has no productions", nonterminal)
+            }
+            GrammarIssue::Unproductive { nonterminal } => {
+                write!(f, "{} never derives a string of terminals", nonterminal)
+            }
+            GrammarIssue::Unreachable { nonterminal } => {
+                write!(f, "{} is unreachable from the entry points", nonterminal)
+            }
+            GrammarIssue::LeftRecursion { cycle } => {
+                write!(f, "left recursion: {} -> {}", cycle.join(" -> "), cycle[0])
+            }
+            GrammarIssue::Conflict {
+                nonterminal,
+                productions: (first, second),
+                lookahead,
+            } => write!(
+                f,
+                "productions {} and {} of {} both apply before {:?}",
+                first, second, nonterminal, lookahead
+            ),
+        }
+    }
+}
+
+impl<T> Default for Grammar<T> {
+    fn default() -> Self {
+        Grammar {
+            names: vec![],
+            productions: vec![],
+            start: 0,
+            entries: vec![],
+        }
+    }
+}
+
+impl<T> Grammar<T>
+where
+    T: Copy + Debug + Eq + Hash,
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // The nonterminal called `name`, created if there isn't one yet.
+    pub fn nonterminal(&mut self, name: &str) -> Symbol<T> {
+        let index = match self.names.iter().position(|n| n == name) {
+            Some(index) => index,
+            None => {
+                self.names.push(name.to_string());
+                self.names.len() - 1
+            }
+        };
+        Symbol::Nonterminal(index)
+    }
+
+    pub fn set_start(&mut self, start: Symbol<T>) {
+        self.start = Self::index(start);
+    }
+
+    pub fn add_entry(&mut self, entry: Symbol<T>) {
+        let entry = Self::index(entry);
+        if !self.entries.contains(&entry) {
+            self.entries.push(entry);
+        }
+    }
+
+    pub fn with_entry(&mut self, entry: Symbol<T>) -> &mut Self {
+        self.add_entry(entry);
+        self
+    }
+
+    pub fn add_production(&mut self, lhs: Symbol<T>, rhs: &[Symbol<T>]) -> usize {
+        self.productions.push(Production {
+            lhs: Self::index(lhs),
+            rhs: rhs.to_vec(),
+        });
+        self.productions.len() - 1
+    }
+
+    pub fn with_production(&mut self, lhs: Symbol<T>, rhs: &[Symbol<T>]) -> &mut Self {
+        self.add_production(lhs, rhs);
+        self
+    }
+
+    fn index(symbol: Symbol<T>) -> usize {
+        match symbol {
+            Symbol::Nonterminal(index) => index,
+            Symbol::Terminal(t) => panic!("{:?} is a terminal, not a nonterminal", t),
+        }
+    }
+
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    // The nonterminals a parse can be of: the start symbol, then the entries added in order.
+    pub fn entries(&self) -> Vec<usize> {
+        let mut entries = vec![self.start];
+        entries.extend(self.entries.iter().filter(|&&entry| entry != self.start));
+        entries
+    }
+
+    pub fn name(&self, nonterminal: usize) -> &str {
+        &self.names[nonterminal]
+    }
+
+    pub fn nonterminal_count(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn productions(&self) -> &[Production<T>] {
+        &self.productions
+    }
+
+    pub fn productions_of(&self, nonterminal: usize) -> impl Iterator<Item = usize> + '_ {
+        (0..self.productions.len()).filter(move |&p| self.productions[p].lhs == nonterminal)
+    }
+
+    // Compute nullable, FIRST and FOLLOW sets, each by iterating to a fixed point.
+    pub fn analyze(&self) -> Analysis<T> {
+        let n = self.names.len();
+        let mut analysis = Analysis {
+            nullable: vec![false; n],
+            first: vec![HashSet::new(); n],
+            follow: vec![HashSet::new(); n],
+        };
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for production in &self.productions {
+                let (first, nullable) = analysis.first_of(&production.rhs);
+                let lhs = production.lhs;
+
+                if nullable && !analysis.nullable[lhs] {
+                    analysis.nullable[lhs] = true;
+                    changed = true;
+                }
+                for t in first {
+                    changed |= analysis.first[lhs].insert(t);
+                }
+            }
+        }
+
+        if n > 0 {
+            for entry in self.entries() {
+                analysis.follow[entry].insert(None);
+            }
+        }
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for production in &self.productions {
+                for (i, &symbol) in production.rhs.iter().enumerate() {
+                    let Symbol::Nonterminal(nonterminal) = symbol else {
+                        continue;
+                    };
+
+                    let (first, nullable) = analysis.first_of(&production.rhs[i + 1..]);
+                    let mut follow = first.into_iter().map(Some).collect::<Vec<_>>();
+                    if nullable {
+                        follow.extend(analysis.follow[production.lhs].iter().copied());
+                    }
+
+                    for t in follow {
+                        changed |= analysis.follow[nonterminal].insert(t);
+                    }
+                }
+            }
+        }
+
+        analysis
+    }
+
+    pub fn validate(&self) -> Vec<GrammarIssue<T>> {
+        let mut issues = vec![];
+        let name = |nonterminal: usize| self.names[nonterminal].clone();
+
+        for nonterminal in 0..self.names.len() {
+            if self.productions_of(nonterminal).next().is_none() {
+                issues.push(GrammarIssue::Undefined {
+                    nonterminal: name(nonterminal),
+                });
+            }
+        }
+
+        let productive = self.productive();
+        for nonterminal in (0..self.names.len()).filter(|&n| !productive[n]) {
+            if self.productions_of(nonterminal).next().is_some() {
+                issues.push(GrammarIssue::Unproductive {
+                    nonterminal: name(nonterminal),
+                });
+            }
+        }
+
+        let reachable = self.reachable();
+        for nonterminal in (0..self.names.len()).filter(|&n| !reachable[n]) {
+            issues.push(GrammarIssue::Unreachable {
+                nonterminal: name(nonterminal),
+            });
+        }
+
+        let analysis = self.analyze();
+
+        for cycle in self.left_recursion(&analysis) {
+            issues.push(GrammarIssue::LeftRecursion {
+                cycle: cycle.into_iter().map(name).collect(),
+            });
+        }
+
+        for nonterminal in 0..self.names.len() {
+            let productions = self.productions_of(nonterminal).collect::<Vec<_>>();
+            let predict = productions
+                .iter()
+                .map(|&p| analysis.predict(&self.productions[p]))
+                .collect::<Vec<_>>();
+
+            for i in 0..productions.len() {
+                for j in i + 1..productions.len() {
+                    let lookahead = predict[i]
+                        .iter()
+                        .filter(|t| predict[j].contains(t))
+                        .copied()
+                        .collect::<Vec<_>>();
+
+                    if !lookahead.is_empty() {
+                        issues.push(GrammarIssue::Conflict {
+                            nonterminal: name(nonterminal),
+                            productions: (productions[i], productions[j]),
+                            lookahead,
+                        });
+                    }
+                }
+            }
+        }
+
+        issues
+    }
+
+    fn productive(&self) -> Vec<bool> {
+        let mut productive = vec![false; self.names.len()];
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for production in &self.productions {
+                if !productive[production.lhs]
+                    && production.rhs.iter().all(|symbol| match *symbol {
+                        Symbol::Terminal(_) => true,
+                        Symbol::Nonterminal(n) => productive[n],
+                    })
+                {
+                    productive[production.lhs] = true;
+                    changed = true;
+                }
+            }
+        }
+
+        productive
+    }
+
+    fn reachable(&self) -> Vec<bool> {
+        let mut reachable = vec![false; self.names.len()];
+        if self.names.is_empty() {
+            return reachable;
+        }
+
+        let mut stack = self.entries();
+        for &entry in &stack {
+            reachable[entry] = true;
+        }
+        while let Some(nonterminal) = stack.pop() {
+            for p in self.productions_of(nonterminal) {
+                for symbol in &self.productions[p].rhs {
+                    if let Symbol::Nonterminal(n) = *symbol {
+                        if !reachable[n] {
+                            reachable[n] = true;
+                            stack.push(n);
+                        }
+                    }
+                }
+            }
+        }
+
+        reachable
+    }
+
+    // One cycle through each nonterminal that is left recursive and not already on a cycle
+    // reported, found by a search along the leftmost symbols each can derive.
+    fn left_recursion(&self, analysis: &Analysis<T>) -> Vec<Vec<usize>> {
+        let n = self.names.len();
+        let mut left = vec![vec![]; n];
+        for production in &self.productions {
+            for symbol in &production.rhs {
+                match *symbol {
+                    Symbol::Terminal(_) => break,
+                    Symbol::Nonterminal(b) => {
+                        if !left[production.lhs].contains(&b) {
+                            left[production.lhs].push(b);
+                        }
+                        if !analysis.nullable[b] {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut reported = vec![false; n];
+        let mut cycles = vec![];
+
+        for start in 0..n {
+            if reported[start] {
+                continue;
+            }
+
+            // Breadth first, so the cycle found is a shortest one.
+            let mut parent = vec![None; n];
+            let mut queue = std::collections::VecDeque::from([start]);
+            let mut found = None;
+            while let Some(a) = queue.pop_front() {
+                for &b in &left[a] {
+                    if b == start {
+                        found = Some(a);
+                        break;
+                    }
+                    if parent[b].is_none() {
+                        parent[b] = Some(a);
+                        queue.push_back(b);
+                    }
+                }
+                if found.is_some() {
+                    break;
+                }
+            }
+
+            let Some(mut last) = found else {
+                continue;
+            };
+
+            let mut cycle = vec![last];
+            while last != start {
+                last = parent[last].unwrap();
+                cycle.push(last);
+            }
+            cycle.reverse();
+
+            for &nonterminal in &cycle {
+                reported[nonterminal] = true;
+            }
+            cycles.push(cycle);
+        }
+
+        cycles
+    }
+}
+
+impl<T> Analysis<T>
+where
+    T: Copy + Eq + Hash,
+{
+    // The terminals a string of `symbols` can start with, and whether it can be empty.
+    pub fn first_of(&self, symbols: &[Symbol<T>]) -> (HashSet<T>, bool) {
+        let mut first = HashSet::new();
+
+        for symbol in symbols {
+            match *symbol {
+                Symbol::Terminal(t) => {
+                    first.insert(t);
+                    return (first, false);
+                }
+                Symbol::Nonterminal(n) => {
+                    first.extend(self.first[n].iter().copied());
+                    if !self.nullable[n] {
+                        return (first, false);
+                    }
+                }
+            }
+        }
+
+        (first, true)
+    }
+
+    // The lookaheads before which a top-down parser would choose `production`.
+    pub fn predict(&self, production: &Production<T>) -> HashSet<Option<T>> {
+        let (first, nullable) = self.first_of(&production.rhs);
+        let mut predict = first.into_iter().map(Some).collect::<HashSet<_>>();
+        if nullable {
+            predict.extend(self.follow[production.lhs].iter().copied());
+        }
+        predict
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use Symbol::Terminal as T;
+
+    fn set<I: IntoIterator<Item = X>, X: Eq + Hash>(items: I) -> HashSet<X> {
+        items.into_iter().collect()
+    }
+
+    // E -> E + T | T; T -> T * F | F; F -> ( E ) | x
+    fn expression_grammar() -> Grammar<char> {
+        let mut grammar = Grammar::new();
+        let e = grammar.nonterminal("E");
+        let t = grammar.nonterminal("T");
+        let f = grammar.nonterminal("F");
+
+        grammar
+            .with_production(e, &[e, T('+'), t])
+            .with_production(e, &[t])
+            .with_production(t, &[t, T('*'), f])
+            .with_production(t, &[f])
+            .with_production(f, &[T('('), e, T(')')])
+            .with_production(f, &[T('x')]);
+        grammar
+    }
+
+    #[test]
+    fn test_first_follow() {
+        let grammar = expression_grammar();
+        let analysis = grammar.analyze();
+
+        assert_eq!(analysis.nullable, vec![false; 3]);
+        for nonterminal in 0..3 {
+            assert_eq!(analysis.first[nonterminal], set(['(', 'x']));
+        }
+        assert_eq!(analysis.follow[0], set([Some('+'), Some(')'), None]));
+        assert_eq!(
+            analysis.follow[1],
+            set([Some('+'), Some('*'), Some(')'), None])
+        );
+        assert_eq!(analysis.follow[2], analysis.follow[1]);
+
+        // S -> A B c; A -> a | ; B -> b |
+        let mut grammar = Grammar::new();
+        let s = grammar.nonterminal("S");
+        let a = grammar.nonterminal("A");
+        let b = grammar.nonterminal("B");
+        grammar
+            .with_production(s, &[a, b, T('c')])
+            .with_production(a, &[T('a')])
+            .with_production(a, &[])
+            .with_production(b, &[T('b')])
+            .with_production(b, &[]);
+
+        let analysis = grammar.analyze();
+        assert_eq!(analysis.nullable, vec![false, true, true]);
+        assert_eq!(analysis.first[0], set(['a', 'b', 'c']));
+        assert_eq!(analysis.follow[1], set([Some('b'), Some('c')]));
+        assert_eq!(analysis.first_of(&[a, b]), (set(['a', 'b']), true));
+        assert!(grammar.validate().is_empty());
+    }
+
+    #[test]
+    fn test_validate() {
+        let grammar = expression_grammar();
+        let issues = grammar.validate();
+
+        let cycles = issues
+            .iter()
+            .filter_map(|issue| match issue {
+                GrammarIssue::LeftRecursion { cycle } => Some(cycle.clone()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(cycles, vec![vec!["E".to_string()], vec!["T".to_string()]]);
+        assert!(issues
+            .iter()
+            .any(|issue| matches!(issue, GrammarIssue::Conflict { nonterminal, .. } if nonterminal == "E")));
+
+        // S -> a | a b | A D; A -> B x; B -> A; C -> c
+        let mut grammar = Grammar::new();
+        let s = grammar.nonterminal("S");
+        let a = grammar.nonterminal("A");
+        let b = grammar.nonterminal("B");
+        let c = grammar.nonterminal("C");
+        let d = grammar.nonterminal("D");
+        grammar
+            .with_production(s, &[T('a')])
+            .with_production(s, &[T('a'), T('b')])
+            .with_production(s, &[a, d])
+            .with_production(a, &[b, T('x')])
+            .with_production(b, &[a])
+            .with_production(c, &[T('c')]);
+
+        let issues = grammar.validate();
+        let strings = issues.iter().map(|i| i.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            strings,
+            vec![
+                "D has no productions",
+                "A never derives a string of terminals",
+                "B never derives a string of terminals",
+                "C is unreachable from the entry points",
+                "left recursion: A -> B -> A",
+                "productions 0 and 1 of S both apply before [Some('a')]",
+            ]
+        );
+
+        // An entry point is reachable, and can be followed by the end of the input.
+        grammar.add_entry(c);
+        assert_eq!(grammar.entries(), vec![0, 3]);
+        assert!(!grammar
+            .validate()
+            .iter()
+            .any(|issue| matches!(issue, GrammarIssue::Unreachable { .. })));
+        assert_eq!(grammar.analyze().follow[3], set([None]));
+    }
+}
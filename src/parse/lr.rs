@@ -0,0 +1,835 @@
+use std::collections::{BTreeSet, HashMap};
+use std::error::Error;
+use std::fmt::{self, Debug, Write};
+use std::hash::Hash;
+
+use crate::lex::lexer::Lexeme;
+use crate::parse::grammar::{Grammar, Symbol};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LrAction {
+    Shift(usize),
+    Reduce(usize),
+    Accept,
+}
+
+// Two actions a state could take before `lookahead`. The table keeps `chosen`, which is the shift
+// for a shift/reduce conflict and the earlier production for a reduce/reduce one, as yacc does.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LrConflict<T> {
+    pub state: usize,
+    pub lookahead: Option<T>,
+    pub chosen: LrAction,
+    pub rejected: LrAction,
+}
+
+// LALR(1) parse tables for a grammar, built by merging the states of its canonical LR(1)
+// automaton that have the same items. Terminals are numbered in the order they first appear in
+// the productions, with the end of the input after them all. Each of the grammar's entry points
+// has a state to start parsing from, and the start symbol's is the first.
+#[derive(Debug, Clone)]
+pub struct LrTable<T> {
+    terminals: Vec<T>,
+    terminal_indices: HashMap<T, usize>,
+    // The nonterminal and length of each production.
+    productions: Vec<(usize, usize)>,
+    // Each entry point's nonterminal and start state.
+    entries: Vec<(usize, usize)>,
+    actions: Vec<HashMap<usize, LrAction>>,
+    gotos: Vec<HashMap<usize, usize>>,
+    conflicts: Vec<LrConflict<T>>,
+}
+
+// Tables are equal when they parse alike, whatever conflicts were met building them.
+impl<T> PartialEq for LrTable<T>
+where
+    T: Eq + Hash,
+{
+    fn eq(&self, other: &Self) -> bool {
+        self.terminals == other.terminals
+            && self.productions == other.productions
+            && self.entries == other.entries
+            && self.actions == other.actions
+            && self.gotos == other.gotos
+    }
+}
+
+impl<T> Eq for LrTable<T> where T: Eq + Hash {}
+
+// Builds the values a parse produces: one for each lexeme shifted, and one from the values of
+// the symbols of each production reduced, in order.
+pub trait Reducer<T> {
+    type Value;
+
+    fn shift(&mut self, lexeme: Lexeme<T>) -> Self::Value;
+    fn reduce(&mut self, production: usize, children: Vec<Self::Value>) -> Self::Value;
+}
+
+impl<T, V, S, R> Reducer<T> for (S, R)
+where
+    S: FnMut(Lexeme<T>) -> V,
+    R: FnMut(usize, Vec<V>) -> V,
+{
+    type Value = V;
+
+    fn shift(&mut self, lexeme: Lexeme<T>) -> V {
+        (self.0)(lexeme)
+    }
+
+    fn reduce(&mut self, production: usize, children: Vec<V>) -> V {
+        (self.1)(production, children)
+    }
+}
+
+// `expected` lists the terminals the parser could have taken instead, with `None` for the end
+// of the input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LrError<T> {
+    UnexpectedToken {
+        token: T,
+        position: usize,
+        expected: Vec<Option<T>>,
+    },
+    UnexpectedEnd {
+        expected: Vec<Option<T>>,
+    },
+}
+
+impl<T> fmt::Display for LrError<T>
+where
+    T: Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LrError::UnexpectedToken {
+                token,
+                position,
+                expected,
+            } => write!(
+                f,
+                "unexpected {:?} at {}, expected one of {:?}",
+                token, position, expected
+            ),
+            LrError::UnexpectedEnd { expected } => {
+                write!(f, "unexpected end of input, expected one of {:?}", expected)
+            }
+        }
+    }
+}
+
+impl<T> Error for LrError<T> where T: Debug {}
+
+// An LR(1) item: a production, how much of it has been seen, and the lookahead after it, with
+// terminals by index.
+type Item = (usize, usize, usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+enum Next {
+    Terminal(usize),
+    Nonterminal(usize),
+}
+
+impl<T> LrTable<T>
+where
+    T: Copy + Debug + Eq + Hash,
+{
+    pub fn new(grammar: &Grammar<T>) -> LrTable<T> {
+        let analysis = grammar.analyze();
+
+        let mut terminals = vec![];
+        let mut terminal_indices = HashMap::new();
+        for production in grammar.productions() {
+            for symbol in &production.rhs {
+                if let Symbol::Terminal(t) = *symbol {
+                    terminal_indices.entry(t).or_insert_with(|| {
+                        terminals.push(t);
+                        terminals.len() - 1
+                    });
+                }
+            }
+        }
+        let end = terminals.len();
+
+        // The productions with their symbols numbered, and S' -> S added at the end for each entry
+        // point S.
+        let mut rhs = grammar
+            .productions()
+            .iter()
+            .map(|production| {
+                production
+                    .rhs
+                    .iter()
+                    .map(|symbol| match *symbol {
+                        Symbol::Terminal(t) => Next::Terminal(terminal_indices[&t]),
+                        Symbol::Nonterminal(n) => Next::Nonterminal(n),
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let mut lhs = grammar
+            .productions()
+            .iter()
+            .map(|production| production.lhs)
+            .collect::<Vec<_>>();
+        let accept = rhs.len();
+        let entries = grammar.entries();
+        for (i, &entry) in entries.iter().enumerate() {
+            rhs.push(vec![Next::Nonterminal(entry)]);
+            lhs.push(grammar.nonterminal_count() + i);
+        }
+
+        let first = |symbols: &[Next], lookahead: usize| {
+            let mut set = BTreeSet::new();
+            for symbol in symbols {
+                match *symbol {
+                    Next::Terminal(t) => {
+                        set.insert(t);
+                        return set;
+                    }
+                    Next::Nonterminal(n) => {
+                        set.extend(analysis.first[n].iter().map(|t| terminal_indices[t]));
+                        if !analysis.nullable[n] {
+                            return set;
+                        }
+                    }
+                }
+            }
+            set.insert(lookahead);
+            set
+        };
+
+        let closure = |mut items: BTreeSet<Item>| {
+            let mut work = items.iter().copied().collect::<Vec<_>>();
+            while let Some((production, dot, lookahead)) = work.pop() {
+                let Some(&Next::Nonterminal(n)) = rhs[production].get(dot) else {
+                    continue;
+                };
+
+                for b in first(&rhs[production][dot + 1..], lookahead) {
+                    for p in grammar.productions_of(n) {
+                        if items.insert((p, 0, b)) {
+                            work.push((p, 0, b));
+                        }
+                    }
+                }
+            }
+            items
+        };
+
+        // The canonical LR(1) states and their transitions, starting with one for each entry.
+        let mut states = (0..entries.len())
+            .map(|i| closure(BTreeSet::from([(accept + i, 0, end)])))
+            .collect::<Vec<_>>();
+        let mut indices = states
+            .iter()
+            .cloned()
+            .enumerate()
+            .map(|(i, state)| (state, i))
+            .collect::<HashMap<_, _>>();
+        let mut transitions = vec![];
+        let mut i = 0;
+        while i < states.len() {
+            let mut next = BTreeSet::new();
+            for &(production, dot, _) in &states[i] {
+                if let Some(&symbol) = rhs[production].get(dot) {
+                    next.insert(symbol);
+                }
+            }
+
+            let mut edges = vec![];
+            for symbol in next {
+                let kernel = states[i]
+                    .iter()
+                    .filter(|&&(production, dot, _)| rhs[production].get(dot) == Some(&symbol))
+                    .map(|&(production, dot, lookahead)| (production, dot + 1, lookahead))
+                    .collect();
+                let state = closure(kernel);
+
+                let target = *indices.entry(state.clone()).or_insert_with(|| {
+                    states.push(state);
+                    states.len() - 1
+                });
+                edges.push((symbol, target));
+            }
+            transitions.push(edges);
+            i += 1;
+        }
+
+        // Merge states with the same items, ignoring lookaheads.
+        let mut merged = vec![0; states.len()];
+        let mut cores = HashMap::new();
+        for (state, items) in states.iter().enumerate() {
+            let core = items
+                .iter()
+                .map(|&(production, dot, _)| (production, dot))
+                .collect::<BTreeSet<_>>();
+            let count = cores.len();
+            merged[state] = *cores.entry(core).or_insert(count);
+        }
+
+        let mut table = LrTable {
+            terminals,
+            terminal_indices,
+            productions: lhs.iter().zip(&rhs).map(|(&l, r)| (l, r.len())).collect(),
+            entries: entries
+                .iter()
+                .enumerate()
+                .map(|(i, &entry)| (entry, merged[i]))
+                .collect(),
+            actions: vec![HashMap::new(); cores.len()],
+            gotos: vec![HashMap::new(); cores.len()],
+            conflicts: vec![],
+        };
+        table.productions.truncate(accept);
+
+        for (state, items) in states.iter().enumerate() {
+            let from = merged[state];
+
+            for &(symbol, target) in &transitions[state] {
+                match symbol {
+                    Next::Terminal(t) => table.set_action(from, t, LrAction::Shift(merged[target])),
+                    Next::Nonterminal(n) => {
+                        table.gotos[from].insert(n, merged[target]);
+                    }
+                }
+            }
+
+            for &(production, dot, lookahead) in items {
+                if dot < rhs[production].len() {
+                    continue;
+                }
+                match production >= accept {
+                    true => table.set_action(from, lookahead, LrAction::Accept),
+                    false => table.set_action(from, lookahead, LrAction::Reduce(production)),
+                }
+            }
+        }
+
+        table
+    }
+
+    fn set_action(&mut self, state: usize, terminal: usize, action: LrAction) {
+        let Some(&existing) = self.actions[state].get(&terminal) else {
+            self.actions[state].insert(terminal, action);
+            return;
+        };
+        if existing == action {
+            return;
+        }
+
+        let (chosen, rejected) = match (existing, action) {
+            (LrAction::Reduce(_), LrAction::Shift(_)) => (action, existing),
+            (LrAction::Reduce(a), LrAction::Reduce(b)) if b < a => (action, existing),
+            _ => (existing, action),
+        };
+        self.actions[state].insert(terminal, chosen);
+
+        let conflict = LrConflict {
+            state,
+            lookahead: self.terminals.get(terminal).copied(),
+            chosen,
+            rejected,
+        };
+        if !self.conflicts.contains(&conflict) {
+            self.conflicts.push(conflict);
+        }
+    }
+
+    // Rebuild a table from the parts `to_rust` writes out.
+    pub fn from_parts(
+        terminals: Vec<T>,
+        productions: Vec<(usize, usize)>,
+        entries: Vec<(usize, usize)>,
+        actions: Vec<Vec<(usize, LrAction)>>,
+        gotos: Vec<Vec<(usize, usize)>>,
+    ) -> LrTable<T> {
+        let terminal_indices = terminals.iter().enumerate().map(|(i, &t)| (t, i)).collect();
+
+        LrTable {
+            terminals,
+            terminal_indices,
+            productions,
+            entries,
+            actions: actions
+                .into_iter()
+                .map(|actions| actions.into_iter().collect())
+                .collect(),
+            gotos: gotos
+                .into_iter()
+                .map(|gotos| gotos.into_iter().collect())
+                .collect(),
+            conflicts: vec![],
+        }
+    }
+
+    // Rust source for a function `name` returning this table, for a build script to write out
+    // so that the table isn't built at run time. The terminals are of the type `terminal_type`,
+    // and `terminal` gives the Rust expression for each, e.g. `Token::LParen`.
+    pub fn to_rust<F>(&self, name: &str, terminal_type: &str, terminal: F) -> String
+    where
+        F: Fn(&T) -> String,
+    {
+        let mut source = String::new();
+        let lr = "turkey::parse::lr";
+
+        writeln!(
+            source,
+            "pub fn {}() -> {}::LrTable<{}> {{",
+            name, lr, terminal_type
+        )
+        .unwrap();
+        writeln!(source, "    {}::LrTable::from_parts(", lr).unwrap();
+
+        write!(source, "        vec![").unwrap();
+        for t in &self.terminals {
+            write!(source, "{}, ", terminal(t)).unwrap();
+        }
+        writeln!(source, "],").unwrap();
+
+        writeln!(source, "        vec!{:?},", self.productions).unwrap();
+        writeln!(source, "        vec!{:?},", self.entries).unwrap();
+
+        writeln!(source, "        vec![").unwrap();
+        for actions in &self.actions {
+            write!(source, "            vec![").unwrap();
+            for terminal in sorted_keys(actions) {
+                write!(
+                    source,
+                    "({}, {}::LrAction::{:?}), ",
+                    terminal, lr, actions[&terminal]
+                )
+                .unwrap();
+            }
+            writeln!(source, "],").unwrap();
+        }
+        writeln!(source, "        ],").unwrap();
+
+        writeln!(source, "        vec![").unwrap();
+        for gotos in &self.gotos {
+            let gotos = sorted_keys(gotos)
+                .into_iter()
+                .map(|n| (n, gotos[&n]))
+                .collect::<Vec<_>>();
+            writeln!(source, "            vec!{:?},", gotos).unwrap();
+        }
+        writeln!(source, "        ],").unwrap();
+
+        writeln!(source, "    )").unwrap();
+        writeln!(source, "}}").unwrap();
+
+        source
+    }
+
+    pub fn state_count(&self) -> usize {
+        self.actions.len()
+    }
+
+    pub fn action(&self, state: usize, lookahead: Option<T>) -> Option<LrAction> {
+        let terminal = match lookahead {
+            Some(t) => *self.terminal_indices.get(&t)?,
+            None => self.terminals.len(),
+        };
+        self.actions[state].get(&terminal).copied()
+    }
+
+    pub fn conflicts(&self) -> &[LrConflict<T>] {
+        &self.conflicts
+    }
+
+    fn expected(&self, state: usize) -> Vec<Option<T>> {
+        let mut terminals = self.actions[state].keys().copied().collect::<Vec<_>>();
+        terminals.sort();
+        terminals
+            .into_iter()
+            .map(|t| self.terminals.get(t).copied())
+            .collect()
+    }
+
+    // The nonterminals a parse can be of, the start symbol first.
+    pub fn entries(&self) -> impl Iterator<Item = usize> + '_ {
+        self.entries.iter().map(|&(entry, _)| entry)
+    }
+
+    // Start parsing with this table, building values with `reducer`.
+    pub fn parser<R>(&self, reducer: R) -> LrParser<'_, T, R>
+    where
+        R: Reducer<T>,
+    {
+        LrParser {
+            table: self,
+            reducer,
+            states: vec![self.entries[0].1],
+            values: vec![],
+            error: None,
+        }
+    }
+
+    // Start parsing an `entry` rather than the start symbol. Panics if it isn't an entry point of
+    // the grammar.
+    pub fn parser_for<R>(&self, entry: Symbol<T>, reducer: R) -> LrParser<'_, T, R>
+    where
+        R: Reducer<T>,
+    {
+        let state = self
+            .entries
+            .iter()
+            .find(|&&(nonterminal, _)| Symbol::Nonterminal(nonterminal) == entry)
+            .map(|&(_, state)| state);
+        let Some(state) = state else {
+            panic!("{:?} isn't an entry point", entry);
+        };
+
+        let mut parser = self.parser(reducer);
+        parser.states = vec![state];
+        parser
+    }
+}
+
+// A parse in progress, fed lexemes as they come like a lexer is fed characters.
+pub struct LrParser<'t, T, R>
+where
+    R: Reducer<T>,
+{
+    table: &'t LrTable<T>,
+    reducer: R,
+    states: Vec<usize>,
+    values: Vec<R::Value>,
+    error: Option<LrError<T>>,
+}
+
+impl<T, R> LrParser<'_, T, R>
+where
+    T: Copy + Debug + Eq + Hash,
+    R: Reducer<T>,
+{
+    pub fn put(&mut self, lexeme: Lexeme<T>) {
+        if self.error.is_some() {
+            return;
+        }
+
+        let state = self.reduce_before(Some(lexeme.token));
+        match state.and_then(|state| self.table.action(state, Some(lexeme.token))) {
+            Some(LrAction::Shift(next)) => {
+                self.states.push(next);
+                let value = self.reducer.shift(lexeme);
+                self.values.push(value);
+            }
+            _ if self.error.is_some() => {}
+            _ => {
+                self.error = Some(LrError::UnexpectedToken {
+                    token: lexeme.token,
+                    position: lexeme.position,
+                    expected: self.table.expected(*self.states.last().unwrap()),
+                })
+            }
+        }
+    }
+
+    // The input has ended. Returns the value of the whole input.
+    pub fn finish(mut self) -> Result<R::Value, LrError<T>> {
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+
+        let state = self.reduce_before(None);
+        match state.and_then(|state| self.table.action(state, None)) {
+            Some(LrAction::Accept) => Ok(self.values.pop().unwrap()),
+            _ => Err(LrError::UnexpectedEnd {
+                expected: self.table.expected(*self.states.last().unwrap()),
+            }),
+        }
+    }
+
+    pub fn get_error(&self) -> Option<&LrError<T>> {
+        self.error.as_ref()
+    }
+
+    // Reduce for as long as the table says to before `lookahead`, returning the state left.
+    fn reduce_before(&mut self, lookahead: Option<T>) -> Option<usize> {
+        loop {
+            let state = *self.states.last().unwrap();
+            let Some(LrAction::Reduce(production)) = self.table.action(state, lookahead) else {
+                return Some(state);
+            };
+
+            let (lhs, len) = self.table.productions[production];
+            self.states.truncate(self.states.len() - len);
+            let children = self.values.split_off(self.values.len() - len);
+            let value = self.reducer.reduce(production, children);
+
+            let top = *self.states.last().unwrap();
+            self.states.push(self.table.gotos[top][&lhs]);
+            self.values.push(value);
+        }
+    }
+}
+
+fn sorted_keys<V>(map: &HashMap<usize, V>) -> Vec<usize> {
+    let mut keys = map.keys().copied().collect::<Vec<_>>();
+    keys.sort();
+    keys
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use Symbol::Terminal as T;
+
+    // E -> E + T | T; T -> T * F | F; F -> ( E ) | n
+    fn expression_grammar() -> Grammar<char> {
+        let mut grammar = Grammar::new();
+        let e = grammar.nonterminal("E");
+        let t = grammar.nonterminal("T");
+        let f = grammar.nonterminal("F");
+
+        grammar
+            .with_production(e, &[e, T('+'), t])
+            .with_production(e, &[t])
+            .with_production(t, &[t, T('*'), f])
+            .with_production(t, &[f])
+            .with_production(f, &[T('('), e, T(')')])
+            .with_production(f, &[T('n')]);
+        grammar
+    }
+
+    fn lexemes(input: &str) -> Vec<Lexeme<char>> {
+        input
+            .char_indices()
+            .map(|(position, c)| Lexeme {
+                token: if c.is_ascii_digit() { 'n' } else { c },
+                position,
+                length: 1,
+                span: Some(c.to_string()),
+            })
+            .collect()
+    }
+
+    fn evaluate(table: &LrTable<char>, input: &str) -> Result<i64, LrError<char>> {
+        let shift = |lexeme: Lexeme<char>| lexeme.span.unwrap().parse::<i64>().unwrap_or(0);
+        let reduce = |production, children: Vec<i64>| match production {
+            0 => children[0] + children[2],
+            2 => children[0] * children[2],
+            4 => children[1],
+            _ => children[0],
+        };
+
+        let mut parser = table.parser((shift, reduce));
+        for lexeme in lexemes(input) {
+            parser.put(lexeme);
+        }
+        parser.finish()
+    }
+
+    #[test]
+    fn test_lalr() {
+        let table = LrTable::new(&expression_grammar());
+        assert_eq!(table.state_count(), 12);
+        assert!(table.conflicts().is_empty());
+
+        assert_eq!(evaluate(&table, "2+3*(4+1)"), Ok(17));
+        assert_eq!(evaluate(&table, "((7))*2*3"), Ok(42));
+
+        assert_eq!(
+            evaluate(&table, "2+*3"),
+            Err(LrError::UnexpectedToken {
+                token: '*',
+                position: 2,
+                expected: vec![Some('('), Some('n')],
+            })
+        );
+        assert_eq!(
+            evaluate(&table, "(2+3"),
+            Err(LrError::UnexpectedEnd {
+                expected: vec![Some('+'), Some(')')],
+            })
+        );
+    }
+
+    #[test]
+    fn test_conflicts() {
+        // E -> E - E | n is ambiguous; preferring the shift makes - right associative.
+        let mut grammar = Grammar::new();
+        let e = grammar.nonterminal("E");
+        grammar
+            .with_production(e, &[e, T('-'), e])
+            .with_production(e, &[T('n')]);
+
+        let table = LrTable::new(&grammar);
+        assert_eq!(table.conflicts().len(), 1);
+        let conflict = &table.conflicts()[0];
+        assert_eq!(conflict.lookahead, Some('-'));
+        assert!(matches!(conflict.chosen, LrAction::Shift(_)));
+        assert_eq!(conflict.rejected, LrAction::Reduce(0));
+
+        let shift = |lexeme: Lexeme<char>| lexeme.span.unwrap().parse::<i64>().unwrap_or(0);
+        let reduce = |production, children: Vec<i64>| match production {
+            0 => children[0] - children[2],
+            _ => children[0],
+        };
+        let mut parser = table.parser((shift, reduce));
+        lexemes("9-5-1")
+            .into_iter()
+            .for_each(|lexeme| parser.put(lexeme));
+        assert_eq!(parser.finish(), Ok(5));
+    }
+
+    #[test]
+    fn test_entries() {
+        // P -> P E ; | with E, the expressions, and F, the factors, as entry points too.
+        let mut grammar = expression_grammar();
+        let e = grammar.nonterminal("E");
+        let f = grammar.nonterminal("F");
+        let p = grammar.nonterminal("P");
+        grammar
+            .with_production(p, &[p, e, T(';')])
+            .with_production(p, &[])
+            .with_entry(e)
+            .with_entry(f)
+            .set_start(p);
+
+        let table = LrTable::new(&grammar);
+        assert!(table.conflicts().is_empty());
+        assert_eq!(table.entries().collect::<Vec<_>>(), vec![3, 0, 2]);
+
+        let parse = |entry, input| {
+            let shift = |lexeme: Lexeme<char>| lexeme.span.unwrap().parse::<i64>().unwrap_or(0);
+            let reduce = |production, children: Vec<i64>| match production {
+                0 => children[0] + children[2],
+                2 => children[0] * children[2],
+                4 => children[1],
+                6 => children[0] + children[1],
+                7 => 0,
+                _ => children[0],
+            };
+
+            let mut parser = match entry {
+                Some(entry) => table.parser_for(entry, (shift, reduce)),
+                None => table.parser((shift, reduce)),
+            };
+            lexemes(input)
+                .into_iter()
+                .for_each(|lexeme| parser.put(lexeme));
+            parser.finish()
+        };
+
+        assert_eq!(parse(None, "1+2;3*4;"), Ok(15));
+        assert_eq!(parse(None, ""), Ok(0));
+        assert_eq!(parse(Some(p), "2;"), Ok(2));
+        assert_eq!(parse(Some(e), "2+3*4"), Ok(14));
+        assert_eq!(parse(Some(f), "(2+3)"), Ok(5));
+
+        assert_eq!(
+            parse(Some(e), "2+3;"),
+            Err(LrError::UnexpectedToken {
+                token: ';',
+                position: 3,
+                expected: vec![Some('+'), None],
+            })
+        );
+        assert_eq!(
+            parse(Some(f), "2+3"),
+            Err(LrError::UnexpectedToken {
+                token: '+',
+                position: 1,
+                expected: vec![None],
+            })
+        );
+    }
+
+    fn paren_grammar() -> Grammar<Paren> {
+        let mut grammar = Grammar::new();
+        let s = grammar.nonterminal("S");
+        grammar
+            .with_production(s, &[T(Paren::Open), s, T(Paren::Close)])
+            .with_production(s, &[T(Paren::Atom)]);
+        grammar
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub enum Paren {
+        Open,
+        Close,
+        Atom,
+    }
+
+    // What `to_rust` writes for `paren_grammar`, which is compiled here to check that it's valid,
+    // and kept as text to compare it with.
+    macro_rules! compiled {
+        ($($source:tt)*) => {
+            $($source)*
+            const PAREN_TABLE: &str = stringify!($($source)*);
+        };
+    }
+
+    mod turkey {
+        pub use crate::parse;
+    }
+
+    #[rustfmt::skip]
+    compiled! {
+        pub fn paren_table() -> turkey::parse::lr::LrTable<Paren> {
+            turkey::parse::lr::LrTable::from_parts(
+                vec![Paren::Open, Paren::Close, Paren::Atom, ],
+                vec![(0, 3), (0, 1)],
+                vec![(0, 0)],
+                vec![
+                    vec![(0, turkey::parse::lr::LrAction::Shift(1)), (2, turkey::parse::lr::LrAction::Shift(2)), ],
+                    vec![(0, turkey::parse::lr::LrAction::Shift(1)), (2, turkey::parse::lr::LrAction::Shift(2)), ],
+                    vec![(1, turkey::parse::lr::LrAction::Reduce(1)), (3, turkey::parse::lr::LrAction::Reduce(1)), ],
+                    vec![(3, turkey::parse::lr::LrAction::Accept), ],
+                    vec![(1, turkey::parse::lr::LrAction::Shift(5)), ],
+                    vec![(1, turkey::parse::lr::LrAction::Reduce(0)), (3, turkey::parse::lr::LrAction::Reduce(0)), ],
+                ],
+                vec![
+                    vec![(0, 3)],
+                    vec![(0, 4)],
+                    vec![],
+                    vec![],
+                    vec![],
+                    vec![],
+                ],
+            )
+        }
+    }
+
+    #[test]
+    fn test_to_rust_enum() {
+        let table = LrTable::new(&paren_grammar());
+        let source = table.to_rust("paren_table", "Paren", |t| format!("Paren::{:?}", t));
+        let tokens = |source: &str| source.split_whitespace().collect::<String>();
+        assert_eq!(tokens(&source), tokens(PAREN_TABLE));
+        assert_eq!(paren_table(), table);
+    }
+
+    #[test]
+    fn test_to_rust() {
+        let table = LrTable::new(&expression_grammar());
+        let source = table.to_rust("expression_table", "char", |c| format!("{:?}", c));
+
+        assert!(
+            source.starts_with("pub fn expression_table() -> turkey::parse::lr::LrTable<char> {\n")
+        );
+        assert!(source.contains("vec!['+', "));
+        assert!(source.contains("turkey::parse::lr::LrAction::Accept"));
+
+        // The parts written out rebuild the same table.
+        let rebuilt = LrTable::from_parts(
+            table.terminals.clone(),
+            table.productions.clone(),
+            table.entries.clone(),
+            table
+                .actions
+                .iter()
+                .map(|actions| actions.iter().map(|(&t, &a)| (t, a)).collect())
+                .collect(),
+            table
+                .gotos
+                .iter()
+                .map(|gotos| gotos.iter().map(|(&n, &s)| (n, s)).collect())
+                .collect(),
+        );
+        assert_eq!(rebuilt, table);
+        assert_eq!(evaluate(&rebuilt, "6*7"), Ok(42));
+    }
+}
@@ -0,0 +1,3 @@
+pub mod grammar;
+pub mod lr;
+pub mod pratt;
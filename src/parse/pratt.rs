@@ -0,0 +1,248 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::hash::Hash;
+use std::iter::Peekable;
+
+// One item of an expression: an operator named by `K`, or an operand already built.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PrattItem<K, V> {
+    Operator(K),
+    Operand(V),
+}
+
+// Builds the value of each operator applied, as the parser finds them.
+pub trait PrattBuilder<K> {
+    type Value;
+
+    fn prefix(&mut self, operator: K, operand: Self::Value) -> Self::Value;
+    fn infix(&mut self, operator: K, lhs: Self::Value, rhs: Self::Value) -> Self::Value;
+    fn postfix(&mut self, operator: K, operand: Self::Value) -> Self::Value;
+}
+
+// `index` is the position of the offending item, or the number of items if they ran out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrattError {
+    // An operand or prefix operator was needed, e.g. after an infix operator.
+    ExpectedOperand { index: usize },
+    // An infix or postfix operator was needed, e.g. between two operands.
+    ExpectedOperator { index: usize },
+}
+
+impl fmt::Display for PrattError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PrattError::ExpectedOperand { index } => write!(f, "expected an operand at {}", index),
+            PrattError::ExpectedOperator { index } => {
+                write!(f, "expected an operator at {}", index)
+            }
+        }
+    }
+}
+
+impl Error for PrattError {}
+
+// The operators of an expression language and their binding powers. An operator binds to an
+// operand on a side where its power is higher than that of the operator on the other side of the
+// operand, so `*` binding more tightly than `+` has higher powers, and a left associative infix
+// operator has a right power higher than its left. The same operator can be both prefix and infix,
+// like `-`, as which one is meant depends on whether an operand is expected.
+#[derive(Debug, Clone)]
+pub struct Operators<K> {
+    prefix: HashMap<K, u8>,
+    infix: HashMap<K, (u8, u8)>,
+    postfix: HashMap<K, u8>,
+}
+
+impl<K> Default for Operators<K> {
+    fn default() -> Self {
+        Operators {
+            prefix: HashMap::new(),
+            infix: HashMap::new(),
+            postfix: HashMap::new(),
+        }
+    }
+}
+
+impl<K> Operators<K>
+where
+    K: Clone + Eq + Hash,
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_prefix(&mut self, operator: K, right: u8) {
+        self.prefix.insert(operator, right);
+    }
+
+    pub fn add_infix(&mut self, operator: K, left: u8, right: u8) {
+        self.infix.insert(operator, (left, right));
+    }
+
+    pub fn add_postfix(&mut self, operator: K, left: u8) {
+        self.postfix.insert(operator, left);
+    }
+
+    pub fn with_prefix(&mut self, operator: K, right: u8) -> &mut Self {
+        self.add_prefix(operator, right);
+        self
+    }
+
+    pub fn with_infix(&mut self, operator: K, left: u8, right: u8) -> &mut Self {
+        self.add_infix(operator, left, right);
+        self
+    }
+
+    pub fn with_postfix(&mut self, operator: K, left: u8) -> &mut Self {
+        self.add_postfix(operator, left);
+        self
+    }
+
+    pub fn is_operator(&self, key: &K) -> bool {
+        self.prefix.contains_key(key)
+            || self.infix.contains_key(key)
+            || self.postfix.contains_key(key)
+    }
+
+    // Parse `items` as a single expression.
+    pub fn parse<I, B>(&self, items: I, builder: &mut B) -> Result<B::Value, PrattError>
+    where
+        I: IntoIterator<Item = PrattItem<K, B::Value>>,
+        B: PrattBuilder<K>,
+    {
+        let items = items.into_iter().collect::<Vec<_>>();
+        let end = items.len();
+
+        let mut items = items.into_iter().enumerate().peekable();
+        let value = self.expression(&mut items, end, 0, builder)?;
+
+        match items.next() {
+            None => Ok(value),
+            Some((index, _)) => Err(PrattError::ExpectedOperator { index }),
+        }
+    }
+
+    // Parse the longest expression whose operators all bind more tightly than `min`. `end` is the
+    // number of items, where an error at the end of them is.
+    fn expression<I, B>(
+        &self,
+        items: &mut Peekable<I>,
+        end: usize,
+        min: u8,
+        builder: &mut B,
+    ) -> Result<B::Value, PrattError>
+    where
+        I: Iterator<Item = (usize, PrattItem<K, B::Value>)>,
+        B: PrattBuilder<K>,
+    {
+        let mut lhs = match items.next() {
+            Some((_, PrattItem::Operand(value))) => value,
+            Some((_, PrattItem::Operator(operator))) if self.prefix.contains_key(&operator) => {
+                let right = self.prefix[&operator];
+                let operand = self.expression(items, end, right, builder)?;
+                builder.prefix(operator, operand)
+            }
+            Some((index, PrattItem::Operator(_))) => {
+                return Err(PrattError::ExpectedOperand { index })
+            }
+            None => return Err(PrattError::ExpectedOperand { index: end }),
+        };
+
+        loop {
+            let operator = match items.peek() {
+                None => return Ok(lhs),
+                Some((index, PrattItem::Operand(_))) => {
+                    return Err(PrattError::ExpectedOperator { index: *index })
+                }
+                Some((_, PrattItem::Operator(operator))) => operator.clone(),
+            };
+
+            if let Some(&left) = self.postfix.get(&operator) {
+                if left < min {
+                    return Ok(lhs);
+                }
+                items.next();
+                lhs = builder.postfix(operator, lhs);
+            } else if let Some(&(left, right)) = self.infix.get(&operator) {
+                if left < min {
+                    return Ok(lhs);
+                }
+                items.next();
+                let rhs = self.expression(items, end, right, builder)?;
+                lhs = builder.infix(operator, lhs, rhs);
+            } else {
+                let (index, _) = items.next().unwrap();
+                return Err(PrattError::ExpectedOperator { index });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Writes expressions out fully bracketed.
+    struct Brackets;
+
+    impl PrattBuilder<char> for Brackets {
+        type Value = String;
+
+        fn prefix(&mut self, operator: char, operand: String) -> String {
+            format!("({}{})", operator, operand)
+        }
+
+        fn infix(&mut self, operator: char, lhs: String, rhs: String) -> String {
+            format!("({} {} {})", lhs, operator, rhs)
+        }
+
+        fn postfix(&mut self, operator: char, operand: String) -> String {
+            format!("({}{})", operand, operator)
+        }
+    }
+
+    fn operators() -> Operators<char> {
+        let mut operators = Operators::new();
+        operators
+            .with_infix('+', 1, 2)
+            .with_infix('-', 1, 2)
+            .with_infix('*', 3, 4)
+            .with_infix('^', 8, 7)
+            .with_prefix('-', 5)
+            .with_postfix('!', 9);
+        operators
+    }
+
+    fn parse(input: &str) -> Result<String, PrattError> {
+        let operators = operators();
+        let items = input.chars().filter(|c| *c != ' ').map(|c| match c {
+            c if operators.is_operator(&c) => PrattItem::Operator(c),
+            c => PrattItem::Operand(c.to_string()),
+        });
+        operators.parse(items, &mut Brackets)
+    }
+
+    #[test]
+    fn test_precedence() {
+        assert_eq!(parse("1 + 2 * x").unwrap(), "(1 + (2 * x))");
+        assert_eq!(parse("a - b - c").unwrap(), "((a - b) - c)");
+        assert_eq!(parse("a ^ b ^ c").unwrap(), "(a ^ (b ^ c))");
+        assert_eq!(parse("-a * b").unwrap(), "((-a) * b)");
+        assert_eq!(parse("-a ^ b").unwrap(), "(-(a ^ b))");
+        assert_eq!(parse("a - -b!").unwrap(), "(a - (-(b!)))");
+        assert_eq!(parse("x").unwrap(), "x");
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(parse(""), Err(PrattError::ExpectedOperand { index: 0 }));
+        assert_eq!(parse("1 +"), Err(PrattError::ExpectedOperand { index: 2 }));
+        assert_eq!(
+            parse("1 + * 2"),
+            Err(PrattError::ExpectedOperand { index: 2 })
+        );
+        assert_eq!(parse("1 2"), Err(PrattError::ExpectedOperator { index: 1 }));
+        assert_eq!(parse("! 2"), Err(PrattError::ExpectedOperand { index: 0 }));
+    }
+}